@@ -0,0 +1,87 @@
+use std::{collections::VecDeque, sync::mpsc};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ppk2::{
+    measurement::{MeasurementAccumulator, MeasurementIterExt},
+    types::{LogicPortPins, Metadata},
+};
+
+/// One second worth of synthetic raw samples, with incrementing counter
+/// and varying ranges and logic port state.
+fn raw_stream() -> Vec<u8> {
+    (0..100_000u32)
+        .flat_map(|i| {
+            let adc = (i * 7) % 0x3FFF;
+            let range = (i / 1000) % 5;
+            let counter = i % 64;
+            let logic = i % 256;
+            (adc | range << 14 | counter << 18 | logic << 24).to_le_bytes()
+        })
+        .collect()
+}
+
+fn parse(c: &mut Criterion) {
+    let bytes = raw_stream();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+
+    group.bench_function("feed_into/4 bytes", |b| {
+        b.iter_batched(
+            || MeasurementAccumulator::new(Metadata::default()),
+            |mut acc| {
+                let mut buf = VecDeque::with_capacity(100_000);
+                for chunk in bytes.chunks(4) {
+                    acc.feed_into(black_box(chunk), &mut buf);
+                }
+                buf
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("feed_into/4096 bytes", |b| {
+        b.iter_batched(
+            || MeasurementAccumulator::new(Metadata::default()),
+            |mut acc| {
+                let mut buf = VecDeque::with_capacity(100_000);
+                for chunk in bytes.chunks(4096) {
+                    acc.feed_into(black_box(chunk), &mut buf);
+                }
+                buf
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn combine(c: &mut Criterion) {
+    let bytes = raw_stream();
+    let mut acc = MeasurementAccumulator::new(Metadata::default());
+    let mut measurements = VecDeque::new();
+    acc.feed_into(&bytes, &mut measurements);
+
+    let mut group = c.benchmark_group("combine");
+    group.throughput(Throughput::Elements(measurements.len() as u64));
+    group.bench_function("combine_matching/1000 samples", |b| {
+        b.iter_batched(
+            || measurements.clone(),
+            |mut measurements| {
+                let (tx, rx) = mpsc::channel();
+                while !measurements.is_empty() {
+                    let end = measurements.len().min(1000);
+                    let m = measurements
+                        .drain(..end)
+                        .combine_matching(0, LogicPortPins::default());
+                    tx.send(m).unwrap();
+                }
+                rx.try_iter().count()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, combine);
+criterion_main!(benches);
@@ -1,15 +1,37 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
 use ppk2::{
-    types::{DevicePower, MeasurementMode, SourceVoltage, LogicPortPins, Level},
-    Ppk2, try_find_ppk2_port, measurement::MeasurementMatch,
+    autozero::AutoZero,
+    clock::SampleClock,
+    export::{self, ExportInfo, ExporterRegistry},
+    measurement::{
+        ByteStats, MeasurementAccumulator, MeasurementEvent, MeasurementMatch, MeasurementOptions,
+        WindowPolicy,
+    },
+    notify::{self, CommandNotifier},
+    ppk2_file,
+    presets::BoardPreset,
+    protocol::ProtocolDescription,
+    session::Session,
+    trigger::{Edge, SoftwareTrigger, Trigger},
+    try_find_ppk2_port,
+    types::{
+        CurrentArg, DevicePower, DurationArg, Level, LogicPortPins, MeasurementMode, Metadata,
+        SampleRate, SourceVoltage, WindowSpec,
+    },
+    Ppk2, StopHandle,
 };
 
 use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::PathBuf,
     sync::mpsc::RecvTimeoutError,
-    time::{Duration, Instant},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tracing::{debug, error, info, Level as LogLevel};
+use tracing::{debug, error, info, warn, Level as LogLevel};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser)]
@@ -56,10 +78,210 @@ struct Args {
         env,
         short = 's',
         long,
-        help = "The maximum number of samples to be taken per second. Uses averaging of device samples Samples are analyzed in chunks, and as such the actual number of samples per second will deviate",
-        default_value = "100"
+        help = "The maximum number of samples to be taken per second. Uses averaging of device samples Samples are analyzed in chunks, and as such the actual number of samples per second will deviate. Defaults to the recommended value of the board preset, or 100"
     )]
-    sps: usize,
+    sps: Option<SampleRate>,
+
+    #[clap(
+        env,
+        short = 'w',
+        long,
+        conflicts_with = "sps",
+        help = "Combine device samples in windows of this length, e.g. 10ms or 1000samples. Alternative to --sps"
+    )]
+    window: Option<WindowSpec>,
+
+    #[clap(
+        env,
+        short = 'd',
+        long,
+        help = "Stop measuring after this duration, e.g. 30s or 1h30m. Measures until interrupted if unspecified"
+    )]
+    duration: Option<DurationArg>,
+
+    #[clap(
+        env,
+        short = 'b',
+        long,
+        help = "Board preset used to classify the measured current: [nrf52840dk | nrf52dk | nrf5340dk | nrf9160dk | thingy53 | thingy91]"
+    )]
+    board: Option<BoardPreset>,
+
+    #[clap(
+        env,
+        long,
+        help = "Emit an overcurrent event when the averaged current exceeds this value, e.g. 1.5mA or 250uA"
+    )]
+    overcurrent: Option<CurrentArg>,
+
+    #[clap(
+        env,
+        long,
+        help = "Compensate drift by briefly disabling device power at this interval to measure the offset, e.g. 10min"
+    )]
+    auto_zero: Option<DurationArg>,
+
+    #[clap(
+        long,
+        help = "Write the raw bytes read from the device to this file, and the device metadata next to it with the .metadata extension. Convert them with the convert subcommand"
+    )]
+    raw_dump: Option<PathBuf>,
+
+    #[clap(
+        env,
+        long,
+        help = "Capture the samples around every rise of the current above this value, e.g. 5mA"
+    )]
+    trigger: Option<CurrentArg>,
+
+    #[clap(
+        env,
+        long,
+        conflicts_with = "trigger",
+        help = "Capture the samples around every edge of this logic port pin, 0 to 7"
+    )]
+    trigger_pin: Option<usize>,
+
+    #[clap(
+        env,
+        long,
+        default_value = "rising",
+        help = "The edge of --trigger-pin to capture: [rising | falling]"
+    )]
+    trigger_edge: Edge,
+
+    #[clap(
+        env,
+        long,
+        default_value = "1ms",
+        help = "Window captured before the trigger, e.g. 1ms or 100samples"
+    )]
+    pre: WindowSpec,
+
+    #[clap(
+        env,
+        long,
+        default_value = "10ms",
+        help = "Window captured after the trigger, e.g. 10ms or 1000samples"
+    )]
+    post: WindowSpec,
+
+    #[clap(
+        env,
+        long,
+        help = "Command to run on measurement events. The event is passed in the PPK2_EVENT and PPK2_EVENT_DETAILS environment variables. Defaults to the notify entry of the device settings, see ppk2::settings"
+    )]
+    on_event: Option<String>,
+
+    #[clap(
+        env,
+        short = 'o',
+        long,
+        help = "Write the combined measurements with this exporter: [csv | app-csv | logic-csv | vcd | ppk2 | custom:<name>]"
+    )]
+    output: Option<String>,
+
+    #[clap(
+        env,
+        long,
+        requires = "output",
+        help = "The file to write the measurements to. Defaults to capture with the extension of the format"
+    )]
+    output_path: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a raw dump of device samples to another format
+    Convert(ConvertArgs),
+    /// Diagnose the environment: device presence, permissions and data rate
+    Doctor,
+    /// Show the current like a multimeter. Press Enter to exit
+    Meter(MeterArgs),
+    /// Print a JSON description of the serial protocol
+    Protocol,
+}
+
+#[derive(Parser)]
+struct MeterArgs {
+    #[clap(
+        short = 'r',
+        long,
+        default_value = "250ms",
+        help = "How often the displayed value is updated, e.g. 250ms or 1s"
+    )]
+    refresh: DurationArg,
+}
+
+#[derive(Parser)]
+struct ConvertArgs {
+    #[clap(help = "The raw dump or .ppk2 file to convert")]
+    input: PathBuf,
+
+    #[clap(
+        long,
+        help = "File containing the metadata of the device the dump was recorded with, as reported by the device. Required for raw dumps"
+    )]
+    metadata: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "The exporter to convert with: [csv | app-csv | logic-csv | vcd | ppk2 | custom:<name>]"
+    )]
+    to: String,
+
+    #[clap(
+        short = 'o',
+        long,
+        help = "The output file. Defaults to the input file with the extension of the format"
+    )]
+    output: Option<PathBuf>,
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+    let (session, start) = if args.input.extension().is_some_and(|e| e == "ppk2") {
+        let file = ppk2_file::read_session(BufReader::new(File::open(&args.input)?))?;
+        (file.session, file.start)
+    } else {
+        let Some(metadata) = &args.metadata else {
+            bail!("--metadata is required to convert raw dumps");
+        };
+        let metadata = Metadata::from_reader(File::open(metadata)?)?;
+        let raw = fs::read(&args.input)?;
+
+        // Fill gaps, as the exported timestamps are derived from the sample index
+        let mut accumulator = MeasurementAccumulator::new(metadata).interpolate_gaps(64);
+        let mut measurements = VecDeque::new();
+        let missed = accumulator.feed_into(&raw, &mut measurements);
+        if missed > 0 {
+            warn!("{missed} samples are missing from the dump and were interpolated");
+        }
+        let session = Session::from_measurements(SampleClock::nominal(), measurements.into());
+        (session, None)
+    };
+
+    let mut registry = ExporterRegistry::new();
+    register_plugins(&mut registry);
+    let output = args.output.unwrap_or_else(|| {
+        args.input
+            .with_extension(registry.extension(&args.to).unwrap_or("out"))
+    });
+    let mut exporter = registry.create(&args.to, &output)?;
+    // Raw dumps don't record when they were made, so use the modification time
+    let start = match start {
+        Some(start) => start,
+        None => fs::metadata(&args.input)?.modified()?,
+    };
+    export::export_session(exporter.as_mut(), &session, start)?;
+    info!(
+        "Converted {} samples to {}",
+        session.len(),
+        output.display()
+    );
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -71,8 +293,151 @@ fn main() -> Result<()> {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let ppk2_port = match args.serial_port {
-        Some(p) => p,
+    match args.command {
+        Some(Command::Convert(convert_args)) => convert(convert_args),
+        Some(Command::Doctor) => doctor(args.serial_port, args.mode),
+        Some(Command::Meter(ref meter_args)) => meter(&args, meter_args),
+        Some(Command::Protocol) => {
+            print!("{}", ProtocolDescription::generate().to_json());
+            Ok(())
+        }
+        None => measure(args),
+    }
+}
+
+fn check(ok: bool, what: &str, advice: &str) -> bool {
+    if ok {
+        println!("[ok] {what}");
+    } else {
+        println!("[!!] {what}\n     {advice}");
+    }
+    ok
+}
+
+fn doctor(serial_port: Option<String>, mode: MeasurementMode) -> Result<()> {
+    // Device presence
+    let port = match serial_port {
+        Some(p) => Ok(p),
+        None => try_find_ppk2_port(),
+    };
+    let found = check(
+        port.is_ok(),
+        "PPK2 found",
+        "Make sure the PPK2 is connected through the USB DATA/POWER port and switched on. \
+         If it is, pass the serial port explicitly with --serial-port.",
+    );
+    if !found {
+        for p in serialport::available_ports().unwrap_or_default() {
+            println!("     Available port: {} ({:?})", p.port_name, p.port_type);
+        }
+        return Ok(());
+    }
+    let port = port?;
+    println!("     Port: {port}");
+
+    // Permissions
+    let access = fs::OpenOptions::new().read(true).write(true).open(&port);
+    let permission_denied =
+        matches!(&access, Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied);
+    if !check(
+        !permission_denied,
+        "Port is accessible",
+        &permission_advice(&port),
+    ) {
+        return Ok(());
+    }
+    drop(access);
+
+    // Opening and metadata
+    let mut ppk2 = match Ppk2::new(port.as_str(), mode) {
+        Ok(ppk2) => ppk2,
+        Err(e) => {
+            check(
+                false,
+                &format!("Device responds: {e}"),
+                "Close other applications using the device, such as the nRF Connect Power Profiler, \
+                 and power cycle the PPK2.",
+            );
+            return Ok(());
+        }
+    };
+    check(true, "Device responds", "");
+    let metadata = ppk2.get_metadata()?;
+    let warnings = metadata.calibration_warnings();
+    check(
+        warnings.is_empty(),
+        "Device calibration is plausible",
+        "Measurements may be inaccurate. Calibrate the device using the nRF Connect Power Profiler.",
+    );
+    if !warnings.is_empty() {
+        for line in metadata.calibration_info().to_string().lines() {
+            println!("     {line}");
+        }
+    }
+    check(
+        (800..=5000).contains(&metadata.vdd) && metadata.hw != 0,
+        &format!(
+            "Metadata is sane (VDD {} mV, HW {})",
+            metadata.vdd, metadata.hw
+        ),
+        "Update the PPK2 firmware using the nRF Connect Power Profiler.",
+    );
+
+    // Data rate
+    let (rx, handle) =
+        ppk2.start_measurement_with(MeasurementOptions::new(SampleRate::default()))?;
+    thread::sleep(Duration::from_secs(2));
+    let byte_stats = handle.byte_stats();
+    let serial_errors = handle.serial_errors();
+    handle.stop()?;
+    drop(rx);
+    check(
+        byte_stats.ratio() > 0.95,
+        &format!(
+            "Data rate is {:.0} bytes per second ({:.1}% of the expected {:.0})",
+            byte_stats.bytes_per_second(),
+            byte_stats.ratio() * 100.,
+            ByteStats::EXPECTED_BYTES_PER_SECOND
+        ),
+        "Connect the PPK2 directly rather than through a hub, and avoid running on a heavily loaded host.",
+    );
+    check(
+        !serial_errors.suspects_data_loss(),
+        "No data lost on the serial connection",
+        "The host doesn't keep up with the device. Close other programs or use a faster host.",
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permission_advice(port: &str) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let group = fs::metadata(port).ok().and_then(|m| {
+        let gid = m.gid().to_string();
+        fs::read_to_string("/etc/group")
+            .ok()?
+            .lines()
+            .find(|l| l.split(':').nth(2) == Some(gid.as_str()))
+            .and_then(|l| l.split(':').next().map(str::to_owned))
+    });
+    match group {
+        Some(group) => format!(
+            "Add your user to the '{group}' group with `sudo usermod -aG {group} $USER` \
+             and log in again, or install the nRF udev rules."
+        ),
+        None => "Install the nRF udev rules, or run with sufficient permissions.".to_owned(),
+    }
+}
+
+#[cfg(not(unix))]
+fn permission_advice(_port: &str) -> String {
+    "Run with sufficient permissions.".to_owned()
+}
+
+fn connect(args: &Args) -> Result<Ppk2> {
+    let ppk2_port = match &args.serial_port {
+        Some(p) => p.clone(),
         None => try_find_ppk2_port()?,
     };
 
@@ -80,6 +445,72 @@ fn main() -> Result<()> {
     let mut ppk2 = Ppk2::new(ppk2_port, args.mode)?;
     ppk2.set_source_voltage(args.voltage)?;
     ppk2.set_device_power(args.power)?;
+    Ok(ppk2)
+}
+
+/// Register third-party exporters, selectable with `--output custom:<name>`.
+/// Add them here behind a cargo feature of their own, like
+/// `#[cfg(feature = "my-format")] registry.register_custom("my-format", "bin", my_format::create);`
+fn register_plugins(_registry: &mut ExporterRegistry) {}
+
+/// Time of an event since the start of the measurement, if it has one.
+fn event_time(event: &MeasurementEvent) -> Option<Duration> {
+    let clock = SampleClock::nominal();
+    match event {
+        MeasurementEvent::Overcurrent { sample, .. }
+        | MeasurementEvent::TagChange { sample, .. }
+        | MeasurementEvent::LogicEdge { sample, .. } => Some(clock.time_at(*sample as u64)),
+        MeasurementEvent::SuspectedDutReset { time, .. } => Some(*time),
+        MeasurementEvent::AutoZero(correction) => Some(correction.time),
+        MeasurementEvent::HostSuspended(suspension) => Some(suspension.time),
+        MeasurementEvent::Reconnected(gap) => Some(gap.time),
+        MeasurementEvent::IrDrop(update) => Some(update.time),
+        _ => None,
+    }
+}
+
+/// Format a current with the largest unit it's at least 1 in.
+fn format_current(micro_amps: f32) -> String {
+    match micro_amps.abs() {
+        a if a >= 1e6 => format!("{:.4} A", micro_amps / 1e6),
+        a if a >= 1e3 => format!("{:.4} mA", micro_amps / 1e3),
+        a if a >= 1. => format!("{:.3} µA", micro_amps),
+        _ => format!("{:.1} nA", micro_amps * 1e3),
+    }
+}
+
+fn meter(args: &Args, meter_args: &MeterArgs) -> Result<()> {
+    let ppk2 = connect(args)?;
+    let refresh = Duration::from(meter_args.refresh);
+    let options =
+        MeasurementOptions::new(SampleRate::per_second(1)?).window(WindowPolicy::Duration(refresh));
+    let (rx, handle) = ppk2.start_measurement_with(options)?;
+
+    // Stop on Enter or Ctrl-C
+    let stop = handle.stop_handle();
+    ctrlc::set_handler({
+        let stop = stop.clone();
+        move || stop.stop()
+    })?;
+    thread::spawn(move || {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        stop.stop();
+    });
+
+    let mut stdout = std::io::stdout();
+    for measurement in rx {
+        if let MeasurementMatch::Match(m) = measurement {
+            write!(stdout, "\r{:>14}", format_current(m.micro_amps))?;
+            stdout.flush()?;
+        }
+    }
+    writeln!(stdout)?;
+    handle.stop()?;
+    Ok(())
+}
+
+fn measure(args: Args) -> Result<()> {
+    let ppk2 = connect(&args)?;
 
     // Set up pin pattern for matching
     // This particular setup will only
@@ -89,13 +520,122 @@ fn main() -> Result<()> {
     let pins = LogicPortPins::with_levels(levels);
 
     // Start measuring.
-    let (rx, kill) = ppk2.start_measurement_matching(pins, args.sps)?;
-
-    // Set up sigkill handler.
-    let mut kill = Some(kill);
-    ctrlc::set_handler(move || {
-        kill.take().unwrap()().unwrap();
+    let clock = ppk2.native_clock();
+    let sps = match (args.window, args.sps, &args.board) {
+        (Some(w), _, _) => {
+            let sps = (clock.rate() as usize / w.samples(&clock).max(1))
+                .clamp(1, SampleRate::max().get());
+            SampleRate::per_second(sps)?
+        }
+        (None, Some(sps), _) => sps,
+        (None, None, Some(board)) => SampleRate::per_second(board.recommended_sps)?,
+        (None, None, None) => SampleRate::default(),
+    };
+    // Stop on Ctrl-C, also while the measurement is starting
+    let stop = StopHandle::new();
+    ctrlc::set_handler({
+        let stop = stop.clone();
+        move || stop.stop()
     })?;
+    let mut options = MeasurementOptions::new(sps)
+        .matching(pins)
+        .stop_handle(stop.clone());
+    if let Some(window) = args.window {
+        // Evenly spaced windows, also when samples are lost
+        options = options.window(WindowPolicy::Duration(window.duration(&clock)));
+    }
+    if let Some(limit) = args.overcurrent {
+        options = options.overcurrent(limit.into());
+    }
+    if let Some(interval) = args.auto_zero {
+        options = options.auto_zero(AutoZero::new(interval.into()));
+    }
+    if let Some(path) = &args.raw_dump {
+        ppk2.metadata()
+            .to_writer(File::create(path.with_extension("metadata"))?)?;
+        options = options.raw_dump(path);
+    }
+    let trigger = match (args.trigger, args.trigger_pin) {
+        (Some(threshold), _) => Some(SoftwareTrigger::new(threshold.into())),
+        (None, Some(pin)) => Some(SoftwareTrigger::on(Trigger::PinEdge {
+            pin,
+            edge: args.trigger_edge,
+        })),
+        (None, None) => None,
+    };
+    if let Some(trigger) = trigger {
+        options = options.software_trigger(trigger.pre(args.pre).post(args.post));
+    }
+    let mut exporter = match &args.output {
+        Some(name) => {
+            let mut registry = ExporterRegistry::new();
+            register_plugins(&mut registry);
+            let path = args.output_path.clone().unwrap_or_else(|| {
+                PathBuf::from("capture").with_extension(registry.extension(name).unwrap_or("out"))
+            });
+            let mut exporter = registry.create(name, &path)?;
+            let chunk = match args.window {
+                Some(window) => window.samples(&clock).max(1),
+                None => clock.chunk_len(sps.get()),
+            };
+            exporter.start(&ExportInfo {
+                clock: SampleClock::with_rate(clock.rate() / chunk as f64),
+                metadata: None,
+                start: SystemTime::now(),
+            })?;
+            info!("Writing measurements to {}", path.display());
+            Some(exporter)
+        }
+        None => None,
+    };
+    // The command line takes precedence over the device profile
+    let notifier = match &args.on_event {
+        Some(command) => Some(CommandNotifier::new(command)),
+        None => ppk2.cached_notifier()?,
+    };
+    let (rx, handle) = ppk2.start_measurement_with(options)?;
+    let annotations = exporter.as_ref().map(|_| handle.subscribe());
+    if trigger.is_some() {
+        let captures = handle.subscribe_captures();
+        thread::spawn(move || {
+            for capture in captures {
+                let max = capture
+                    .measurements
+                    .iter()
+                    .map(|m| m.micro_amps)
+                    .fold(f32::MIN, f32::max);
+                info!(
+                    "Triggered at {:.6?}: {} samples, peak {:.4} μA",
+                    clock.time_at(capture.sample as u64),
+                    capture.measurements.len(),
+                    max
+                );
+            }
+        });
+    }
+    if let Some(notifier) = notifier {
+        notify::spawn(handle.subscribe(), notifier);
+    }
+    if args.auto_zero.is_some() {
+        let events = handle.subscribe();
+        thread::spawn(move || {
+            for event in events {
+                if let MeasurementEvent::AutoZero(correction) = event {
+                    info!(
+                        "Auto-zero at {:.3?}: offset {:.4} μA",
+                        correction.time, correction.offset
+                    );
+                }
+            }
+        });
+    }
+
+    if let Some(duration) = args.duration {
+        thread::spawn(move || {
+            thread::sleep(duration.into());
+            stop.stop();
+        });
+    }
 
     // Receive measurements
     let mut count = 0usize;
@@ -106,7 +646,21 @@ fn main() -> Result<()> {
         use MeasurementMatch::*;
         match rcv_res {
             Ok(Match(m)) => {
-                debug!("Last chunk average: {:.4} μA", m.micro_amps);
+                let envelope = m
+                    .envelope
+                    .map(|e| format!(", min {:.4} μA, max {:.4} μA", e.min, e.max))
+                    .unwrap_or_default();
+                match &args.board {
+                    Some(board) => debug!(
+                        "Last chunk average: {:.4} μA{envelope} ({:?})",
+                        m.micro_amps,
+                        board.classify(m.micro_amps)
+                    ),
+                    None => debug!("Last chunk average: {:.4} μA{envelope}", m.micro_amps),
+                }
+                if let Some(exporter) = &mut exporter {
+                    exporter.write_batch(std::slice::from_ref(&m))?;
+                }
             }
             Ok(NoMatch) => {
                 debug!("No match in the last chunk of measurements");
@@ -117,10 +671,46 @@ fn main() -> Result<()> {
                 break Err(e)?;
             }
         }
+        if let (Some(exporter), Some(events)) = (&mut exporter, &annotations) {
+            for event in events.try_iter() {
+                if let Some(time) = event_time(&event) {
+                    exporter
+                        .annotate(time, &format!("{}: {event:?}", notify::event_kind(&event)))?;
+                }
+            }
+        }
     };
+    if let Some(exporter) = &mut exporter {
+        exporter.finish()?;
+    }
     let sample_time = Instant::now().duration_since(start).as_secs() as usize;
     info!("Samples per second: {}", count / sample_time);
+    let byte_stats = handle.byte_stats();
+    info!(
+        "Bytes read per second: {:.0} ({:.1}% of the expected {:.0})",
+        byte_stats.bytes_per_second(),
+        byte_stats.ratio() * 100.,
+        ByteStats::EXPECTED_BYTES_PER_SECOND
+    );
+    info!("Quality of service: {}", handle.qos_report());
+    debug!("Pipeline metrics: {:?}", handle.metrics());
+    let charge = handle.charge();
+    info!(
+        "Charge: {:.3} µAh over {:.1?} (average {:.4} μA)",
+        charge.micro_amp_hours,
+        charge.duration,
+        charge.avg_micro_amps()
+    );
+    let serial_errors = handle.serial_errors();
+    if serial_errors.suspects_data_loss() {
+        warn!(
+            "Data was lost on the serial connection: {} samples lost unnoticed, driver errors: {:?}",
+            serial_errors.suspected_lost_samples(),
+            serial_errors.os
+        );
+    }
     info!("Stopping measurements and resetting");
+    handle.stop()?;
     info!("Goodbye!");
     r
 }
@@ -0,0 +1,109 @@
+//! Measures host-side throughput of the measurement parsing pipeline,
+//! using a synthetic raw sample stream. No device needs to be connected.
+use anyhow::Result;
+use clap::Parser;
+use ppk2::{
+    clock::SampleClock,
+    measurement::{MeasurementAccumulator, MeasurementIterExt, MeasurementMatch},
+    types::{LogicPortPins, Metadata},
+};
+use std::{
+    collections::VecDeque,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Parser)]
+struct Args {
+    #[clap(
+        short = 'n',
+        long,
+        help = "The number of seconds worth of device samples to parse",
+        default_value = "10"
+    )]
+    seconds: u32,
+
+    #[clap(
+        short = 'r',
+        long,
+        help = "The number of bytes fed to the accumulator at once",
+        default_value = "4"
+    )]
+    read_size: usize,
+
+    #[clap(
+        short = 's',
+        long,
+        help = "The number of combined measurements per second",
+        default_value = "100"
+    )]
+    sps: usize,
+}
+
+fn raw_stream(seconds: u32) -> Vec<u8> {
+    (0..seconds * SampleClock::NOMINAL_RATE as u32)
+        .flat_map(|i| {
+            let adc = (i * 7) % 0x3FFF;
+            let range = (i / 1000) % 5;
+            let counter = i % 64;
+            let logic = i % 256;
+            (adc | range << 14 | counter << 18 | logic << 24).to_le_bytes()
+        })
+        .collect()
+}
+
+fn rate(n: usize, elapsed: Duration) -> f64 {
+    n as f64 / elapsed.as_secs_f64()
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let bytes = raw_stream(args.seconds);
+    let clock = SampleClock::nominal();
+    let chunk_size = clock.chunk_len(args.sps);
+
+    // Parsing only
+    let mut acc = MeasurementAccumulator::new(Metadata::default());
+    let mut buf = VecDeque::with_capacity(SampleClock::NOMINAL_RATE);
+    let mut samples = 0;
+    let start = Instant::now();
+    for chunk in bytes.chunks(args.read_size) {
+        acc.feed_into(chunk, &mut buf);
+        samples += buf.len();
+        buf.clear();
+    }
+    let parse_time = start.elapsed();
+
+    // Parsing, combining and sending over a channel, like the measurement worker does
+    let (tx, rx) = mpsc::channel::<MeasurementMatch>();
+    let receiver = thread::spawn(move || rx.iter().count());
+    let mut acc = MeasurementAccumulator::new(Metadata::default());
+    let start = Instant::now();
+    for chunk in bytes.chunks(args.read_size) {
+        acc.feed_into(chunk, &mut buf);
+        if buf.len() >= chunk_size {
+            tx.send(buf.drain(..).combine_matching(0, LogicPortPins::default()))?;
+        }
+    }
+    drop(tx);
+    let delivered = receiver.join().expect("Receiver thread panicked");
+    let pipeline_time = start.elapsed();
+
+    println!(
+        "Parsed {} bytes in {:.3?}: {:.0} bytes/s, {:.0} samples/s ({:.1}x real time)",
+        bytes.len(),
+        parse_time,
+        rate(bytes.len(), parse_time),
+        rate(samples, parse_time),
+        rate(samples, parse_time) / clock.rate(),
+    );
+    println!(
+        "Pipeline delivered {} combined measurements in {:.3?}: {:.0} samples/s, channel and combine overhead {:.3?}",
+        delivered,
+        pipeline_time,
+        rate(samples, pipeline_time),
+        pipeline_time.saturating_sub(parse_time),
+    );
+    Ok(())
+}
@@ -0,0 +1,123 @@
+#ifndef PPK2_H
+#define PPK2_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The call succeeded.
+ */
+#define PPK2_OK 0
+
+/**
+ * A required pointer argument was null.
+ */
+#define PPK2_ERR_NULL -1
+
+/**
+ * An argument was out of range, or not valid UTF-8.
+ */
+#define PPK2_ERR_INVALID_ARG -2
+
+/**
+ * Communicating with the device failed.
+ */
+#define PPK2_ERR_DEVICE -3
+
+/**
+ * The call is not valid in the current state, e.g. starting a
+ * measurement that is already running.
+ */
+#define PPK2_ERR_STATE -4
+
+/**
+ * The call panicked. This is a bug.
+ */
+#define PPK2_ERR_PANIC -5
+
+/**
+ * An opened PPK2. Created with [ppk2_open], and freed with [ppk2_close].
+ */
+typedef struct Ppk2Device Ppk2Device;
+
+/**
+ * Receives a combined measurement: the current in µA, and the logic port
+ * pin levels with D0 in the least significant bit. Called from a thread
+ * owned by the library, so it must be safe to call from any thread.
+ * May be null in C, which is rejected with [PPK2_ERR_NULL].
+ */
+typedef void (*Ppk2SampleCallback)(void *user_data, float micro_amps, uint8_t pins);
+
+/**
+ * Open the PPK2 at the serial port `port`, or the first PPK2 found if
+ * `port` is null, and configure the measurement mode: 1 for ampere meter,
+ * 2 for source meter. On success, `*out` points to the opened device.
+ *
+ * # Safety
+ * `port` must be null or a valid null-terminated string, and `out` must
+ * be a valid pointer.
+ */
+int32_t ppk2_open(const char *port, uint8_t mode, Ppk2Device **out);
+
+/**
+ * Set the source voltage in millivolts.
+ *
+ * # Safety
+ * `device` must be null or a pointer obtained from [ppk2_open].
+ */
+int32_t ppk2_set_source_voltage(Ppk2Device *device, uint16_t millivolts);
+
+/**
+ * Enable or disable power to the device under test.
+ *
+ * # Safety
+ * `device` must be null or a pointer obtained from [ppk2_open].
+ */
+int32_t ppk2_set_device_power(Ppk2Device *device, bool enabled);
+
+/**
+ * Start measuring at `sps` combined measurements per second. Each combined
+ * measurement is passed to `callback`, along with `user_data`, until
+ * [ppk2_stop] is called. Returns [PPK2_ERR_NULL] if `callback` is null.
+ * If starting fails with [PPK2_ERR_DEVICE], the device can only be closed.
+ *
+ * # Safety
+ * `device` must be null or a pointer obtained from [ppk2_open], and
+ * `callback` must be safe to call with `user_data` from another thread.
+ */
+int32_t ppk2_start(Ppk2Device *device,
+                   uint32_t sps,
+                   Ppk2SampleCallback callback,
+                   void *user_data);
+
+/**
+ * Stop the measurement started with [ppk2_start]. The callback is not
+ * called anymore once this returns. If stopping fails with
+ * [PPK2_ERR_DEVICE], the device can only be closed.
+ *
+ * # Safety
+ * `device` must be null or a pointer obtained from [ppk2_open].
+ */
+int32_t ppk2_stop(Ppk2Device *device);
+
+/**
+ * Stop measuring if needed, and close the device. `device` must not be
+ * used afterwards. Errors while stopping are available through
+ * [ppk2_last_error].
+ *
+ * # Safety
+ * `device` must be null or a pointer obtained from [ppk2_open].
+ */
+void ppk2_close(Ppk2Device *device);
+
+/**
+ * Description of the last error on the calling thread, or null if there
+ * was none. Valid until the next call on this thread.
+ */
+const char *ppk2_last_error(void);
+
+#endif /* PPK2_H */
@@ -1,5 +1,5 @@
 //! Command definitions
-use crate::types::{DevicePower, MeasurementMode, SourceVoltage};
+use crate::types::{DevicePower, MeasurementMode, MeasurementRange, SourceVoltage};
 
 #[repr(u8)]
 /// Serial command opcodes
@@ -7,22 +7,33 @@ use crate::types::{DevicePower, MeasurementMode, SourceVoltage};
 #[allow(missing_docs)]
 pub enum Command {
     NoOp,
-    TriggerSet,
-    AvgNumSet,
-    TriggerWindowSet,
-    TriggerIntervalSet,
+    /// Set the trigger threshold in µA. Only the lower 24 bits are sent.
+    TriggerSet(u32),
+    /// Set the number of ADC samples the device averages into one sample
+    AvgNumSet(u16),
+    /// Set the number of samples captured when the trigger fires
+    TriggerWindowSet(u16),
+    /// Set the interval between triggered captures in samples
+    TriggerIntervalSet(u16),
+    /// Capture a single window when the trigger fires
     TriggerSingleSet,
     AverageStart,
     AverageStop,
-    RangeSet,
+    /// Lock the measurement range, disabling auto-ranging
+    RangeSet(MeasurementRange),
     LcdSet,
+    /// Disarm the trigger
     TriggerStop,
     /// Enable or disable device
     DeviceRunningSet(DevicePower),
     /// Set device source voltage
     RegulatorSet(SourceVoltage),
-    SwitchPointDown,
-    SwitchPointUp,
+    /// Set the digital potentiometer value that determines at which
+    /// current the device switches to a more sensitive range
+    SwitchPointDown(u16),
+    /// Set the digital potentiometer value that determines at which
+    /// current the device switches to a less sensitive range
+    SwitchPointUp(u16),
     TriggerExtToggle,
     /// Set measurement mode
     SetPowerMode(MeasurementMode),
@@ -33,7 +44,9 @@ pub enum Command {
     GetMetaData,
     /// Reset the device
     Reset,
-    SetUserGains,
+    /// Set the user gain of a measurement range, by which the
+    /// calibrated current of the range is multiplied
+    SetUserGains(MeasurementRange, f32),
 }
 
 impl Command {
@@ -45,20 +58,20 @@ impl Command {
     pub fn expected_response_len(&self) -> usize {
         match self {
             Command::NoOp => 0,
-            Command::TriggerSet => 0,
-            Command::AvgNumSet => 0,
-            Command::TriggerWindowSet => 0,
-            Command::TriggerIntervalSet => 0,
+            Command::TriggerSet(_) => 0,
+            Command::AvgNumSet(_) => 0,
+            Command::TriggerWindowSet(_) => 0,
+            Command::TriggerIntervalSet(_) => 0,
             Command::TriggerSingleSet => 0,
             Command::AverageStart => 0,
             Command::AverageStop => 0,
-            Command::RangeSet => 0,
+            Command::RangeSet(_) => 0,
             Command::LcdSet => 0,
             Command::TriggerStop => 0,
             Command::DeviceRunningSet(_) => 0,
             Command::RegulatorSet(_) => 0,
-            Command::SwitchPointDown => 0,
-            Command::SwitchPointUp => 0,
+            Command::SwitchPointDown(_) => 0,
+            Command::SwitchPointUp(_) => 0,
             Command::TriggerExtToggle => 0,
             Command::SetPowerMode(_) => 0,
             Command::ResUserSet => 0,
@@ -66,11 +79,12 @@ impl Command {
             Command::SpikeFilteringOff => 0,
             Command::GetMetaData => 512,
             Command::Reset => 0,
-            Command::SetUserGains => 0,
+            Command::SetUserGains(..) => 0,
         }
     }
 
-    pub(crate) fn response_complete(&self, response: &[u8]) -> bool {
+    /// Whether the passed response to this command was received completely.
+    pub fn response_complete(&self, response: &[u8]) -> bool {
         match self {
             Command::GetMetaData => response.ends_with(b"END\n"),
             _ => self.expected_response_len() >= response.len(),
@@ -101,22 +115,36 @@ impl<'c> Iterator for CommandBytes<'c> {
         use Command::*;
         let b = match (self.cmd, self.index) {
             (NoOp, 0) => Some(0x00),
-            (TriggerSet, 0) => Some(0x01),
-            (AvgNumSet, 0) => Some(0x02),
-            (TriggerWindowSet, 0) => Some(0x03),
-            (TriggerIntervalSet, 0) => Some(0x04),
+            (TriggerSet(_), 0) => Some(0x01),
+            // 24 bit big endian
+            (TriggerSet(micro_amps), i) if (1..=3).contains(&i) => {
+                Some(micro_amps.to_be_bytes()[i])
+            }
+            (AvgNumSet(_), 0) => Some(0x02),
+            (AvgNumSet(n), i) if (1..=2).contains(&i) => Some(n.to_be_bytes()[i - 1]),
+            (TriggerWindowSet(_), 0) => Some(0x03),
+            (TriggerWindowSet(samples), i) if (1..=2).contains(&i) => {
+                Some(samples.to_be_bytes()[i - 1])
+            }
+            (TriggerIntervalSet(_), 0) => Some(0x04),
+            (TriggerIntervalSet(samples), i) if (1..=2).contains(&i) => {
+                Some(samples.to_be_bytes()[i - 1])
+            }
             (TriggerSingleSet, 0) => Some(0x05),
             (AverageStart, 0) => Some(0x06),
             (AverageStop, 0) => Some(0x07),
-            (RangeSet, 0) => Some(0x08),
+            (RangeSet(_), 0) => Some(0x08),
+            (RangeSet(range), 1) => Some((*range).into()),
             (LcdSet, 0) => Some(0x09),
             (TriggerStop, 0) => Some(0x0A),
             (DeviceRunningSet(_), 0) => Some(0x0C),
             (DeviceRunningSet(pwr), 1) => Some((*pwr).into()),
             (RegulatorSet(_), 0) => Some(0x0D),
             (RegulatorSet(vdd), i) if (1..=2).contains(&i) => Some(vdd.raw()[i - 1]),
-            (SwitchPointDown, 0) => Some(0x0E),
-            (SwitchPointUp, 0) => Some(0x0F),
+            (SwitchPointDown(_), 0) => Some(0x0E),
+            (SwitchPointDown(pot), i) if (1..=2).contains(&i) => Some(pot.to_be_bytes()[i - 1]),
+            (SwitchPointUp(_), 0) => Some(0x0F),
+            (SwitchPointUp(pot), i) if (1..=2).contains(&i) => Some(pot.to_be_bytes()[i - 1]),
             (TriggerExtToggle, 0) => Some(0x10),
             (SetPowerMode(_), 0) => Some(0x11),
             (SetPowerMode(mode), 1) => Some((*mode).into()),
@@ -125,7 +153,10 @@ impl<'c> Iterator for CommandBytes<'c> {
             (SpikeFilteringOff, 0) => Some(0x16),
             (GetMetaData, 0) => Some(0x19),
             (Reset, 0) => Some(0x20),
-            (SetUserGains, 0) => Some(0x25),
+            (SetUserGains(..), 0) => Some(0x25),
+            (SetUserGains(range, _), 1) => Some((*range).into()),
+            // 32 bit float little endian
+            (SetUserGains(_, gain), i) if (2..=5).contains(&i) => Some(gain.to_le_bytes()[i - 2]),
             _ => None,
         };
         self.index += 1;
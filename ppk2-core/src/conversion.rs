@@ -0,0 +1,61 @@
+//! Conversion of raw ADC values to currents, using the calibration
+//! coefficients the device reports in its metadata.
+
+/// Number of measurement ranges of the device.
+pub const RANGES: usize = 5;
+
+/// Volts per ADC step, after multiplying the ADC value by 4.
+const ADC_MULTIPLIER: f32 = 1.8 / 163840.;
+
+/// Calibration coefficients of all measurement ranges, as reported in the
+/// device metadata. Defaults to the nominal, uncalibrated coefficients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Modifiers {
+    /// Shunt resistances in Ω
+    pub r: [f32; RANGES],
+    /// Quadratic gains
+    pub gs: [f32; RANGES],
+    /// Linear gains
+    pub gi: [f32; RANGES],
+    /// ADC offsets
+    pub o: [f32; RANGES],
+    /// Gains depending on the source voltage
+    pub s: [f32; RANGES],
+    /// Current offsets in A
+    pub i: [f32; RANGES],
+    /// User gains
+    pub ug: [f32; RANGES],
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Self {
+            r: [1031.64, 101.65, 10.15, 0.94, 0.043],
+            gs: [1., 1., 1., 1., 1.],
+            gi: [1., 1., 1., 1., 1.],
+            o: [0., 0., 0., 0., 0.],
+            s: [0., 0., 0., 0., 0.],
+            i: [0., 0., 0., 0., 0.],
+            ug: [1., 1., 1., 1., 1.],
+        }
+    }
+}
+
+impl Modifiers {
+    /// Convert the ADC value of a sample, measured in the passed range,
+    /// to a current in A, at the passed source voltage in millivolts.
+    /// Ranges above 4 are treated as range 4.
+    pub fn convert(&self, vdd_mv: u16, adc: u32, range: usize) -> f32 {
+        self.convert_scaled(vdd_mv, adc * 4, range.min(RANGES - 1))
+    }
+
+    /// Convert an ADC value that was already multiplied by 4, as done by
+    /// the official implementation.
+    pub fn convert_scaled(&self, vdd_mv: u16, adc_val: u32, range: usize) -> f32 {
+        let result_without_gain: f32 =
+            (adc_val as f32 - self.o[range]) * (ADC_MULTIPLIER / self.r[range]);
+        self.ug[range]
+            * (result_without_gain * (self.gs[range] * result_without_gain + self.gi[range])
+                + (self.s[range] * (f32::from(vdd_mv) / 1000.) + self.i[range]))
+    }
+}
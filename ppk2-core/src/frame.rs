@@ -0,0 +1,62 @@
+//! Bit extraction from the 4 byte sample frames the device sends.
+
+/// Size in bytes of a single raw sample as sent by the device.
+pub const SAMPLE_SIZE: usize = 4;
+
+/// The bitfields of a little endian sample word: name, width in bits
+/// and position of the least significant bit.
+pub const SAMPLE_FIELDS: [(&str, u32, u32); 4] = [
+    ("adc", 14, 0),
+    ("range", 3, 14),
+    ("counter", 6, 18),
+    ("logic", 8, 24),
+];
+
+const fn generate_mask(bits: u32, pos: u32) -> u32 {
+    (2u32.pow(bits) - 1) << pos
+}
+
+macro_rules! masked_value {
+    ($(#[$doc:meta])* $name:ident, $field:literal) => {
+        $(#[$doc])*
+        pub const fn $name(raw: u32) -> u32 {
+            let (_, bits, pos) = SAMPLE_FIELDS[$field];
+            (raw & generate_mask(bits, pos)) >> pos
+        }
+    };
+}
+
+masked_value!(
+    /// The raw ADC value of a sample word
+    adc,
+    0
+);
+masked_value!(
+    /// The measurement range of a sample word
+    range,
+    1
+);
+masked_value!(
+    /// The 6 bit sample counter of a sample word
+    counter,
+    2
+);
+masked_value!(
+    /// The logic port pin levels of a sample word
+    logic,
+    3
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{adc, counter, logic, range};
+
+    #[test]
+    pub fn test_frame() {
+        let raw = u32::from_le_bytes([0x34, 0x92, 0xAC, 0xA5]);
+        assert_eq!(adc(raw), 0x1234);
+        assert_eq!(range(raw), 2);
+        assert_eq!(counter(raw), 0x2B);
+        assert_eq!(logic(raw), 0xA5);
+    }
+}
@@ -0,0 +1,16 @@
+//! The `no_std` core of the [ppk2](https://crates.io/crates/ppk2) driver:
+//! command encoding, sample frame parsing and conversion of ADC values to
+//! currents. It contains no I/O, so it can be used to parse the sample
+//! stream of a PPK2 on devices without `std`. Parsing the types from
+//! strings requires the `alloc` feature, which is enabled by default.
+
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod cmd;
+pub mod conversion;
+pub mod frame;
+pub mod types;
@@ -0,0 +1,164 @@
+//! The device settings carried by [crate::cmd::Command]s.
+
+#[cfg(feature = "alloc")]
+use alloc::{borrow::ToOwned, string::String};
+#[cfg(feature = "alloc")]
+use core::{fmt::Display, num::ParseIntError, str::FromStr};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// Error parsing one of the types defined by this crate.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct ParseTypeError(String, &'static str);
+
+#[cfg(feature = "alloc")]
+impl ParseTypeError {
+    /// Create a new [ParseTypeError] for the string `got`, describing
+    /// the values that were `expected`.
+    pub fn new(got: impl Into<String>, expected: &'static str) -> Self {
+        Self(got.into(), expected)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl core::error::Error for ParseTypeError {}
+
+#[cfg(feature = "alloc")]
+impl Display for ParseTypeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Error parsing: expected one of {}, but got {}",
+            self.1, self.0
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Device source voltage.
+pub struct SourceVoltage {
+    raw: [u8; 2],
+}
+
+#[cfg(feature = "alloc")]
+impl FromStr for SourceVoltage {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mv = s.parse()?;
+        Ok(Self::from_millivolts(mv))
+    }
+}
+
+impl SourceVoltage {
+    const VDD_MIN_MV: u16 = 800;
+    const VDD_MAX_MV: u16 = 5000;
+    const OFFSET: u16 = 32;
+
+    /// Create a [SourceVoltage] from the passed amount of millivolts.
+    pub fn from_millivolts(mv: u16) -> Self {
+        let mv = mv.clamp(Self::VDD_MIN_MV, Self::VDD_MAX_MV);
+
+        let diff_to_baseline = mv - Self::VDD_MIN_MV + Self::OFFSET;
+
+        let ratio = (diff_to_baseline / 256) as u8;
+        let remainder = (diff_to_baseline % 256) as u8;
+
+        Self {
+            raw: [ratio + 3, remainder],
+        }
+    }
+
+    /// The source voltage in millivolts.
+    pub fn millivolts(&self) -> u16 {
+        (self.raw[0] as u16 - 3) * 256 + self.raw[1] as u16 + Self::VDD_MIN_MV - Self::OFFSET
+    }
+
+    /// The encoding of the source voltage sent to the device.
+    pub fn raw(&self) -> &[u8; 2] {
+        &self.raw
+    }
+}
+
+#[repr(u8)]
+#[derive(TryFromPrimitive, IntoPrimitive, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Device current measurement mode
+pub enum MeasurementMode {
+    /// Act as ammeter, measuring the current through the
+    /// VIN and GND pins.
+    Ampere = 0x01,
+    #[default]
+    /// Act as source meter, returing the current supplied
+    /// from the device voltage source.
+    Source = 0x02,
+}
+
+#[cfg(feature = "alloc")]
+impl FromStr for MeasurementMode {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ampere" | "amp" | "a" => Ok(Self::Ampere),
+            "source" | "s" => Ok(Self::Source),
+            _ => Err(ParseTypeError(
+                s.to_owned(),
+                "[ampere | amp | a | source | s]",
+            )),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(TryFromPrimitive, IntoPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+/// Measurement range of the device, each using a different shunt resistor.
+/// Range 0 has the largest shunt, and measures the lowest currents.
+pub enum MeasurementRange {
+    #[allow(missing_docs)]
+    Range0 = 0,
+    #[allow(missing_docs)]
+    Range1 = 1,
+    #[allow(missing_docs)]
+    Range2 = 2,
+    #[allow(missing_docs)]
+    Range3 = 3,
+    #[allow(missing_docs)]
+    Range4 = 4,
+}
+
+#[cfg(feature = "alloc")]
+impl FromStr for MeasurementRange {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u8>()
+            .ok()
+            .and_then(|r| Self::try_from(r).ok())
+            .ok_or_else(|| ParseTypeError(s.to_owned(), "[0 | 1 | 2 | 3 | 4]"))
+    }
+}
+
+#[repr(u8)]
+#[derive(TryFromPrimitive, IntoPrimitive, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Device power
+pub enum DevicePower {
+    #[default]
+    /// Device is disabled
+    Disabled = 0x00,
+    /// Device is enabled
+    Enabled = 0x01,
+}
+
+#[cfg(feature = "alloc")]
+impl FromStr for DevicePower {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disabled" | "d" => Ok(Self::Disabled),
+            "enabled" | "e" => Ok(Self::Enabled),
+            _ => Err(ParseTypeError(s.to_owned(), "[disabled | d | enabled | e]")),
+        }
+    }
+}
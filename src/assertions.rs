@@ -0,0 +1,344 @@
+//! Assertions on current and energy consumption, for integration tests.
+//!
+//! ```
+//! use ppk2::{assert_avg_current_below, assert_energy_between};
+//! # use ppk2::{charge::EnergyAccumulator, clock::SampleClock, measurement::Measurement,
+//! #     stats::Stats, types::SourceVoltage};
+//! # let measurements = vec![Measurement { micro_amps: 120., ..Default::default() }; 100_000];
+//! # let mut stats = Stats::new();
+//! # stats.extend(&measurements);
+//! # let mut acc = EnergyAccumulator::new(SourceVoltage::from_millivolts(3000));
+//! # acc.extend(&measurements);
+//! # let region = acc.energy(&SampleClock::nominal());
+//! assert_avg_current_below!(stats, 200 µA);
+//! assert_energy_between!(region, 0.3..0.4 mJ);
+//! ```
+//!
+//! Units are written after the value: `A`, `mA`, `µA` and `nA` for
+//! currents, `J`, `mJ`, `µJ` and `nJ` for energy. As the micro sign trips
+//! the `uncommon_codepoints` lint in the calling crate, `uA` and `uJ` are
+//! accepted as well. Failure
+//! messages include the measured value and, if the measurements are
+//! available, a sparkline of the current over time:
+//!
+//! ```text
+//! average current of `session` is 250.0 µA, expected below 200.0 µA
+//!   current: [..........:::::##@@@##::::..........] 10.0 µA to 5.00 mA
+//! ```
+
+use crate::{
+    charge::{Energy, EnergyAccumulator},
+    measurement::{Measurement, SegmentSummary},
+    session::Session,
+    stats::Stats,
+    types::SourceVoltage,
+};
+
+/// Number of characters in the sparkline of a failure message.
+const SPARKLINE_WIDTH: usize = 40;
+
+/// Characters of the sparkline, from the lowest to the highest current.
+const SPARKLINE_LEVELS: &[u8] = b" .:-=+*#%@";
+
+/// Something the current consumption can be asserted on.
+pub trait Region {
+    /// The average current in µA, or [None] if nothing was measured.
+    fn avg_micro_amps(&self) -> Option<f64>;
+
+    /// The energy in J, or [None] if unknown.
+    fn joules(&self) -> Option<f64> {
+        None
+    }
+
+    /// The measurements, for the sparkline in failure messages.
+    fn measurements(&self) -> &[Measurement] {
+        &[]
+    }
+}
+
+impl Region for Stats {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        self.mean()
+    }
+}
+
+impl Region for Energy {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        (self.charge.samples > 0).then(|| self.charge.avg_micro_amps())
+    }
+
+    fn joules(&self) -> Option<f64> {
+        Some(Energy::joules(self))
+    }
+}
+
+impl Region for SegmentSummary {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        self.avg_micro_amps.map(f64::from)
+    }
+}
+
+impl Region for [Measurement] {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        let sum: f64 = self.iter().map(|m| m.micro_amps as f64).sum();
+        (!self.is_empty()).then(|| sum / self.len() as f64)
+    }
+
+    fn measurements(&self) -> &[Measurement] {
+        self
+    }
+}
+
+impl Region for Vec<Measurement> {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        self.as_slice().avg_micro_amps()
+    }
+
+    fn measurements(&self) -> &[Measurement] {
+        self
+    }
+}
+
+/// The energy of a session is known if its metadata is.
+impl Region for Session {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        self.measurements.avg_micro_amps()
+    }
+
+    fn joules(&self) -> Option<f64> {
+        let metadata = self.metadata.as_ref()?;
+        let mut acc = EnergyAccumulator::new(SourceVoltage::from_millivolts(metadata.vdd));
+        acc.extend(&self.measurements);
+        Some(acc.energy(&self.clock).joules())
+    }
+
+    fn measurements(&self) -> &[Measurement] {
+        &self.measurements
+    }
+}
+
+impl<R: Region + ?Sized> Region for &R {
+    fn avg_micro_amps(&self) -> Option<f64> {
+        (**self).avg_micro_amps()
+    }
+
+    fn joules(&self) -> Option<f64> {
+        (**self).joules()
+    }
+
+    fn measurements(&self) -> &[Measurement] {
+        (**self).measurements()
+    }
+}
+
+/// Render the currents of the passed measurements as a sparkline of at
+/// most `width` characters, on a logarithmic scale. Returns the sparkline
+/// along with the lowest and highest current in µA, or [None] if there
+/// are no measurements.
+pub fn sparkline(measurements: &[Measurement], width: usize) -> Option<(String, f32, f32)> {
+    let per_char = measurements.len().div_ceil(width.max(1)).max(1);
+    let avgs: Vec<f32> = measurements
+        .chunks(per_char)
+        .map(|c| c.iter().map(|m| m.micro_amps).sum::<f32>() / c.len() as f32)
+        .collect();
+    let min = avgs.iter().copied().reduce(f32::min)?;
+    let max = avgs.iter().copied().reduce(f32::max)?;
+    // Currents may be slightly negative around zero
+    let log = |v: f32| (v - min + 1.).ln();
+    let span = log(max).max(f32::EPSILON);
+    let line = avgs
+        .iter()
+        .map(|&v| {
+            let level = (log(v) / span * (SPARKLINE_LEVELS.len() - 1) as f32).round() as usize;
+            SPARKLINE_LEVELS[level.min(SPARKLINE_LEVELS.len() - 1)] as char
+        })
+        .collect();
+    Some((line, min, max))
+}
+
+/// Format a current in µA with a fitting unit.
+fn format_current(micro_amps: f64) -> String {
+    match micro_amps.abs() {
+        a if a >= 1e6 => format!("{:.3} A", micro_amps / 1e6),
+        a if a >= 1e3 => format!("{:.2} mA", micro_amps / 1e3),
+        a if a >= 1. || a == 0. => format!("{micro_amps:.1} µA"),
+        _ => format!("{:.1} nA", micro_amps * 1e3),
+    }
+}
+
+/// Format an energy in J with a fitting unit.
+fn format_energy(joules: f64) -> String {
+    match joules.abs() {
+        j if j >= 1. || j == 0. => format!("{joules:.3} J"),
+        j if j >= 1e-3 => format!("{:.3} mJ", joules * 1e3),
+        j if j >= 1e-6 => format!("{:.3} µJ", joules * 1e6),
+        _ => format!("{:.3} nJ", joules * 1e9),
+    }
+}
+
+/// Panic with `msg`, followed by a sparkline of the region if available.
+#[track_caller]
+fn fail(region: &impl Region, msg: String) -> ! {
+    match sparkline(region.measurements(), SPARKLINE_WIDTH) {
+        Some((line, min, max)) => panic!(
+            "{msg}\n  current: [{line}] {} to {}",
+            format_current(min as f64),
+            format_current(max as f64)
+        ),
+        None => panic!("{msg}"),
+    }
+}
+
+/// Assert that the average current of `region` is below `limit` µA.
+/// Used by [crate::assert_avg_current_below].
+#[track_caller]
+pub fn avg_current_below(region: impl Region, limit: f64, name: &str) {
+    match region.avg_micro_amps() {
+        Some(avg) if avg < limit => {}
+        Some(avg) => fail(
+            &region,
+            format!(
+                "average current of `{name}` is {}, expected below {}",
+                format_current(avg),
+                format_current(limit)
+            ),
+        ),
+        None => fail(&region, format!("`{name}` contains no measurements")),
+    }
+}
+
+/// Assert that the energy of `region` is within `range` J.
+/// Used by [crate::assert_energy_between].
+#[track_caller]
+pub fn energy_between(region: impl Region, range: std::ops::Range<f64>, name: &str) {
+    match region.joules() {
+        Some(joules) if range.contains(&joules) => {}
+        Some(joules) => fail(
+            &region,
+            format!(
+                "energy of `{name}` is {}, expected between {} and {}",
+                format_energy(joules),
+                format_energy(range.start),
+                format_energy(range.end)
+            ),
+        ),
+        None => fail(&region, format!("energy of `{name}` is unknown")),
+    }
+}
+
+/// Convert a current with a unit to µA. Used by the assertion macros.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __micro_amps {
+    ($v:literal A) => {
+        $v as f64 * 1e6
+    };
+    ($v:literal mA) => {
+        $v as f64 * 1e3
+    };
+    ($v:literal µA) => {
+        $v as f64
+    };
+    ($v:literal uA) => {
+        $v as f64
+    };
+    ($v:literal nA) => {
+        $v as f64 * 1e-3
+    };
+}
+
+/// Convert an energy with a unit to J. Used by the assertion macros.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __joules {
+    ($v:literal J) => {
+        $v as f64
+    };
+    ($v:literal mJ) => {
+        $v as f64 * 1e-3
+    };
+    ($v:literal µJ) => {
+        $v as f64 * 1e-6
+    };
+    ($v:literal uJ) => {
+        $v as f64 * 1e-6
+    };
+    ($v:literal nJ) => {
+        $v as f64 * 1e-9
+    };
+}
+
+/// Assert that the average current of a [Region] is below a limit,
+/// e.g. `assert_avg_current_below!(stats, 200 µA)`.
+#[macro_export]
+macro_rules! assert_avg_current_below {
+    ($region:expr, $limit:literal $unit:ident) => {
+        $crate::assertions::avg_current_below(
+            &$region,
+            $crate::__micro_amps!($limit $unit),
+            stringify!($region),
+        )
+    };
+}
+
+/// Assert that the energy of a [Region] is within a range,
+/// e.g. `assert_energy_between!(region, 1.0..1.5 mJ)`.
+#[macro_export]
+macro_rules! assert_energy_between {
+    ($region:expr, $low:literal .. $high:literal $unit:ident) => {
+        $crate::assertions::energy_between(
+            &$region,
+            $crate::__joules!($low $unit)..$crate::__joules!($high $unit),
+            stringify!($region),
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::sparkline;
+    use crate::{clock::SampleClock, measurement::Measurement, session::Session, types::Metadata};
+
+    #[test]
+    pub fn test_assertions() {
+        let measurements: Vec<_> = (0..100_000)
+            .map(|i| Measurement {
+                micro_amps: if (40_000..60_000).contains(&i) {
+                    5000.
+                } else {
+                    10.
+                },
+                ..Default::default()
+            })
+            .collect();
+        let (line, min, max) = sparkline(&measurements, 10).unwrap();
+        assert_eq!(line, "    @@    ");
+        assert_eq!((min, max), (10., 5000.));
+        assert!(sparkline(&[], 10).is_none());
+
+        let metadata = Metadata {
+            vdd: 3000,
+            ..Default::default()
+        };
+        let session = Session::from_measurements(SampleClock::nominal(), measurements)
+            .with_metadata(metadata);
+        // 1008 µA for 1 s at 3 V
+        assert_avg_current_below!(session, 1.1 mA);
+        assert_energy_between!(session, 3000..3100 uJ);
+
+        let failure = panic::catch_unwind(|| assert_avg_current_below!(session, 1000 uA));
+        let msg = *failure.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            msg.starts_with("average current of `session` is 1.01 mA"),
+            "{msg}"
+        );
+        let line = format!(
+            "[{0}{1}{0}] 10.0 µA to 5.00 mA",
+            " ".repeat(16),
+            "@".repeat(8)
+        );
+        assert!(msg.ends_with(&line), "{msg}");
+    }
+}
@@ -0,0 +1,463 @@
+//! Async API based on `tokio-serial`. Instead of running the measurement
+//! pipeline in background threads, [Ppk2Async] exposes the combined
+//! measurements as a [Stream], parsing samples as they are polled.
+//!
+//! Only the [MeasurementOptions] that affect parsing and combining are
+//! applied: `sps`, the window policy, pin matching and voting, initial
+//! sync, gap interpolation, the glitch filter, and strict, logic-only and
+//! current-only mode. Events, history, tags, segments, IR drop emulation
+//! and power ramps are only supported by [crate::Ppk2].
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use serialport::{ClearBuffer, FlowControl, SerialPort};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::{
+    calibration::Calibration,
+    clock::{CaptureAnchor, SampleClock},
+    cmd::Command,
+    measurement::{
+        ChunkTimer, Measurement, MeasurementAccumulator, MeasurementIterExt, MeasurementMatch,
+        MeasurementOptions, PinVote, SAMPLE_SIZE,
+    },
+    types::{
+        DevicePower, LogicPortPins, MeasurementMode, MeasurementRange, Metadata, SampleRate,
+        SourceVoltage,
+    },
+    Error, Result,
+};
+
+/// Async counterpart of [crate::Ppk2].
+pub struct Ppk2Async {
+    port: SerialStream,
+    metadata: Metadata,
+    mode: MeasurementMode,
+    clock: SampleClock,
+}
+
+impl Ppk2Async {
+    /// Open the PPK2 connected to the serial port at the passed path,
+    /// and set its measurement mode.
+    pub async fn new<'a>(path: impl Into<Cow<'a, str>>, mode: MeasurementMode) -> Result<Self> {
+        let mut port = tokio_serial::new(path, 9600)
+            .flow_control(FlowControl::Hardware)
+            .open_native_async()?;
+
+        if let Err(e) = port.clear(ClearBuffer::All) {
+            tracing::warn!("failed to clear buffers: {:?}", e);
+        }
+
+        // Required to work on Windows.
+        if let Err(e) = port.write_data_terminal_ready(true) {
+            tracing::warn!("failed to set DTR: {:?}", e);
+        }
+
+        let mut ppk2 = Self {
+            port,
+            metadata: Metadata::default(),
+            mode,
+            clock: SampleClock::nominal(),
+        };
+
+        ppk2.metadata = ppk2.get_metadata().await?;
+        Calibration::from(&ppk2.metadata).validate()?;
+        for warning in ppk2.metadata.calibration_warnings() {
+            tracing::warn!("Calibration: {warning}");
+        }
+        ppk2.set_power_mode(mode).await?;
+        Ok(ppk2)
+    }
+
+    /// Send a raw command and return the result.
+    pub async fn send_command(&mut self, command: Command) -> Result<Vec<u8>> {
+        self.port
+            .write_all(&Vec::from_iter(command.bytes()))
+            .await?;
+        // Doesn't allocate if expected response length is 0
+        let mut response = Vec::with_capacity(command.expected_response_len());
+        let mut buf = [0u8; 128];
+        while !command.response_complete(&response) {
+            let n = self.port.read(&mut buf).await?;
+            response.extend_from_slice(&buf[..n]);
+        }
+        Ok(response)
+    }
+
+    /// Get the device metadata.
+    pub async fn get_metadata(&mut self) -> Result<Metadata> {
+        let mut result: Result<Metadata> = Err(Error::Parse("Metadata".to_string()));
+
+        // Retry a few times, as the metadata command sometimes fails
+        for _ in 0..3 {
+            let response = self.send_command(Command::GetMetaData).await;
+            match response.and_then(|r| Metadata::from_bytes(&r)) {
+                Ok(metadata) => {
+                    result = Ok(metadata);
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Error fetching metadata: {:?}. Retrying..", e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The metadata the device reported when it was opened.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The measurement mode of the device.
+    pub fn mode(&self) -> MeasurementMode {
+        self.mode
+    }
+
+    /// Enable or disable the device power.
+    pub async fn set_device_power(&mut self, power: DevicePower) -> Result<()> {
+        self.send_command(Command::DeviceRunningSet(power)).await?;
+        Ok(())
+    }
+
+    /// Lock the measurement range. See [crate::Ppk2::set_measurement_range].
+    pub async fn set_measurement_range(&mut self, range: MeasurementRange) -> Result<()> {
+        self.send_command(Command::RangeSet(range)).await?;
+        Ok(())
+    }
+
+    /// Set the switch points of the auto-ranging.
+    /// See [crate::Ppk2::set_switch_points].
+    pub async fn set_switch_points(&mut self, up: u16, down: u16) -> Result<()> {
+        self.send_command(Command::SwitchPointUp(up)).await?;
+        self.send_command(Command::SwitchPointDown(down)).await?;
+        Ok(())
+    }
+
+    /// Set the user gain of the passed range. See [crate::Ppk2::set_user_gain].
+    pub async fn set_user_gain(&mut self, range: MeasurementRange, gain: f32) -> Result<()> {
+        if !gain.is_finite() || gain == 0. {
+            return Err(Error::InvalidCalibration(format!(
+                "user gain of range {} is {gain}",
+                u8::from(range)
+            )));
+        }
+        self.send_command(Command::SetUserGains(range, gain))
+            .await?;
+        self.metadata.modifiers.ug[u8::from(range) as usize] = gain;
+        Ok(())
+    }
+
+    /// Reset the user calibration of the device, and fetch the metadata again.
+    /// See [crate::Ppk2::reset_user_calibration].
+    pub async fn reset_user_calibration(&mut self) -> Result<()> {
+        self.send_command(Command::ResUserSet).await?;
+        self.metadata = self.get_metadata().await?;
+        Ok(())
+    }
+
+    /// Enable or disable the spike filtering of the device firmware.
+    /// See [crate::Ppk2::set_hardware_spike_filtering].
+    pub async fn set_hardware_spike_filtering(&mut self, enabled: bool) -> Result<()> {
+        let command = match enabled {
+            true => Command::SpikeFilteringOn,
+            false => Command::SpikeFilteringOff,
+        };
+        self.send_command(command).await?;
+        Ok(())
+    }
+
+    /// Set the voltage of the device voltage source.
+    pub async fn set_source_voltage(&mut self, vdd: SourceVoltage) -> Result<()> {
+        self.send_command(Command::RegulatorSet(vdd)).await?;
+        Ok(())
+    }
+
+    /// Get the clock at which the device produces samples.
+    /// See [crate::Ppk2::native_clock].
+    pub fn native_clock(&self) -> SampleClock {
+        self.clock
+    }
+
+    /// Set the clock at which the device produces samples.
+    /// See [crate::Ppk2::set_native_clock].
+    pub fn set_native_clock(&mut self, clock: SampleClock) {
+        self.clock = clock;
+    }
+
+    /// Start measurements, taking `sps` combined measurements per second.
+    pub async fn start_measurement(self, sps: SampleRate) -> Result<MeasurementStream> {
+        self.start_measurement_with(MeasurementOptions::new(sps))
+            .await
+    }
+
+    /// Start measurements with the passed options. Stop the measurements
+    /// and get the [Ppk2Async] back with [MeasurementStream::stop].
+    pub async fn start_measurement_with(
+        mut self,
+        options: MeasurementOptions,
+    ) -> Result<MeasurementStream> {
+        self.port.clear(ClearBuffer::Input)?;
+        self.send_command(Command::AverageStart).await?;
+        let anchor = CaptureAnchor::now();
+        let parser = SampleParser::new(options, self.metadata.clone(), self.clock);
+        Ok(MeasurementStream {
+            ppk2: Some(self),
+            parser,
+            anchor,
+        })
+    }
+
+    async fn set_power_mode(&mut self, mode: MeasurementMode) -> Result<()> {
+        self.send_command(Command::SetPowerMode(mode)).await?;
+        self.mode = mode;
+        Ok(())
+    }
+}
+
+/// [Stream] of combined measurements, returned by
+/// [Ppk2Async::start_measurement_with]. The stream ends after an error.
+///
+/// Dropping the stream without calling [MeasurementStream::stop] stops the
+/// measurement on a best-effort basis: the stop command is only sent if it
+/// can be written without blocking.
+pub struct MeasurementStream {
+    ppk2: Option<Ppk2Async>,
+    parser: SampleParser,
+    anchor: CaptureAnchor,
+}
+
+impl MeasurementStream {
+    /// Receive the next combined measurement, or [None] if the stream ended.
+    pub async fn next(&mut self) -> Option<Result<MeasurementMatch>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// The host time at which the measurement started.
+    /// See [crate::MeasurementHandle::anchor].
+    pub fn anchor(&self) -> CaptureAnchor {
+        self.anchor
+    }
+
+    /// Stop measuring and get the [Ppk2Async] back.
+    pub async fn stop(mut self) -> Result<Ppk2Async> {
+        let mut ppk2 = self.ppk2.take().expect("device is only taken on stop");
+        ppk2.send_command(Command::AverageStop).await?;
+        Ok(ppk2)
+    }
+}
+
+impl Drop for MeasurementStream {
+    fn drop(&mut self) {
+        let Some(ppk2) = self.ppk2.as_mut() else {
+            return;
+        };
+        let command = Vec::from_iter(Command::AverageStop.bytes());
+        if let Err(e) = ppk2.port.try_write(&command) {
+            tracing::warn!("failed to stop the dropped measurement: {:?}", e);
+        }
+    }
+}
+
+impl Stream for MeasurementStream {
+    type Item = Result<MeasurementMatch>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let Some(ppk2) = this.ppk2.as_mut() else {
+            return Poll::Ready(None);
+        };
+        this.parser.poll_next(Pin::new(&mut ppk2.port), cx)
+    }
+}
+
+/// Parses and combines the samples read from the device.
+struct SampleParser {
+    accumulator: MeasurementAccumulator,
+    chunk_timer: ChunkTimer,
+    measurement_buf: VecDeque<Measurement>,
+    pins: LogicPortPins,
+    pin_vote: PinVote,
+    strict: bool,
+    current_only: bool,
+    read_buf: Vec<u8>,
+    output: VecDeque<MeasurementMatch>,
+    done: bool,
+}
+
+impl SampleParser {
+    fn new(options: MeasurementOptions, metadata: Metadata, clock: SampleClock) -> Self {
+        let MeasurementOptions {
+            sps,
+            pins,
+            initial_sync,
+            max_gap,
+            glitch_filter,
+            pin_vote,
+            strict,
+            logic_only,
+            current_only,
+            non_finite,
+            spike_filter,
+            window,
+            ..
+        } = options;
+        let accumulator = MeasurementAccumulator::new(metadata)
+            .clock(clock)
+            .initial_sync(initial_sync)
+            .interpolate_gaps(max_gap)
+            .glitch_filter(glitch_filter)
+            .logic_only(logic_only)
+            .current_only(current_only)
+            .non_finite(non_finite)
+            .spike_filter(spike_filter);
+        Self {
+            accumulator,
+            chunk_timer: ChunkTimer::new(sps, window, clock),
+            measurement_buf: VecDeque::with_capacity(SampleClock::NOMINAL_RATE),
+            pins,
+            pin_vote,
+            strict,
+            current_only,
+            read_buf: vec![0; 1024],
+            output: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Feed a sample, queueing a combined measurement if a chunk is complete.
+    fn feed_sample(&mut self, bytes: &[u8]) -> Result<()> {
+        let prev_len = self.measurement_buf.len();
+        let new_missed = if self.strict {
+            self.accumulator
+                .try_feed_into(bytes, &mut self.measurement_buf)?
+        } else {
+            self.accumulator.feed_into(bytes, &mut self.measurement_buf)
+        };
+        let len = self.measurement_buf.len();
+        let received = self
+            .measurement_buf
+            .range(prev_len..)
+            .filter(|m| !m.synthetic)
+            .count();
+        self.chunk_timer.received(len - prev_len);
+        self.chunk_timer.advance(new_missed + received);
+        if !self.chunk_timer.should_flush(len) {
+            return Ok(());
+        }
+        self.chunk_timer.flushed();
+        let measurements = self.measurement_buf.drain(..);
+        let measurement = if self.current_only {
+            measurements.combine_current()
+        } else {
+            measurements.combine_matching_with(self.pins, self.pin_vote)
+        };
+        self.output.push_back(measurement);
+        Ok(())
+    }
+
+    /// Read samples from `port` until a combined measurement is ready.
+    fn poll_next(
+        &mut self,
+        mut port: Pin<&mut impl AsyncRead>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<MeasurementMatch>>> {
+        loop {
+            if let Some(measurement) = self.output.pop_front() {
+                return Poll::Ready(Some(Ok(measurement)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+            let mut read_buf = std::mem::take(&mut self.read_buf);
+            let mut buf = ReadBuf::new(&mut read_buf);
+            let res = match port.as_mut().poll_read(cx, &mut buf) {
+                Poll::Pending => {
+                    self.read_buf = read_buf;
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(e)) => Err(e.into()),
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))
+                }
+                Poll::Ready(Ok(())) => buf
+                    .filled()
+                    .chunks(SAMPLE_SIZE)
+                    .try_for_each(|sample| self.feed_sample(sample)),
+            };
+            self.read_buf = read_buf;
+            if let Err(e) = res {
+                self.done = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Waker;
+
+    use super::*;
+    use crate::{mock::MockPpk2, types::SampleRate};
+
+    /// Encode `micro_amps` as a stream of device samples.
+    fn raw_samples(micro_amps: impl IntoIterator<Item = f32>, metadata: &Metadata) -> Vec<u8> {
+        let calibration = Calibration::from(metadata);
+        micro_amps
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, micro_amps)| {
+                let m = Measurement {
+                    micro_amps,
+                    ..Default::default()
+                };
+                MockPpk2::encode(&calibration, &m, i as u64).to_le_bytes()
+            })
+            .collect()
+    }
+
+    /// Poll the parser over `bytes` until it ends.
+    fn parse(parser: &mut SampleParser, mut bytes: &[u8]) -> Vec<Result<MeasurementMatch>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        while let Poll::Ready(Some(m)) = parser.poll_next(Pin::new(&mut bytes), &mut cx) {
+            out.push(m);
+        }
+        out
+    }
+
+    #[test]
+    pub fn test_parse_stream() {
+        let metadata = Metadata {
+            calibrated: true,
+            vdd: 3000,
+            ..Default::default()
+        };
+        let options = MeasurementOptions::new(SampleRate::per_second(1000).unwrap());
+        let mut parser = SampleParser::new(options, metadata.clone(), SampleClock::nominal());
+        // Two full windows of 100 samples, and half a window
+        let currents = (0..250).map(|i| if i < 100 { 1000. } else { 3000. });
+        let out = parse(&mut parser, &raw_samples(currents, &metadata));
+
+        assert_eq!(out.len(), 3);
+        for (m, expected) in out.iter().zip([1000., 3000.]) {
+            let Ok(MeasurementMatch::Match(m)) = m else {
+                panic!("expected a measurement");
+            };
+            assert!((m.micro_amps - expected).abs() < expected * 0.01);
+        }
+        // The half window is dropped when the port is closed
+        assert!(matches!(&out[2], Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+        assert!(parse(&mut parser, &[]).is_empty());
+    }
+}
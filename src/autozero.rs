@@ -0,0 +1,271 @@
+//! Compensation of slow offset drift over long captures, by periodically
+//! measuring a reference window with the device under test powered off.
+//!
+//! During a reference window, device power is disabled, and after a
+//! settling time the current is averaged. The difference with the expected
+//! current is the offset, which is subtracted from all measurements until
+//! the next window. The samples of the window itself are dropped, and count
+//! as missed samples. In source meter mode, nothing draws current while
+//! device power is off, so the expected current is 0 µA. In ampere meter
+//! mode, the device under test is powered externally, so set the expected
+//! current to what it draws while the PPK2 disables its power output.
+//! Note that in source meter mode, every window power cycles the device
+//! under test, so choose an interval that it tolerates.
+//!
+//! Windows that are due while device power is disabled with
+//! [crate::ControlHandle::set_device_power] are skipped, as the device
+//! under test isn't running. After a window, the device power is restored
+//! to what was last set through the [crate::ControlHandle].
+
+use std::time::Duration;
+
+use crate::clock::SampleClock;
+
+/// Configuration of periodic auto-zero windows.
+/// See [crate::measurement::MeasurementOptions::auto_zero].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoZero {
+    interval: Duration,
+    settle: Duration,
+    window: Duration,
+    expected: f32,
+}
+
+impl AutoZero {
+    /// Create a new [AutoZero], starting a reference window every
+    /// `interval`, the first one at the start of the measurement.
+    /// Settles for 10 ms and averages over 10 ms by default.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            settle: Duration::from_millis(10),
+            window: Duration::from_millis(10),
+            expected: 0.,
+        }
+    }
+
+    /// Set the time to wait after disabling device power,
+    /// before averaging the reference current.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Set the time over which the reference current is averaged.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set the current in µA expected during the reference window.
+    /// Defaults to 0 µA.
+    pub fn expected(mut self, micro_amps: f32) -> Self {
+        self.expected = micro_amps;
+        self
+    }
+}
+
+/// An offset correction made after a reference window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZeroCorrection {
+    /// Index of the first sample the correction applies to, counted from
+    /// the start of the measurement and including missed samples.
+    pub sample: usize,
+    /// Time of that sample since the start of the measurement.
+    pub time: Duration,
+    /// The offset in µA that is subtracted from subsequent measurements.
+    pub offset: f32,
+}
+
+/// What to do with a sample fed to [AutoZeroState].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ZeroStep {
+    /// Keep the sample, with the corrected current in µA
+    Keep(f32),
+    /// Drop the sample, as it is part of a reference window
+    Drop,
+    /// Disable device power and drop the sample, starting a reference window
+    PowerOff,
+    /// Restore device power and drop the sample, ending a reference window
+    Restore(ZeroCorrection),
+}
+
+/// State of the periodic auto-zero windows.
+#[derive(Debug, Clone)]
+pub(crate) struct AutoZeroState {
+    interval: usize,
+    settle: usize,
+    window: usize,
+    expected: f32,
+    next_at: usize,
+    /// Index of the first sample after powering off in the window in progress
+    started: Option<usize>,
+    sum: f64,
+    count: usize,
+    offset: f32,
+    clock: SampleClock,
+}
+
+impl AutoZeroState {
+    /// Create the state for a device sampling at the rate of `clock`.
+    pub(crate) fn new(config: AutoZero, clock: SampleClock) -> Self {
+        Self {
+            interval: clock.samples_in(config.interval).max(1),
+            settle: clock.samples_in(config.settle),
+            window: clock.samples_in(config.window).max(1),
+            expected: config.expected,
+            next_at: 0,
+            started: None,
+            sum: 0.,
+            count: 0,
+            offset: 0.,
+            clock,
+        }
+    }
+
+    /// Whether a reference window is in progress, during which
+    /// device power is disabled.
+    pub(crate) fn in_window(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Feed the current in µA of the sample with the passed index.
+    /// `powered` tells whether device power is enabled, otherwise a window
+    /// that is due is skipped until the next interval.
+    pub(crate) fn feed(&mut self, sample: usize, micro_amps: f32, powered: bool) -> ZeroStep {
+        let Some(started) = self.started else {
+            if sample < self.next_at {
+                return ZeroStep::Keep(micro_amps - self.offset);
+            }
+            if !powered {
+                self.next_at = sample + self.interval;
+                return ZeroStep::Keep(micro_amps - self.offset);
+            }
+            self.started = Some(sample + 1);
+            return ZeroStep::PowerOff;
+        };
+
+        let elapsed = sample.saturating_sub(started);
+        if elapsed < self.settle {
+            return ZeroStep::Drop;
+        }
+        if elapsed < self.settle + self.window {
+            self.sum += f64::from(micro_amps);
+            self.count += 1;
+            return ZeroStep::Drop;
+        }
+
+        // Keep the previous offset if all samples of the window were missed
+        if self.count > 0 {
+            self.offset = (self.sum / self.count as f64) as f32 - self.expected;
+        }
+        self.started = None;
+        (self.sum, self.count) = (0., 0);
+        self.next_at = sample + self.interval;
+        ZeroStep::Restore(ZeroCorrection {
+            sample: sample + 1,
+            time: self.clock.time_at(sample as u64 + 1),
+            offset: self.offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AutoZero, AutoZeroState, ZeroStep};
+    use crate::clock::SampleClock;
+
+    #[test]
+    pub fn test_auto_zero() {
+        // Windows every 1 ms, settling for 2 samples and averaging 3
+        let config = AutoZero::new(Duration::from_millis(1))
+            .settle(Duration::from_micros(20))
+            .window(Duration::from_micros(30));
+        let mut state = AutoZeroState::new(config, SampleClock::nominal());
+
+        assert_eq!(state.feed(0, 100., true), ZeroStep::PowerOff);
+        assert!(state.in_window());
+        let steps: Vec<_> = [50., 50., 1., 2., 3.]
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| state.feed(i + 1, c, false))
+            .collect();
+        assert!(steps.iter().all(|s| *s == ZeroStep::Drop));
+
+        let ZeroStep::Restore(correction) = state.feed(6, 100., false) else {
+            panic!("Expected the window to end");
+        };
+        assert_eq!((correction.sample, correction.offset), (7, 2.));
+        assert_eq!(state.feed(7, 100., true), ZeroStep::Keep(98.));
+        // The next window starts an interval after the previous one ended
+        assert_eq!(state.feed(105, 100., true), ZeroStep::Keep(98.));
+        assert_eq!(state.feed(106, 100., true), ZeroStep::PowerOff);
+    }
+
+    #[test]
+    pub fn test_auto_zero_unpowered() {
+        let mut state = AutoZeroState::new(
+            AutoZero::new(Duration::from_millis(1)),
+            SampleClock::nominal(),
+        );
+        // The window is skipped while device power is disabled
+        assert_eq!(state.feed(0, 100., false), ZeroStep::Keep(100.));
+        assert!(!state.in_window());
+        assert_eq!(state.feed(1, 100., true), ZeroStep::Keep(100.));
+        assert_eq!(state.feed(100, 100., true), ZeroStep::PowerOff);
+    }
+
+    #[test]
+    #[cfg(feature = "serial")]
+    pub fn test_auto_zero_device_power() {
+        use crate::{
+            measurement::{MeasurementEvent, MeasurementOptions},
+            mock::MockPpk2,
+            types::{DevicePower, MeasurementMode, SampleRate},
+            Ppk2,
+        };
+
+        let measure = |ppk2: Ppk2| {
+            let config = AutoZero::new(Duration::from_millis(20))
+                .settle(Duration::from_millis(1))
+                .window(Duration::from_millis(1));
+            let options =
+                MeasurementOptions::new(SampleRate::per_second(1000).unwrap()).auto_zero(config);
+            let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+            let events = handle.subscribe();
+            rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+            let ppk2 = handle.stop().unwrap();
+            let corrections = events
+                .try_iter()
+                .filter(|e| matches!(e, MeasurementEvent::AutoZero(_)))
+                .count();
+            (ppk2, corrections)
+        };
+
+        // No windows while device power is disabled
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        ppk2.set_device_power(DevicePower::Disabled).unwrap();
+        let (mut ppk2, corrections) = measure(ppk2);
+        assert_eq!(corrections, 0);
+        let power_sets = |mock: &MockPpk2| -> Vec<_> {
+            mock.commands()
+                .into_iter()
+                .filter(|c| c[0] == 0x0C)
+                .map(|c| c[1])
+                .collect()
+        };
+        assert_eq!(power_sets(&mock), [0]);
+
+        // Windows power off the device, and restore the power afterwards
+        ppk2.set_device_power(DevicePower::Enabled).unwrap();
+        let (ppk2, corrections) = measure(ppk2);
+        assert!(corrections > 0);
+        let sets = power_sets(&mock);
+        assert!(sets.contains(&0) && sets.ends_with(&[1]));
+        assert_eq!(ppk2.device_power(), DevicePower::Enabled);
+    }
+}
@@ -0,0 +1,145 @@
+//! Battery emulation helpers.
+
+use std::time::Duration;
+
+use crate::types::SourceVoltage;
+
+/// Internal resistance model of a battery, used to emulate the voltage
+/// sag a battery shows under load. When passed to
+/// [crate::measurement::MeasurementOptions::ir_drop], the source voltage
+/// is lowered proportionally to the measured current while measuring.
+/// Only has effect if the device is in [crate::types::MeasurementMode::Source].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrDropModel {
+    open_circuit_mv: u16,
+    internal_resistance: f32,
+    update_interval: Duration,
+}
+
+impl IrDropModel {
+    /// The PPK2 takes a while to settle after a regulator update,
+    /// so updating more often than this is pointless.
+    const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Create a new [IrDropModel] with the passed open circuit voltage
+    /// in millivolts and internal resistance in ohms.
+    pub fn new(open_circuit_mv: u16, internal_resistance: f32) -> Self {
+        Self {
+            open_circuit_mv,
+            internal_resistance: internal_resistance.max(0.),
+            update_interval: Duration::from_millis(50),
+        }
+    }
+
+    /// Approximation of a fresh CR2032 coin cell: 3.0V with 15Ω internal resistance.
+    pub fn cr2032() -> Self {
+        Self::new(3000, 15.)
+    }
+
+    /// Set the interval at which the source voltage is updated. Clamped
+    /// to the minimum interval the device can keep up with.
+    pub fn update_interval(mut self, interval: Duration) -> Self {
+        self.update_interval = interval.max(Self::MIN_UPDATE_INTERVAL);
+        self
+    }
+
+    /// Get the interval at which the source voltage is updated.
+    pub fn interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    /// Get the open circuit voltage in millivolts.
+    pub fn open_circuit_mv(&self) -> u16 {
+        self.open_circuit_mv
+    }
+
+    /// Get the internal resistance in ohms.
+    pub fn internal_resistance(&self) -> f32 {
+        self.internal_resistance
+    }
+
+    /// Calculate the terminal voltage in millivolts when the passed current is drawn.
+    pub fn millivolts_at(&self, micro_amps: f32) -> u16 {
+        // µA * Ω = µV, so divide by 1000 to get mV
+        let drop_mv = micro_amps.max(0.) * self.internal_resistance / 1000.;
+        (f32::from(self.open_circuit_mv) - drop_mv).max(0.) as u16
+    }
+
+    /// Calculate the [SourceVoltage] to apply when the passed current is drawn.
+    pub fn source_voltage_at(&self, micro_amps: f32) -> SourceVoltage {
+        SourceVoltage::from_millivolts(self.millivolts_at(micro_amps))
+    }
+}
+
+/// A source voltage update made by the [IrDropModel] emulation.
+/// See [crate::measurement::MeasurementEvent::IrDrop].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrDropUpdate {
+    /// The model the voltage was calculated with.
+    pub model: IrDropModel,
+    /// Index of the sample after the update interval, counted from the start
+    /// of the measurement and including missed samples.
+    pub sample: usize,
+    /// Time of that sample since the start of the measurement.
+    pub time: Duration,
+    /// Average current over the update interval in µA.
+    pub micro_amps: f32,
+    /// The source voltage that was applied.
+    pub vdd: SourceVoltage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IrDropModel;
+
+    #[test]
+    pub fn test_millivolts_at() {
+        let model = IrDropModel::new(3000, 20.);
+        assert_eq!(model.millivolts_at(0.), 3000);
+        // 10 mA through 20Ω drops 200 mV
+        assert_eq!(model.millivolts_at(10_000.), 2800);
+        // Negative currents don't raise the voltage
+        assert_eq!(model.millivolts_at(-10_000.), 3000);
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    pub fn test_ir_drop_events() {
+        use std::time::Duration;
+
+        use crate::{
+            clock::SampleClock,
+            measurement::MeasurementOptions,
+            mock::MockPpk2,
+            session::Session,
+            types::{MeasurementMode, SampleRate, SourceVoltage},
+            Ppk2,
+        };
+
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        ppk2.set_source_voltage(SourceVoltage::from_millivolts(3000))
+            .unwrap();
+        let model = IrDropModel::new(3000, 20.).update_interval(Duration::from_millis(10));
+        let options = MeasurementOptions::new(SampleRate::per_second(100).unwrap()).ir_drop(model);
+        let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+        let events = handle.subscribe();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        handle.stop().unwrap();
+
+        let mut session = Session::new(SampleClock::nominal());
+        events.try_iter().for_each(|e| session.record(&e));
+        assert_eq!(session.ir_drop, Some(model));
+        assert!(!session.ir_drop_updates.is_empty());
+        for update in &session.ir_drop_updates {
+            // 1 mA through 20Ω drops 20 mV
+            assert!((2979..=2981).contains(&update.vdd.millivolts()));
+            assert!((update.micro_amps - 1000.).abs() < 10.);
+        }
+        let times: Vec<_> = session.ir_drop_updates.iter().map(|u| u.time).collect();
+        assert!(times.windows(2).all(|t| t[0] < t[1]));
+        let regulator_sets = mock.commands().iter().filter(|c| c[0] == 0x0D).count();
+        assert!(regulator_sets > session.ir_drop_updates.len());
+    }
+}
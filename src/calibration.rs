@@ -0,0 +1,498 @@
+//! Conversion of raw ADC values to currents, using the calibration
+//! coefficients the device reports in its [Metadata].
+
+use ppk2_core::conversion::Modifiers;
+
+use crate::{
+    types::{MeasurementRange, Metadata, SourceVoltage},
+    Error, Result,
+};
+
+pub use ppk2_core::conversion::RANGES;
+
+/// Calibration coefficients of a single measurement range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangeCoefficients {
+    /// Shunt resistance in Ω
+    pub r: f32,
+    /// Quadratic gain
+    pub gs: f32,
+    /// Linear gain
+    pub gi: f32,
+    /// ADC offset
+    pub o: f32,
+    /// Gain depending on the source voltage
+    pub s: f32,
+    /// Current offset in A
+    pub i: f32,
+    /// User gain
+    pub ug: f32,
+}
+
+/// A reason to distrust the calibration of a device. The device may
+/// need recalibration before its measurements can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationWarning {
+    /// The device reports it was never calibrated.
+    NotCalibrated,
+    /// The shunt resistance of a range is not lower than that
+    /// of the range below it.
+    ResistanceOrder {
+        /// The range
+        range: usize,
+    },
+    /// The shunt resistance of a range deviates more than 50% from nominal.
+    ResistanceOutOfRange {
+        /// The range
+        range: usize,
+        /// The shunt resistance in Ω
+        r: f32,
+    },
+    /// The linear or user gain of a range deviates more than 50% from 1.
+    GainOutOfRange {
+        /// The range
+        range: usize,
+        /// The deviating gain
+        gain: f32,
+    },
+}
+
+impl std::fmt::Display for CalibrationWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalibrationWarning::NotCalibrated => write!(f, "device is not calibrated"),
+            CalibrationWarning::ResistanceOrder { range } => write!(
+                f,
+                "shunt resistance of range {range} is not lower than that of range {}",
+                range - 1
+            ),
+            CalibrationWarning::ResistanceOutOfRange { range, r } => {
+                write!(f, "shunt resistance of range {range} is implausible: {r} Ω")
+            }
+            CalibrationWarning::GainOutOfRange { range, gain } => {
+                write!(f, "gain of range {range} is implausible: {gain}")
+            }
+        }
+    }
+}
+
+/// Converts raw ADC values to currents. Can be constructed from
+/// device [Metadata], or from explicit coefficients for offline use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    modifiers: Modifiers,
+    vdd_mv: u16,
+}
+
+impl Calibration {
+    /// Create a new [Calibration] with the uncalibrated default coefficients,
+    /// for a device with the passed source voltage in millivolts.
+    pub fn new(vdd_mv: u16) -> Self {
+        Self {
+            modifiers: Modifiers::default(),
+            vdd_mv,
+        }
+    }
+
+    /// Set the coefficients of the passed range.
+    pub fn with_range(mut self, range: usize, coefficients: RangeCoefficients) -> Self {
+        let m = &mut self.modifiers;
+        let RangeCoefficients {
+            r,
+            gs,
+            gi,
+            o,
+            s,
+            i,
+            ug,
+        } = coefficients;
+        (m.r[range], m.gs[range], m.gi[range], m.o[range]) = (r, gs, gi, o);
+        (m.s[range], m.i[range], m.ug[range]) = (s, i, ug);
+        self
+    }
+
+    /// Get the coefficients of the passed range.
+    pub fn range(&self, range: usize) -> RangeCoefficients {
+        let m = &self.modifiers;
+        RangeCoefficients {
+            r: m.r[range],
+            gs: m.gs[range],
+            gi: m.gi[range],
+            o: m.o[range],
+            s: m.s[range],
+            i: m.i[range],
+            ug: m.ug[range],
+        }
+    }
+
+    /// Check that the coefficients can produce finite currents. Some units
+    /// report degenerate metadata, such as all-zero gains, which would
+    /// otherwise result in NaN or infinite currents.
+    pub fn validate(&self) -> Result<()> {
+        for range in 0..RANGES {
+            let c = self.range(range);
+            let invalid = |what: &str| {
+                Err(Error::InvalidCalibration(format!(
+                    "{what} in range {range}: {c:?}"
+                )))
+            };
+            if [c.r, c.gs, c.gi, c.o, c.s, c.i, c.ug]
+                .iter()
+                .any(|v| !v.is_finite())
+            {
+                return invalid("non-finite coefficient");
+            }
+            if c.r <= 0. {
+                return invalid("non-positive shunt resistance");
+            }
+            if c.ug == 0. || (c.gs == 0. && c.gi == 0.) {
+                return invalid("zero gain");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the coefficients against the nominal values of the device,
+    /// returning a warning for each implausible coefficient.
+    pub fn warnings(&self) -> Vec<CalibrationWarning> {
+        let nominal = Modifiers::default();
+        let implausible = |value: f32, nominal: f32| !(0.5..=1.5).contains(&(value / nominal));
+        let mut warnings = Vec::new();
+        for range in 0..RANGES {
+            let c = self.range(range);
+            if range > 0 && c.r >= self.range(range - 1).r {
+                warnings.push(CalibrationWarning::ResistanceOrder { range });
+            }
+            if implausible(c.r, nominal.r[range]) {
+                warnings.push(CalibrationWarning::ResistanceOutOfRange { range, r: c.r });
+            }
+            for gain in [c.gi, c.ug] {
+                if implausible(gain, 1.) {
+                    warnings.push(CalibrationWarning::GainOutOfRange { range, gain });
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Convert the ADC value of a sample, measured in the passed range,
+    /// to a current in A. Ranges above 4 are treated as range 4.
+    pub fn convert(&self, adc: u32, range: usize) -> f32 {
+        self.modifiers.convert(self.vdd_mv, adc, range)
+    }
+
+    /// Convert an ADC value that was already multiplied by 4, as done by
+    /// the official implementation.
+    pub(crate) fn convert_scaled(&self, adc_val: u32, range: usize) -> f32 {
+        self.modifiers.convert_scaled(self.vdd_mv, adc_val, range)
+    }
+}
+
+impl From<&Metadata> for Calibration {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            modifiers: metadata.modifiers.clone(),
+            vdd_mv: metadata.vdd,
+        }
+    }
+}
+
+/// A structured view of the calibration a device reports in its
+/// [Metadata], for sanity-checking a device. Displays as a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationInfo {
+    /// Whether the device reports it was calibrated
+    pub calibrated: bool,
+    /// Source voltage in mV
+    pub vdd: u16,
+    /// Coefficients per range, the most sensitive range first
+    pub ranges: [RangeCoefficients; RANGES],
+    /// Warnings about the plausibility of the coefficients
+    pub warnings: Vec<CalibrationWarning>,
+}
+
+impl From<&Metadata> for CalibrationInfo {
+    fn from(metadata: &Metadata) -> Self {
+        let calibration = Calibration::from(metadata);
+        Self {
+            calibrated: metadata.calibrated,
+            vdd: metadata.vdd,
+            ranges: std::array::from_fn(|range| calibration.range(range)),
+            warnings: metadata.calibration_warnings(),
+        }
+    }
+}
+
+impl std::fmt::Display for CalibrationInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Calibrated: {}, VDD: {} mV", self.calibrated, self.vdd)?;
+        writeln!(
+            f,
+            "{:>5} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12} {:>6}",
+            "Range", "R (Ω)", "GS", "GI", "O", "S", "I (A)", "UG"
+        )?;
+        for (range, c) in self.ranges.iter().enumerate() {
+            writeln!(
+                f,
+                "{range:>5} {:>10.4} {:>10.4} {:>10.4} {:>10.4} {:>12.9} {:>12.9} {:>6.3}",
+                c.r, c.gs, c.gi, c.o, c.s, c.i, c.ug
+            )?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "Warning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A precision resistor connected across the device under test terminals,
+/// drawing a known current to calibrate a measurement range against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferenceLoad {
+    /// The range to calibrate
+    pub range: MeasurementRange,
+    /// Resistance of the load in Ω
+    pub ohms: f32,
+}
+
+/// A guided calibration of the user gains, see
+/// [crate::Ppk2::calibrate_user_gains]. For each [ReferenceLoad], the
+/// current drawn from the source voltage is measured, and the user gain
+/// of its range is set so the measured current matches Ohm's law.
+/// The source voltage is assumed to be exact, so the accuracy of the
+/// result depends on that of the source voltage and the loads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuidedCalibration {
+    vdd: SourceVoltage,
+    loads: Vec<ReferenceLoad>,
+    samples: usize,
+}
+
+impl GuidedCalibration {
+    /// Create a new [GuidedCalibration] at the passed source voltage,
+    /// averaging 10000 samples per load by default.
+    pub fn new(vdd: SourceVoltage) -> Self {
+        Self {
+            vdd,
+            loads: Vec::new(),
+            samples: 10_000,
+        }
+    }
+
+    /// Add a load of `ohms` Ω to calibrate the passed range with. The load
+    /// must draw a current within the range, so the auto-ranging of the
+    /// device selects it.
+    pub fn load(mut self, range: MeasurementRange, ohms: f32) -> Self {
+        self.loads.push(ReferenceLoad { range, ohms });
+        self
+    }
+
+    /// Set the number of samples averaged per load.
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// Get the source voltage.
+    pub fn vdd(&self) -> SourceVoltage {
+        self.vdd
+    }
+
+    /// Get the loads, in the order they are measured.
+    pub fn loads(&self) -> &[ReferenceLoad] {
+        &self.loads
+    }
+
+    /// Get the number of samples averaged per load.
+    pub fn sample_count(&self) -> usize {
+        self.samples
+    }
+
+    /// Get the current in µA the passed load draws at the source voltage.
+    pub fn expected_micro_amps(&self, load: &ReferenceLoad) -> f32 {
+        f32::from(self.vdd.millivolts()) / load.ohms * 1e3
+    }
+
+    /// Compute the calibrated gain of a load from the current in µA
+    /// measured with the user gain `ug` in effect.
+    pub fn gain(&self, load: &ReferenceLoad, measured: f32, ug: f32) -> Result<CalibratedGain> {
+        let expected = self.expected_micro_amps(load);
+        let gain = ug * expected / measured;
+        if !gain.is_finite() || gain <= 0. {
+            return Err(Error::InvalidCalibration(format!(
+                "measured {measured} µA in range {}, expected {expected} µA",
+                u8::from(load.range)
+            )));
+        }
+        Ok(CalibratedGain {
+            range: load.range,
+            expected,
+            measured,
+            gain,
+        })
+    }
+}
+
+/// The result of calibrating a range with a [ReferenceLoad].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibratedGain {
+    /// The calibrated range
+    pub range: MeasurementRange,
+    /// The current in µA the load should draw
+    pub expected: f32,
+    /// The current in µA measured before calibrating
+    pub measured: f32,
+    /// The new user gain of the range
+    pub gain: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Calibration, CalibrationInfo, CalibrationWarning, RangeCoefficients};
+    use crate::types::Metadata;
+
+    /// Metadata as reported by a real device
+    const METADATA: &str = "Calibrated: 0
+R0: 1003.3506
+R1: 101.5865
+R2: 10.3027
+R3: 0.9636
+R4: 0.0564
+GS0: 0.0000
+GS1: 112.7890
+GS2: 18.0115
+GS3: 2.4217
+GS4: 0.0729
+GI0: 1.0000
+GI1: 0.9695
+GI2: 0.9609
+GI3: 0.9519
+GI4: 0.9582
+O0: 112.9420
+O1: 75.4627
+O2: 64.6020
+O3: 50.4983
+O4: 87.2177
+VDD: 3741
+HW: 9173
+mode: 2
+S0: 0.000000048
+S1: 0.000000596
+S2: 0.000005281
+S3: 0.000062577
+S4: 0.002940743
+I0: -0.000000104
+I1: -0.000001443
+I2: 0.000036439
+I3: -0.000374119
+I4: -0.009388455
+UG0: 1.00
+UG1: 1.00
+UG2: 1.00
+UG3: 1.00
+UG4: 1.00
+IA: 56
+END
+";
+
+    #[test]
+    #[allow(clippy::excessive_precision)]
+    pub fn test_convert() {
+        let metadata = Metadata::from_bytes(METADATA.as_bytes()).unwrap();
+        let calibration = Calibration::from(&metadata);
+
+        // Result of the official JS implementation for ADC value 108 / 4 in range 0
+        let micro_amps = calibration.convert(27, 0) * 1e6;
+        assert!((micro_amps - 0.021454880761611544).abs() < f32::EPSILON);
+
+        // Explicit coefficients give the same result
+        let explicit = Calibration::new(3741).with_range(0, calibration.range(0));
+        assert_eq!(explicit.convert(27, 0), calibration.convert(27, 0));
+        assert!(calibration.validate().is_ok());
+        assert_eq!(
+            metadata.calibration_warnings(),
+            [CalibrationWarning::NotCalibrated]
+        );
+        let swapped = calibration
+            .clone()
+            .with_range(0, calibration.range(1))
+            .with_range(1, calibration.range(0));
+        assert!(swapped
+            .warnings()
+            .contains(&CalibrationWarning::ResistanceOrder { range: 1 }));
+        let zero_gain = RangeCoefficients {
+            gs: 0.,
+            gi: 0.,
+            ..calibration.range(2)
+        };
+        let degenerate = calibration.clone().with_range(2, zero_gain);
+        assert!(degenerate.validate().is_err());
+
+        let info = metadata.calibration_info();
+        assert!(!info.calibrated);
+        assert_eq!(info.vdd, 3741);
+        assert_eq!(info.ranges[3], calibration.range(3));
+        assert_eq!(info.warnings, [CalibrationWarning::NotCalibrated]);
+        assert_eq!(CalibrationInfo::from(&metadata), info);
+        assert_eq!(info.to_string().lines().count(), 2 + 5 + 1);
+
+        assert_eq!(
+            explicit.range(0),
+            RangeCoefficients {
+                r: 1003.3506,
+                gs: 0.,
+                gi: 1.,
+                o: 112.942,
+                s: 0.000000048,
+                i: -0.000000104,
+                ug: 1.
+            }
+        );
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    pub fn test_guided_calibration() {
+        use super::GuidedCalibration;
+        use crate::{
+            measurement::Measurement,
+            mock::MockPpk2,
+            types::{DevicePower, MeasurementMode, MeasurementRange, SourceVoltage},
+            Ppk2,
+        };
+
+        // The device reads 10% high
+        let mock = MockPpk2::new().samples(|_| {
+            Some(Measurement {
+                micro_amps: 1100.,
+                ..Default::default()
+            })
+        });
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let calibration = GuidedCalibration::new(SourceVoltage::from_millivolts(3000))
+            .load(MeasurementRange::Range1, 3000.)
+            .samples(1000);
+        let mut connected = 0;
+        let gains = ppk2
+            .calibrate_user_gains(&calibration, |_| connected += 1)
+            .unwrap();
+        assert_eq!(connected, 1);
+        assert_eq!(gains.len(), 1);
+        assert!((gains[0].expected - 1000.).abs() < 0.01);
+        assert!((gains[0].gain - 1000. / 1100.).abs() < 0.01, "{gains:?}");
+        assert_eq!(
+            Calibration::from(ppk2.metadata()).range(1).ug,
+            gains[0].gain
+        );
+        assert_eq!(ppk2.device_power(), DevicePower::Disabled);
+
+        // No samples are measured in a range the load doesn't draw current in
+        let wrong_range = GuidedCalibration::new(SourceVoltage::from_millivolts(3000))
+            .load(MeasurementRange::Range0, 3000.)
+            .samples(100);
+        assert!(ppk2.calibrate_user_gains(&wrong_range, |_| ()).is_err());
+
+        let mut ampere = Ppk2::with_port(Box::new(mock), MeasurementMode::Ampere).unwrap();
+        assert!(ampere.calibrate_user_gains(&calibration, |_| ()).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Accumulation of the charge drawn by the device under test.
+//!
+//! The charge is integrated over the device samples using the sample
+//! period of the device, rather than the time at which data is received
+//! by the host. Data arrives in bursts, so integrating over receive
+//! timestamps gives wildly wrong totals.
+
+use std::time::Duration;
+
+use crate::{clock::SampleClock, measurement::Measurement, types::SourceVoltage};
+
+/// Charge drawn over a number of device samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Charge {
+    /// Number of device samples the charge was integrated over.
+    pub samples: u64,
+    /// Time spanned by the samples.
+    pub duration: Duration,
+    /// The charge in µAh.
+    pub micro_amp_hours: f64,
+}
+
+impl Charge {
+    /// The charge in mAh.
+    pub fn milli_amp_hours(&self) -> f64 {
+        self.micro_amp_hours / 1e3
+    }
+
+    /// The charge in C.
+    pub fn coulombs(&self) -> f64 {
+        self.micro_amp_hours * 3600. / 1e6
+    }
+
+    /// The average current over the samples in µA.
+    pub fn avg_micro_amps(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            secs if secs > 0. => self.micro_amp_hours * 3600. / secs,
+            _ => 0.,
+        }
+    }
+}
+
+/// Accumulates the charge of a stream of [Measurement]s, each of which is
+/// taken to span exactly one device sample period. Interpolated measurements
+/// are included, so gaps in the data don't shorten the integration.
+#[derive(Debug, Clone, Default)]
+pub struct ChargeAccumulator {
+    samples: u64,
+    /// Sum of the sample currents in µA
+    sum: f64,
+}
+
+impl ChargeAccumulator {
+    /// Create a new, empty [ChargeAccumulator].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a single device sample.
+    pub fn add(&mut self, measurement: &Measurement) {
+        self.samples += 1;
+        self.sum += f64::from(measurement.micro_amps);
+    }
+
+    /// Number of device samples added.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// The accumulated charge, with the sample period of the passed clock.
+    /// Use [SampleClock::measured] to correct for the actual device rate.
+    pub fn charge(&self, clock: &SampleClock) -> Charge {
+        let period = 1. / clock.rate();
+        Charge {
+            samples: self.samples,
+            duration: clock.time_at(self.samples),
+            micro_amp_hours: self.sum * period / 3600.,
+        }
+    }
+}
+
+impl<'a> Extend<&'a Measurement> for ChargeAccumulator {
+    fn extend<T: IntoIterator<Item = &'a Measurement>>(&mut self, iter: T) {
+        iter.into_iter().for_each(|m| self.add(m));
+    }
+}
+
+/// Charge and energy drawn over a number of device samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Energy {
+    /// The charge
+    pub charge: Charge,
+    /// The energy in µWh
+    pub micro_watt_hours: f64,
+}
+
+impl Energy {
+    /// The energy in J.
+    pub fn joules(&self) -> f64 {
+        self.micro_watt_hours * 3600. / 1e6
+    }
+}
+
+/// Accumulates the charge and energy of a stream of [Measurement]s, at a
+/// fixed source voltage. Unlike [ChargeAccumulator], gaps are accounted
+/// for explicitly: interpolated measurements are ignored, and the samples
+/// registered with [EnergyAccumulator::add_missed] are taken to carry the
+/// current of the last received sample. This way, missed samples count
+/// towards the duration, without distorting the average current.
+#[derive(Debug, Clone)]
+pub struct EnergyAccumulator {
+    charge: ChargeAccumulator,
+    vdd: SourceVoltage,
+    last: Option<f32>,
+    /// Missed samples before the first received sample
+    missed_before_first: u64,
+}
+
+impl EnergyAccumulator {
+    /// Create a new, empty [EnergyAccumulator] for a device
+    /// powered at the passed source voltage.
+    pub fn new(vdd: SourceVoltage) -> Self {
+        Self {
+            charge: ChargeAccumulator::new(),
+            vdd,
+            last: None,
+            missed_before_first: 0,
+        }
+    }
+
+    /// Add a single device sample. Interpolated samples are ignored.
+    pub fn add(&mut self, measurement: &Measurement) {
+        if measurement.synthetic {
+            return;
+        }
+        if self.last.is_none() {
+            // Fill the leading gap with the first current seen
+            let missed = std::mem::take(&mut self.missed_before_first);
+            self.add_held(measurement.micro_amps, missed);
+        }
+        self.charge.add(measurement);
+        self.last = Some(measurement.micro_amps);
+    }
+
+    /// Register missed samples, as returned by
+    /// [crate::measurement::MeasurementAccumulator::feed_into].
+    pub fn add_missed(&mut self, missed: usize) {
+        match self.last {
+            Some(last) => self.add_held(last, missed as u64),
+            None => self.missed_before_first += missed as u64,
+        }
+    }
+
+    fn add_held(&mut self, micro_amps: f32, samples: u64) {
+        self.charge.samples += samples;
+        self.charge.sum += f64::from(micro_amps) * samples as f64;
+    }
+
+    /// The accumulated charge and energy, with the sample period of the
+    /// passed clock.
+    pub fn energy(&self, clock: &SampleClock) -> Energy {
+        let charge = self.charge.charge(clock);
+        Energy {
+            charge,
+            micro_watt_hours: charge.micro_amp_hours * f64::from(self.vdd.millivolts()) / 1e3,
+        }
+    }
+}
+
+impl<'a> Extend<&'a Measurement> for EnergyAccumulator {
+    fn extend<T: IntoIterator<Item = &'a Measurement>>(&mut self, iter: T) {
+        iter.into_iter().for_each(|m| self.add(m));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChargeAccumulator, EnergyAccumulator};
+    use crate::{
+        clock::SampleClock,
+        measurement::Measurement,
+        types::{LogicPortPins, SourceVoltage},
+    };
+
+    #[test]
+    pub fn test_charge() {
+        let m = Measurement {
+            micro_amps: 1000.,
+            pins: LogicPortPins::default(),
+            synthetic: false,
+            envelope: None,
+            ..Default::default()
+        };
+        let mut acc = ChargeAccumulator::new();
+        // 36 seconds at 1 mA
+        let samples = 36 * SampleClock::NOMINAL_RATE;
+        acc.extend(std::iter::repeat_n(&m, samples));
+
+        let charge = acc.charge(&SampleClock::nominal());
+        assert_eq!(charge.samples, samples as u64);
+        assert!((charge.micro_amp_hours - 10.).abs() < 1e-6);
+        assert!((charge.avg_micro_amps() - 1000.).abs() < 1e-6);
+
+        // A device running 1% slow spans more time with the same samples
+        let slow = acc.charge(&SampleClock::with_rate(99_000.));
+        assert!((slow.milli_amp_hours() - 0.01 * 100_000. / 99_000.).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn test_energy() {
+        let m = |micro_amps| Measurement {
+            micro_amps,
+            pins: LogicPortPins::default(),
+            synthetic: false,
+            envelope: None,
+            ..Default::default()
+        };
+        let mut acc = EnergyAccumulator::new(SourceVoltage::from_millivolts(3000));
+        acc.add_missed(10);
+        acc.extend(std::iter::repeat_n(&m(1000.), 90));
+        // The gap holds the last current
+        acc.add_missed(100);
+        acc.extend(std::iter::repeat_n(&m(3000.), 200));
+
+        let energy = acc.energy(&SampleClock::nominal());
+        assert_eq!(energy.charge.samples, 400);
+        // 200 samples at 1 mA and 200 at 3 mA, 10 µs each
+        let micro_amp_hours = (200. * 1000. + 200. * 3000.) * 1e-5 / 3600.;
+        assert!((energy.charge.micro_amp_hours - micro_amp_hours).abs() < 1e-12);
+        assert!((energy.micro_watt_hours - micro_amp_hours * 3.).abs() < 1e-12);
+        assert!((energy.charge.coulombs() - 8e-6).abs() < 1e-15);
+    }
+}
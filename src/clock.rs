@@ -0,0 +1,154 @@
+//! Conversion between sample indices and time.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// Converts between sample indices and time, based on the rate
+/// at which the device produces samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleClock {
+    rate: f64,
+}
+
+impl Default for SampleClock {
+    fn default() -> Self {
+        Self::nominal()
+    }
+}
+
+impl SampleClock {
+    /// The nominal number of samples the PPK2 produces per second.
+    pub const NOMINAL_RATE: usize = 100_000;
+
+    /// The lowest fraction of the nominal rate at which a device is
+    /// considered to run at the nominal rate, see [SampleClock::is_nominal].
+    pub const MIN_RATE_RATIO: f64 = 0.9;
+
+    /// Create a [SampleClock] running at the nominal rate of the device.
+    pub const fn nominal() -> Self {
+        Self {
+            rate: Self::NOMINAL_RATE as f64,
+        }
+    }
+
+    /// Create a [SampleClock] running at the passed number of samples per second.
+    /// Rates that are not positive are replaced by the nominal rate.
+    pub fn with_rate(rate: f64) -> Self {
+        if rate.is_finite() && rate > 0. {
+            Self { rate }
+        } else {
+            Self::nominal()
+        }
+    }
+
+    /// Create a [SampleClock] running at the effective rate derived from
+    /// the number of samples that were produced within the passed duration.
+    pub fn measured(samples: u64, elapsed: Duration) -> Self {
+        Self::with_rate(samples as f64 / elapsed.as_secs_f64())
+    }
+
+    /// Number of samples per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Whether the clock runs at least at [SampleClock::MIN_RATE_RATIO]
+    /// of the nominal rate. Devices with older firmware deliver
+    /// far fewer samples per second.
+    pub fn is_nominal(&self) -> bool {
+        self.rate >= Self::NOMINAL_RATE as f64 * Self::MIN_RATE_RATIO
+    }
+
+    /// Time between two samples.
+    pub fn period(&self) -> Duration {
+        Duration::from_secs_f64(1. / self.rate)
+    }
+
+    /// Time of the sample at the passed index, relative to the first sample.
+    pub fn time_at(&self, index: u64) -> Duration {
+        Duration::from_secs_f64(index as f64 / self.rate)
+    }
+
+    /// Index of the sample taken at the passed time, relative to the first sample.
+    pub fn index_at(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * self.rate).round() as u64
+    }
+
+    /// Number of samples that span the passed duration.
+    pub fn samples_in(&self, duration: Duration) -> usize {
+        self.index_at(duration) as usize
+    }
+
+    /// Number of device samples to combine into one in order to produce
+    /// the passed number of samples per second. At least 1.
+    pub fn chunk_len(&self, sps: usize) -> usize {
+        (self.rate / sps.max(1) as f64).floor().max(1.) as usize
+    }
+}
+
+/// The host time at which a capture started, to correlate the time of
+/// samples, see [crate::measurement::Measurement::time], with timestamped
+/// logs. Taken right after the start command was written, so it's off by
+/// the USB latency, typically a millisecond or less. Over long captures,
+/// the device clock drifts from the host clock, which can be compensated
+/// for with a measured [SampleClock].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureAnchor {
+    /// Wall-clock time of the first sample
+    pub system_time: SystemTime,
+    /// Monotonic time of the first sample
+    pub instant: Instant,
+}
+
+impl CaptureAnchor {
+    /// Anchor a capture starting now.
+    pub fn now() -> Self {
+        Self {
+            system_time: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// Wall-clock time of the sample taken at the passed time into the capture.
+    pub fn system_time_at(&self, time: Duration) -> SystemTime {
+        self.system_time + time
+    }
+
+    /// Monotonic time of the sample taken at the passed time into the capture.
+    pub fn instant_at(&self, time: Duration) -> Instant {
+        self.instant + time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{CaptureAnchor, SampleClock};
+
+    #[test]
+    pub fn test_nominal() {
+        let clock = SampleClock::nominal();
+        assert_eq!(clock.period(), Duration::from_micros(10));
+        assert_eq!(clock.time_at(100_000), Duration::from_secs(1));
+        assert_eq!(clock.index_at(Duration::from_millis(5)), 500);
+        assert_eq!(clock.chunk_len(100), 1000);
+        assert_eq!(clock.chunk_len(1_000_000), 1);
+        assert!(clock.is_nominal());
+        assert!(SampleClock::with_rate(95_000.).is_nominal());
+        assert!(!SampleClock::with_rate(10_000.).is_nominal());
+    }
+
+    #[test]
+    pub fn test_capture_anchor() {
+        let anchor = CaptureAnchor {
+            system_time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            ..CaptureAnchor::now()
+        };
+        let time = SampleClock::nominal().time_at(250_000);
+        assert_eq!(
+            anchor.system_time_at(time),
+            UNIX_EPOCH + Duration::from_millis(1_700_000_002_500)
+        );
+        assert_eq!(anchor.instant_at(time) - anchor.instant, time);
+    }
+}
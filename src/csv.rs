@@ -0,0 +1,255 @@
+//! Reading and writing captures in the CSV layout of the nRF Connect Power
+//! Profiler app export.
+//!
+//! The exported data can be used as a reference to compare results of this
+//! crate against, for the same physical capture. Captures written with
+//! [write_csv] can be processed by the same scripts as app exports.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    time::Duration,
+};
+
+use crate::{clock::SampleClock, measurement::Measurement, types::LogicPortPins, Error, Result};
+
+/// Header of the CSV export of the nRF Connect Power Profiler app,
+/// as written by [write_csv].
+pub const HEADER: &str = "Timestamp(ms),Current(uA),D0-D7";
+
+/// Write the measurements in the CSV layout of the nRF Connect Power
+/// Profiler app: the time since the start in ms, the current in µA, and the
+/// logic port pins as a string of 0s and 1s, D0 first.
+pub fn write_csv<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+) -> Result<()> {
+    writeln!(writer, "{HEADER}")?;
+    write_rows(&mut writer, measurements, clock, 0)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the rows of [write_csv], the first one being sample `first`.
+pub(crate) fn write_rows<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+    first: u64,
+) -> Result<()> {
+    for (i, m) in measurements.into_iter().enumerate() {
+        let ms = clock.time_at(first + i as u64).as_secs_f64() * 1e3;
+        let pins: String = m
+            .pins
+            .inner()
+            .iter()
+            .map(|l| if l.is_high() { '1' } else { '0' })
+            .collect();
+        writeln!(writer, "{ms:.3},{},{pins}", m.micro_amps)?;
+    }
+    Ok(())
+}
+
+/// A single sample from an exported capture.
+#[derive(Debug, Clone)]
+pub struct CsvRecord {
+    /// Time since the start of the capture.
+    pub timestamp: Duration,
+    /// The measured current in µA.
+    pub micro_amps: f32,
+    /// Logic port state, if the export contained digital channels.
+    pub pins: Option<LogicPortPins>,
+}
+
+/// A capture exported by the nRF Connect Power Profiler app.
+#[derive(Debug, Clone, Default)]
+pub struct CsvCapture {
+    /// The samples in the capture
+    pub records: Vec<CsvRecord>,
+}
+
+enum Digital {
+    None,
+    /// Single column with all pins as a string of 0s and 1s, D0 first
+    Combined(usize),
+    /// One column per pin
+    Separate([usize; 8]),
+}
+
+impl CsvCapture {
+    /// Read a capture from CSV exported by the nRF Connect Power Profiler app.
+    /// The header determines the layout: a `Timestamp(ms)` or `Timestamp(us)`
+    /// column, a `Current(uA)` or `Current(nA)` column and optionally a `D0-D7`
+    /// column, or separate `D0` to `D7` columns. Comment lines starting with
+    /// `#`, like the annotations of [crate::export::AppCsvExporter], are skipped.
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
+        use Error::Parse;
+
+        let mut lines = BufReader::new(reader).lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| Parse("CSV header".to_owned()))??;
+        let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+        let find = |prefix: &str| columns.iter().position(|c| c.starts_with(prefix));
+
+        let ts_col = find("Timestamp").ok_or_else(|| Parse(header.clone()))?;
+        let ts_to_us = match columns[ts_col] {
+            "Timestamp(us)" => 1.,
+            "Timestamp(ms)" => 1_000.,
+            "Timestamp(s)" => 1_000_000.,
+            _ => return Err(Parse(header.clone())),
+        };
+        let current_col = find("Current").ok_or_else(|| Parse(header.clone()))?;
+        let current_to_ua = match columns[current_col] {
+            "Current(nA)" => 0.001,
+            "Current(uA)" => 1.,
+            "Current(mA)" => 1_000.,
+            _ => return Err(Parse(header.clone())),
+        };
+        let digital = if let Some(col) = find("D0-D7") {
+            Digital::Combined(col)
+        } else {
+            let mut cols = [0; 8];
+            let mut found = true;
+            for (i, col) in cols.iter_mut().enumerate() {
+                match columns.iter().position(|c| *c == format!("D{i}")) {
+                    Some(c) => *col = c,
+                    None => found = false,
+                }
+            }
+            if found {
+                Digital::Separate(cols)
+            } else {
+                Digital::None
+            }
+        };
+
+        let mut records = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let field = |col: usize| fields.get(col).ok_or_else(|| Parse(line.clone()));
+            let parse_f64 = |col: usize| -> Result<f64> {
+                field(col)?.parse().map_err(|_| Parse(line.clone()))
+            };
+            let parse_bit = |s: &str| match s {
+                "0" => Ok(false),
+                "1" => Ok(true),
+                _ => Err(Parse(line.clone())),
+            };
+
+            let timestamp = Duration::from_secs_f64(parse_f64(ts_col)?.max(0.) * ts_to_us / 1e6);
+            let micro_amps = (parse_f64(current_col)? * current_to_ua) as f32;
+            let pins = match &digital {
+                Digital::None => None,
+                Digital::Combined(col) => {
+                    let bits = field(*col)?;
+                    if bits.len() != 8 || !bits.is_ascii() {
+                        return Err(Parse(line.clone()));
+                    }
+                    let mut pins = [false; 8];
+                    for (i, pin) in pins.iter_mut().enumerate() {
+                        *pin = parse_bit(&bits[i..i + 1])?;
+                    }
+                    Some(pins.into())
+                }
+                Digital::Separate(cols) => {
+                    let mut pins = [false; 8];
+                    for (pin, col) in pins.iter_mut().zip(cols) {
+                        *pin = parse_bit(field(*col)?)?;
+                    }
+                    Some(pins.into())
+                }
+            };
+
+            records.push(CsvRecord {
+                timestamp,
+                micro_amps,
+                pins,
+            });
+        }
+
+        Ok(Self { records })
+    }
+
+    /// Duration of the capture, from the first to the last timestamp.
+    pub fn duration(&self) -> Duration {
+        match (self.records.first(), self.records.last()) {
+            (Some(first), Some(last)) => last.timestamp.saturating_sub(first.timestamp),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Average current in µA over all samples, or [None] if the capture is empty.
+    pub fn average_micro_amps(&self) -> Option<f32> {
+        if self.records.is_empty() {
+            return None;
+        }
+        let sum: f64 = self.records.iter().map(|r| r.micro_amps as f64).sum();
+        Some((sum / self.records.len() as f64) as f32)
+    }
+
+    /// Total charge in µC, integrating each sample over the time until the next one.
+    pub fn charge_micro_coulombs(&self) -> f64 {
+        self.records
+            .windows(2)
+            .map(|w| {
+                let dt = w[1].timestamp.saturating_sub(w[0].timestamp);
+                w[0].micro_amps as f64 * dt.as_secs_f64()
+            })
+            .sum()
+    }
+
+    /// Total energy in µJ, given the source voltage in millivolts.
+    pub fn energy_micro_joules(&self, vdd_mv: u16) -> f64 {
+        self.charge_micro_coulombs() * f64::from(vdd_mv) / 1000.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_csv, CsvCapture};
+    use crate::{clock::SampleClock, measurement::Measurement};
+
+    #[test]
+    pub fn test_from_reader() {
+        let csv = "Timestamp(ms),Current(uA),D0-D7\n\
+                   0.000,10.5,10000000\n\
+                   0.010,20.5,11000000\n\
+                   0.020,30.5,00000001\n";
+        let capture = CsvCapture::from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(capture.records.len(), 3);
+        assert_eq!(capture.average_micro_amps(), Some(20.5));
+        let pins = capture.records[1].pins.unwrap();
+        assert!(pins.pin_is_high(0) && pins.pin_is_high(1) && pins.pin_is_low(7));
+        assert!(capture.records[2].pins.unwrap().pin_is_high(7));
+        // 10.5 µA and 20.5 µA for 10 µs each
+        assert!((capture.charge_micro_coulombs() - 31e-5).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn test_write_csv() {
+        let measurements: Vec<_> = [(10.5, 0b0000_0001u8), (20.25, 0b1000_0010)]
+            .into_iter()
+            .map(|(micro_amps, pins)| Measurement {
+                micro_amps,
+                pins: pins.into(),
+                ..Default::default()
+            })
+            .collect();
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &measurements, &SampleClock::nominal()).unwrap();
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "Timestamp(ms),Current(uA),D0-D7\n0.000,10.5,10000000\n0.010,20.25,01000001\n"
+        );
+
+        let capture = CsvCapture::from_reader(csv.as_slice()).unwrap();
+        assert_eq!(capture.duration().as_micros(), 10);
+        let pins = capture.records[1].pins.unwrap();
+        assert_eq!(u8::from(pins), 0b1000_0010);
+    }
+}
@@ -0,0 +1,588 @@
+//! Pluggable writers of captures. Implement [Exporter] to write measurements
+//! in a format this crate doesn't support, and register it with an
+//! [ExporterRegistry] to make it selectable by name, like the CLI example
+//! does for its `--output` argument and `convert` command.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    clock::SampleClock,
+    csv,
+    measurement::{Measurement, SegmentSummary},
+    ppk2_file, saleae,
+    session::Session,
+    types::Metadata,
+    vcd::VcdWriter,
+    Error, Result,
+};
+
+/// Information about a capture, passed to [Exporter::start].
+#[derive(Debug, Clone)]
+pub struct ExportInfo {
+    /// The clock the measurements are sampled at.
+    pub clock: SampleClock,
+    /// Metadata of the device the capture is recorded with, if known.
+    pub metadata: Option<Metadata>,
+    /// When the capture started.
+    pub start: SystemTime,
+}
+
+/// Writer of a capture in some format. Called with [Exporter::start] first,
+/// then any number of [Exporter::write_batch], [Exporter::annotate] and
+/// [Exporter::segment] calls, and [Exporter::finish] last.
+pub trait Exporter: Send {
+    /// Start writing a capture.
+    fn start(&mut self, info: &ExportInfo) -> Result<()>;
+
+    /// Write a batch of consecutive measurements.
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()>;
+
+    /// Annotate the capture at the passed time since its start, e.g. with
+    /// a measurement event. Ignored by default.
+    fn annotate(&mut self, time: Duration, text: &str) -> Result<()> {
+        let _ = (time, text);
+        Ok(())
+    }
+
+    /// Record a labeled segment of the capture, starting at the passed
+    /// time since the start of the capture. Annotates the start of the
+    /// segment with its label by default.
+    fn segment(&mut self, time: Duration, segment: &SegmentSummary) -> Result<()> {
+        self.annotate(time, &format!("segment {}", segment.label))
+    }
+
+    /// Finish writing the capture, flushing any buffered data.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Write a whole session, annotated with its auto-zero corrections and
+/// labeled segments. The start of a segment is taken to be at the nominal
+/// sample rate of the device.
+pub fn export_session(
+    exporter: &mut dyn Exporter,
+    session: &Session,
+    start: SystemTime,
+) -> Result<()> {
+    exporter.start(&ExportInfo {
+        clock: session.clock,
+        metadata: session.metadata.clone(),
+        start,
+    })?;
+    exporter.write_batch(&session.measurements)?;
+    for correction in &session.corrections {
+        let text = format!("auto-zero offset {} µA", correction.offset);
+        exporter.annotate(correction.time, &text)?;
+    }
+    let device_clock = SampleClock::nominal();
+    for segment in &session.segments {
+        exporter.segment(device_clock.time_at(segment.start as u64), segment)?;
+    }
+    exporter.finish()
+}
+
+/// Writes the current in the Saleae Logic 2 analog CSV layout.
+/// See [saleae::write_analog_csv].
+pub struct CsvExporter<W> {
+    writer: W,
+    clock: SampleClock,
+    written: u64,
+}
+
+impl<W: Write + Send> CsvExporter<W> {
+    /// Create a new [CsvExporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            clock: SampleClock::nominal(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write + Send> Exporter for CsvExporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.clock = info.clock;
+        writeln!(self.writer, "Time [s],Channel 0")?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        saleae::write_analog_rows(&mut self.writer, measurements, &self.clock, self.written)?;
+        self.written += measurements.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the current and logic port pins in the CSV layout of the
+/// nRF Connect Power Profiler app export. See [csv::write_csv].
+/// Annotations, like segment labels, are written as comment lines
+/// starting with `#`, followed by the time in ms.
+pub struct AppCsvExporter<W> {
+    writer: W,
+    clock: SampleClock,
+    written: u64,
+}
+
+impl<W: Write + Send> AppCsvExporter<W> {
+    /// Create a new [AppCsvExporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            clock: SampleClock::nominal(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write + Send> Exporter for AppCsvExporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.clock = info.clock;
+        writeln!(self.writer, "{}", csv::HEADER)?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        csv::write_rows(&mut self.writer, measurements, &self.clock, self.written)?;
+        self.written += measurements.len() as u64;
+        Ok(())
+    }
+
+    fn annotate(&mut self, time: Duration, text: &str) -> Result<()> {
+        let ms = time.as_secs_f64() * 1e3;
+        writeln!(self.writer, "# {ms:.3} {}", text.replace('\n', " "))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the logic port pins in the Saleae Logic 2 digital CSV layout.
+/// See [saleae::write_digital_csv].
+pub struct LogicCsvExporter<W> {
+    writer: W,
+    clock: SampleClock,
+    written: u64,
+    prev: Option<[u8; 8]>,
+}
+
+impl<W: Write + Send> LogicCsvExporter<W> {
+    /// Create a new [LogicCsvExporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            clock: SampleClock::nominal(),
+            written: 0,
+            prev: None,
+        }
+    }
+}
+
+impl<W: Write + Send> Exporter for LogicCsvExporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.clock = info.clock;
+        writeln!(
+            self.writer,
+            "Time [s],Channel 0,Channel 1,Channel 2,Channel 3,Channel 4,Channel 5,Channel 6,Channel 7"
+        )?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        saleae::write_digital_rows(
+            &mut self.writer,
+            measurements,
+            &self.clock,
+            self.written,
+            &mut self.prev,
+        )?;
+        self.written += measurements.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the current and logic port pins as a Value Change Dump.
+/// Annotations are written as comments. See [crate::vcd].
+pub struct VcdExporter<W> {
+    writer: W,
+    vcd: VcdWriter,
+}
+
+impl<W: Write + Send> VcdExporter<W> {
+    /// Create a new [VcdExporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            vcd: VcdWriter::new(SampleClock::nominal()),
+        }
+    }
+}
+
+impl<W: Write + Send> Exporter for VcdExporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.vcd = VcdWriter::new(info.clock);
+        self.vcd.write_header(&mut self.writer)
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        self.vcd.write_rows(&mut self.writer, measurements)
+    }
+
+    fn annotate(&mut self, time: Duration, text: &str) -> Result<()> {
+        self.vcd.write_comment(&mut self.writer, time, text)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "unstable")]
+/// Writes an Apache Parquet file. The measurements are buffered, and
+/// written on [Exporter::finish]. See [crate::parquet]. Requires the
+/// `unstable` feature, and isn't one of the built-in exporters of the
+/// [ExporterRegistry].
+pub struct ParquetExporter<W> {
+    writer: W,
+    clock: SampleClock,
+    measurements: Vec<Measurement>,
+}
+
+#[cfg(feature = "unstable")]
+impl<W: Write + Send> ParquetExporter<W> {
+    /// Create a new [ParquetExporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            clock: SampleClock::nominal(),
+            measurements: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "unstable")]
+impl<W: Write + Send> Exporter for ParquetExporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.clock = info.clock;
+        self.measurements.clear();
+        Ok(())
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        self.measurements.extend_from_slice(measurements);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        crate::parquet::write_parquet(&mut self.writer, &self.measurements, &self.clock)
+    }
+}
+
+/// Writes the `.ppk2` format of the nRF Connect Power Profiler app.
+/// The measurements are buffered, and written on [Exporter::finish].
+/// Segments are written too, other annotations are ignored. See [ppk2_file].
+pub struct Ppk2Exporter<W> {
+    writer: W,
+    session: Session,
+    start: SystemTime,
+}
+
+impl<W: Write + Send> Ppk2Exporter<W> {
+    /// Create a new [Ppk2Exporter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            session: Session::default(),
+            start: SystemTime::now(),
+        }
+    }
+}
+
+impl<W: Write + Send> Exporter for Ppk2Exporter<W> {
+    fn start(&mut self, info: &ExportInfo) -> Result<()> {
+        self.session = Session::new(info.clock);
+        self.session.metadata = info.metadata.clone();
+        self.start = info.start;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+        self.session.extend(measurements.iter().cloned());
+        Ok(())
+    }
+
+    fn segment(&mut self, _time: Duration, segment: &SegmentSummary) -> Result<()> {
+        self.session.segments.push(segment.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        ppk2_file::write_session(&mut self.writer, &self.session, self.start)
+    }
+}
+
+/// Creates an [Exporter] writing to the file at the passed path.
+pub type ExporterFactory = fn(&Path) -> Result<Box<dyn Exporter>>;
+
+/// Prefix of the names of exporters registered with
+/// [ExporterRegistry::register_custom].
+pub const CUSTOM_PREFIX: &str = "custom:";
+
+struct Registration {
+    name: String,
+    extension: &'static str,
+    factory: ExporterFactory,
+}
+
+/// Set of [Exporter]s selectable by name. Contains the built-in `csv`,
+/// `app-csv`, `logic-csv`, `vcd` and `ppk2` exporters. Third-party exporters are registered as `custom:<name>`,
+/// so they can't shadow built-in ones.
+pub struct ExporterRegistry {
+    exporters: Vec<Registration>,
+}
+
+impl Default for ExporterRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            exporters: Vec::new(),
+        };
+        registry.register("csv".into(), "csv", |path| {
+            Ok(Box::new(CsvExporter::new(BufWriter::new(File::create(
+                path,
+            )?))))
+        });
+        registry.register("app-csv".into(), "app.csv", |path| {
+            Ok(Box::new(AppCsvExporter::new(BufWriter::new(File::create(
+                path,
+            )?))))
+        });
+        registry.register("logic-csv".into(), "logic.csv", |path| {
+            Ok(Box::new(LogicCsvExporter::new(BufWriter::new(
+                File::create(path)?,
+            ))))
+        });
+        registry.register("vcd".into(), "vcd", |path| {
+            Ok(Box::new(VcdExporter::new(BufWriter::new(File::create(
+                path,
+            )?))))
+        });
+        registry.register("ppk2".into(), "ppk2", |path| {
+            Ok(Box::new(Ppk2Exporter::new(BufWriter::new(File::create(
+                path,
+            )?))))
+        });
+        registry
+    }
+}
+
+impl ExporterRegistry {
+    /// Create a new [ExporterRegistry] with the built-in exporters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(&mut self, name: String, extension: &'static str, factory: ExporterFactory) {
+        self.exporters.retain(|r| r.name != name);
+        self.exporters.push(Registration {
+            name,
+            extension,
+            factory,
+        });
+    }
+
+    /// Register a third-party exporter as `custom:<name>`, writing files with
+    /// the passed extension. Replaces an exporter registered with the same name.
+    pub fn register_custom(
+        &mut self,
+        name: &str,
+        extension: &'static str,
+        factory: ExporterFactory,
+    ) {
+        self.register(format!("{CUSTOM_PREFIX}{name}"), extension, factory);
+    }
+
+    /// Names of the registered exporters.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.exporters.iter().map(|r| r.name.as_str())
+    }
+
+    /// The file extension of the exporter with the passed name.
+    pub fn extension(&self, name: &str) -> Option<&'static str> {
+        self.find(name).ok().map(|r| r.extension)
+    }
+
+    /// Create the exporter with the passed name, writing to `path`.
+    pub fn create(&self, name: &str, path: &Path) -> Result<Box<dyn Exporter>> {
+        (self.find(name)?.factory)(path)
+    }
+
+    fn find(&self, name: &str) -> Result<&Registration> {
+        self.exporters
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| Error::UnknownExporter(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use super::{
+        export_session, AppCsvExporter, CsvExporter, ExportInfo, Exporter, ExporterRegistry,
+        Ppk2Exporter, VcdExporter,
+    };
+    use crate::{
+        autozero::ZeroCorrection,
+        clock::SampleClock,
+        measurement::{Measurement, SegmentSummary},
+        ppk2_file,
+        session::Session,
+        Error, Result,
+    };
+
+    /// Counts the calls made to it.
+    #[derive(Default)]
+    struct Counter(Arc<Mutex<Vec<String>>>);
+
+    impl Exporter for Counter {
+        fn start(&mut self, _info: &ExportInfo) -> Result<()> {
+            self.0.lock().unwrap().push("start".into());
+            Ok(())
+        }
+
+        fn write_batch(&mut self, measurements: &[Measurement]) -> Result<()> {
+            self.0.lock().unwrap().push(measurements.len().to_string());
+            Ok(())
+        }
+
+        fn annotate(&mut self, time: Duration, text: &str) -> Result<()> {
+            self.0.lock().unwrap().push(format!("{time:?} {text}"));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push("finish".into());
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_exporters() {
+        let measurements: Vec<_> = [1., 2.]
+            .into_iter()
+            .map(|micro_amps| Measurement {
+                micro_amps,
+                ..Default::default()
+            })
+            .collect();
+        let mut session = Session::from_measurements(SampleClock::with_rate(10.), measurements);
+        session.corrections.push(ZeroCorrection {
+            sample: 1,
+            time: Duration::from_millis(100),
+            offset: 0.5,
+        });
+        session.segments.push(SegmentSummary {
+            label: "idle".into(),
+            start: 10_000,
+            samples: 1,
+            missed: 0,
+            avg_micro_amps: Some(2.),
+        });
+
+        let mut csv = CsvExporter::new(Vec::new());
+        export_session(&mut csv, &session, SystemTime::now()).unwrap();
+        let csv = String::from_utf8(csv.writer).unwrap();
+        assert_eq!(
+            csv,
+            "Time [s],Channel 0\n0.000000000,1e-6\n0.100000000,2e-6\n"
+        );
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut counter = Counter(calls.clone());
+        export_session(&mut counter, &session, SystemTime::now()).unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "start",
+                "2",
+                "100ms auto-zero offset 0.5 µA",
+                "100ms segment idle",
+                "finish"
+            ]
+        );
+
+        let mut app_csv = AppCsvExporter::new(Vec::new());
+        export_session(&mut app_csv, &session, SystemTime::now()).unwrap();
+        let app_csv = String::from_utf8(app_csv.writer).unwrap();
+        assert!(app_csv.ends_with("# 100.000 segment idle\n"), "{app_csv}");
+        let capture = crate::csv::CsvCapture::from_reader(app_csv.as_bytes()).unwrap();
+        assert_eq!(capture.records.len(), 2);
+
+        let mut vcd = VcdExporter::new(Vec::new());
+        export_session(&mut vcd, &session, SystemTime::now()).unwrap();
+        let vcd = String::from_utf8(vcd.writer).unwrap();
+        assert!(vcd.contains("#100000000\nr2 !\n"), "{vcd}");
+        assert!(vcd.ends_with("$comment 100.000 ms: segment idle $end\n"));
+
+        let mut ppk2 = Ppk2Exporter::new(Vec::new());
+        export_session(&mut ppk2, &session, SystemTime::now()).unwrap();
+        let read = ppk2_file::read_session(ppk2.writer.as_slice()).unwrap();
+        let [segment] = read.session.segments.as_slice() else {
+            panic!("Expected one segment");
+        };
+        assert_eq!(
+            (
+                segment.label.as_str(),
+                segment.start,
+                segment.avg_micro_amps
+            ),
+            ("idle", 10_000, Some(2.))
+        );
+
+        let mut registry = ExporterRegistry::new();
+        registry.register_custom("counter", "txt", |_: &Path| {
+            Ok(Box::new(Counter::default()))
+        });
+        let names: Vec<_> = registry.names().collect();
+        assert_eq!(
+            names,
+            [
+                "csv",
+                "app-csv",
+                "logic-csv",
+                "vcd",
+                "ppk2",
+                "custom:counter"
+            ]
+        );
+        assert_eq!(registry.extension("custom:counter"), Some("txt"));
+        assert!(registry.create("custom:counter", Path::new("")).is_ok());
+        assert!(matches!(
+            registry.create("counter", Path::new("")),
+            Err(Error::UnknownExporter(_))
+        ));
+    }
+}
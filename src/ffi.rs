@@ -0,0 +1,317 @@
+//! C ABI for driving a PPK2 from C or C++. Requires the `ffi` feature.
+//!
+//! The declarations are in `include/ppk2.h`, which is generated with
+//! [cbindgen](https://github.com/mozilla/cbindgen):
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --output include/ppk2.h
+//! ```
+//!
+//! Build a shared or static library to link against with
+//! `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`).
+//!
+//! All functions return [PPK2_OK] on success, or a negative error code.
+//! A description of the last error on the calling thread is available
+//! through [ppk2_last_error].
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    measurement::{MeasurementMatch, MeasurementOptions},
+    try_find_ppk2_port,
+    types::{DevicePower, MeasurementMode, SampleRate, SourceVoltage},
+    MeasurementHandle, Ppk2,
+};
+
+/// The call succeeded.
+pub const PPK2_OK: i32 = 0;
+/// A required pointer argument was null.
+pub const PPK2_ERR_NULL: i32 = -1;
+/// An argument was out of range, or not valid UTF-8.
+pub const PPK2_ERR_INVALID_ARG: i32 = -2;
+/// Communicating with the device failed.
+pub const PPK2_ERR_DEVICE: i32 = -3;
+/// The call is not valid in the current state, e.g. starting a
+/// measurement that is already running.
+pub const PPK2_ERR_STATE: i32 = -4;
+/// The call panicked. This is a bug.
+pub const PPK2_ERR_PANIC: i32 = -5;
+
+/// Receives a combined measurement: the current in µA, and the logic port
+/// pin levels with D0 in the least significant bit. Called from a thread
+/// owned by the library, so it must be safe to call from any thread.
+/// May be null in C, which is rejected with [PPK2_ERR_NULL].
+pub type Ppk2SampleCallback =
+    Option<extern "C" fn(user_data: *mut c_void, micro_amps: f32, pins: u8)>;
+
+/// An opened PPK2. Created with [ppk2_open], and freed with [ppk2_close].
+pub struct Ppk2Device {
+    /// [None] if the device was lost because starting or stopping
+    /// a measurement failed
+    state: Option<State>,
+}
+
+enum State {
+    Idle(Box<Ppk2>),
+    Measuring {
+        handle: Box<MeasurementHandle>,
+        delivery: JoinHandle<()>,
+    },
+}
+
+/// Pointer passed back to the callback, from the delivery thread.
+struct UserData(*mut c_void);
+
+// The caller guarantees the callback can be called with it from any thread
+unsafe impl Send for UserData {}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, translating errors and panics to an error code.
+fn guard(f: impl FnOnce() -> Result<(), (i32, String)>) -> i32 {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => PPK2_OK,
+        Ok(Err((code, msg))) => {
+            set_last_error(msg);
+            code
+        }
+        Err(_) => {
+            set_last_error("panicked");
+            PPK2_ERR_PANIC
+        }
+    }
+}
+
+fn device_err(e: crate::Error) -> (i32, String) {
+    (PPK2_ERR_DEVICE, e.to_string())
+}
+
+/// Get the device behind `device`, if it's not measuring.
+fn idle<'a>(device: *mut Ppk2Device) -> Result<&'a mut Ppk2, (i32, String)> {
+    // SAFETY: the caller passes a pointer obtained from ppk2_open
+    match unsafe { device.as_mut() } {
+        None => Err((PPK2_ERR_NULL, "device is null".to_owned())),
+        Some(Ppk2Device {
+            state: Some(State::Idle(ppk2)),
+        }) => Ok(ppk2),
+        Some(_) => Err((PPK2_ERR_STATE, "device is measuring or failed".to_owned())),
+    }
+}
+
+/// Open the PPK2 at the serial port `port`, or the first PPK2 found if
+/// `port` is null, and configure the measurement mode: 1 for ampere meter,
+/// 2 for source meter. On success, `*out` points to the opened device.
+///
+/// # Safety
+/// `port` must be null or a valid null-terminated string, and `out` must
+/// be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_open(
+    port: *const c_char,
+    mode: u8,
+    out: *mut *mut Ppk2Device,
+) -> i32 {
+    guard(|| {
+        if out.is_null() {
+            return Err((PPK2_ERR_NULL, "out is null".to_owned()));
+        }
+        // TryFrom falls back to the default mode for unknown values
+        let mode = match mode {
+            1 => MeasurementMode::Ampere,
+            2 => MeasurementMode::Source,
+            _ => return Err((PPK2_ERR_INVALID_ARG, format!("invalid mode {mode}"))),
+        };
+        let port = match port.is_null() {
+            true => try_find_ppk2_port().map_err(device_err)?,
+            false => unsafe { CStr::from_ptr(port) }
+                .to_str()
+                .map_err(|e| (PPK2_ERR_INVALID_ARG, e.to_string()))?
+                .to_owned(),
+        };
+        let ppk2 = Ppk2::new(port, mode).map_err(device_err)?;
+        let device = Box::new(Ppk2Device {
+            state: Some(State::Idle(Box::new(ppk2))),
+        });
+        unsafe { *out = Box::into_raw(device) };
+        Ok(())
+    })
+}
+
+/// Set the source voltage in millivolts.
+///
+/// # Safety
+/// `device` must be null or a pointer obtained from [ppk2_open].
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_set_source_voltage(device: *mut Ppk2Device, millivolts: u16) -> i32 {
+    guard(|| {
+        idle(device)?
+            .set_source_voltage(SourceVoltage::from_millivolts(millivolts))
+            .map_err(device_err)
+    })
+}
+
+/// Enable or disable power to the device under test.
+///
+/// # Safety
+/// `device` must be null or a pointer obtained from [ppk2_open].
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_set_device_power(device: *mut Ppk2Device, enabled: bool) -> i32 {
+    guard(|| {
+        let power = match enabled {
+            true => DevicePower::Enabled,
+            false => DevicePower::Disabled,
+        };
+        idle(device)?.set_device_power(power).map_err(device_err)
+    })
+}
+
+/// Start measuring at `sps` combined measurements per second. Each combined
+/// measurement is passed to `callback`, along with `user_data`, until
+/// [ppk2_stop] is called. Returns [PPK2_ERR_NULL] if `callback` is null.
+/// If starting fails with [PPK2_ERR_DEVICE], the device can only be closed.
+///
+/// # Safety
+/// `device` must be null or a pointer obtained from [ppk2_open], and
+/// `callback` must be safe to call with `user_data` from another thread.
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_start(
+    device: *mut Ppk2Device,
+    sps: u32,
+    callback: Ppk2SampleCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        idle(device)?;
+        let Some(callback) = callback else {
+            return Err((PPK2_ERR_NULL, "callback is null".to_owned()));
+        };
+        let sps = SampleRate::per_second(sps as usize)
+            .map_err(|e| (PPK2_ERR_INVALID_ARG, e.to_string()))?;
+        // SAFETY: checked by idle
+        let device = unsafe { &mut *device };
+        let Some(State::Idle(ppk2)) = device.state.take() else {
+            unreachable!("checked by idle");
+        };
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(sps))
+            .map_err(device_err)?;
+        let user_data = UserData(user_data);
+        let delivery = thread::spawn(move || {
+            let user_data = user_data;
+            for m in rx {
+                if let MeasurementMatch::Match(m) = m {
+                    callback(user_data.0, m.micro_amps, m.pins.into());
+                }
+            }
+        });
+        device.state = Some(State::Measuring {
+            handle: Box::new(handle),
+            delivery,
+        });
+        Ok(())
+    })
+}
+
+/// Stop the measurement started with [ppk2_start]. The callback is not
+/// called anymore once this returns. If stopping fails with
+/// [PPK2_ERR_DEVICE], the device can only be closed.
+///
+/// # Safety
+/// `device` must be null or a pointer obtained from [ppk2_open].
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_stop(device: *mut Ppk2Device) -> i32 {
+    guard(|| {
+        let Some(device) = (unsafe { device.as_mut() }) else {
+            return Err((PPK2_ERR_NULL, "device is null".to_owned()));
+        };
+        let Some(State::Measuring { handle, delivery }) = device.state.take() else {
+            return Err((PPK2_ERR_STATE, "device is not measuring".to_owned()));
+        };
+        let stopped = handle.stop();
+        // The measurement sender is dropped once the pipeline stopped
+        let _ = delivery.join();
+        device.state = Some(State::Idle(Box::new(stopped.map_err(device_err)?)));
+        Ok(())
+    })
+}
+
+/// Stop measuring if needed, and close the device. `device` must not be
+/// used afterwards. Errors while stopping are available through
+/// [ppk2_last_error].
+///
+/// # Safety
+/// `device` must be null or a pointer obtained from [ppk2_open].
+#[no_mangle]
+pub unsafe extern "C" fn ppk2_close(device: *mut Ppk2Device) {
+    if device.is_null() {
+        return;
+    }
+    guard(|| {
+        if matches!(unsafe { &(*device).state }, Some(State::Measuring { .. })) {
+            unsafe { ppk2_stop(device) };
+        }
+        drop(unsafe { Box::from_raw(device) });
+        Ok(())
+    });
+}
+
+/// Description of the last error on the calling thread, or null if there
+/// was none. Valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn ppk2_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CStr, ptr};
+
+    use super::{
+        ppk2_close, ppk2_last_error, ppk2_open, ppk2_set_device_power, ppk2_start, ppk2_stop,
+        Ppk2Device, State, PPK2_ERR_INVALID_ARG, PPK2_ERR_NULL,
+    };
+    use crate::{mock::MockPpk2, types::MeasurementMode, Ppk2};
+
+    #[test]
+    pub fn test_ffi_errors() {
+        let mut device = ptr::null_mut();
+        assert_eq!(
+            unsafe { ppk2_open(ptr::null(), 7, &mut device) },
+            PPK2_ERR_INVALID_ARG
+        );
+        assert!(device.is_null());
+        let msg = unsafe { CStr::from_ptr(ppk2_last_error()) };
+        assert_eq!(msg.to_str().unwrap(), "invalid mode 7");
+
+        assert_eq!(
+            unsafe { ppk2_set_device_power(device, true) },
+            PPK2_ERR_NULL
+        );
+        assert_eq!(unsafe { ppk2_stop(device) }, PPK2_ERR_NULL);
+
+        let ppk2 = Ppk2::with_port(Box::new(MockPpk2::new()), MeasurementMode::Source).unwrap();
+        let device = Box::into_raw(Box::new(Ppk2Device {
+            state: Some(State::Idle(Box::new(ppk2))),
+        }));
+        assert_eq!(
+            unsafe { ppk2_start(device, 100, None, ptr::null_mut()) },
+            PPK2_ERR_NULL
+        );
+        let msg = unsafe { CStr::from_ptr(ppk2_last_error()) };
+        assert_eq!(msg.to_str().unwrap(), "callback is null");
+        unsafe { ppk2_close(device) };
+    }
+}
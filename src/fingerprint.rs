@@ -0,0 +1,231 @@
+//! Energy fingerprints of captures, for comparing a capture against a
+//! known good "golden" capture, for instance as a release qualification gate.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::{measurement::SegmentSummary, session::Session, Error};
+
+/// Energy used in a single region of a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionEnergy {
+    /// Region label
+    pub label: String,
+    /// Energy used within the region in µJ.
+    pub micro_joules: f64,
+}
+
+/// Reduction of a capture to the energy used per region.
+/// Can be stored as text using its [Display] and [FromStr] implementations:
+/// one region per line, with the label and energy separated by a tab.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fingerprint {
+    /// Energy per region, in order of occurrence.
+    pub regions: Vec<RegionEnergy>,
+}
+
+/// Maximum allowed deviation of the energy of a region from the golden
+/// fingerprint. A region passes if it's within either tolerance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// Relative tolerance, e.g. 0.05 for 5%.
+    pub relative: f64,
+    /// Absolute tolerance in µJ.
+    pub absolute_micro_joules: f64,
+}
+
+/// Result of comparing a single region to the golden fingerprint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionDeviation {
+    /// Region label
+    pub label: String,
+    /// Energy of the region in the golden fingerprint in µJ, if present.
+    pub golden: Option<f64>,
+    /// Energy of the region in the compared fingerprint in µJ, if present.
+    pub actual: Option<f64>,
+    /// Whether the region is present in both fingerprints and within tolerance.
+    pub passed: bool,
+}
+
+impl RegionDeviation {
+    /// Relative deviation from the golden energy, if the region is present
+    /// in both fingerprints and its golden energy is not zero.
+    pub fn relative(&self) -> Option<f64> {
+        match (self.golden, self.actual) {
+            (Some(golden), Some(actual)) if golden != 0. => Some((actual - golden) / golden.abs()),
+            _ => None,
+        }
+    }
+}
+
+/// Result of comparing a fingerprint to a golden fingerprint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    /// Per-region comparison results, in order of the golden fingerprint,
+    /// followed by regions missing from the golden fingerprint.
+    pub regions: Vec<RegionDeviation>,
+}
+
+impl Comparison {
+    /// Whether all regions passed.
+    pub fn passed(&self) -> bool {
+        self.regions.iter().all(|r| r.passed)
+    }
+}
+
+impl Fingerprint {
+    /// Create a [Fingerprint] from the summaries of labeled segments,
+    /// given the rate the samples were taken at and the source voltage in mV.
+    pub fn from_segments<'s>(
+        segments: impl IntoIterator<Item = &'s SegmentSummary>,
+        sample_rate: f64,
+        vdd_mv: u16,
+    ) -> Self {
+        let regions = segments
+            .into_iter()
+            .map(|s| {
+                let seconds = (s.samples + s.missed) as f64 / sample_rate;
+                let micro_amps = s.avg_micro_amps.unwrap_or_default() as f64;
+                RegionEnergy {
+                    label: s.label.clone(),
+                    micro_joules: micro_amps * seconds * f64::from(vdd_mv) / 1000.,
+                }
+            })
+            .collect();
+        Self { regions }
+    }
+
+    /// Create a [Fingerprint] from a [Session], with a region for every stretch
+    /// of measurements during which the passed logic port pin was high.
+    /// Regions are labeled by their index, starting at 0.
+    pub fn from_session(session: &Session, marker_pin: usize, vdd_mv: u16) -> Self {
+        let period = session.clock.period().as_secs_f64();
+        let mut regions: Vec<RegionEnergy> = Vec::new();
+        let mut in_region = false;
+        for m in &session.measurements {
+            let high = m.pins.pin_is_high(marker_pin);
+            if high && !in_region {
+                regions.push(RegionEnergy {
+                    label: regions.len().to_string(),
+                    micro_joules: 0.,
+                });
+            }
+            if let (true, Some(region)) = (high, regions.last_mut()) {
+                region.micro_joules += m.micro_amps as f64 * period * f64::from(vdd_mv) / 1000.;
+            }
+            in_region = high;
+        }
+        Self { regions }
+    }
+
+    /// Compare this fingerprint to a golden fingerprint. Regions are matched by label.
+    pub fn compare(&self, golden: &Fingerprint, tolerance: Tolerance) -> Comparison {
+        let find = |fp: &Fingerprint, label: &str| {
+            fp.regions
+                .iter()
+                .find(|r| r.label == label)
+                .map(|r| r.micro_joules)
+        };
+
+        let mut regions: Vec<RegionDeviation> = golden
+            .regions
+            .iter()
+            .map(|g| {
+                let actual = find(self, &g.label);
+                let passed = actual.is_some_and(|a| {
+                    let diff = (a - g.micro_joules).abs();
+                    diff <= tolerance.absolute_micro_joules
+                        || diff <= tolerance.relative * g.micro_joules.abs()
+                });
+                RegionDeviation {
+                    label: g.label.clone(),
+                    golden: Some(g.micro_joules),
+                    actual,
+                    passed,
+                }
+            })
+            .collect();
+        regions.extend(
+            self.regions
+                .iter()
+                .filter(|r| find(golden, &r.label).is_none())
+                .map(|r| RegionDeviation {
+                    label: r.label.clone(),
+                    golden: None,
+                    actual: Some(r.micro_joules),
+                    passed: false,
+                }),
+        );
+        Comparison { regions }
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for region in &self.regions {
+            writeln!(f, "{}\t{}", region.label, region.micro_joules)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let regions = s
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|line| {
+                let (label, energy) = line
+                    .rsplit_once('\t')
+                    .ok_or_else(|| Error::Parse(line.to_owned()))?;
+                Ok(RegionEnergy {
+                    label: label.to_owned(),
+                    micro_joules: energy.parse().map_err(|_| Error::Parse(line.to_owned()))?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { regions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fingerprint, RegionDeviation, RegionEnergy, Tolerance};
+
+    #[test]
+    pub fn test_compare() {
+        let golden: Fingerprint = "boot\t100\nradio\t50\n".parse().unwrap();
+        assert_eq!(golden.to_string().parse::<Fingerprint>().unwrap(), golden);
+
+        let actual = Fingerprint {
+            regions: vec![
+                RegionEnergy {
+                    label: "boot".to_owned(),
+                    micro_joules: 104.,
+                },
+                RegionEnergy {
+                    label: "radio".to_owned(),
+                    micro_joules: 60.,
+                },
+            ],
+        };
+        let tolerance = Tolerance {
+            relative: 0.05,
+            absolute_micro_joules: 1.,
+        };
+        let comparison = actual.compare(&golden, tolerance);
+        assert!(comparison.regions[0].passed);
+        assert!(!comparison.regions[1].passed);
+        assert_eq!(comparison.regions[1].relative(), Some(0.2));
+        assert!(!comparison.passed());
+
+        let zero = RegionDeviation {
+            label: "idle".to_owned(),
+            golden: Some(0.),
+            actual: Some(1.),
+            passed: false,
+        };
+        assert_eq!(zero.relative(), None);
+    }
+}
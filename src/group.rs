@@ -0,0 +1,295 @@
+//! Measuring with several PPK2s at once, for profiling boards with
+//! multiple power domains. The measurements of all devices are merged
+//! into one channel, tagged with the id of the device they came from.
+//!
+//! The devices are started at approximately the same time, each from its
+//! own thread. Their clocks are not synchronized, so use the
+//! [crate::clock::CaptureAnchor] of each device, see [GroupHandle::anchors],
+//! to align [crate::measurement::Measurement::time] across devices.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Barrier,
+    },
+    thread,
+};
+
+use crate::{
+    clock::CaptureAnchor,
+    measurement::{MeasurementMatch, MeasurementOptions},
+    types::MeasurementMode,
+    Error, MeasurementHandle, Ppk2, Ppk2DeviceInfo, Result,
+};
+
+/// A measurement of one of the devices in a [Ppk2Group].
+#[derive(Debug, Clone)]
+pub struct GroupMeasurement {
+    /// Id of the device the measurement came from
+    pub device: Arc<str>,
+    /// The measurement
+    pub measurement: MeasurementMatch,
+}
+
+/// A set of PPK2s that are measured with together.
+#[derive(Default)]
+pub struct Ppk2Group {
+    devices: Vec<(Arc<str>, Ppk2)>,
+}
+
+impl Ppk2Group {
+    /// Create a new, empty [Ppk2Group].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the passed devices in the passed mode, identifying each by its
+    /// USB serial number, or its port name if it doesn't report one.
+    pub fn open(devices: &[Ppk2DeviceInfo], mode: MeasurementMode) -> Result<Self> {
+        devices.iter().try_fold(Self::new(), |group, device| {
+            let id = device.serial_number.as_ref().unwrap_or(&device.port_name);
+            Ok(group.add(id.as_str(), Ppk2::new(device.port_name.as_str(), mode)?))
+        })
+    }
+
+    /// Add a device, identified by `id` in the merged measurements.
+    pub fn add(mut self, id: impl Into<Arc<str>>, ppk2: Ppk2) -> Self {
+        self.devices.push((id.into(), ppk2));
+        self
+    }
+
+    /// Get the ids of the devices, in the order they were added.
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.devices.iter().map(|(id, _)| id.as_ref())
+    }
+
+    /// Get the device with the passed id, for configuring it.
+    pub fn device_mut(&mut self, id: &str) -> Option<&mut Ppk2> {
+        self.devices
+            .iter_mut()
+            .find(|(i, _)| i.as_ref() == id)
+            .map(|(_, ppk2)| ppk2)
+    }
+
+    /// Start measuring on all devices with the same options. Returns a
+    /// [Receiver] of the merged measurements, and a [GroupHandle] to stop
+    /// all devices. If a device fails to start, the devices that did
+    /// start are stopped, and the error is returned. A
+    /// [MeasurementOptions::raw_dump] is written to a file per device, with
+    /// the device id appended to the file name, e.g. `dump-a.bin`.
+    pub fn start_measurement_with(
+        self,
+        options: MeasurementOptions,
+    ) -> Result<(Receiver<GroupMeasurement>, GroupHandle)> {
+        let barrier = Arc::new(Barrier::new(self.devices.len()));
+        let starters: Vec<_> = self
+            .devices
+            .into_iter()
+            .map(|(id, ppk2)| {
+                let barrier = barrier.clone();
+                let mut options = options.clone();
+                if let Some(path) = &options.raw_dump {
+                    options.raw_dump = Some(device_path(path, &id));
+                }
+                thread::spawn(move || {
+                    barrier.wait();
+                    ppk2.start_measurement_with(options)
+                        .map(|(rx, handle)| (id, rx, handle))
+                })
+            })
+            .collect();
+
+        let mut started = Vec::with_capacity(starters.len());
+        let mut error = None;
+        for starter in starters {
+            match starter.join().expect("Measurement start thread panicked") {
+                Ok(device) => started.push(device),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        if let Some(e) = error {
+            for (_, _, handle) in started {
+                if let Err(e) = handle.stop() {
+                    tracing::error!("Error stopping measurement: {:?}", e);
+                }
+            }
+            return Err(e);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut handles = Vec::with_capacity(started.len());
+        let mut forwarders = Vec::with_capacity(started.len());
+        for (id, device_rx, handle) in started {
+            let tx = tx.clone();
+            let device = id.clone();
+            forwarders.push(thread::spawn(move || {
+                for measurement in device_rx {
+                    let measurement = GroupMeasurement {
+                        device: device.clone(),
+                        measurement,
+                    };
+                    if tx.send(measurement).is_err() {
+                        break;
+                    }
+                }
+            }));
+            handles.push((id, handle));
+        }
+        Ok((
+            rx,
+            GroupHandle {
+                handles,
+                forwarders,
+            },
+        ))
+    }
+}
+
+/// Append the device id to the file name of `path`, keeping the extension.
+/// Characters that may not be valid in a file name are replaced.
+fn device_path(path: &Path, id: &str) -> PathBuf {
+    let id: String = id
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("-");
+    name.push(id);
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// Handle to the running measurements of a [Ppk2Group], returned by
+/// [Ppk2Group::start_measurement_with].
+pub struct GroupHandle {
+    handles: Vec<(Arc<str>, MeasurementHandle)>,
+    forwarders: Vec<thread::JoinHandle<()>>,
+}
+
+impl GroupHandle {
+    /// Get the [MeasurementHandle] of the device with the passed id.
+    pub fn handle(&self, id: &str) -> Option<&MeasurementHandle> {
+        self.handles
+            .iter()
+            .find(|(i, _)| i.as_ref() == id)
+            .map(|(_, handle)| handle)
+    }
+
+    /// Get the [CaptureAnchor] of each device, by id, for aligning
+    /// the time of their measurements.
+    pub fn anchors(&self) -> Vec<(&str, CaptureAnchor)> {
+        self.handles
+            .iter()
+            .map(|(id, handle)| (id.as_ref(), handle.anchor()))
+            .collect()
+    }
+
+    /// Stop all devices and return the group. All devices are signaled to
+    /// stop before waiting for any of them. If stopping a device fails,
+    /// the first error is returned after all devices were stopped.
+    pub fn stop(self) -> Result<Ppk2Group> {
+        for (_, handle) in &self.handles {
+            handle.stop_handle().stop();
+        }
+        let mut group = Ppk2Group::new();
+        let mut error: Option<Error> = None;
+        for (id, handle) in self.handles {
+            match handle.join() {
+                Ok(ppk2) => group = group.add(id, ppk2),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        for forwarder in self.forwarders {
+            forwarder
+                .join()
+                .expect("Measurement forwarding thread panicked");
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(group),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, time::Duration};
+
+    use super::{device_path, Ppk2Group};
+    use crate::{
+        measurement::{Measurement, MeasurementMatch, MeasurementOptions},
+        mock::MockPpk2,
+        types::{MeasurementMode, SampleRate},
+        Ppk2,
+    };
+
+    #[test]
+    pub fn test_group() {
+        let device = |micro_amps| {
+            let mock = MockPpk2::new().samples(move |_| {
+                Some(Measurement {
+                    micro_amps,
+                    ..Default::default()
+                })
+            });
+            Ppk2::with_port(Box::new(mock), MeasurementMode::Source).unwrap()
+        };
+        let group = Ppk2Group::new()
+            .add("a", device(100.))
+            .add("b", device(2000.));
+        assert_eq!(group.ids().collect::<Vec<_>>(), ["a", "b"]);
+
+        let dump = env::temp_dir().join(format!("ppk2-group-{}.bin", std::process::id()));
+        let (rx, handle) = group
+            .start_measurement_with(
+                MeasurementOptions::new(SampleRate::per_second(100).unwrap()).raw_dump(&dump),
+            )
+            .unwrap();
+        let mut seen = [false; 2];
+        while seen != [true; 2] {
+            let m = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            let MeasurementMatch::Match(measurement) = m.measurement else {
+                continue;
+            };
+            let (index, expected) = match m.device.as_ref() {
+                "a" => (0, 100.),
+                _ => (1, 2000.),
+            };
+            assert!((measurement.micro_amps - expected).abs() < expected / 100.);
+            seen[index] = true;
+        }
+        assert_eq!(handle.anchors().len(), 2);
+        assert!(handle.handle("b").is_some());
+        let group = handle.stop().unwrap();
+        assert_eq!(group.ids().count(), 2);
+        // The merged channel closes once all devices are stopped
+        while rx.recv().is_ok() {}
+        // Each device dumps to its own file
+        for id in ["a", "b"] {
+            let path = device_path(&dump, id);
+            assert!(fs::metadata(&path).unwrap().len() > 0);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_device_path() {
+        assert_eq!(
+            device_path(Path::new("out/dump.bin"), "a"),
+            Path::new("out/dump-a.bin")
+        );
+        assert_eq!(
+            device_path(Path::new("dump"), "/dev/ttyACM0"),
+            Path::new("dump-_dev_ttyACM0")
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Adapter for hardware-in-the-loop test frameworks, measuring the
+//! current consumption of each test as a labeled segment.
+
+use std::{
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    measurement::{MeasurementEvent, MeasurementMatch, MeasurementOptions, SegmentSummary},
+    types::{DevicePower, SampleRate, SourceVoltage},
+    Error, MeasurementHandle, Ppk2, Result,
+};
+
+/// Statistics of the measurements taken during a single test: the summary
+/// of the segment the test was measured in. Implements
+/// [crate::assertions::Region], so it can be checked with the assertions.
+pub type RegionStats = SegmentSummary;
+
+/// A power measurement fixture, to be driven by a test framework.
+pub trait PowerFixture {
+    /// Mark the start of a test.
+    fn begin_test(&mut self, name: &str) -> Result<()>;
+
+    /// Mark the end of the test that was started last, returning
+    /// statistics of the measurements taken during the test.
+    fn end_test(&mut self) -> Result<RegionStats>;
+}
+
+/// Requirements a test suite has on the power fixture.
+#[derive(Debug, Clone)]
+pub struct FixtureRequirements {
+    /// Source voltage to supply the device under test with, if any.
+    pub source_voltage: Option<SourceVoltage>,
+    /// Whether the device under test should be powered.
+    pub power: DevicePower,
+    /// Measurement options. Segment labels are managed by the fixture.
+    pub options: MeasurementOptions,
+}
+
+impl Default for FixtureRequirements {
+    fn default() -> Self {
+        Self {
+            source_voltage: None,
+            power: DevicePower::Enabled,
+            options: MeasurementOptions::new(SampleRate::default()),
+        }
+    }
+}
+
+/// [PowerFixture] implementation using a PPK2. The time between tests
+/// is measured as a segment with an empty label.
+pub struct Ppk2Fixture {
+    handle: MeasurementHandle,
+    /// Discards the combined measurements, which are not used
+    drain: JoinHandle<()>,
+    events: Receiver<MeasurementEvent>,
+    current_test: Option<String>,
+}
+
+impl Ppk2Fixture {
+    /// How long to wait for the summary of a test after it ended.
+    const SUMMARY_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Set up the device according to the [FixtureRequirements] and start measuring.
+    pub fn new(mut ppk2: Ppk2, requirements: FixtureRequirements) -> Result<Self> {
+        if let Some(vdd) = requirements.source_voltage {
+            ppk2.set_source_voltage(vdd)?;
+        }
+        ppk2.set_device_power(requirements.power)?;
+        let (measurements, handle) =
+            ppk2.start_measurement_with(requirements.options.segment(""))?;
+        let events = handle.subscribe();
+        // Keep the channel empty, so a bounded channel never blocks the
+        // pipeline before it can report the end of a test
+        let drain = thread::spawn(move || drain(measurements));
+        Ok(Self {
+            handle,
+            drain,
+            events,
+            current_test: None,
+        })
+    }
+
+    /// Stop measuring and return the device.
+    pub fn finish(self) -> Result<Ppk2> {
+        let ppk2 = self.handle.stop();
+        self.drain
+            .join()
+            .expect("Measurement drain thread panicked");
+        ppk2
+    }
+}
+
+impl PowerFixture for Ppk2Fixture {
+    fn begin_test(&mut self, name: &str) -> Result<()> {
+        self.handle.next_segment(name)?;
+        self.current_test = Some(name.to_owned());
+        Ok(())
+    }
+
+    fn end_test(&mut self) -> Result<RegionStats> {
+        let name = self.current_test.take().ok_or(Error::NoTestStarted)?;
+        self.handle.next_segment("")?;
+        loop {
+            match self.events.recv_timeout(Self::SUMMARY_TIMEOUT) {
+                Ok(MeasurementEvent::SegmentEnd(summary)) if summary.label == name => {
+                    return Ok(summary)
+                }
+                Ok(_) => {}
+                Err(_) => return Err(Error::WorkerStopped),
+            }
+        }
+    }
+}
+
+/// Receive and discard measurements until the measurement stops.
+fn drain(measurements: Receiver<MeasurementMatch>) {
+    measurements.into_iter().for_each(drop);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::{FixtureRequirements, PowerFixture, Ppk2Fixture};
+    use crate::{mock::MockPpk2, types::MeasurementMode, Error, Ppk2};
+
+    #[test]
+    pub fn test_fixture() {
+        // A bounded channel that is never read from must not block the fixture
+        let ppk2 = Ppk2::builder()
+            .channel_capacity(Some(1))
+            .with_port(Box::new(MockPpk2::new()), MeasurementMode::Source)
+            .unwrap();
+        let mut fixture = Ppk2Fixture::new(ppk2, FixtureRequirements::default()).unwrap();
+        assert!(matches!(fixture.end_test(), Err(Error::NoTestStarted)));
+
+        for name in ["first", "second"] {
+            fixture.begin_test(name).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            let stats = fixture.end_test().unwrap();
+            assert_eq!(stats.label, name);
+            assert!(stats.samples > 0);
+            assert!((stats.avg_micro_amps.unwrap() - 1000.).abs() < 10.);
+        }
+        fixture.finish().unwrap();
+    }
+}
@@ -1,33 +1,131 @@
 #![doc = include_str!("../README.md")]
 #![deny(missing_docs)]
+// The assertion macros accept units written with the micro sign
+#![allow(uncommon_codepoints)]
 
-use measurement::{MeasurementAccumulator, MeasurementIterExt, MeasurementMatch};
-use serialport::{ClearBuffer::Input, FlowControl, SerialPort};
+use measurement::{MeasurementMatch, ProtocolViolation};
+use std::io;
 use std::str::Utf8Error;
-use std::sync::mpsc::{self, Receiver, SendError, TryRecvError};
-use std::{
-    borrow::Cow,
-    collections::VecDeque,
-    io,
-    sync::{Arc, Condvar, Mutex},
-    thread,
-    time::Duration,
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{SendError, TryRecvError},
+    Arc,
 };
 use thiserror::Error;
-use types::{DevicePower, LogicPortPins, MeasurementMode, Metadata, SourceVoltage};
 
-use crate::cmd::Command;
+#[cfg(feature = "serial")]
+use {
+    measurement::{
+        ByteStats, CaptureSummary, ChunkTimer, EventSubscribers, History, Measurement,
+        MeasurementAccumulator, MeasurementEvent, MeasurementIterExt, MeasurementOptions,
+        PipelineCounters, PipelineMetrics, SegmentAccumulator, SegmentSummary, Subscribers,
+        WindowPolicy,
+    },
+    serialport::{ClearBuffer::Input, FlowControl, SerialPort},
+    std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    std::{
+        borrow::Cow,
+        collections::VecDeque,
+        fs::File,
+        ops::Deref,
+        panic::{self, AssertUnwindSafe},
+        sync::{Condvar, Mutex},
+        thread,
+        time::{Duration, Instant},
+    },
+    types::{
+        DevicePower, LogicPortPins, MeasurementMode, MeasurementRange, Metadata, SampleRate,
+        SourceVoltage,
+    },
+};
+
+#[cfg(feature = "serial")]
+use crate::{
+    calibration::{
+        CalibratedGain, Calibration, CalibrationInfo, CalibrationWarning, GuidedCalibration,
+        ReferenceLoad,
+    },
+    charge::{Charge, ChargeAccumulator},
+    clock::{CaptureAnchor, SampleClock},
+    cmd::Command,
+    notify::CommandNotifier,
+    qos::QosReport,
+    ramp::PowerRamp,
+    sampling::SamplingPlan,
+    serial_errors::{OsCounterSource, OsSerialCounters, SerialErrors},
+    settings::CachedSettings,
+    stats::Stats,
+    sweep::{SweepStep, VoltageSweep},
+    tags::{TagAccumulator, TagStats},
+    trigger::Capture,
+    worker::{DeviceSetup, MeasurementSender, PortOpener, WorkerContext},
+};
 
-pub mod cmd;
+pub mod assertions;
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod autozero;
+pub mod battery;
+pub mod calibration;
+pub mod charge;
+pub mod clock;
+pub use ppk2_core::cmd;
+pub mod csv;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fingerprint;
+#[cfg(feature = "serial")]
+pub mod group;
+#[cfg(feature = "serial")]
+pub mod hil;
 pub mod measurement;
+#[cfg(any(all(test, feature = "serial"), feature = "mock"))]
+pub mod mock;
+pub mod notify;
+#[cfg(feature = "unstable")]
+pub mod parquet;
+#[cfg(not(feature = "unstable"))]
+#[allow(dead_code)]
+mod parquet;
+pub mod ppk2_file;
+pub mod presets;
+pub mod preview;
+pub mod protocol;
+pub mod qos;
+pub mod ramp;
+pub mod reconnect;
+pub mod replay;
+pub mod reset;
+pub mod saleae;
+pub mod sampling;
+#[cfg(feature = "serial")]
+pub mod serial_errors;
+pub mod session;
+pub mod settings;
+pub mod stats;
+#[cfg(feature = "futures")]
+pub mod stream;
+pub mod suspend;
+pub mod sweep;
+pub mod tags;
+#[cfg(feature = "unstable")]
+pub mod trigger;
+#[cfg(not(feature = "unstable"))]
+#[allow(dead_code)]
+mod trigger;
 pub mod types;
-
-const SPS_MAX: usize = 100_000;
+#[cfg(feature = "unstable")]
+mod unstable;
+pub mod vcd;
+mod worker;
+mod zip;
 
 #[derive(Error, Debug)]
 /// PPK2 communication or data parsing error.
 #[allow(missing_docs)]
 pub enum Error {
+    #[cfg(feature = "serial")]
     #[error("Serial port error: {0}")]
     SerialPort(#[from] serialport::Error),
     #[error("PPK2 not found. Is the device connected and are permissions set correctly?")]
@@ -44,27 +142,165 @@ pub enum Error {
     SendStopSignal(#[from] SendError<()>),
     #[error("Worker thread signal error: {0}")]
     WorkerSignalError(#[from] TryRecvError),
+    #[error("Notification error: {0}")]
+    Notify(String),
+    #[error("No test was started")]
+    NoTestStarted,
+    #[error("No measurement was started before")]
+    NoPreviousMeasurement,
+    #[error("Measurement worker thread has stopped")]
+    WorkerStopped,
+    #[error("Protocol violation: {0}")]
+    Protocol(#[from] ProtocolViolation),
     #[error("Error deserializeing a measurement: {0:?}")]
     DeserializeMeasurement(Vec<u8>),
+    #[error("Invalid calibration coefficients: {0}")]
+    InvalidCalibration(String),
+    #[error("Unknown exporter \"{0}\"")]
+    UnknownExporter(String),
+    #[error("Unsupported sample rate of {0} per second, expected 1 to 100000")]
+    UnsupportedSampleRate(usize),
+    #[error("Measurement quality below minimum: {0}")]
+    QosBelowMinimum(qos::QosViolation),
+    #[error("Device delivers {0:.0} samples per second, expected about 100000. Is the firmware up to date?")]
+    LowSampleRate(f64),
+    #[error("{0} requires source meter mode")]
+    SourceModeRequired(&'static str),
 }
 
 #[allow(missing_docs)]
 pub type Result<T> = std::result::Result<T, Error>;
 
+#[cfg(feature = "serial")]
 /// PPK2 device representation.
 pub struct Ppk2 {
     port: Box<dyn SerialPort>,
     metadata: Metadata,
+    mode: MeasurementMode,
+    power: DevicePower,
+    vdd: Option<SourceVoltage>,
+    last_options: Option<MeasurementOptions>,
+    serial_number: Option<String>,
+    cache_settings: bool,
+    os_counters: OsCounterSource,
+    clock: SampleClock,
+    read_buf_size: usize,
+    channel_capacity: Option<usize>,
+    timeout: Duration,
+    flow_control: FlowControl,
+    /// Reopens the serial port after a disconnect
+    open_port: PortOpener,
+    range: Option<MeasurementRange>,
+    switch_points: Option<(u16, u16)>,
+    spike_filtering: Option<bool>,
+}
+
+#[cfg(feature = "serial")]
+/// Connection parameters of a [Ppk2], see [Ppk2::builder].
+#[derive(Debug, Clone)]
+pub struct Ppk2Builder {
+    timeout: Duration,
+    flow_control: FlowControl,
+    read_buf_size: usize,
+    channel_capacity: Option<usize>,
+}
+
+#[cfg(feature = "serial")]
+impl Default for Ppk2Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Ppk2Builder {
+    /// Create a new [Ppk2Builder] with the parameters used by [Ppk2::new].
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            flow_control: FlowControl::Hardware,
+            read_buf_size: worker::READ_BUF_SIZE,
+            channel_capacity: None,
+        }
+    }
+
+    /// Set the timeout of serial port reads and writes. Defaults to 500 ms.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the flow control of the serial port. Defaults to [FlowControl::Hardware].
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// Set the size in bytes of the buffers the measurement pipeline reads
+    /// the serial port into. Defaults to 1024 bytes.
+    pub fn read_buf_size(mut self, size: usize) -> Self {
+        self.read_buf_size = size.max(measurement::SAMPLE_SIZE);
+        self
+    }
+
+    /// Set the number of combined measurements the channel returned by
+    /// [Ppk2::start_measurement_with] holds. When the channel is full, the
+    /// pipeline waits until measurements are received, while bytes keep being
+    /// read from the serial port. [None], the default, doesn't limit the channel.
+    pub fn channel_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Open the device at the passed serial port, and configure the given
+    /// [MeasurementMode].
+    pub fn open<'a>(&self, path: impl Into<Cow<'a, str>>, mode: MeasurementMode) -> Result<Ppk2> {
+        let path = path.into();
+        let serial_number = usb_serial_number(&path);
+        let (port, os_counters) = serial_errors::open(
+            serialport::new(path, 9600)
+                .timeout(self.timeout)
+                .flow_control(self.flow_control),
+        )?;
+
+        Ppk2::open(port, os_counters, serial_number, mode, self)
+    }
+
+    /// Use the device on an already opened serial port, like a [mock::MockPpk2],
+    /// and configure the given [MeasurementMode]. The timeout and flow control
+    /// of the port are left as they are, and only used when the port is
+    /// reopened after a disconnect, see [reconnect].
+    pub fn with_port(&self, port: Box<dyn SerialPort>, mode: MeasurementMode) -> Result<Ppk2> {
+        Ppk2::open(port, OsCounterSource::default(), None, mode, self)
+    }
 }
 
+#[cfg(feature = "serial")]
 impl Ppk2 {
     /// Create a new instance and configure the given [MeasurementMode].
     pub fn new<'a>(path: impl Into<Cow<'a, str>>, mode: MeasurementMode) -> Result<Self> {
-        let mut port = serialport::new(path, 9600)
-            .timeout(Duration::from_millis(500))
-            .flow_control(FlowControl::Hardware)
-            .open()?;
+        Ppk2Builder::new().open(path, mode)
+    }
 
+    /// Create a new instance on an already opened serial port, like a
+    /// [mock::MockPpk2], and configure the given [MeasurementMode].
+    pub fn with_port(port: Box<dyn SerialPort>, mode: MeasurementMode) -> Result<Self> {
+        Ppk2Builder::new().with_port(port, mode)
+    }
+
+    /// Create a [Ppk2Builder], to configure the connection parameters
+    /// before opening the device.
+    pub fn builder() -> Ppk2Builder {
+        Ppk2Builder::new()
+    }
+
+    fn open(
+        mut port: Box<dyn SerialPort>,
+        os_counters: OsCounterSource,
+        serial_number: Option<String>,
+        mode: MeasurementMode,
+        builder: &Ppk2Builder,
+    ) -> Result<Self> {
         if let Err(e) = port.clear(serialport::ClearBuffer::All) {
             tracing::warn!("failed to clear buffers: {:?}", e);
         }
@@ -77,13 +313,105 @@ impl Ppk2 {
         let mut ppk2 = Self {
             port,
             metadata: Metadata::default(),
+            mode,
+            power: DevicePower::default(),
+            vdd: None,
+            last_options: None,
+            serial_number,
+            cache_settings: false,
+            os_counters,
+            clock: SampleClock::nominal(),
+            read_buf_size: builder.read_buf_size,
+            channel_capacity: builder.channel_capacity,
+            timeout: builder.timeout,
+            flow_control: builder.flow_control,
+            open_port: Arc::new(worker::open_port),
+            range: None,
+            switch_points: None,
+            spike_filtering: None,
         };
 
         ppk2.metadata = ppk2.get_metadata()?;
+        Calibration::from(&ppk2.metadata).validate()?;
+        for warning in ppk2.metadata.calibration_warnings() {
+            tracing::warn!("Calibration: {warning}");
+        }
         ppk2.set_power_mode(mode)?;
         Ok(ppk2)
     }
 
+    /// Create a new instance and restore the settings that were last used with
+    /// this device, identified by its serial number. If no settings were cached,
+    /// the passed [MeasurementMode] is configured. Settings changed afterwards
+    /// are cached again. See [CachedSettings].
+    pub fn open_with_cached_settings<'a>(
+        path: impl Into<Cow<'a, str>>,
+        default_mode: MeasurementMode,
+    ) -> Result<Self> {
+        let path = path.into();
+        let cached = usb_serial_number(&path)
+            .map(|serial| CachedSettings::load(&serial))
+            .transpose()?
+            .flatten();
+
+        let mode = cached.as_ref().map_or(default_mode, |c| c.mode);
+        let mut ppk2 = Self::new(path, mode)?;
+        if let Some(cached) = cached {
+            if let Some(vdd) = cached.vdd {
+                ppk2.set_source_voltage(vdd)?;
+            }
+            ppk2.set_device_power(cached.power)?;
+            for (range, gain) in cached.user_gains.into_iter().flatten().enumerate() {
+                if gain != ppk2.metadata.modifiers.ug[range] {
+                    let range = MeasurementRange::try_from(range as u8).expect("5 ranges");
+                    ppk2.set_user_gain(range, gain)?;
+                }
+            }
+        }
+        ppk2.cache_settings = true;
+        ppk2.save_settings()?;
+        Ok(ppk2)
+    }
+
+    /// Get the USB serial number of the device, if it could be determined.
+    pub fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_deref()
+    }
+
+    /// Cache the current settings of the device, to be restored by
+    /// [Ppk2::open_with_cached_settings]. Settings that are not device
+    /// state, like [CachedSettings::notify], are kept. Does nothing if the
+    /// serial number of the device is unknown.
+    pub fn save_settings(&self) -> Result<()> {
+        let Some(serial) = &self.serial_number else {
+            return Ok(());
+        };
+        CachedSettings {
+            mode: self.mode,
+            vdd: self.vdd,
+            power: self.power,
+            user_gains: Some(self.metadata.modifiers.ug),
+            ..CachedSettings::load(serial)?.unwrap_or_default()
+        }
+        .save(serial)
+    }
+
+    /// Get the [CommandNotifier] configured in the settings of this device,
+    /// if any. See [CachedSettings::notifier].
+    pub fn cached_notifier(&self) -> Result<Option<CommandNotifier>> {
+        let Some(serial) = &self.serial_number else {
+            return Ok(None);
+        };
+        Ok(CachedSettings::load(serial)?.and_then(|s| s.notifier()))
+    }
+
+    fn settings_changed(&self) -> Result<()> {
+        if self.cache_settings {
+            self.save_settings()?;
+        }
+        Ok(())
+    }
+
     /// Send a raw command and return the result.
     pub fn send_command(&mut self, command: Command) -> Result<Vec<u8>> {
         self.port.write_all(&Vec::from_iter(command.bytes()))?;
@@ -125,23 +453,214 @@ impl Ppk2 {
     /// Enable or disable the device power.
     pub fn set_device_power(&mut self, power: DevicePower) -> Result<()> {
         self.send_command(Command::DeviceRunningSet(power))?;
+        self.power = power;
+        self.settings_changed()
+    }
+
+    /// Lock the measurement range, disabling auto-ranging, to avoid the
+    /// artifacts of range changes when profiling fast switching loads.
+    /// Currents outside of the range clip.
+    pub fn set_measurement_range(&mut self, range: MeasurementRange) -> Result<()> {
+        self.send_command(Command::RangeSet(range))?;
+        self.range = Some(range);
         Ok(())
     }
 
+    /// Set the switch points of the auto-ranging, as the raw values of the
+    /// digital potentiometers that set the current thresholds: `up` for
+    /// switching to a less sensitive range, `down` for switching to a more
+    /// sensitive one. Misconfigured switch points make the device oscillate between ranges,
+    /// which shows in [Measurement::range].
+    pub fn set_switch_points(&mut self, up: u16, down: u16) -> Result<()> {
+        self.send_command(Command::SwitchPointUp(up))?;
+        self.send_command(Command::SwitchPointDown(down))?;
+        self.switch_points = Some((up, down));
+        Ok(())
+    }
+
+    /// Set the user gain of the passed range, by which the calibrated current
+    /// measured in the range is multiplied, to apply a calibration correction.
+    /// The gain is updated in the [Metadata], see [Ppk2::metadata], and used
+    /// by subsequent measurements.
+    pub fn set_user_gain(&mut self, range: MeasurementRange, gain: f32) -> Result<()> {
+        if !gain.is_finite() || gain == 0. {
+            return Err(Error::InvalidCalibration(format!(
+                "user gain of range {} is {gain}",
+                u8::from(range)
+            )));
+        }
+        self.send_command(Command::SetUserGains(range, gain))?;
+        self.metadata.modifiers.ug[u8::from(range) as usize] = gain;
+        self.settings_changed()
+    }
+
+    /// Reset the user calibration of the device, and fetch the [Metadata]
+    /// again, so it reflects the coefficients the device uses afterwards.
+    /// This undoes [Ppk2::set_user_gain].
+    pub fn reset_user_calibration(&mut self) -> Result<()> {
+        self.send_command(Command::ResUserSet)?;
+        self.metadata = self.get_metadata()?;
+        self.settings_changed()
+    }
+
+    /// Calibrate the user gains with the passed [GuidedCalibration], in
+    /// source meter mode. Enables device power at the source voltage of the
+    /// calibration, and for each load, calls `connect`, which should return
+    /// once the load is connected. The load is then measured, averaging only
+    /// the samples measured in its range, and the calibrated gain is applied
+    /// with [Ppk2::set_user_gain]. Restores the device power afterwards.
+    pub fn calibrate_user_gains(
+        &mut self,
+        calibration: &GuidedCalibration,
+        mut connect: impl FnMut(&ReferenceLoad),
+    ) -> Result<Vec<CalibratedGain>> {
+        if self.mode != MeasurementMode::Source {
+            return Err(Error::SourceModeRequired("Guided calibration"));
+        }
+        let power = self.power;
+        self.set_source_voltage(calibration.vdd())?;
+        self.set_device_power(DevicePower::Enabled)?;
+
+        let res = (|| -> Result<Vec<CalibratedGain>> {
+            let mut buf = vec![Measurement::default(); calibration.sample_count()];
+            let mut gains = Vec::with_capacity(calibration.loads().len());
+            for load in calibration.loads() {
+                connect(load);
+                let (n, _) = self.measure_into(&mut buf, SampleRate::max())?;
+                let range = u8::from(load.range);
+                let (sum, count) = buf[..n]
+                    .iter()
+                    .filter(|m| m.range == range)
+                    .fold((0., 0), |(sum, count), m| {
+                        (sum + f64::from(m.micro_amps), count + 1)
+                    });
+                if count == 0 {
+                    return Err(Error::InvalidCalibration(format!(
+                        "no samples measured in range {range} with a load of {} Ω",
+                        load.ohms
+                    )));
+                }
+                let ug = self.metadata.modifiers.ug[range as usize];
+                let gain = calibration.gain(load, (sum / count as f64) as f32, ug)?;
+                self.set_user_gain(load.range, gain.gain)?;
+                gains.push(gain);
+            }
+            Ok(gains)
+        })();
+
+        self.set_device_power(power)?;
+        res
+    }
+
+    /// Enable or disable the spike filtering of the device firmware, which
+    /// smooths the samples right after a measurement range change, like the
+    /// corresponding setting of the official app. This is separate from the
+    /// software filter, see [MeasurementAccumulator::spike_filter].
+    pub fn set_hardware_spike_filtering(&mut self, enabled: bool) -> Result<()> {
+        let command = match enabled {
+            true => Command::SpikeFilteringOn,
+            false => Command::SpikeFilteringOff,
+        };
+        self.send_command(command)?;
+        self.spike_filtering = Some(enabled);
+        Ok(())
+    }
+
+    /// Enable the device power, raising the source voltage gradually according
+    /// to the passed [PowerRamp] instead of applying the target voltage at once.
+    /// Blocks for the duration of the ramp.
+    pub fn enable_power_ramped(&mut self, ramp: &PowerRamp) -> Result<()> {
+        let mut voltages = ramp.voltages();
+        if let Some(start) = voltages.next() {
+            self.send_command(Command::RegulatorSet(start))?;
+        }
+        self.send_command(Command::DeviceRunningSet(DevicePower::Enabled))?;
+        for vdd in voltages {
+            thread::sleep(ramp.step_interval());
+            self.send_command(Command::RegulatorSet(vdd))?;
+        }
+        self.vdd = Some(ramp.target());
+        self.power = DevicePower::Enabled;
+        self.settings_changed()
+    }
+
     /// Set the voltage of the device voltage source.
     pub fn set_source_voltage(&mut self, vdd: SourceVoltage) -> Result<()> {
         self.send_command(Command::RegulatorSet(vdd))?;
-        Ok(())
+        self.vdd = Some(vdd);
+        self.settings_changed()
+    }
+
+    /// Get the configured [MeasurementMode].
+    pub fn measurement_mode(&self) -> MeasurementMode {
+        self.mode
+    }
+
+    /// Get the last configured [DevicePower].
+    pub fn device_power(&self) -> DevicePower {
+        self.power
+    }
+
+    /// Get the last configured [SourceVoltage], or [None] if it was
+    /// not set since the device was opened.
+    pub fn source_voltage(&self) -> Option<SourceVoltage> {
+        self.vdd
+    }
+
+    /// Get the warnings about the plausibility of the device calibration,
+    /// based on the metadata read when the device was opened.
+    pub fn calibration_warnings(&self) -> Vec<CalibrationWarning> {
+        self.metadata.calibration_warnings()
+    }
+
+    /// Get the calibration coefficients of the device, see [Metadata::calibration_info].
+    pub fn calibration_info(&self) -> CalibrationInfo {
+        self.metadata.calibration_info()
+    }
+
+    /// Get the metadata read from the device when it was opened,
+    /// containing its calibration.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Get the clock at which the device produces samples. Defaults to
+    /// [SampleClock::NOMINAL_RATE], the rate of all known firmware revisions.
+    pub fn native_clock(&self) -> SampleClock {
+        self.clock
+    }
+
+    /// Set the clock at which the device produces samples, for firmware
+    /// revisions or device variants that sample at a different rate than
+    /// [SampleClock::NOMINAL_RATE]. Used for combining samples, for the
+    /// timestamps of events, and for integrating charge.
+    pub fn set_native_clock(&mut self, clock: SampleClock) {
+        self.clock = clock;
+    }
+
+    /// Get the [MeasurementOptions] of the last measurement, if any.
+    pub fn last_measurement_options(&self) -> Option<&MeasurementOptions> {
+        self.last_options.as_ref()
+    }
+
+    /// Start measurements using the [MeasurementOptions] of the previous
+    /// measurement. Returns [Error::NoPreviousMeasurement] if no measurement
+    /// was started before.
+    pub fn restart_measurement(self) -> Result<(Receiver<MeasurementMatch>, MeasurementHandle)> {
+        match self.last_options.clone() {
+            Some(options) => self.start_measurement_with(options),
+            None => Err(Error::NoPreviousMeasurement),
+        }
     }
 
     /// Start measurements. Returns a tuple of:
     /// - [Ppk2<Measuring>],
     /// - [Receiver] of [measurement::MeasurementMatch], and
     /// - A closure that can be called to stop the measurement parsing pipeline and return the
-    /// device.
+    ///   device.
     pub fn start_measurement(
         self,
-        sps: usize,
+        sps: SampleRate,
     ) -> Result<(Receiver<MeasurementMatch>, impl FnOnce() -> Result<Self>)> {
         self.start_measurement_matching(LogicPortPins::default(), sps)
     }
@@ -150,88 +669,378 @@ impl Ppk2 {
     /// - [Ppk2<Measuring>],
     /// - [Receiver] of [measurement::Result], and
     /// - A closure that can be called to stop the measurement parsing pipeline and return the
-    /// device.
+    ///   device.
     pub fn start_measurement_matching(
-        mut self,
+        self,
         pins: LogicPortPins,
-        sps: usize,
+        sps: SampleRate,
     ) -> Result<(Receiver<MeasurementMatch>, impl FnOnce() -> Result<Self>)> {
+        let (rx, handle) =
+            self.start_measurement_with(MeasurementOptions::new(sps).matching(pins))?;
+        Ok((rx, move || handle.stop()))
+    }
+
+    /// Start measurements with the passed [MeasurementOptions]. Returns a tuple of:
+    /// - [Receiver] of [measurement::MeasurementMatch], and
+    /// - A [MeasurementHandle] that can be used to control the measurement parsing pipeline,
+    ///   and stop it to return the device.
+    pub fn start_measurement_with(
+        mut self,
+        options: MeasurementOptions,
+    ) -> Result<(Receiver<MeasurementMatch>, MeasurementHandle)> {
+        self.last_options = Some(options.clone());
+        let mut options = options;
+        // While ramping, the ramp is measured as a separate segment
+        let power_ramp = options.power_ramp;
+        let after_ramp = power_ramp
+            .map(|_| std::mem::replace(&mut options.segment, PowerRamp::SEGMENT_LABEL.to_owned()));
         // Stuff needed to communicate with the main thread
         // ready allows main thread to signal worker when serial input buf is cleared.
         let ready = Arc::new((Mutex::new(false), Condvar::new()));
-        // This channel is for sending measurements to the main thread.
-        let (meas_tx, meas_rx) = mpsc::channel::<MeasurementMatch>();
-        // This channel allows the main thread to notify that the worker thread can stop
+        // This flag allows the main thread to notify that the worker thread can stop
         // parsing data.
-        let (sig_tx, sig_rx) = mpsc::channel::<()>();
-
-        let task_ready = ready.clone();
-        let mut port = self.port.try_clone()?;
-        let metadata = self.metadata.clone();
-
-        let t = thread::spawn(move || {
-            let r = || -> Result<()> {
-                // Create an accumulator with the current device metadata
-                let mut accumulator = MeasurementAccumulator::new(metadata);
-                // First wait for main thread to clear
-                // serial port input buffer
-                let (lock, cvar) = &*task_ready;
-                let _l = cvar
-                    .wait_while(lock.lock().unwrap(), |ready| !*ready)
-                    .unwrap();
-
-                /* 4 bytes is the size of a single sample, and the PPK pushes 100,000 samples per second.
-                   Having size of `buf` at eg.1024 blocks port.read() until the buffer is full with 1024 bytes (128 samples).
-                   The measurement returned will be the average of the 128 samples. But we want to get every single sample when
-                   requested sps is 100,000. Hence, we set the buffer size to 4 bytes, and read the port in a loop,
-                   feeding the accumulator with the data.
-                */
-                let mut buf = [0u8; 4];
-                let mut measurement_buf = VecDeque::with_capacity(SPS_MAX);
-                let mut missed = 0;
-                loop {
-                    // Check whether the main thread has signaled
-                    // us to stop
-                    match sig_rx.try_recv() {
-                        Ok(_) => return Ok(()),
-                        Err(TryRecvError::Empty) => {}
-                        Err(e) => return Err(e.into()),
-                    }
+        let stop = options.stop.clone().unwrap_or_default();
+        // This channel is for sending measurements to the main thread.
+        let (meas_tx, meas_rx) = match self.channel_capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::sync_channel::<MeasurementMatch>(capacity);
+                (MeasurementSender::Bounded(tx, stop.clone()), rx)
+            }
+            None => {
+                let (tx, rx) = mpsc::channel::<MeasurementMatch>();
+                (MeasurementSender::Unbounded(tx), rx)
+            }
+        };
+        // This channel allows the main thread to start a new segment
+        let (seg_tx, seg_rx) = mpsc::channel::<String>();
+        let events = EventSubscribers::default();
+        let counters = Arc::new(PipelineCounters::new());
+        let history = History::new(options.history, self.clock);
+        let tags = options
+            .tags
+            .map(|mask| Arc::new(Mutex::new(TagAccumulator::new(mask))));
+        let charge = Arc::new(Mutex::new(ChargeAccumulator::new()));
+        let sampling_plan = match options.max_hardware_averages {
+            1 => SamplingPlan::default(),
+            max => SamplingPlan::for_sps(options.sps, max),
+        };
+        let complement = Subscribers::default();
+        let captures = Subscribers::default();
+        let raw_dump = options.raw_dump.as_ref().map(File::create).transpose()?;
+        let requested_sps = match options.window {
+            WindowPolicy::Sps => options.sps as f64,
+            WindowPolicy::Duration(window) => 1. / window.as_secs_f64(),
+        };
 
-                    // Now we read chunks and feed them to the accumulator
-                    let n = port.read(&mut buf)?;
-                    missed += accumulator.feed_into(&buf[..n], &mut measurement_buf);
-                    let len = measurement_buf.len();
-                    if len >= SPS_MAX / sps {
-                        let measurement = measurement_buf.drain(..).combine_matching(missed, pins);
-                        meas_tx.send(measurement)?;
-                        missed = 0;
-                    }
-                }
-            };
-            let res = r();
-            if let Err(e) = &res {
-                tracing::error!("Error fetching measurements: {:?}", e);
-            };
-            res
+        let control = ControlHandle::new(&self)?;
+        let t = worker::spawn(WorkerContext {
+            port: self.port.try_clone()?,
+            metadata: self.metadata.clone(),
+            clock: self.clock,
+            options,
+            raw_dump,
+            read_buf_size: self.read_buf_size,
+            ready: ready.clone(),
+            meas_tx,
+            seg_rx,
+            stop: stop.clone(),
+            events: events.clone(),
+            counters: counters.clone(),
+            history: history.clone(),
+            tags: tags.clone(),
+            charge: charge.clone(),
+            complement: complement.clone(),
+            captures: captures.clone(),
+            control: control.clone(),
+            setup: DeviceSetup {
+                timeout: self.timeout,
+                flow_control: self.flow_control,
+                open: self.open_port.clone(),
+                mode: self.mode,
+                range: self.range,
+                switch_points: self.switch_points,
+                spike_filtering: self.spike_filtering,
+                user_gains: self.metadata.modifiers.ug,
+                hardware_averages: sampling_plan.hardware_averages,
+            },
         });
-        self.port.clear(Input)?;
-
+        // The input buffer is cleared before the worker starts reading
+        let cleared = self.port.clear(Input);
         let (lock, cvar) = &*ready;
-        let mut ready = lock.lock().unwrap();
-        *ready = true;
+        *lock.lock().unwrap() = true;
         cvar.notify_all();
 
-        self.send_command(Command::AverageStart)?;
+        let started = (|| -> Result<CaptureAnchor> {
+            cleared?;
+            if sampling_plan.hardware_averages > 1 {
+                self.send_command(Command::AvgNumSet(sampling_plan.hardware_averages))?;
+            }
+            self.send_command(Command::AverageStart)?;
+            let anchor = CaptureAnchor::now();
 
-        let stop = move || {
-            sig_tx.send(())?;
-            t.join().expect("Data receive thread panicked")?;
-            self.send_command(Command::AverageStop)?;
-            Ok(self)
+            if let (Some(ramp), Some(label)) = (power_ramp, after_ramp) {
+                self.enable_power_ramped(&ramp)?;
+                control.state.lock().unwrap().power = self.power;
+                seg_tx.send(label).map_err(|_| Error::WorkerStopped)?;
+            }
+            Ok(anchor)
+        })();
+        let anchor = match started {
+            Ok(anchor) => anchor,
+            Err(e) => {
+                // Don't leave the worker running
+                stop.stop();
+                let _ = t.join();
+                let _ = self.send_command(Command::AverageStop);
+                return Err(e);
+            }
         };
 
-        Ok((meas_rx, stop))
+        let os_baseline = self.os_counters.read();
+        let handle = MeasurementHandle {
+            os_baseline,
+            control,
+            ppk2: self,
+            worker: t,
+            stop,
+            seg_tx,
+            counters,
+            history,
+            events,
+            tags,
+            charge,
+            complement,
+            captures,
+            sampling_plan,
+            requested_sps,
+            anchor,
+        };
+
+        Ok((meas_rx, handle))
+    }
+
+    /// Start measurements with the passed [MeasurementOptions], like
+    /// [Ppk2::start_measurement_with], but returning a [MeasurementSession]
+    /// guard that stops the measurement when dropped.
+    pub fn start_measurement_session(
+        self,
+        options: MeasurementOptions,
+    ) -> Result<(Receiver<MeasurementMatch>, MeasurementSession)> {
+        let (rx, handle) = self.start_measurement_with(options)?;
+        Ok((rx, handle.into()))
+    }
+
+    /// Run a measurement for the duration of the passed closure. The closure receives
+    /// the [Receiver] of [measurement::MeasurementMatch] and the [MeasurementHandle].
+    /// The measurement is stopped when the closure returns, after which the device and
+    /// the value returned by the closure are returned. If the closure panics, the
+    /// measurement is stopped before the panic is resumed.
+    pub fn with_measurement<T>(
+        self,
+        options: MeasurementOptions,
+        f: impl FnOnce(&Receiver<MeasurementMatch>, &MeasurementHandle) -> T,
+    ) -> Result<(Self, T)> {
+        let (rx, handle) = self.start_measurement_with(options)?;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&rx, &handle)));
+        let stopped = handle.stop();
+        match res {
+            Ok(value) => Ok((stopped?, value)),
+            Err(payload) => {
+                if let Err(e) = stopped {
+                    tracing::error!("Error stopping measurement: {:?}", e);
+                }
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Arm the device trigger, and capture a window of samples starting at
+    /// the first sample with a current of at least `threshold` µA. Blocks
+    /// until the window is captured. Firmware that doesn't implement the
+    /// trigger keeps streaming all samples, so the trigger condition is
+    /// checked on the host as well. Requires the `unstable` feature.
+    #[cfg(feature = "unstable")]
+    pub fn start_triggered_capture(
+        &mut self,
+        threshold: f32,
+        window: types::WindowSpec,
+    ) -> Result<Vec<Measurement>> {
+        unstable::warn("Ppk2::start_triggered_capture");
+        let len = window.samples(&self.clock).max(1);
+        let window_len = u16::try_from(len).unwrap_or(u16::MAX);
+        self.send_command(Command::TriggerWindowSet(window_len))?;
+        self.send_command(Command::TriggerSet(threshold.max(0.) as u32))?;
+        self.send_command(Command::TriggerSingleSet)?;
+        self.port.clear(Input)?;
+        self.send_command(Command::AverageStart)?;
+
+        let mut capture = trigger::TriggerCapture::new(threshold, len);
+        let res = (|| -> Result<()> {
+            let mut accumulator =
+                MeasurementAccumulator::new(self.metadata.clone()).clock(self.clock);
+            let mut measurements = VecDeque::new();
+            let mut buf = [0u8; 1024];
+            while !capture.is_complete() {
+                let n = self.port.read(&mut buf)?;
+                accumulator.feed_into(&buf[..n], &mut measurements);
+                capture.feed(measurements.drain(..));
+            }
+            Ok(())
+        })();
+
+        self.send_command(Command::AverageStop)?;
+        self.send_command(Command::TriggerStop)?;
+        res.map(|_| capture.finish())
+    }
+
+    /// Measure for the passed duration with the passed [MeasurementOptions],
+    /// and get a [CaptureSummary] of the measurement. Blocks until done, after
+    /// which the device is returned along with the summary. Ends early if the
+    /// measurement parsing pipeline stops, returning its error if it failed.
+    pub fn measure_for(
+        self,
+        duration: Duration,
+        options: MeasurementOptions,
+    ) -> Result<(Self, CaptureSummary)> {
+        let (rx, handle) = self.start_measurement_with(options)?;
+        let deadline = Instant::now() + duration;
+        let mut stats = Stats::new();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(MeasurementMatch::Match(m)) => stats.extend([&m]),
+                Ok(MeasurementMatch::NoMatch) => {}
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        let charge = handle.charge();
+        let qos = handle.qos_report();
+        let ppk2 = handle.stop()?;
+        Ok((
+            ppk2,
+            CaptureSummary {
+                duration: qos.elapsed,
+                measurements: stats.count(),
+                avg_micro_amps: stats.mean(),
+                min_micro_amps: stats.min(),
+                max_micro_amps: stats.max(),
+                charge,
+                missed: qos.missed_samples,
+            },
+        ))
+    }
+
+    /// Measure until the passed buffer is filled with combined measurements,
+    /// taking `sps` combined measurements per second. Blocks until done, and
+    /// doesn't allocate per measurement, which makes it suitable for tests and
+    /// memory-constrained hosts. Chunks without samples are skipped. Returns
+    /// the number of measurements written, and a summary of the device samples.
+    pub fn measure_into(
+        &mut self,
+        buf: &mut [Measurement],
+        sps: SampleRate,
+    ) -> Result<(usize, SegmentSummary)> {
+        self.port.clear(Input)?;
+        self.send_command(Command::AverageStart)?;
+
+        let mut filled = 0;
+        let mut summary = SegmentAccumulator::new(String::new(), 0);
+        let res = (|| -> Result<()> {
+            let mut accumulator =
+                MeasurementAccumulator::new(self.metadata.clone()).clock(self.clock);
+            let mut chunk_timer = ChunkTimer::new(sps.get(), WindowPolicy::default(), self.clock);
+            let mut measurements = VecDeque::with_capacity(SampleClock::NOMINAL_RATE);
+            let mut missed = 0;
+            let mut read_buf = [0u8; 1024];
+            while filled < buf.len() {
+                let n = self.port.read(&mut read_buf)?;
+                for sample in read_buf[..n].chunks(measurement::SAMPLE_SIZE) {
+                    let prev_len = measurements.len();
+                    let new_missed = accumulator.feed_into(sample, &mut measurements);
+                    missed += new_missed;
+                    summary.add_missed(new_missed);
+                    measurements.range(prev_len..).for_each(|m| summary.add(m));
+                    chunk_timer.received(measurements.len() - prev_len);
+                    if !chunk_timer.should_flush(measurements.len()) {
+                        continue;
+                    }
+                    chunk_timer.flushed();
+                    if let MeasurementMatch::Match(m) = measurements.drain(..).combine(missed) {
+                        buf[filled] = m;
+                        filled += 1;
+                    }
+                    missed = 0;
+                    if filled == buf.len() {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        })();
+
+        self.send_command(Command::AverageStop)?;
+        res.map(|_| (filled, summary.finish()))
+    }
+
+    /// Step the source voltage as set by the passed [VoltageSweep], and get
+    /// statistics of the current measured at each step. Requires source meter
+    /// mode, with device power enabled. Blocks until the sweep is done, after
+    /// which the source voltage is restored, if it was set before.
+    pub fn sweep_voltage(&mut self, sweep: &VoltageSweep) -> Result<Vec<SweepStep>> {
+        if self.mode != MeasurementMode::Source {
+            return Err(Error::SourceModeRequired("Voltage sweep"));
+        }
+        let vdd = self.vdd;
+        let res = (|| -> Result<Vec<SweepStep>> {
+            let mut buf = vec![Measurement::default(); sweep.measurements_per_step()];
+            let mut steps = Vec::new();
+            for step_vdd in sweep.voltages() {
+                self.set_source_voltage(step_vdd)?;
+                thread::sleep(sweep.settle_time());
+                let (n, _) = self.measure_into(&mut buf, sweep.sps())?;
+                let mut stats = Stats::new();
+                stats.extend(&buf[..n]);
+                steps.push(SweepStep {
+                    vdd: step_vdd,
+                    stats,
+                });
+            }
+            Ok(steps)
+        })();
+
+        let restored = vdd.map_or(Ok(()), |vdd| self.set_source_voltage(vdd));
+        let steps = res?;
+        restored?;
+        Ok(steps)
+    }
+
+    /// Measure the rate at which the device delivers samples for the passed
+    /// duration, returning [Error::LowSampleRate] if the device doesn't run
+    /// at the nominal rate, see [SampleClock::is_nominal]. Older firmware
+    /// delivers far fewer samples per second, which otherwise goes unnoticed.
+    /// Returns the measured clock, which can be passed to [Ppk2::set_native_clock].
+    pub fn check_sample_rate(&mut self, duration: Duration) -> Result<SampleClock> {
+        self.port.clear(Input)?;
+        self.send_command(Command::AverageStart)?;
+
+        let start = Instant::now();
+        let res = (|| -> Result<usize> {
+            let mut bytes = 0;
+            let mut buf = [0u8; 1024];
+            while start.elapsed() < duration {
+                bytes += self.port.read(&mut buf)?;
+            }
+            Ok(bytes)
+        })();
+        let elapsed = start.elapsed();
+
+        self.send_command(Command::AverageStop)?;
+        let samples = res? / measurement::SAMPLE_SIZE;
+        let clock = SampleClock::measured(samples as u64, elapsed);
+        match clock.is_nominal() {
+            true => Ok(clock),
+            false => Err(Error::LowSampleRate(clock.rate())),
+        }
     }
 
     /// Reset the device, making the device unusable.
@@ -242,20 +1051,782 @@ impl Ppk2 {
 
     fn set_power_mode(&mut self, mode: MeasurementMode) -> Result<()> {
         self.send_command(Command::SetPowerMode(mode))?;
+        self.mode = mode;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serial")]
+/// Handle to a running measurement, returned by [Ppk2::start_measurement_with].
+/// Dropping it stops the measurement and drops the device. Use
+/// [MeasurementHandle::stop] to get the device back instead.
+pub struct MeasurementHandle {
+    ppk2: Ppk2,
+    worker: thread::JoinHandle<Result<Option<Box<dyn SerialPort>>>>,
+    stop: StopHandle,
+    seg_tx: Sender<String>,
+    counters: Arc<PipelineCounters>,
+    history: History,
+    events: EventSubscribers,
+    tags: Option<Arc<Mutex<TagAccumulator>>>,
+    charge: Arc<Mutex<ChargeAccumulator>>,
+    complement: Subscribers<MeasurementMatch>,
+    #[cfg_attr(not(feature = "unstable"), allow(dead_code))]
+    captures: Subscribers<Capture>,
+    sampling_plan: SamplingPlan,
+    os_baseline: Option<OsSerialCounters>,
+    requested_sps: f64,
+    anchor: CaptureAnchor,
+    control: ControlHandle,
+}
+
+#[cfg(feature = "serial")]
+impl MeasurementHandle {
+    /// Close the current segment and start a new one with the passed label,
+    /// without stopping the device. A [MeasurementEvent::SegmentEnd] containing
+    /// the summary of the closed segment is sent to all event subscribers.
+    pub fn next_segment(&self, label: impl Into<String>) -> Result<()> {
+        self.seg_tx
+            .send(label.into())
+            .map_err(|_| Error::WorkerStopped)
+    }
+
+    /// Get a [ControlHandle], to control the device while it measures.
+    pub fn control(&self) -> ControlHandle {
+        self.control.clone()
+    }
+
+    /// The host time at which the measurement started, which together with
+    /// [Measurement::time] gives the host time of each measurement.
+    pub fn anchor(&self) -> CaptureAnchor {
+        self.anchor
+    }
+
+    /// Subscribe to [MeasurementEvent]s emitted by the measurement pipeline.
+    /// Only events emitted after subscribing are received.
+    pub fn subscribe(&self) -> Receiver<MeasurementEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribe to the complement of the combined measurements: for every
+    /// chunk, the combination of the samples that did *not* match the pins
+    /// set with [MeasurementOptions::matching]. This gives a baseline to
+    /// compare the matching measurements to within a single run. The
+    /// complement is only computed while there are subscribers.
+    pub fn subscribe_complement(&self) -> Receiver<MeasurementMatch> {
+        self.complement.subscribe()
+    }
+
+    /// Subscribe to the [Capture]s of the software trigger set with
+    /// [MeasurementOptions::software_trigger].
+    /// Only captures completed after subscribing are received.
+    /// Requires the `unstable` feature.
+    #[cfg(feature = "unstable")]
+    pub fn subscribe_captures(&self) -> Receiver<Capture> {
+        unstable::warn("MeasurementHandle::subscribe_captures");
+        self.captures.subscribe()
+    }
+
+    /// Get the [Measurement]s of the last `duration`, up to the duration
+    /// configured with [MeasurementOptions::history]. Returns an empty [Vec]
+    /// if history is disabled.
+    pub fn capture_last(&self, duration: Duration) -> Vec<Measurement> {
+        self.history.last(duration)
+    }
+
+    /// Get the statistics per tag so far, ordered by tag. Returns an empty
+    /// [Vec] if tags were not enabled with [MeasurementOptions::tags].
+    pub fn tag_stats(&self) -> Vec<TagStats> {
+        self.tags
+            .as_ref()
+            .map(|tags| tags.lock().unwrap().stats())
+            .unwrap_or_default()
+    }
+
+    /// Get the charge drawn since the measurement started, integrated over
+    /// the device samples combined so far. The sample period is corrected
+    /// for the measured device rate once enough data has been received.
+    pub fn charge(&self) -> Charge {
+        let elapsed = self.counters.elapsed();
+        let clock = if elapsed >= Duration::from_secs(1) {
+            SampleClock::measured(self.counters.samples(), elapsed)
+        } else {
+            self.ppk2.clock
+        };
+        self.charge.lock().unwrap().charge(&clock)
+    }
+
+    /// Get the [SamplingPlan] chosen for the measurement. Without
+    /// [MeasurementOptions::hardware_averaging], all downsampling
+    /// is done on the host.
+    pub fn sampling_plan(&self) -> SamplingPlan {
+        self.sampling_plan
+    }
+
+    /// Get statistics on the raw bytes read from the serial port so far.
+    /// Compare [ByteStats::bytes_per_second] to [ByteStats::EXPECTED_BYTES_PER_SECOND]
+    /// to see whether a low sample rate is caused by the device or serial connection.
+    pub fn byte_stats(&self) -> ByteStats {
+        self.counters.byte_stats()
+    }
+
+    /// Get the runtime counters of the measurement parsing pipeline so far,
+    /// for finding out where throughput is lost: in the device and serial
+    /// connection, in parsing, or in delivering the combined measurements.
+    pub fn metrics(&self) -> PipelineMetrics {
+        self.counters.metrics()
+    }
+
+    /// Get statistics on data lost between the device and the host, for finding
+    /// out whether inaccurate data is caused by the serial connection.
+    pub fn serial_errors(&self) -> SerialErrors {
+        let os = self
+            .ppk2
+            .os_counters
+            .read()
+            .zip(self.os_baseline)
+            .map(|(now, start)| now.since(&start));
+        SerialErrors {
+            os,
+            samples: self.counters.samples(),
+            elapsed: self.counters.elapsed(),
+        }
+    }
+
+    /// Get the delivery quality of the measurement so far: the achieved
+    /// versus requested number of measurements per second, the jitter in the
+    /// number of device samples per measurement, and the missed samples.
+    pub fn qos_report(&self) -> QosReport {
+        self.counters.qos_report(self.requested_sps)
+    }
+
+    /// Get a [StopHandle] that can be used to stop the measurement
+    /// from elsewhere, for instance a signal handler.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Stop the measurement parsing pipeline and return the device.
+    pub fn stop(self) -> Result<Ppk2> {
+        self.stop.stop();
+        self.join()
+    }
+
+    /// Wait for the measurement parsing pipeline to finish, either because
+    /// it was stopped through a [StopHandle] or because an error occurred,
+    /// and return the device. If the pipeline failed, the device is stopped
+    /// if it is still connected, and the error is returned.
+    /// If [MeasurementOptions::qos] requirements were set and not met,
+    /// [Error::QosBelowMinimum] is returned after the device is restored,
+    /// holding the device, see [qos::QosViolation::into_device].
+    pub fn join(mut self) -> Result<Ppk2> {
+        match self.worker.join().expect("Data receive thread panicked") {
+            // The port is reopened if a host suspend closed it
+            Ok(Some(port)) => self.ppk2.port = port,
+            Ok(None) => {}
+            Err(e) => {
+                // Don't leave the device streaming, if it is still there
+                if let Err(stop_err) = self.ppk2.send_command(Command::AverageStop) {
+                    tracing::warn!("Failed to stop the device: {stop_err:?}");
+                }
+                return Err(e);
+            }
+        }
+        let report = self.counters.qos_report(self.requested_sps);
+        tracing::debug!("Measurement quality: {report}");
+        self.ppk2.send_command(Command::AverageStop)?;
+        if self.sampling_plan.hardware_averages > 1 {
+            self.ppk2.send_command(Command::AvgNumSet(1))?;
+        }
+
+        let settings = self.control.settings();
+        let changed = settings != (self.ppk2.power, self.ppk2.vdd);
+        (self.ppk2.power, self.ppk2.vdd) = settings;
+        // IR drop emulation changes the source voltage, so restore it
+        let ir_drop = matches!(&self.ppk2.last_options, Some(o) if o.ir_drop.is_some());
+        if let Some(vdd) = self.ppk2.vdd.filter(|_| ir_drop) {
+            self.ppk2.send_command(Command::RegulatorSet(vdd))?;
+        }
+        // The device is stopped already, so don't fail on the cache
+        if changed {
+            if let Err(e) = self.ppk2.settings_changed() {
+                tracing::warn!("Failed to cache settings: {e:?}");
+            }
+        }
+        if let Some(qos) = self.ppk2.last_options.as_ref().and_then(|o| o.qos) {
+            if let Err(Error::QosBelowMinimum(violation)) = qos.check(&report) {
+                return Err(Error::QosBelowMinimum(violation.with_device(self.ppk2)));
+            }
+        }
+        Ok(self.ppk2)
+    }
+}
+
+#[cfg(feature = "serial")]
+/// Guard of a running measurement, returned by [Ppk2::start_measurement_session].
+/// Dereferences to its [MeasurementHandle]. Dropping the guard, for instance on
+/// an early return or a panic, stops the measurement and drops the device. Use
+/// [MeasurementSession::stop] to get the device back instead.
+pub struct MeasurementSession {
+    handle: Option<MeasurementHandle>,
+}
+
+#[cfg(feature = "serial")]
+impl MeasurementSession {
+    /// Stop the measurement parsing pipeline and return the device.
+    pub fn stop(mut self) -> Result<Ppk2> {
+        self.take().stop()
+    }
+
+    /// Release the [MeasurementHandle] from the guard, so the measurement
+    /// is no longer stopped on drop.
+    pub fn into_handle(mut self) -> MeasurementHandle {
+        self.take()
+    }
+
+    fn take(&mut self) -> MeasurementHandle {
+        self.handle.take().expect("Measurement was already stopped")
+    }
+}
+
+#[cfg(feature = "serial")]
+impl From<MeasurementHandle> for MeasurementSession {
+    fn from(handle: MeasurementHandle) -> Self {
+        Self {
+            handle: Some(handle),
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Deref for MeasurementSession {
+    type Target = MeasurementHandle;
+
+    fn deref(&self) -> &MeasurementHandle {
+        self.handle
+            .as_ref()
+            .expect("Measurement was already stopped")
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Drop for MeasurementSession {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.stop() {
+                tracing::error!("Error stopping measurement: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Clonable handle to stop a running measurement. Stopping is idempotent,
+/// and only sets a flag, so it's safe to do from a signal handler.
+/// Can be created before starting the measurement and passed to
+/// [measurement::MeasurementOptions::stop_handle], so it can be shared with, e.g.,
+/// a Ctrl-C handler and a timeout thread up front.
+/// Use [MeasurementHandle::join] to obtain the device after stopping.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "serial")]
+/// Handle to control the device while it measures, returned by
+/// [MeasurementHandle::control]. Can be cloned and sent to other threads.
+/// Settings changed through it are reflected in the [Ppk2] returned
+/// when the measurement stops. Note that IR drop emulation, see
+/// [MeasurementOptions::ir_drop], overrides the source voltage.
+#[derive(Clone)]
+pub struct ControlHandle {
+    state: Arc<Mutex<ControlState>>,
+}
+
+#[cfg(feature = "serial")]
+struct ControlState {
+    port: Box<dyn SerialPort>,
+    power: DevicePower,
+    vdd: Option<SourceVoltage>,
+}
+
+#[cfg(feature = "serial")]
+impl ControlHandle {
+    fn new(ppk2: &Ppk2) -> Result<Self> {
+        let state = ControlState {
+            port: ppk2.port.try_clone()?,
+            power: ppk2.power,
+            vdd: ppk2.vdd,
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Enable or disable the device power, e.g. to reset the
+    /// device under test. See [Ppk2::set_device_power].
+    pub fn set_device_power(&self, power: DevicePower) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let command = Command::DeviceRunningSet(power);
+        state.port.write_all(&Vec::from_iter(command.bytes()))?;
+        state.power = power;
         Ok(())
     }
+
+    /// Set the voltage of the device voltage source.
+    /// See [Ppk2::set_source_voltage].
+    pub fn set_source_voltage(&self, vdd: SourceVoltage) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let command = Command::RegulatorSet(vdd);
+        state.port.write_all(&Vec::from_iter(command.bytes()))?;
+        state.vdd = Some(vdd);
+        Ok(())
+    }
+
+    /// Get the last configured [DevicePower].
+    pub fn device_power(&self) -> DevicePower {
+        self.state.lock().unwrap().power
+    }
+
+    /// Get the last configured [SourceVoltage], if any.
+    pub fn source_voltage(&self) -> Option<SourceVoltage> {
+        self.state.lock().unwrap().vdd
+    }
+
+    pub(crate) fn settings(&self) -> (DevicePower, Option<SourceVoltage>) {
+        let state = self.state.lock().unwrap();
+        (state.power, state.vdd)
+    }
+
+    /// Send a command that doesn't change the settings, ordered with the
+    /// commands sent through the handle.
+    pub(crate) fn send(&self, command: Command) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.port.write_all(&Vec::from_iter(command.bytes()))?;
+        Ok(())
+    }
+
+    /// Send the device power last set through the handle again, e.g. after
+    /// auto-zero disabled it for a reference window.
+    pub(crate) fn restore_device_power(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let command = Command::DeviceRunningSet(state.power);
+        state.port.write_all(&Vec::from_iter(command.bytes()))?;
+        Ok(())
+    }
+
+    /// Send commands to `port` from now on, after the reader reopened it.
+    pub(crate) fn set_port(&self, port: Box<dyn SerialPort>) {
+        self.state.lock().unwrap().port = port;
+    }
 }
 
-/// Try to find the serial port the PPK2 is connected to.
+impl StopHandle {
+    /// Create a new [StopHandle] that is not stopped.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signal the measurement parsing pipeline to stop.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether the measurement was signaled to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "serial")]
+/// Try to find the serial port the PPK2 is connected to. If multiple
+/// devices are connected, use [list_ppk2_devices] to choose one.
 pub fn try_find_ppk2_port() -> Result<String> {
+    Ok(list_ppk2_devices()?
+        .into_iter()
+        .next()
+        .ok_or(Error::Ppk2NotFound)?
+        .port_name)
+}
+
+#[cfg(feature = "serial")]
+/// A connected PPK2, as found by [list_ppk2_devices].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ppk2DeviceInfo {
+    /// Name of the serial port, to pass to [Ppk2::new]
+    pub port_name: String,
+    /// USB serial number, if reported
+    pub serial_number: Option<String>,
+    /// Position on the USB bus as `bus-port.port...`, which stays the same
+    /// as long as the device is plugged into the same port. Not available
+    /// on all platforms.
+    pub location: Option<String>,
+}
+
+#[cfg(feature = "serial")]
+/// List all connected PPK2 devices, ordered by location and port name.
+pub fn list_ppk2_devices() -> Result<Vec<Ppk2DeviceInfo>> {
     use serialport::SerialPortType::UsbPort;
 
-    Ok(serialport::available_ports()?
+    let mut devices: Vec<_> = serialport::available_ports()?
         .into_iter()
-        .find(|p| match &p.port_type {
-            UsbPort(usb) => usb.vid == 0x1915 && usb.pid == 0xc00a,
-            _ => false,
+        .filter_map(|p| match p.port_type {
+            UsbPort(usb) if usb.vid == 0x1915 && usb.pid == 0xc00a => Some(Ppk2DeviceInfo {
+                port_name: p.port_name,
+                serial_number: usb.serial_number,
+                location: usb.location.map(|l| l.to_string()),
+            }),
+            _ => None,
         })
-        .ok_or(Error::Ppk2NotFound)?
-        .port_name)
+        .collect();
+    devices.sort_by(|a, b| (&a.location, &a.port_name).cmp(&(&b.location, &b.port_name)));
+    Ok(devices)
+}
+
+#[cfg(feature = "serial")]
+/// Wait until a PPK2 is connected, e.g. after switching on its USB power,
+/// and return it. Returns immediately if one is connected already. Returns
+/// [Error::Ppk2NotFound] if none was connected within `timeout`, or
+/// waits indefinitely if `timeout` is [None].
+pub fn watch_for_ppk2(timeout: Option<Duration>) -> Result<Ppk2DeviceInfo> {
+    /// Interval between checks of the available serial ports
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    let start = Instant::now();
+    loop {
+        if let Some(device) = list_ppk2_devices()?.into_iter().next() {
+            return Ok(device);
+        }
+        let remaining = match timeout {
+            Some(timeout) => timeout.saturating_sub(start.elapsed()),
+            None => POLL_INTERVAL,
+        };
+        if remaining.is_zero() {
+            return Err(Error::Ppk2NotFound);
+        }
+        thread::sleep(remaining.min(POLL_INTERVAL));
+    }
+}
+
+#[cfg(feature = "serial")]
+/// Look up the USB serial number of the device at the passed port.
+fn usb_serial_number(path: &str) -> Option<String> {
+    use serialport::SerialPortType::UsbPort;
+
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| p.port_name == path)
+        .and_then(|p| match p.port_type {
+            UsbPort(usb) => usb.serial_number,
+            _ => None,
+        })
+}
+
+#[cfg(all(test, feature = "serial"))]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        clock::SampleClock,
+        measurement::{MeasurementEnd, MeasurementEvent, MeasurementMatch, MeasurementOptions},
+        mock::MockPpk2,
+        types::{DevicePower, MeasurementMode, SampleRate, SourceVoltage},
+        Error, Ppk2, StopHandle,
+    };
+
+    #[test]
+    pub fn test_check_sample_rate() {
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock), MeasurementMode::Source).unwrap();
+        let clock = ppk2.check_sample_rate(Duration::from_millis(200)).unwrap();
+        assert!(clock.is_nominal(), "{} sps", clock.rate());
+
+        let slow = MockPpk2::new().clock(SampleClock::with_rate(10_000.));
+        let mut ppk2 = Ppk2::with_port(Box::new(slow), MeasurementMode::Source).unwrap();
+        assert!(matches!(
+            ppk2.check_sample_rate(Duration::from_millis(200)),
+            Err(Error::LowSampleRate(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_builder() {
+        let mock = MockPpk2::new();
+        let ppk2 = Ppk2::builder()
+            .read_buf_size(64)
+            .channel_capacity(Some(2))
+            .with_port(Box::new(mock), MeasurementMode::Source)
+            .unwrap();
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(1000).unwrap(),
+            ))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        // The channel holds at most 2 measurements, plus one that is being sent
+        assert!((2..=3).contains(&rx.try_iter().count()));
+        let MeasurementMatch::Match(m) = rx.recv_timeout(Duration::from_secs(1)).unwrap() else {
+            panic!("Expected a matching measurement");
+        };
+        assert!((m.micro_amps - 1000.).abs() < 10.);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    pub fn test_control() {
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        ppk2.set_device_power(DevicePower::Enabled).unwrap();
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(100).unwrap(),
+            ))
+            .unwrap();
+        let control = handle.control();
+        std::thread::spawn(move || {
+            control.set_device_power(DevicePower::Disabled).unwrap();
+            control
+                .set_source_voltage(SourceVoltage::from_millivolts(1800))
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+        // Measurements keep coming
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(mock.commands().contains(&vec![0x0C, 0x00]));
+        assert_eq!(handle.control().device_power(), DevicePower::Disabled);
+
+        let ppk2 = handle.stop().unwrap();
+        assert_eq!(ppk2.device_power(), DevicePower::Disabled);
+        assert_eq!(
+            ppk2.source_voltage(),
+            Some(SourceVoltage::from_millivolts(1800))
+        );
+    }
+
+    #[test]
+    pub fn test_measure_for() {
+        let ppk2 = Ppk2::with_port(Box::new(MockPpk2::new()), MeasurementMode::Source).unwrap();
+        let (_ppk2, summary) = ppk2
+            .measure_for(
+                Duration::from_millis(200),
+                MeasurementOptions::new(SampleRate::per_second(100).unwrap()),
+            )
+            .unwrap();
+        assert!(summary.measurements > 0);
+        assert!((summary.avg_micro_amps.unwrap() - 1000.).abs() < 10.);
+        assert!(summary.min_micro_amps <= summary.max_micro_amps);
+        assert!(summary.charge.samples > 0);
+        assert!(summary.duration >= Duration::from_millis(200));
+    }
+
+    #[test]
+    pub fn test_measurement_session() {
+        let mock = MockPpk2::new();
+        let ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let options = MeasurementOptions::new(SampleRate::per_second(100).unwrap());
+        let (rx, session) = ppk2.start_measurement_session(options.clone()).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(session.qos_report().chunks > 0);
+        let ppk2 = session.stop().unwrap();
+        assert_eq!(mock.commands().last().unwrap(), &[0x07]);
+
+        let average_stops = || mock.commands().iter().filter(|c| c[..] == [0x07]).count();
+        let stops = average_stops();
+        let (rx, session) = ppk2.start_measurement_session(options).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        drop(session);
+        assert!(average_stops() > stops);
+        // The worker was joined, so the channel is closed
+        rx.iter().for_each(drop);
+    }
+
+    #[test]
+    pub fn test_drop_handle() {
+        let mock = MockPpk2::new();
+        let ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(100).unwrap(),
+            ))
+            .unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        drop(handle);
+        // The worker stops, so the channel is closed
+        rx.iter().for_each(drop);
+        assert_eq!(mock.commands().last().unwrap(), &[0x07]);
+    }
+
+    #[test]
+    pub fn test_qos_below_minimum() {
+        use crate::{measurement::Measurement, qos::QosRequirements};
+
+        // Every other sample is lost
+        let mock = MockPpk2::new().samples(|i| (i % 2 == 0).then(Measurement::default));
+        let ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let options = MeasurementOptions::new(SampleRate::per_second(100).unwrap())
+            .qos(QosRequirements::new().max_missed_ratio(0.01));
+        let (rx, handle) = ppk2.start_measurement_with(options.clone()).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        let Err(Error::QosBelowMinimum(violation)) = handle.stop() else {
+            panic!("Expected a QoS violation");
+        };
+        assert!(violation.violations().contains("missed"));
+        assert_eq!(mock.commands().last().unwrap(), &[0x07]);
+
+        // The device can be used again
+        let ppk2 = violation.into_device().unwrap();
+        let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(handle.stop().is_err());
+    }
+
+    #[test]
+    pub fn test_stop_handle() {
+        let ppk2 = Ppk2::with_port(Box::new(MockPpk2::new()), MeasurementMode::Source).unwrap();
+        let stop = StopHandle::new();
+        let options =
+            MeasurementOptions::new(SampleRate::per_second(100).unwrap()).stop_handle(stop.clone());
+        let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(!handle.stop_handle().is_stopped());
+        stop.stop();
+        assert!(handle.stop_handle().is_stopped());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_ended_event() {
+        let mock = MockPpk2::new();
+        let options = MeasurementOptions::new(SampleRate::per_second(100).unwrap());
+        let ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let (_rx, handle) = ppk2.start_measurement_with(options.clone()).unwrap();
+        let events = handle.subscribe();
+        let ppk2 = handle.stop().unwrap();
+        let ended = events.try_iter().find_map(|e| match e {
+            MeasurementEvent::Ended(end) => Some(end),
+            _ => None,
+        });
+        assert_eq!(ended, Some(MeasurementEnd::Stopped));
+
+        let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+        let events = handle.subscribe();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        mock.disconnect();
+        let ended = events
+            .iter()
+            .find_map(|e| match e {
+                MeasurementEvent::Ended(end) => Some(end),
+                _ => None,
+            })
+            .unwrap();
+        assert!(matches!(ended, MeasurementEnd::Failed(_)));
+        assert!(handle.join().is_err());
+        // The device is stopped even though the pipeline failed
+        assert_eq!(mock.commands().last().unwrap(), &[0x07]);
+    }
+
+    #[test]
+    pub fn test_reconnect() {
+        use crate::reconnect::ReconnectPolicy;
+
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let replugged = mock.clone();
+        ppk2.open_port = std::sync::Arc::new(move |_, _| {
+            // The device takes a while to enumerate again
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(Box::new(replugged.clone()))
+        });
+        let options = MeasurementOptions::new(SampleRate::per_second(1000).unwrap())
+            .reconnect(Some(ReconnectPolicy::new()));
+        let (rx, handle) = ppk2.start_measurement_with(options).unwrap();
+        let events = handle.subscribe();
+        let MeasurementMatch::Match(before) = rx.recv_timeout(Duration::from_secs(1)).unwrap()
+        else {
+            panic!("expected a measurement");
+        };
+        mock.glitch();
+        let gap = events
+            .iter()
+            .find_map(|e| match e {
+                MeasurementEvent::Reconnected(gap) => Some(gap),
+                _ => None,
+            })
+            .unwrap();
+        assert!(gap.duration >= Duration::from_millis(20));
+        assert!(gap.sample as u64 > before.index);
+
+        // The measurements after the gap continue at the sample after it
+        let lost = SampleClock::nominal().samples_in(gap.duration) as u64;
+        let after = rx
+            .iter()
+            .find_map(|m| match m {
+                MeasurementMatch::Match(m) if m.index > gap.sample as u64 => Some(m),
+                _ => None,
+            })
+            .unwrap();
+        assert!(after.index >= gap.sample as u64 + lost);
+        handle.stop().unwrap();
+        assert!(mock.commands().iter().filter(|c| c == &&[0x06]).count() >= 2);
+    }
+
+    #[test]
+    pub fn test_next_segment() {
+        let ppk2 = Ppk2::with_port(Box::new(MockPpk2::new()), MeasurementMode::Source).unwrap();
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(100).unwrap(),
+            ))
+            .unwrap();
+        let events = handle.subscribe();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        handle.next_segment("a").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        handle.next_segment("b").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        handle.stop().unwrap();
+
+        let segments: Vec<_> = events
+            .try_iter()
+            .filter_map(|e| match e {
+                MeasurementEvent::SegmentEnd(summary) => Some(summary),
+                _ => None,
+            })
+            .collect();
+        let labels: Vec<_> = segments.iter().map(|s| s.label.as_str()).collect();
+        assert_eq!(labels, ["", "a", "b"]);
+        let [first, a, _] = segments.as_slice() else {
+            unreachable!()
+        };
+        assert_eq!(a.start, first.start + first.samples + first.missed);
+        assert!(a.samples > 0);
+        assert!((a.avg_micro_amps.unwrap() - 1000.).abs() < 10.);
+    }
+
+    #[test]
+    pub fn test_metrics() {
+        let ppk2 = Ppk2::with_port(Box::new(MockPpk2::new()), MeasurementMode::Source).unwrap();
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(100).unwrap(),
+            ))
+            .unwrap();
+        let events = handle.subscribe();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let metrics = handle.metrics();
+        assert!(metrics.bytes_read >= metrics.bytes_parsed);
+        assert_eq!(metrics.frames_parsed, metrics.bytes_parsed / 4);
+        assert!(metrics.windows > 0);
+        assert_eq!(metrics.send_failures, 0);
+
+        // The pipeline fails once the receiver is gone
+        drop(rx);
+        events
+            .iter()
+            .find(|e| matches!(e, MeasurementEvent::Ended(_)))
+            .unwrap();
+        assert_eq!(handle.metrics().send_failures, 1);
+        assert!(handle.join().is_err());
+    }
 }
@@ -1,39 +1,253 @@
 //! Measurement parsing and preprocessing
 
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{types::{LogicPortPins, Metadata}};
+use crate::{
+    autozero::{AutoZero, ZeroCorrection},
+    battery::{IrDropModel, IrDropUpdate},
+    calibration::Calibration,
+    charge::Charge,
+    clock::SampleClock,
+    qos::{QosReport, QosRequirements},
+    ramp::PowerRamp,
+    reconnect::{Disconnection, ReconnectPolicy},
+    reset::ResetSignature,
+    stats::Stats,
+    suspend::{self, Suspension},
+    tags::TagMask,
+    trigger::SoftwareTrigger,
+    types::{LogicPortPins, Metadata, SampleRate},
+    StopHandle,
+};
+use ppk2_core::frame;
+
+pub use crate::charge::EnergyAccumulator;
+pub(crate) use ppk2_core::frame::SAMPLE_FIELDS;
+/// Size in bytes of a single raw sample as sent by the device.
+pub use ppk2_core::frame::SAMPLE_SIZE;
 
-const ADC_MULTIPLIER: f32 = 1.8 / 163840.;
 const SPIKE_FILTER_ALPHA: f32 = 0.18;
 const SPIKE_FILTER_ALPHA_5: f32 = 0.06;
 const SPIKE_FILTER_SAMPLES: isize = 3;
+const COUNTER_MASK: u8 = 0x3F;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 /// A single parsed measurement
 pub struct Measurement {
     /// The measured current in mA.
     pub micro_amps: f32,
     /// Logic port bits
     pub pins: LogicPortPins,
+    /// Whether the measurement was interpolated to fill a gap of missed
+    /// samples, rather than received from the device.
+    /// See [MeasurementAccumulator::interpolate_gaps].
+    pub synthetic: bool,
+    /// The lowest and highest current of the samples that were combined
+    /// into this measurement, or [None] for a single device sample.
+    pub envelope: Option<Envelope>,
+    /// The measurement range the device selected, from 0 for the lowest
+    /// currents to 4 for the highest, see [crate::types::MeasurementRange].
+    /// For combined measurements, that of the last sample. Interpolated
+    /// measurements take it from the sample before.
+    pub range: u8,
+    /// The raw 14-bit ADC value, with the same provenance as [Measurement::range].
+    pub adc: u16,
+    /// Index of the sample, counted from the first sample fed to the
+    /// [MeasurementAccumulator] and including missed samples, so gaps show
+    /// as jumps. Derived from the sample counter, which wraps every 64
+    /// samples. For combined measurements, that of the last sample.
+    pub index: u64,
+    /// Time of the sample since the first sample fed to the
+    /// [MeasurementAccumulator], derived from [Measurement::index] and the
+    /// sample clock. See [MeasurementAccumulator::clock].
+    pub time: Duration,
+}
+
+/// The lowest and highest current of a set of combined [Measurement]s,
+/// which shows short spikes that are hidden by the average.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    /// The lowest current in µA
+    pub min: f32,
+    /// The highest current in µA
+    pub max: f32,
 }
 
-struct AccumulatorState {
+impl Envelope {
+    /// Widen the envelope to include the passed measurement, which may
+    /// itself have an envelope.
+    fn include(envelope: Option<Self>, m: &Measurement) -> Option<Self> {
+        let (min, max) = m
+            .envelope
+            .map_or((m.micro_amps, m.micro_amps), |e| (e.min, e.max));
+        Some(match envelope {
+            Some(e) => Self {
+                min: e.min.min(min),
+                max: e.max.max(max),
+            },
+            None => Self { min, max },
+        })
+    }
+}
+
+/// Software spike filter, as applied by the official app. When the measurement
+/// range changes, the following samples are replaced by a rolling average, to
+/// suppress the spikes caused by switching ranges. Works on converted currents,
+/// see [crate::calibration::Calibration::convert].
+#[derive(Debug, Clone, Default)]
+pub struct SpikeFilter {
     rolling_avg_4: Option<f32>,
     rolling_avg: Option<f32>,
     prev_range: Option<usize>,
     after_spike: isize,
     consecutive_range_sample: usize,
-    expected_counter: Option<u8>,
+}
+
+/// Determines how a [MeasurementAccumulator] synchronizes to the
+/// sample counter of the first samples it receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InitialSync {
+    /// Accept the counter of the first sample as baseline.
+    #[default]
+    AcceptFirst,
+    /// Discard the passed number of samples, and accept the counter
+    /// of the sample after those as baseline.
+    Skip(usize),
+}
+
+/// Determines what a [MeasurementAccumulator] does with samples for which
+/// the current conversion produced NaN or an infinite value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// Drop the sample, counting it as missed, so it doesn't poison averages.
+    #[default]
+    Drop,
+    /// Replace NaN by 0, and infinite values by ±[NonFinitePolicy::CLAMP_MICRO_AMPS].
+    Clamp,
+    /// Pass the value on unchanged.
+    Keep,
+}
+
+impl NonFinitePolicy {
+    /// Magnitude infinite currents are clamped to, in µA. Well beyond the
+    /// 1 A the device can measure.
+    pub const CLAMP_MICRO_AMPS: f32 = 10e6;
+}
+
+/// Suppresses logic port glitches: a pin only changes level once the new
+/// level was seen for at least `min_width` consecutive samples. This delays
+/// edges by `min_width - 1` samples.
+#[derive(Debug, Clone)]
+pub(crate) struct GlitchFilter {
+    min_width: usize,
+    stable: Option<u8>,
+    counts: [usize; 8],
+}
+
+impl GlitchFilter {
+    pub(crate) fn new(min_width: usize) -> Self {
+        Self {
+            min_width,
+            stable: None,
+            counts: [0; 8],
+        }
+    }
+
+    pub(crate) fn apply(&mut self, logic: u8) -> u8 {
+        let stable = *self.stable.get_or_insert(logic);
+        let mut out = stable;
+        for (i, count) in self.counts.iter_mut().enumerate() {
+            let mask = 1 << i;
+            if (logic ^ stable) & mask == 0 {
+                *count = 0;
+                continue;
+            }
+            *count += 1;
+            if *count >= self.min_width {
+                out ^= mask;
+                *count = 0;
+            }
+        }
+        self.stable = Some(out);
+        out
+    }
+}
+
+/// Unexpected protocol condition, reported by
+/// [MeasurementAccumulator::try_feed_into] and in strict mode.
+/// See [MeasurementOptions::strict].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProtocolViolation {
+    /// The sample counter skipped, meaning samples were lost
+    /// or the data stream is out of sync.
+    CounterGap {
+        /// Index of the sample, counted from the first sample fed.
+        sample: usize,
+        /// The expected counter value
+        expected: u8,
+        /// The received counter value
+        counter: u8,
+    },
+    /// A sample reported a measurement range the device doesn't have.
+    InvalidRange {
+        /// Index of the sample, counted from the first sample fed.
+        sample: usize,
+        /// The received range
+        range: u8,
+    },
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolViolation::CounterGap {
+                sample,
+                expected,
+                counter,
+            } => write!(
+                f,
+                "sample {sample} has counter {counter}, but {expected} was expected"
+            ),
+            ProtocolViolation::InvalidRange { sample, range } => {
+                write!(f, "sample {sample} has invalid measurement range {range}")
+            }
+        }
+    }
 }
 
 /// An acumulator for [Measurement]s. Keeps an internal state
 /// as well as a byte buffer and builds [Measurement]s from bytes
 /// that were fed. See [MeasurementAccumulator::feed_into] for more details.
 pub struct MeasurementAccumulator {
-    state: AccumulatorState,
+    /// [None] if disabled
+    spike_filter: Option<SpikeFilter>,
+    expected_counter: Option<u8>,
     buf: Vec<u8>,
-    metadata: Metadata,
+    calibration: Calibration,
+    skip: usize,
+    max_gap: usize,
+    last: Option<Measurement>,
+    glitch_filter: Option<GlitchFilter>,
+    samples_seen: usize,
+    /// Index of the next sample, see [Measurement::index]
+    next_index: u64,
+    clock: SampleClock,
+    logic_only: bool,
+    current_only: bool,
+    non_finite_policy: NonFinitePolicy,
+    non_finite: usize,
 }
 
 impl MeasurementAccumulator {
@@ -42,24 +256,143 @@ impl MeasurementAccumulator {
     /// [Metadata] is recent.
     pub fn new(metadata: Metadata) -> Self {
         Self {
-            metadata,
-            state: AccumulatorState {
-                rolling_avg_4: None,
-                rolling_avg: None,
-                prev_range: None,
-                after_spike: 0,
-                consecutive_range_sample: 0,
-                expected_counter: None,
-            },
+            calibration: Calibration::from(&metadata),
+            spike_filter: Some(SpikeFilter::new()),
+            expected_counter: None,
             buf: Vec::with_capacity(4096),
+            skip: 0,
+            max_gap: 0,
+            last: None,
+            glitch_filter: None,
+            samples_seen: 0,
+            next_index: 0,
+            clock: SampleClock::nominal(),
+            logic_only: false,
+            current_only: false,
+            non_finite_policy: NonFinitePolicy::default(),
+            non_finite: 0,
         }
     }
 
-    /// Feed a number of bytes to the accumulator, pushing the [Result]s into the
-    /// passed ring buffer.
+    /// Filter out logic port pulses shorter than `min_width` samples.
+    /// A pin only changes level once the new level was seen for `min_width`
+    /// consecutive samples, which delays edges by `min_width - 1` samples.
+    /// A `min_width` of 0 or 1 disables the filter, which is the default.
+    pub fn glitch_filter(mut self, min_width: usize) -> Self {
+        self.glitch_filter = (min_width > 1).then(|| GlitchFilter::new(min_width));
+        self
+    }
+
+    /// Skip current conversion and spike filtering, for when only the logic
+    /// port pins are of interest. The current of all measurements is 0.
+    pub fn logic_only(mut self, logic_only: bool) -> Self {
+        self.logic_only = logic_only;
+        self
+    }
+
+    /// Skip logic port pin extraction and glitch filtering, for when only
+    /// the current is of interest. All pins of all measurements are low.
+    pub fn current_only(mut self, current_only: bool) -> Self {
+        self.current_only = current_only;
+        self
+    }
+
+    /// Enable or disable the software [SpikeFilter], which is enabled by
+    /// default. Disabling it passes the converted ADC values on as is, which
+    /// keeps the amplitude of very short current pulses intact, at the cost
+    /// of spikes when the measurement range changes.
+    pub fn spike_filter(mut self, enabled: bool) -> Self {
+        self.spike_filter = enabled.then(SpikeFilter::new);
+        self
+    }
+
+    /// Set the clock the samples are taken at, from which the time of
+    /// each measurement is derived. Defaults to [SampleClock::nominal].
+    pub fn clock(mut self, clock: SampleClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Register samples that were lost without the sample counter
+    /// showing it, e.g. while the host was suspended.
+    #[cfg_attr(not(feature = "serial"), allow(dead_code))]
+    pub(crate) fn add_missed(&mut self, missed: usize) {
+        self.next_index += missed as u64;
+    }
+
+    /// Drop the bytes of an incomplete sample and resynchronize to the
+    /// sample counter, after the device restarted sending samples, e.g.
+    /// after reopening the serial port. Use the passed [Metadata], which
+    /// was read again from the device.
+    #[cfg_attr(not(feature = "serial"), allow(dead_code))]
+    pub(crate) fn restart(&mut self, metadata: Option<&Metadata>) {
+        self.buf.clear();
+        self.expected_counter = None;
+        if let Some(metadata) = metadata {
+            self.calibration = Calibration::from(metadata);
+        }
+    }
+
+    /// Set what to do with samples whose current is NaN or infinite.
+    /// Defaults to [NonFinitePolicy::Drop].
+    pub fn non_finite(mut self, policy: NonFinitePolicy) -> Self {
+        self.non_finite_policy = policy;
+        self
+    }
+
+    /// Number of samples fed so far whose current was NaN or infinite.
+    pub fn non_finite_samples(&self) -> usize {
+        self.non_finite
+    }
+
+    /// Fill gaps of at most `max_gap` missed samples with measurements
+    /// linearly interpolated between the samples around the gap. Interpolated
+    /// measurements have [Measurement::synthetic] set, and are still counted
+    /// as missed. Disabled by default.
+    pub fn interpolate_gaps(mut self, max_gap: usize) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Set how the accumulator synchronizes to the sample counter
+    /// of the first samples. Defaults to [InitialSync::AcceptFirst].
+    pub fn initial_sync(mut self, sync: InitialSync) -> Self {
+        self.skip = match sync {
+            InitialSync::AcceptFirst => 0,
+            InitialSync::Skip(n) => n,
+        };
+        self
+    }
+
+    /// Feed a number of bytes to the accumulator, pushing the [Measurement]s into the
+    /// passed ring buffer. Returns the number of samples that were missed, based on
+    /// the sample counter.
     pub fn feed_into(&mut self, bytes: &[u8], buf: &mut VecDeque<Measurement>) -> usize {
+        match self.feed(bytes, buf, false) {
+            Ok(missed) => missed,
+            Err(_) => unreachable!("Protocol violations are only reported in strict mode"),
+        }
+    }
+
+    /// Like [MeasurementAccumulator::feed_into], but returns a [ProtocolViolation]
+    /// on any unexpected condition instead of papering over it. The samples
+    /// before the violating sample are pushed into the ring buffer.
+    pub fn try_feed_into(
+        &mut self,
+        bytes: &[u8],
+        buf: &mut VecDeque<Measurement>,
+    ) -> std::result::Result<usize, ProtocolViolation> {
+        self.feed(bytes, buf, true)
+    }
+
+    fn feed(
+        &mut self,
+        bytes: &[u8],
+        buf: &mut VecDeque<Measurement>,
+        strict: bool,
+    ) -> std::result::Result<usize, ProtocolViolation> {
         if bytes.is_empty() {
-            return 0;
+            return Ok(0);
         }
         self.buf.extend_from_slice(bytes);
         let end = self.buf.len() - self.buf.len() % 4;
@@ -67,107 +400,652 @@ impl MeasurementAccumulator {
             .chunks_exact(4)
             .map(|c| c.try_into().unwrap());
         let mut samples_missed = 0;
+        let mut violation = None;
+        let mut consumed = 0;
         for chunk in chunks {
+            consumed += SAMPLE_SIZE;
             let raw = u32::from_le_bytes(chunk);
-            let current_measurement_range = get_range(raw).min(4) as usize;
-            let counter = get_counter(raw) as u8;
-
-            let prev_expected_counter = self.state.expected_counter;
-            // Wrap at 63 + 1
-            self.state.expected_counter.replace((counter + 1) & 0x3F);
-            if let Some(prev_count) = prev_expected_counter {
-                if prev_count < counter {
-                    samples_missed += (counter - prev_count) as usize;
-                    continue;
-                } else if prev_expected_counter > Some(counter) {
-                    samples_missed += (prev_count - counter) as usize;
-                    continue;
-                }
+            let range = frame::range(raw);
+            let current_measurement_range = range.min(4) as usize;
+            let counter = frame::counter(raw) as u8;
+
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+
+            let sample = self.samples_seen;
+            self.samples_seen += 1;
+            if strict && range > 4 {
+                violation = Some(ProtocolViolation::InvalidRange {
+                    sample,
+                    range: range as u8,
+                });
+                break;
             }
 
-            let adc_result = get_adc(raw) * 4;
-            let pins = get_logic(raw).into();
-            let micro_amps = get_adc_result(
-                &self.metadata,
-                &mut self.state,
-                current_measurement_range,
-                adc_result,
-            ) * 10f32.powi(6);
-            if self.state.expected_counter.is_none() {
-                self.state.expected_counter.replace(counter);
+            let mut gap = 0;
+            if let Some(expected) = self.expected_counter {
+                // Counter wraps at 63 + 1
+                gap = (counter.wrapping_sub(expected) & COUNTER_MASK) as usize;
+                if strict && gap > 0 {
+                    violation = Some(ProtocolViolation::CounterGap {
+                        sample,
+                        expected,
+                        counter,
+                    });
+                    break;
+                }
+                samples_missed += gap;
             }
+            self.expected_counter = Some((counter + 1) & COUNTER_MASK);
+            let index = self.next_index + gap as u64;
+            self.next_index = index + 1;
 
-            buf.push_back(Measurement {
+            let adc = frame::adc(raw);
+            let adc_result = adc * 4;
+            let pins = if self.current_only {
+                LogicPortPins::default()
+            } else {
+                let mut logic = frame::logic(raw) as u8;
+                if let Some(filter) = &mut self.glitch_filter {
+                    logic = filter.apply(logic);
+                }
+                logic.into()
+            };
+            let micro_amps = if self.logic_only {
+                0.
+            } else {
+                let amps = self
+                    .calibration
+                    .convert_scaled(adc_result, current_measurement_range);
+                let amps = match &mut self.spike_filter {
+                    Some(filter) => filter.apply(amps, current_measurement_range),
+                    None => amps,
+                };
+                amps * 10f32.powi(6)
+            };
+            let micro_amps = if micro_amps.is_finite() {
+                micro_amps
+            } else {
+                self.non_finite += 1;
+                match self.non_finite_policy {
+                    NonFinitePolicy::Drop => {
+                        samples_missed += 1;
+                        continue;
+                    }
+                    NonFinitePolicy::Clamp if micro_amps.is_nan() => 0.,
+                    NonFinitePolicy::Clamp => {
+                        NonFinitePolicy::CLAMP_MICRO_AMPS.copysign(micro_amps)
+                    }
+                    NonFinitePolicy::Keep => micro_amps,
+                }
+            };
+
+            match &self.last {
+                Some(last) if gap > 0 && gap <= self.max_gap => {
+                    let step = (micro_amps - last.micro_amps) / (gap + 1) as f32;
+                    buf.extend((1..=gap).map(|i| Measurement {
+                        micro_amps: last.micro_amps + step * i as f32,
+                        synthetic: true,
+                        index: last.index + i as u64,
+                        time: self.clock.time_at(last.index + i as u64),
+                        ..last.clone()
+                    }));
+                }
+                _ => {}
+            }
+            let measurement = Measurement {
                 micro_amps,
                 pins,
-            })
+                synthetic: false,
+                envelope: None,
+                // Out of spec ranges are converted as the highest range
+                range: current_measurement_range as u8,
+                adc: adc as u16,
+                index,
+                time: self.clock.time_at(index),
+            };
+            self.last = Some(measurement.clone());
+            buf.push_back(measurement)
         }
-        self.buf.drain(..end);
-        samples_missed
-    }
-}
-
-fn get_adc_result(
-    metadata: &Metadata,
-    state: &mut AccumulatorState,
-    range: usize,
-    adc_val: u32,
-) -> f32 {
-    let modifiers = &metadata.modifiers;
-
-    let result_without_gain: f32 =
-        (adc_val as f32 - modifiers.o[range]) * (ADC_MULTIPLIER / modifiers.r[range]);
-    let mut adc = modifiers.ug[range]
-        * (result_without_gain * (modifiers.gs[range] * result_without_gain + modifiers.gi[range])
-            + (modifiers.s[range] * (f32::from(metadata.vdd) / 1000.) + modifiers.i[range]));
-
-    let prev_rolling_avg_4 = state.rolling_avg_4;
-    let prev_rolling_avg = state.rolling_avg;
-
-    state
-        .rolling_avg
-        .replace(if let Some(rolling_avg) = state.rolling_avg {
-            SPIKE_FILTER_ALPHA * adc + (1. - SPIKE_FILTER_ALPHA) * rolling_avg
-        } else {
-            adc
-        });
+        self.buf.drain(..consumed);
+        match violation {
+            Some(violation) => Err(violation),
+            None => Ok(samples_missed),
+        }
+    }
+}
 
-    state
-        .rolling_avg_4
-        .replace(if let Some(rolling_avg_4) = state.rolling_avg_4 {
-            SPIKE_FILTER_ALPHA_5 * adc + (1. - SPIKE_FILTER_ALPHA_5) * rolling_avg_4
-        } else {
-            adc
-        });
+impl SpikeFilter {
+    /// Create a new [SpikeFilter].
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    state.prev_range.get_or_insert(range);
+    /// Filter the passed current in A, measured in the passed range.
+    pub fn apply(&mut self, amps: f32, range: usize) -> f32 {
+        let mut adc = amps;
 
-    if !matches!(state.prev_range, Some(r) if r == range) || state.after_spike > 0 {
-        if matches!(state.prev_range, Some(r) if r == range) {
-            state.consecutive_range_sample = 0;
-            state.after_spike = SPIKE_FILTER_SAMPLES;
-        } else {
-            state.consecutive_range_sample += 1;
-        }
+        let prev_rolling_avg_4 = self.rolling_avg_4;
+        let prev_rolling_avg = self.rolling_avg;
+
+        self.rolling_avg
+            .replace(if let Some(rolling_avg) = self.rolling_avg {
+                SPIKE_FILTER_ALPHA * adc + (1. - SPIKE_FILTER_ALPHA) * rolling_avg
+            } else {
+                adc
+            });
+
+        self.rolling_avg_4
+            .replace(if let Some(rolling_avg_4) = self.rolling_avg_4 {
+                SPIKE_FILTER_ALPHA_5 * adc + (1. - SPIKE_FILTER_ALPHA_5) * rolling_avg_4
+            } else {
+                adc
+            });
+
+        self.prev_range.get_or_insert(range);
 
-        if range == 4 {
-            if state.consecutive_range_sample < 2 {
-                state.rolling_avg_4 = prev_rolling_avg_4;
-                state.rolling_avg = prev_rolling_avg;
+        if !matches!(self.prev_range, Some(r) if r == range) || self.after_spike > 0 {
+            if matches!(self.prev_range, Some(r) if r == range) {
+                self.consecutive_range_sample = 0;
+                self.after_spike = SPIKE_FILTER_SAMPLES;
+            } else {
+                self.consecutive_range_sample += 1;
             }
-            adc = state.rolling_avg_4.unwrap();
-        } else {
-            adc = state.rolling_avg.unwrap();
+
+            if range == 4 {
+                if self.consecutive_range_sample < 2 {
+                    self.rolling_avg_4 = prev_rolling_avg_4;
+                    self.rolling_avg = prev_rolling_avg;
+                }
+                adc = self.rolling_avg_4.unwrap();
+            } else {
+                adc = self.rolling_avg.unwrap();
+            }
+            self.after_spike -= 1;
+        }
+        self.prev_range = Some(range);
+        adc
+    }
+}
+
+/// Options for a measurement run. See [crate::Ppk2::start_measurement_with].
+#[derive(Debug, Clone)]
+pub struct MeasurementOptions {
+    pub(crate) sps: usize,
+    pub(crate) pins: LogicPortPins,
+    pub(crate) ir_drop: Option<IrDropModel>,
+    pub(crate) segment: String,
+    pub(crate) initial_sync: InitialSync,
+    pub(crate) max_gap: usize,
+    pub(crate) history: Duration,
+    pub(crate) overcurrent: Option<f32>,
+    pub(crate) glitch_filter: usize,
+    pub(crate) pin_vote: PinVote,
+    pub(crate) power_ramp: Option<PowerRamp>,
+    pub(crate) reset_signature: Option<ResetSignature>,
+    pub(crate) tags: Option<TagMask>,
+    pub(crate) strict: bool,
+    pub(crate) logic_only: bool,
+    pub(crate) current_only: bool,
+    pub(crate) non_finite: NonFinitePolicy,
+    pub(crate) spike_filter: bool,
+    pub(crate) window: WindowPolicy,
+    pub(crate) software_trigger: Option<SoftwareTrigger>,
+    pub(crate) max_hardware_averages: u16,
+    pub(crate) auto_zero: Option<AutoZero>,
+    pub(crate) qos: Option<QosRequirements>,
+    pub(crate) raw_dump: Option<PathBuf>,
+    pub(crate) suspend_threshold: Option<Duration>,
+    pub(crate) reconnect: Option<ReconnectPolicy>,
+    pub(crate) stop: Option<StopHandle>,
+}
+
+impl MeasurementOptions {
+    /// Create a new [MeasurementOptions], producing the passed amount of
+    /// combined measurements per second.
+    pub fn new(sps: SampleRate) -> Self {
+        Self {
+            sps: sps.get(),
+            pins: LogicPortPins::default(),
+            ir_drop: None,
+            segment: String::new(),
+            initial_sync: InitialSync::default(),
+            max_gap: 0,
+            history: Duration::ZERO,
+            overcurrent: None,
+            glitch_filter: 0,
+            pin_vote: PinVote::default(),
+            power_ramp: None,
+            reset_signature: None,
+            tags: None,
+            strict: false,
+            logic_only: false,
+            current_only: false,
+            non_finite: NonFinitePolicy::default(),
+            spike_filter: true,
+            window: WindowPolicy::default(),
+            software_trigger: None,
+            max_hardware_averages: 1,
+            auto_zero: None,
+            qos: None,
+            raw_dump: None,
+            suspend_threshold: Some(suspend::DEFAULT_THRESHOLD),
+            reconnect: None,
+            stop: None,
+        }
+    }
+
+    /// Only combine measurements for which the logic port state matches `pins`.
+    pub fn matching(mut self, pins: LogicPortPins) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Emulate the internal resistance of a battery by lowering the
+    /// source voltage according to the measured current.
+    pub fn ir_drop(mut self, model: IrDropModel) -> Self {
+        self.ir_drop = Some(model);
+        self
+    }
+
+    /// Set how the sample counter of the first samples is handled.
+    /// See [MeasurementAccumulator::initial_sync].
+    pub fn initial_sync(mut self, sync: InitialSync) -> Self {
+        self.initial_sync = sync;
+        self
+    }
+
+    /// Fill short gaps of missed samples with interpolated measurements.
+    /// See [MeasurementAccumulator::interpolate_gaps].
+    pub fn interpolate_gaps(mut self, max_gap: usize) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Keep the [Measurement]s of the passed duration in memory, so they can be
+    /// retrieved with [crate::MeasurementHandle::capture_last]. Disabled by default.
+    pub fn history(mut self, duration: Duration) -> Self {
+        self.history = duration;
+        self
+    }
+
+    /// Filter out logic port pulses shorter than `min_width` samples, before
+    /// the logic port state is used for anything else.
+    /// See [MeasurementAccumulator::glitch_filter].
+    pub fn glitch_filter(mut self, min_width: usize) -> Self {
+        self.glitch_filter = min_width;
+        self
+    }
+
+    /// Set the rule for deciding the logic port pin levels of combined
+    /// measurements. Defaults to [PinVote::Majority].
+    pub fn pin_vote(mut self, vote: PinVote) -> Self {
+        self.pin_vote = vote;
+        self
+    }
+
+    /// Emit a [MeasurementEvent::Overcurrent] when a combined measurement
+    /// exceeds the passed current in µA.
+    pub fn overcurrent(mut self, micro_amps: f32) -> Self {
+        self.overcurrent = Some(micro_amps);
+        self
+    }
+
+    /// Enable device power with the passed [PowerRamp] once measuring started.
+    /// The ramp is measured as a segment labeled [PowerRamp::SEGMENT_LABEL],
+    /// after which the segment set with [MeasurementOptions::segment] starts.
+    pub fn power_ramp(mut self, ramp: PowerRamp) -> Self {
+        self.power_ramp = Some(ramp);
+        self
+    }
+
+    /// Emit a [MeasurementEvent::SuspectedDutReset] whenever the current
+    /// matches the passed [ResetSignature].
+    pub fn detect_resets(mut self, signature: ResetSignature) -> Self {
+        self.reset_signature = Some(signature);
+        self
+    }
+
+    /// Interpret the logic port pins selected by the passed [TagMask] as a tag
+    /// set by firmware. Statistics per tag are available through
+    /// [crate::MeasurementHandle::tag_stats], and a [MeasurementEvent::TagChange]
+    /// is emitted whenever the tag changes.
+    pub fn tags(mut self, mask: TagMask) -> Self {
+        self.tags = Some(mask);
+        self
+    }
+
+    /// Terminate the measurement with [crate::Error::Protocol] on any unexpected
+    /// protocol condition, such as a skip in the sample counter, instead of
+    /// compensating for it. Meant for measurements where failing is preferable
+    /// over silent inaccuracy. Missed samples are never interpolated in strict
+    /// mode. Note that device metadata with unknown keys is always rejected.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Use the PPK2 as a logic recorder only, skipping current conversion to
+    /// save CPU time. The combined measurements carry the logic port pins
+    /// with a current of 0, and every change of the pins is sent to the event
+    /// subscribers as a [MeasurementEvent::LogicEdge]. Options that act on
+    /// the current, such as [MeasurementOptions::overcurrent], have no effect.
+    pub fn logic_only(mut self) -> Self {
+        self.logic_only = true;
+        self
+    }
+
+    /// Only measure the current, skipping logic port pin extraction, matching
+    /// and voting to save CPU time. The pins of the combined measurements are
+    /// all low. Options that act on the pins, such as [MeasurementOptions::matching]
+    /// and [MeasurementOptions::tags], have no effect.
+    pub fn current_only(mut self) -> Self {
+        self.current_only = true;
+        self
+    }
+
+    /// Set what to do with samples whose current is NaN or infinite, which
+    /// can happen with degenerate calibration coefficients.
+    /// Defaults to [NonFinitePolicy::Drop].
+    pub fn non_finite(mut self, policy: NonFinitePolicy) -> Self {
+        self.non_finite = policy;
+        self
+    }
+
+    /// Enable or disable the software spike filter. Enabled by default.
+    /// See [MeasurementAccumulator::spike_filter].
+    pub fn spike_filter(mut self, enabled: bool) -> Self {
+        self.spike_filter = enabled;
+        self
+    }
+
+    /// Set when device samples are combined. Defaults to [WindowPolicy::Sps].
+    pub fn window(mut self, policy: WindowPolicy) -> Self {
+        self.window = policy;
+        self
+    }
+
+    /// Capture the samples around every rising crossing of the threshold of
+    /// the passed [SoftwareTrigger]. The captures are sent to the subscribers
+    /// of [crate::MeasurementHandle::subscribe_captures].
+    /// Requires the `unstable` feature.
+    #[cfg(feature = "unstable")]
+    pub fn software_trigger(mut self, trigger: SoftwareTrigger) -> Self {
+        crate::unstable::warn("MeasurementOptions::software_trigger");
+        self.software_trigger = Some(trigger);
+        self
+    }
+
+    /// Let the device average up to `max` ADC samples into one sample before
+    /// sending it, and combine the remainder on the host, to reach the
+    /// requested number of measurements per second. See [crate::sampling] for
+    /// the trade-offs, and [crate::MeasurementHandle::sampling_plan] for the
+    /// chosen configuration. Disabled by default, which equals a `max` of 1.
+    pub fn hardware_averaging(mut self, max: u16) -> Self {
+        self.max_hardware_averages = max.max(1);
+        self
+    }
+
+    /// Periodically measure a reference window with device power disabled,
+    /// and subtract the measured offset from subsequent measurements to
+    /// compensate for slow drift. See [crate::autozero]. The corrections are
+    /// reported with [MeasurementEvent::AutoZero].
+    pub fn auto_zero(mut self, auto_zero: AutoZero) -> Self {
+        self.auto_zero = Some(auto_zero);
+        self
+    }
+
+    /// Fail [crate::MeasurementHandle::join] with [crate::Error::QosBelowMinimum]
+    /// if the delivery quality of the measurement falls below the passed
+    /// requirements. See [crate::MeasurementHandle::qos_report].
+    pub fn qos(mut self, requirements: QosRequirements) -> Self {
+        self.qos = Some(requirements);
+        self
+    }
+
+    /// Write the raw bytes read from the serial port to the file at `path`,
+    /// so the exact byte stream can be inspected and replayed when
+    /// diagnosing an issue. Decoding the dump requires the device metadata,
+    /// see [crate::Ppk2::metadata].
+    pub fn raw_dump(mut self, path: impl Into<PathBuf>) -> Self {
+        self.raw_dump = Some(path.into());
+        self
+    }
+
+    /// Set the minimum gap between the wall-clock time and the sample-clock
+    /// time of the samples read that is considered a host suspend, see
+    /// [crate::suspend]. Suspends are reported with [MeasurementEvent::HostSuspended],
+    /// after which the serial port is reopened if the suspend closed it.
+    /// Defaults to [suspend::DEFAULT_THRESHOLD]. [None] disables detection.
+    pub fn suspend_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.suspend_threshold = threshold;
+        self
+    }
+
+    /// Reopen the serial port and continue measuring when the device is
+    /// disconnected, instead of ending the measurement, see [crate::reconnect].
+    /// Disconnects are reported with [MeasurementEvent::Reconnected].
+    /// Disabled by default.
+    pub fn reconnect(mut self, policy: Option<ReconnectPolicy>) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Stop the measurement when the passed [StopHandle] is stopped, instead
+    /// of with a new one. If the handle was already stopped, the measurement
+    /// stops right after starting. The handle is also returned by
+    /// [crate::MeasurementHandle::stop_handle].
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Set the label of the first segment. Empty by default.
+    /// See [crate::MeasurementHandle::next_segment].
+    pub fn segment(mut self, label: impl Into<String>) -> Self {
+        self.segment = label.into();
+        self
+    }
+}
+
+/// Event emitted by the measurement pipeline.
+/// See [crate::MeasurementHandle::subscribe].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum MeasurementEvent {
+    /// A labeled segment was closed.
+    SegmentEnd(SegmentSummary),
+    /// A combined measurement exceeded the limit set with
+    /// [MeasurementOptions::overcurrent]. Emitted once when the
+    /// limit is crossed, and again only after the current dropped below it.
+    Overcurrent {
+        /// Index of the last sample of the combined measurement, counted from
+        /// the start of the measurement and including missed samples.
+        sample: usize,
+        /// Average current of the combined measurement in µA.
+        micro_amps: f32,
+    },
+    /// The current matched the [ResetSignature] set with
+    /// [MeasurementOptions::detect_resets], suggesting the device
+    /// under test was reset.
+    SuspectedDutReset {
+        /// Index of the first sample of the current dip, counted from the
+        /// start of the measurement and including missed samples.
+        sample: usize,
+        /// Time of the first sample of the dip since the start of the measurement.
+        time: Duration,
+    },
+    /// The tag encoded on the logic port pins changed.
+    /// See [MeasurementOptions::tags].
+    TagChange {
+        /// Index of the first sample with the new tag, counted from the
+        /// start of the measurement and including missed samples.
+        sample: usize,
+        /// The previous tag
+        from: u8,
+        /// The new tag
+        to: u8,
+    },
+    /// The logic port pins changed. Only emitted in logic-only mode.
+    /// See [MeasurementOptions::logic_only].
+    LogicEdge {
+        /// Index of the first sample with the new pin levels, counted from
+        /// the start of the measurement and including missed samples.
+        sample: usize,
+        /// The previous pin levels
+        from: LogicPortPins,
+        /// The new pin levels
+        to: LogicPortPins,
+    },
+    /// A reference window ended, and the offset correction was updated.
+    /// See [MeasurementOptions::auto_zero].
+    AutoZero(ZeroCorrection),
+    /// The host was suspended, leaving a gap in the measurement.
+    /// See [MeasurementOptions::suspend_threshold].
+    HostSuspended(Suspension),
+    /// The serial port was reopened after a USB disconnect, leaving a gap
+    /// in the measurement. See [MeasurementOptions::reconnect].
+    Reconnected(Disconnection),
+    /// The source voltage was lowered by the internal resistance emulation.
+    /// See [MeasurementOptions::ir_drop].
+    IrDrop(IrDropUpdate),
+    /// The measurement parsing pipeline ended. This is the last event of a
+    /// measurement, sent after the last combined measurement.
+    Ended(MeasurementEnd),
+}
+
+/// How a measurement ended, see [MeasurementEvent::Ended].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeasurementEnd {
+    /// The measurement was stopped through its [crate::StopHandle].
+    Stopped,
+    /// The measurement ended because of an error, such as a failed read of
+    /// the serial port. Contains the error message. The error itself is
+    /// returned by [crate::MeasurementHandle::join].
+    Failed(String),
+}
+
+/// Summary of a labeled segment of a measurement.
+#[derive(Debug, Clone)]
+pub struct SegmentSummary {
+    /// The segment label
+    pub label: String,
+    /// Index of the first sample in the segment, counted from the start of the
+    /// measurement and including missed samples.
+    pub start: usize,
+    /// Number of samples in the segment, excluding missed samples.
+    pub samples: usize,
+    /// Number of samples missed within the segment.
+    pub missed: usize,
+    /// Average current in µA, or [None] if the segment contains no samples.
+    pub avg_micro_amps: Option<f32>,
+}
+
+/// Summary of a measurement of a fixed duration, as returned by
+/// [crate::Ppk2::measure_for].
+#[derive(Debug, Clone)]
+pub struct CaptureSummary {
+    /// Time the device was measuring.
+    pub duration: Duration,
+    /// Number of combined measurements received.
+    pub measurements: u64,
+    /// Average of the combined measurements in µA, or [None] if none were received.
+    pub avg_micro_amps: Option<f64>,
+    /// Lowest combined measurement in µA, or [None] if none were received.
+    pub min_micro_amps: Option<f32>,
+    /// Highest combined measurement in µA, or [None] if none were received.
+    pub max_micro_amps: Option<f32>,
+    /// Charge drawn, integrated over the device samples.
+    pub charge: Charge,
+    /// Number of device samples that were missed.
+    pub missed: u64,
+}
+
+/// Accumulates [Measurement]s into a [SegmentSummary]
+pub(crate) struct SegmentAccumulator {
+    label: String,
+    start: usize,
+    samples: usize,
+    missed: usize,
+    sum: f32,
+}
+
+impl SegmentAccumulator {
+    pub(crate) fn new(label: String, start: usize) -> Self {
+        Self {
+            label,
+            start,
+            samples: 0,
+            missed: 0,
+            sum: 0.,
+        }
+    }
+
+    pub(crate) fn add(&mut self, measurement: &Measurement) {
+        if measurement.synthetic {
+            return;
+        }
+        self.samples += 1;
+        self.sum += measurement.micro_amps;
+    }
+
+    pub(crate) fn add_missed(&mut self, missed: usize) {
+        self.missed += missed;
+    }
+
+    pub(crate) fn finish(self) -> SegmentSummary {
+        SegmentSummary {
+            avg_micro_amps: (self.samples > 0).then(|| self.sum / self.samples as f32),
+            label: self.label,
+            start: self.start,
+            samples: self.samples,
+            missed: self.missed,
+        }
+    }
+}
+
+/// Set of subscribers to items sent by the measurement worker
+#[derive(Clone)]
+pub(crate) struct Subscribers<T> {
+    senders: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+impl<T> Default for Subscribers<T> {
+    fn default() -> Self {
+        Self {
+            senders: Arc::default(),
         }
-        state.after_spike -= 1;
     }
-    state.prev_range = Some(range);
-    adc
 }
 
+impl<T: Clone> Subscribers<T> {
+    pub(crate) fn subscribe(&self) -> Receiver<T> {
+        let (tx, rx) = mpsc::channel();
+        self.senders.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Whether anyone is subscribed, to skip work that has no receiver.
+    pub(crate) fn has_subscribers(&self) -> bool {
+        !self.senders.lock().unwrap().is_empty()
+    }
+
+    /// Send an item to all subscribers, dropping the ones that hung up.
+    pub(crate) fn emit(&self, item: T) {
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(item.clone()).is_ok());
+    }
+}
+
+/// Set of subscribers to [MeasurementEvent]s
+pub(crate) type EventSubscribers = Subscribers<MeasurementEvent>;
 
 /// Indicates whether a set of [Measurement]s matched
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MeasurementMatch {
     /// A set of [Measurement]s did match
     Match(Measurement),
@@ -175,29 +1053,102 @@ pub enum MeasurementMatch {
     NoMatch,
 }
 
+/// Check whether the levels of `pins` match `matching_pins`.
+fn pins_match(pins: LogicPortPins, matching_pins: LogicPortPins) -> bool {
+    pins.inner()
+        .iter()
+        .enumerate()
+        .all(|(i, l)| l.matches(matching_pins.inner()[i]))
+}
+
+/// Rule for deciding whether a logic port pin is high in a combined [Measurement],
+/// based on the fraction of the combined [Measurement]s in which the pin was high.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PinVote {
+    /// High if the pin was high in any of the measurements.
+    Any,
+    /// High if the pin was high in more than half of the measurements.
+    #[default]
+    Majority,
+    /// High if the pin was high in all of the measurements.
+    All,
+    /// High if the pin was high in at least the passed fraction of the measurements.
+    Fraction(f32),
+}
+
+impl PinVote {
+    /// Decide whether a pin is high, given the number of measurements in which the
+    /// pin was high out of the total number of measurements.
+    pub fn is_high(&self, high_count: usize, count: usize) -> bool {
+        match self {
+            PinVote::Any => high_count > 0,
+            PinVote::Majority => high_count > count / 2,
+            PinVote::All => count > 0 && high_count == count,
+            PinVote::Fraction(f) => count > 0 && high_count as f32 >= f * count as f32,
+        }
+    }
+}
+
 /// Extension trait for VecDeque<Measurement>
 pub trait MeasurementIterExt {
     /// Combine items into a single [MeasurementMatch::Match], if there are items.
     /// If there are none, [MeasurementMatch::NoMatch] is returned.
     /// Set combined logic port pin high if and only if more than half
-    /// of the measurements indicate the pin was high
+    /// of the measurements indicate the pin was high. `missed` is ignored,
+    /// as the average is taken over the received measurements only.
     fn combine(self, missed: usize) -> MeasurementMatch;
 
     /// Combine items with matching logic port state into a single [MeasurementMatch::Match],
     /// if there are items. If there are none, [MeasurementMatch::NoMatch] is returned.
     /// Set combined logic port pin high if and only if more than half
-    /// of the measurements indicate the pin was high
+    /// of the measurements indicate the pin was high. `missed` is ignored,
+    /// as for [MeasurementIterExt::combine].
     fn combine_matching(self, missed: usize, matching_pins: LogicPortPins) -> MeasurementMatch;
+
+    /// Like [MeasurementIterExt::combine], but with the passed [PinVote]
+    /// deciding the combined logic port pin levels.
+    fn combine_with(self, vote: PinVote) -> MeasurementMatch;
+
+    /// Like [MeasurementIterExt::combine_matching], but with the passed [PinVote]
+    /// deciding the combined logic port pin levels.
+    fn combine_matching_with(self, matching_pins: LogicPortPins, vote: PinVote)
+        -> MeasurementMatch;
+
+    /// Combine the currents of the items into a single [MeasurementMatch::Match],
+    /// ignoring the logic port pins, which are all low in the result.
+    /// If there are no items, [MeasurementMatch::NoMatch] is returned.
+    fn combine_current(self) -> MeasurementMatch;
+
+    /// Like [MeasurementIterExt::combine_matching_with], but also combines the
+    /// items that don't match `matching_pins`. Returns the combined matching
+    /// and the combined non-matching items, in that order.
+    fn combine_split(
+        self,
+        matching_pins: LogicPortPins,
+        vote: PinVote,
+    ) -> (MeasurementMatch, MeasurementMatch);
 }
 
 impl<I: Iterator<Item = Measurement>> MeasurementIterExt for I {
-    fn combine(self, missed: usize) -> MeasurementMatch {
+    fn combine(self, _missed: usize) -> MeasurementMatch {
+        self.combine_with(PinVote::Majority)
+    }
+
+    fn combine_matching(self, _missed: usize, matching_pins: LogicPortPins) -> MeasurementMatch {
+        self.combine_matching_with(matching_pins, PinVote::Majority)
+    }
+
+    fn combine_with(self, vote: PinVote) -> MeasurementMatch {
         let mut pin_high_count = [0usize; 8];
         let mut count = 0;
         let mut sum = 0f32;
-        self.for_each(|m| {
+        let mut envelope = None;
+        let (mut range, mut adc, mut index, mut time) = (0, 0, 0, Duration::ZERO);
+        self.filter(|m| !m.synthetic).for_each(|m| {
             count += 1;
             sum += m.micro_amps;
+            envelope = Envelope::include(envelope, &m);
+            (range, adc, index, time) = (m.range, m.adc, m.index, m.time);
             m.pins
                 .inner()
                 .iter()
@@ -211,118 +1162,647 @@ impl<I: Iterator<Item = Measurement>> MeasurementIterExt for I {
             return MeasurementMatch::NoMatch;
         }
 
-        // Set combined pin high if and only if more than half
-        // of the measurements indicate the pin was high
         let mut pins = [false; 8];
         pin_high_count
             .into_iter()
             .enumerate()
-            .filter(|(_, p)| *p > count / 2)
+            .filter(|(_, p)| vote.is_high(*p, count))
             .for_each(|(i, _)| pins[i] = true);
-        let avg = sum / (count - missed) as f32;
+        // Missed samples are not part of the sum, so they
+        // don't count towards the average either
+        let avg = sum / count as f32;
 
         MeasurementMatch::Match(Measurement {
             micro_amps: avg,
             pins: pins.into(),
+            synthetic: false,
+            envelope,
+            range,
+            adc,
+            index,
+            time,
         })
     }
 
-    fn combine_matching(self, missed: usize, matching_pins: LogicPortPins) -> MeasurementMatch {
-        let iter = self.filter(|m| {
-            m.pins
-                .inner()
-                .iter()
-                .enumerate()
-                .all(|(i, l)| l.matches(matching_pins.inner()[i]))
-        });
-        iter.combine(missed)
+    fn combine_matching_with(
+        self,
+        matching_pins: LogicPortPins,
+        vote: PinVote,
+    ) -> MeasurementMatch {
+        let iter = self.filter(|m| pins_match(m.pins, matching_pins));
+        iter.combine_with(vote)
+    }
+
+    fn combine_split(
+        self,
+        matching_pins: LogicPortPins,
+        vote: PinVote,
+    ) -> (MeasurementMatch, MeasurementMatch) {
+        let (matching, other): (Vec<_>, Vec<_>) =
+            self.partition(|m| pins_match(m.pins, matching_pins));
+        (
+            matching.into_iter().combine_with(vote),
+            other.into_iter().combine_with(vote),
+        )
+    }
+
+    fn combine_current(self) -> MeasurementMatch {
+        let (count, sum, envelope, last) = self.filter(|m| !m.synthetic).fold(
+            (0usize, 0f32, None, (0, 0, 0, Duration::ZERO)),
+            |(count, sum, envelope, _), m| {
+                (
+                    count + 1,
+                    sum + m.micro_amps,
+                    Envelope::include(envelope, &m),
+                    (m.range, m.adc, m.index, m.time),
+                )
+            },
+        );
+        if count == 0 {
+            return MeasurementMatch::NoMatch;
+        }
+        MeasurementMatch::Match(Measurement {
+            micro_amps: sum / count as f32,
+            pins: LogicPortPins::default(),
+            synthetic: false,
+            envelope,
+            range: last.0,
+            adc: last.1,
+            index: last.2,
+            time: last.3,
+        })
     }
 }
 
-const fn generate_mask(bits: u32, pos: u32) -> u32 {
-    (2u32.pow(bits as u32) - 1) << pos
+/// Statistics on the raw bytes read from the serial port during a measurement.
+/// See [crate::MeasurementHandle::byte_stats].
+#[derive(Debug, Clone, Copy)]
+pub struct ByteStats {
+    /// Total number of bytes read since the measurement started.
+    pub bytes: u64,
+    /// Time since the measurement started.
+    pub elapsed: Duration,
 }
 
-macro_rules! masked_value {
-    ($name:ident, $bits:literal, $pos:literal) => {
-        fn $name(raw: u32) -> u32 {
-            (raw & generate_mask($bits, $pos)) >> $pos
+impl ByteStats {
+    /// Number of bytes per second the device produces at its nominal sample rate.
+    pub const EXPECTED_BYTES_PER_SECOND: f64 = (SampleClock::NOMINAL_RATE * SAMPLE_SIZE) as f64;
+
+    /// Average number of bytes read per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Ratio of the average number of bytes read per second to the expected
+    /// number of bytes per second. Significantly lower than 1 indicates that
+    /// the device, USB connection or serial driver don't keep up.
+    pub fn ratio(&self) -> f64 {
+        self.bytes_per_second() / Self::EXPECTED_BYTES_PER_SECOND
+    }
+}
+
+/// Runtime counters of the measurement parsing pipeline, for diagnosing
+/// throughput problems. See [crate::MeasurementHandle::metrics].
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineMetrics {
+    /// Number of bytes read from the serial port.
+    pub bytes_read: u64,
+    /// Number of bytes passed from the reader thread to the parser.
+    pub bytes_parsed: u64,
+    /// Number of sample frames parsed.
+    pub frames_parsed: u64,
+    /// Number of device samples, including missed ones.
+    pub samples: u64,
+    /// Number of device samples missed, in the windows emitted so far.
+    pub missed_samples: u64,
+    /// Number of windows combined into a measurement and emitted.
+    pub windows: u64,
+    /// Number of combined measurements that couldn't be delivered, because
+    /// the receiver was dropped, or a full channel was not drained before
+    /// stopping. See [crate::Ppk2Builder::channel_capacity].
+    pub send_failures: u64,
+    /// Time since the measurement started.
+    pub elapsed: Duration,
+}
+
+impl PipelineMetrics {
+    /// Number of bytes read, but not parsed yet. A growing backlog
+    /// means the parser doesn't keep up with the device.
+    pub fn backlog_bytes(&self) -> u64 {
+        self.bytes_read.saturating_sub(self.bytes_parsed)
+    }
+}
+
+/// Determines when the buffered device samples are combined
+/// into a single measurement.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowPolicy {
+    /// Aim for the number of combined measurements per second passed to
+    /// [MeasurementOptions::new], adapting to the rate at which samples
+    /// actually arrive. Windows close early if no data arrives in time.
+    #[default]
+    Sps,
+    /// Close a window every time the passed duration of device time has
+    /// passed, as derived from the sample index, including missed samples.
+    /// This gives evenly spaced outputs, regardless of how data arrives.
+    Duration(Duration),
+}
+
+/// Decides when the measurement worker combines the buffered
+/// [Measurement]s, according to a [WindowPolicy].
+/// With [WindowPolicy::Sps], a chunk is combined when either the expected
+/// number of samples has been received, or when the time per chunk has passed.
+/// The expected number of samples follows the rate at which samples
+/// actually arrive, so output keeps its cadence if the device under-delivers.
+pub(crate) struct ChunkTimer {
+    sps: usize,
+    period: Duration,
+    chunk_len: usize,
+    start: Instant,
+    last_flush: Instant,
+    received: u64,
+    /// Window length in samples, with [WindowPolicy::Duration]
+    window: Option<usize>,
+    /// Sample indices advanced since the start of the window
+    index: usize,
+    /// Whether samples arrive in real time, so windows can be
+    /// closed on time and the chunk length adapted to the arrival rate
+    realtime: bool,
+}
+
+impl ChunkTimer {
+    /// Create a [ChunkTimer] for a device sampling at the rate of `clock`.
+    pub(crate) fn new(sps: usize, policy: WindowPolicy, clock: SampleClock) -> Self {
+        let now = Instant::now();
+        let sps = sps.max(1);
+        let window = match policy {
+            WindowPolicy::Sps => None,
+            WindowPolicy::Duration(d) => Some(clock.samples_in(d).max(1)),
+        };
+        Self {
+            sps,
+            period: Duration::from_secs_f64(1. / sps as f64),
+            chunk_len: clock.chunk_len(sps),
+            start: now,
+            last_flush: now,
+            received: 0,
+            window,
+            index: 0,
+            realtime: true,
         }
-    };
+    }
+
+    /// Close windows based on the number of samples only, for
+    /// samples that don't arrive in real time, like when replaying.
+    pub(crate) fn offline(mut self) -> Self {
+        self.realtime = false;
+        self
+    }
+
+    /// Register newly received samples
+    pub(crate) fn received(&mut self, n: usize) {
+        self.received += n as u64;
+    }
+
+    /// Register that the sample index advanced by `n`, including missed samples.
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.index += n;
+    }
+
+    /// Check whether a chunk of `len` samples should be combined now.
+    pub(crate) fn should_flush(&self, len: usize) -> bool {
+        match self.window {
+            Some(window) => self.index >= window,
+            None => {
+                len >= self.chunk_len
+                    || (self.realtime && len > 0 && self.last_flush.elapsed() >= self.period)
+            }
+        }
+    }
+
+    /// Register that a chunk was combined, adapting the expected
+    /// chunk length to the measured arrival rate.
+    pub(crate) fn flushed(&mut self) {
+        if let Some(window) = self.window {
+            // Keep the phase of the windows after a gap
+            self.index %= window;
+            return;
+        }
+        if !self.realtime {
+            return;
+        }
+        self.last_flush = Instant::now();
+        let elapsed = self.start.elapsed();
+        // Don't adapt on too little data
+        if elapsed >= Duration::from_millis(100) {
+            self.chunk_len = SampleClock::measured(self.received, elapsed).chunk_len(self.sps);
+        }
+    }
+}
+
+/// Ring buffer of the most recent [Measurement]s, shared between
+/// the measurement worker and its handle
+#[derive(Clone)]
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+pub(crate) struct History {
+    clock: SampleClock,
+    capacity: usize,
+    buf: Arc<Mutex<VecDeque<Measurement>>>,
+}
+
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+impl History {
+    pub(crate) fn new(duration: Duration, clock: SampleClock) -> Self {
+        let capacity = clock.samples_in(duration);
+        Self {
+            clock,
+            capacity,
+            buf: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub(crate) fn extend<'m>(&self, measurements: impl Iterator<Item = &'m Measurement>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut buf = self.buf.lock().unwrap();
+        for m in measurements {
+            if buf.len() == self.capacity {
+                buf.pop_front();
+            }
+            buf.push_back(m.clone());
+        }
+    }
+
+    pub(crate) fn last(&self, duration: Duration) -> Vec<Measurement> {
+        let n = self.clock.samples_in(duration);
+        let buf = self.buf.lock().unwrap();
+        buf.range(buf.len().saturating_sub(n)..).cloned().collect()
+    }
+}
+
+/// Counters shared between the measurement worker and its handle
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+pub(crate) struct PipelineCounters {
+    start: Instant,
+    bytes: AtomicU64,
+    samples: AtomicU64,
+    missed: AtomicU64,
+    /// Number of bytes passed to the parser
+    parsed_bytes: AtomicU64,
+    /// Number of combined measurements that couldn't be delivered
+    send_failures: AtomicU64,
+    /// Number of device samples per combined measurement
+    chunks: Mutex<Stats>,
 }
 
-masked_value!(get_adc, 14, 0);
-masked_value!(get_range, 3, 14);
-masked_value!(get_counter, 6, 18);
-masked_value!(get_logic, 8, 24);
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+impl PipelineCounters {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            bytes: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            parsed_bytes: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            chunks: Mutex::new(Stats::new()),
+        }
+    }
+
+    pub(crate) fn add_bytes(&self, n: usize) {
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_parsed_bytes(&self, n: usize) {
+        self.parsed_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_send_failure(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Register received samples, including missed ones.
+    pub(crate) fn add_samples(&self, n: usize) {
+        self.samples.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Register a combined measurement of `len` device samples,
+    /// of which `missed` were missed.
+    pub(crate) fn add_chunk(&self, len: usize, missed: usize) {
+        self.missed.fetch_add(missed as u64, Ordering::Relaxed);
+        self.chunks.lock().unwrap().add(len as f32);
+    }
+
+    pub(crate) fn samples(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub(crate) fn qos_report(&self, requested_sps: f64) -> QosReport {
+        let chunks = self.chunks.lock().unwrap();
+        QosReport {
+            requested_sps,
+            chunks: chunks.count(),
+            mean_chunk_len: chunks.mean().unwrap_or_default(),
+            chunk_jitter: chunks.std_dev().unwrap_or_default(),
+            samples: self.samples(),
+            missed_samples: self.missed.load(Ordering::Relaxed),
+            elapsed: self.elapsed(),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> PipelineMetrics {
+        let parsed_bytes = self.parsed_bytes.load(Ordering::Relaxed);
+        PipelineMetrics {
+            bytes_read: self.bytes.load(Ordering::Relaxed),
+            bytes_parsed: parsed_bytes,
+            frames_parsed: parsed_bytes / SAMPLE_SIZE as u64,
+            samples: self.samples(),
+            missed_samples: self.missed.load(Ordering::Relaxed),
+            windows: self.chunks.lock().unwrap().count(),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            elapsed: self.elapsed(),
+        }
+    }
+
+    pub(crate) fn byte_stats(&self) -> ByteStats {
+        ByteStats {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
     use crate::{
-        measurement::{get_adc_result, AccumulatorState},
-        types::Metadata,
+        clock::SampleClock,
+        measurement::{
+            ChunkTimer, Envelope, GlitchFilter, InitialSync, Measurement, MeasurementAccumulator,
+            MeasurementIterExt, MeasurementMatch, NonFinitePolicy, PinVote, ProtocolViolation,
+            SpikeFilter, WindowPolicy,
+        },
+        types::{Level, LogicPortPins, Metadata},
     };
 
+    fn raw_samples(counters: &[u8]) -> Vec<u8> {
+        counters
+            .iter()
+            .flat_map(|&c| (100u32 | (c as u32) << 18).to_le_bytes())
+            .collect()
+    }
+
     #[test]
-    pub fn test_get_adc_result() {
-        let raw_metadata = r#"Calibrated: 0
-R0: 1003.3506
-R1: 101.5865
-R2: 10.3027
-R3: 0.9636
-R4: 0.0564
-GS0: 0.0000
-GS1: 112.7890
-GS2: 18.0115
-GS3: 2.4217
-GS4: 0.0729
-GI0: 1.0000
-GI1: 0.9695
-GI2: 0.9609
-GI3: 0.9519
-GI4: 0.9582
-O0: 112.9420
-O1: 75.4627
-O2: 64.6020
-O3: 50.4983
-O4: 87.2177
-VDD: 3741
-HW: 9173
-mode: 2
-S0: 0.000000048
-S1: 0.000000596
-S2: 0.000005281
-S3: 0.000062577
-S4: 0.002940743
-I0: -0.000000104
-I1: -0.000001443
-I2: 0.000036439
-I3: -0.000374119
-I4: -0.009388455
-UG0: 1.00
-UG1: 1.00
-UG2: 1.00
-UG3: 1.00
-UG4: 1.00
-IA: 56
-END
-"#;
-        let metadata =
-            Metadata::from_bytes(raw_metadata.as_bytes()).expect("Error parsing metadata");
-
-        let mut state = AccumulatorState {
-            rolling_avg_4: Some(9.478947833765696e-8),
-            rolling_avg: Some(1.0589385070753649e-7),
-            prev_range: Some(0),
-            after_spike: 0,
-            consecutive_range_sample: 0,
-            expected_counter: Some(62),
+    pub fn test_counter_wrap() {
+        let mut acc = MeasurementAccumulator::new(Metadata::default());
+        let mut buf = VecDeque::new();
+        let missed = acc.feed_into(&raw_samples(&[61, 62, 63, 0, 1]), &mut buf);
+        assert_eq!(missed, 0);
+        assert_eq!(buf.len(), 5);
+
+        // Gap across the wrap: 63 and 0 are missing
+        let missed = acc.feed_into(&raw_samples(&[2, 3, 4, 5, 6, 7, 8, 61, 62, 1]), &mut buf);
+        assert_eq!(missed, 52 + 2);
+        assert_eq!(buf.len(), 15);
+
+        // After a restart, half a sample is dropped and the counter isn't
+        // expected to continue
+        acc.feed_into(&raw_samples(&[2])[..2], &mut buf);
+        acc.restart(None);
+        let missed = acc.feed_into(&raw_samples(&[40, 41]), &mut buf);
+        assert_eq!(missed, 0);
+        assert_eq!(buf.len(), 17);
+        assert_eq!(buf.back().unwrap().index, 70);
+    }
+
+    #[test]
+    pub fn test_strict() {
+        let mut acc = MeasurementAccumulator::new(Metadata::default());
+        let mut buf = VecDeque::new();
+        let res = acc.try_feed_into(&raw_samples(&[10, 11, 13, 14]), &mut buf);
+        assert_eq!(
+            res,
+            Err(ProtocolViolation::CounterGap {
+                sample: 2,
+                expected: 12,
+                counter: 13
+            })
+        );
+        assert_eq!(buf.len(), 2);
+    }
+
+    #[test]
+    pub fn test_interpolate_gaps() {
+        let mut acc = MeasurementAccumulator::new(Metadata::default()).interpolate_gaps(2);
+        let mut buf = VecDeque::new();
+        let missed = acc.feed_into(&raw_samples(&[0, 1, 4, 5, 9]), &mut buf);
+        assert_eq!(missed, 2 + 3);
+        // Gap of 2 is filled, gap of 3 is not
+        assert_eq!(buf.len(), 5 + 2);
+        let synthetic: Vec<_> = buf.iter().map(|m| m.synthetic).collect();
+        assert_eq!(synthetic, [false, false, true, true, false, false, false]);
+        let indices: Vec<_> = buf.iter().map(|m| m.index).collect();
+        assert_eq!(indices, [0, 1, 2, 3, 4, 5, 9]);
+        assert_eq!(buf[6].time, Duration::from_micros(90));
+    }
+
+    #[test]
+    pub fn test_combine_missed() {
+        let mut acc = MeasurementAccumulator::new(Metadata::default());
+        let mut buf = VecDeque::new();
+        let missed = acc.feed_into(&raw_samples(&[0, 1, 2, 5, 6, 7]), &mut buf);
+        assert_eq!((missed, buf.len()), (2, 6));
+        let expected = buf.iter().map(|m| m.micro_amps).sum::<f32>() / buf.len() as f32;
+        // Missed samples aren't part of the sum, so they don't count towards the average
+        let MeasurementMatch::Match(m) = buf.drain(..).combine(missed) else {
+            panic!("Expected a matching measurement");
         };
-        let range: usize = 0;
-        let adc_val: u32 = 108;
-        let adc_result = get_adc_result(&metadata, &mut state, range, adc_val) * 10f32.powi(6);
+        assert_eq!(m.micro_amps, expected);
+    }
+
+    #[test]
+    pub fn test_range_and_adc() {
+        let mut acc = MeasurementAccumulator::new(Metadata::default()).interpolate_gaps(1);
+        let mut buf = VecDeque::new();
+        let raw: Vec<u8> = [(0x1234u32, 3u32, 0u32), (0x0042, 1, 2), (0x0042, 6, 3)]
+            .iter()
+            .flat_map(|&(adc, range, counter)| (adc | range << 14 | counter << 18).to_le_bytes())
+            .collect();
+        acc.feed_into(&raw, &mut buf);
+        let ranges: Vec<_> = buf.iter().map(|m| (m.range, m.adc)).collect();
+        // Range 6 is out of spec, and reported as the range it is converted in
+        assert_eq!(ranges, [(3, 0x1234), (3, 0x1234), (1, 0x0042), (4, 0x0042)]);
+
+        let MeasurementMatch::Match(m) = buf.into_iter().combine(0) else {
+            panic!("Expected a matching measurement");
+        };
+        assert_eq!((m.range, m.adc), (4, 0x0042));
+    }
+
+    #[test]
+    pub fn test_glitch_filter() {
+        let mut filter = GlitchFilter::new(3);
+        let input = [0, 1, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0];
+        let output: Vec<u8> = input.iter().map(|&l| filter.apply(l)).collect();
+        assert_eq!(output, [0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    pub fn test_initial_sync_skip() {
+        let mut acc =
+            MeasurementAccumulator::new(Metadata::default()).initial_sync(InitialSync::Skip(2));
+        let mut buf = VecDeque::new();
+        // The first two samples are discarded, and 10 is the baseline
+        let missed = acc.feed_into(&raw_samples(&[40, 3, 10, 11]), &mut buf);
+        assert_eq!(missed, 0);
+        assert_eq!(buf.len(), 2);
+    }
 
-        // JS result: 0.021454880761611544
-        assert!((adc_result - 0.021454880761611544).abs() < f32::EPSILON)
+    #[test]
+    pub fn test_logic_only() {
+        let raw: Vec<u8> = [(0u32, 0x01u32), (1, 0x81)]
+            .iter()
+            .flat_map(|&(c, logic)| (100 | c << 18 | logic << 24).to_le_bytes())
+            .collect();
+        let mut acc = MeasurementAccumulator::new(Metadata::default()).logic_only(true);
+        let mut buf = VecDeque::new();
+        acc.feed_into(&raw, &mut buf);
+        let pins: Vec<u8> = buf.iter().map(|m| m.pins.into()).collect();
+        assert_eq!(pins, [0x01, 0x81]);
+        assert!(buf.iter().all(|m| m.micro_amps == 0.));
+    }
+
+    #[test]
+    pub fn test_current_only() {
+        let raw: Vec<u8> = (0..4u32)
+            .flat_map(|c| (100 | c << 18 | 0xFF << 24).to_le_bytes())
+            .collect();
+        let mut acc = MeasurementAccumulator::new(Metadata::default()).current_only(true);
+        let mut buf = VecDeque::new();
+        acc.feed_into(&raw, &mut buf);
+        assert!(buf.iter().all(|m| u8::from(m.pins) == 0));
+        let MeasurementMatch::Match(m) = buf.drain(..).combine_current() else {
+            panic!("Expected a match");
+        };
+        assert_eq!(u8::from(m.pins), 0);
+    }
+
+    #[test]
+    pub fn test_non_finite() {
+        let mut metadata = Metadata::default();
+        // A zero shunt resistance makes range 0 produce infinite currents
+        metadata.modifiers.r[0] = 0.;
+        let feed = |policy| {
+            let mut acc = MeasurementAccumulator::new(metadata.clone()).non_finite(policy);
+            let mut buf = VecDeque::new();
+            let missed = acc.feed_into(&raw_samples(&[0, 1, 2]), &mut buf);
+            assert_eq!(acc.non_finite_samples(), 3);
+            (missed, buf)
+        };
+
+        let (missed, buf) = feed(NonFinitePolicy::Drop);
+        assert_eq!((missed, buf.len()), (3, 0));
+        let (missed, buf) = feed(NonFinitePolicy::Clamp);
+        assert_eq!((missed, buf.len()), (0, 3));
+        assert!(buf.iter().all(|m| m.micro_amps.is_finite()));
+    }
+
+    #[test]
+    pub fn test_duration_window() {
+        let mut timer = ChunkTimer::new(
+            1,
+            WindowPolicy::Duration(Duration::from_millis(1)),
+            SampleClock::nominal(),
+        );
+        // 100 samples per window at the nominal rate
+        timer.advance(99);
+        assert!(!timer.should_flush(99));
+        timer.advance(1);
+        assert!(timer.should_flush(100));
+        timer.flushed();
+        // A gap of missed samples keeps the window phase
+        timer.advance(250);
+        assert!(timer.should_flush(10));
+        timer.flushed();
+        timer.advance(49);
+        assert!(!timer.should_flush(49));
+        timer.advance(1);
+        assert!(timer.should_flush(50));
+
+        // Windows and chunks follow the native rate of the device
+        let clock = SampleClock::with_rate(50_000.);
+        let mut timer = ChunkTimer::new(1, WindowPolicy::Duration(Duration::from_millis(1)), clock);
+        timer.advance(50);
+        assert!(timer.should_flush(50));
+        let timer = ChunkTimer::new(10, WindowPolicy::Sps, clock);
+        assert!(!timer.should_flush(4999));
+        assert!(timer.should_flush(5000));
+    }
+
+    #[test]
+    pub fn test_combine_split() {
+        let measurement = |micro_amps: f32, pin0: bool| Measurement {
+            micro_amps,
+            pins: [pin0, false, false, false, false, false, false, false].into(),
+            synthetic: false,
+            envelope: None,
+            ..Default::default()
+        };
+        let mut levels = [Level::Either; 8];
+        levels[0] = Level::High;
+        let (matching, other) = [
+            measurement(10., true),
+            measurement(20., true),
+            measurement(1., false),
+        ]
+        .into_iter()
+        .combine_split(LogicPortPins::with_levels(levels), PinVote::Majority);
+        let (MeasurementMatch::Match(matching), MeasurementMatch::Match(other)) = (matching, other)
+        else {
+            panic!("Expected matches");
+        };
+        assert_eq!((matching.micro_amps, other.micro_amps), (15., 1.));
+        assert_eq!(matching.envelope, Some(Envelope { min: 10., max: 20. }));
+
+        // Combining combined measurements widens their envelopes
+        let MeasurementMatch::Match(combined) = [matching, other].into_iter().combine_current()
+        else {
+            panic!("Expected a match");
+        };
+        assert_eq!(combined.envelope, Some(Envelope { min: 1., max: 20. }));
+    }
+
+    #[test]
+    pub fn test_spike_filter() {
+        let mut filter = SpikeFilter::new();
+        let output: Vec<f32> = [(1., 0), (1., 0), (5., 1), (5., 1)]
+            .into_iter()
+            .map(|(amps, range)| filter.apply(amps, range))
+            .collect();
+        // The first sample after the range change is replaced by the rolling average
+        assert_eq!(output[..2], [1., 1.]);
+        assert!((output[2] - (0.18 * 5. + 0.82 * 1.)).abs() < f32::EPSILON);
+        assert_eq!(output[3], 5.);
+
+        // Range 0 followed by range 1, at the same ADC value
+        let raw: Vec<u8> = (0..4u32)
+            .flat_map(|i| (1000 | (i / 2) << 14 | i << 18).to_le_bytes())
+            .collect();
+        let parse = |enabled| {
+            let mut acc = MeasurementAccumulator::new(Metadata::default()).spike_filter(enabled);
+            let mut buf = VecDeque::new();
+            acc.feed_into(&raw, &mut buf);
+            buf.iter().map(|m| m.micro_amps).collect::<Vec<_>>()
+        };
+        let (filtered, unfiltered) = (parse(true), parse(false));
+        assert_eq!(filtered[..2], unfiltered[..2]);
+        assert_ne!(filtered[2], unfiltered[2]);
+        assert_eq!(unfiltered[2], unfiltered[3]);
     }
 }
@@ -0,0 +1,415 @@
+//! A simulated PPK2, for testing measurement pipelines without hardware.
+//! Requires the `mock` feature.
+//!
+//! [MockPpk2] implements [SerialPort], answering metadata requests and
+//! streaming synthetic samples in real time while measuring, like the
+//! device does. Open it with [crate::Ppk2::with_port]:
+//!
+//! ```no_run
+//! # fn main() -> ppk2::Result<()> {
+//! use ppk2::{measurement::Measurement, mock::MockPpk2, types::*, Ppk2};
+//!
+//! let mock = MockPpk2::new().samples(|i| {
+//!     Some(Measurement {
+//!         micro_amps: if i % 1000 < 100 { 5000. } else { 10. },
+//!         ..Default::default()
+//!     })
+//! });
+//! let ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source)?;
+//! let (rx, stop) = ppk2.start_measurement(SampleRate::per_second(100)?)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::{
+    calibration::{Calibration, RANGES},
+    clock::SampleClock,
+    measurement::{Measurement, SAMPLE_FIELDS, SAMPLE_SIZE},
+    types::Metadata,
+};
+
+/// Produces the sample at the passed index, or [None] to leave it out
+/// of the stream, as if it were lost.
+type SampleFn = Box<dyn FnMut(u64) -> Option<Measurement> + Send>;
+
+struct MockState {
+    metadata: Metadata,
+    samples: SampleFn,
+    clock: SampleClock,
+    /// Response bytes not read yet
+    output: VecDeque<u8>,
+    /// When measuring, the start time and the number of samples produced
+    measuring: Option<(Instant, u64)>,
+    commands: Vec<Vec<u8>>,
+    /// Whether the device was unplugged, failing all reads
+    disconnected: bool,
+    /// Whether the device is replugged, failing the next read
+    glitched: bool,
+}
+
+/// A simulated PPK2. Clones share the same simulated device, so a clone
+/// can be kept to inspect the commands sent to it with [MockPpk2::commands].
+#[derive(Clone)]
+pub struct MockPpk2 {
+    state: Arc<Mutex<MockState>>,
+    timeout: Duration,
+}
+
+impl Default for MockPpk2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockPpk2 {
+    /// Opcode of [crate::cmd::Command::AverageStart]
+    const AVERAGE_START: u8 = 0x06;
+    /// Opcode of [crate::cmd::Command::AverageStop]
+    const AVERAGE_STOP: u8 = 0x07;
+    /// Opcode of [crate::cmd::Command::GetMetaData]
+    const GET_METADATA: u8 = 0x19;
+
+    /// Create a new [MockPpk2] with the nominal calibration, sampling
+    /// a constant 1 mA at the nominal rate.
+    pub fn new() -> Self {
+        let metadata = Metadata {
+            calibrated: true,
+            vdd: 3000,
+            ..Default::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                metadata,
+                samples: Box::new(|_| {
+                    Some(Measurement {
+                        micro_amps: 1000.,
+                        ..Default::default()
+                    })
+                }),
+                clock: SampleClock::nominal(),
+                output: VecDeque::new(),
+                measuring: None,
+                commands: Vec::new(),
+                disconnected: false,
+                glitched: false,
+            })),
+            timeout: Duration::from_millis(500),
+        }
+    }
+
+    /// Set the metadata reported by the device. Its calibration is
+    /// used to encode the samples.
+    pub fn metadata(self, metadata: Metadata) -> Self {
+        self.state.lock().unwrap().metadata = metadata;
+        self
+    }
+
+    /// Set the function producing the current and logic port pins of the
+    /// sample at the passed index, counted from the start of the measurement.
+    /// Returning [None] leaves the sample out, as if it were lost. The
+    /// current is encoded in the most sensitive range that can hold it.
+    pub fn samples(self, samples: impl FnMut(u64) -> Option<Measurement> + Send + 'static) -> Self {
+        self.state.lock().unwrap().samples = Box::new(samples);
+        self
+    }
+
+    /// Set the rate at which samples are streamed.
+    /// Defaults to [SampleClock::nominal].
+    pub fn clock(self, clock: SampleClock) -> Self {
+        self.state.lock().unwrap().clock = clock;
+        self
+    }
+
+    /// Get the commands sent to the device so far, one per write.
+    pub fn commands(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Simulate unplugging the device, making all further reads fail.
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().disconnected = true;
+    }
+
+    /// Simulate unplugging and replugging the device, making the next read
+    /// fail and resetting the device.
+    pub fn glitch(&self) {
+        self.state.lock().unwrap().glitched = true;
+    }
+
+    /// Encode a sample as the device would, for the passed calibration.
+    pub(crate) fn encode(calibration: &Calibration, m: &Measurement, counter: u64) -> u32 {
+        let [(_, adc_bits, adc_pos), (_, _, range_pos), (_, counter_bits, counter_pos), (_, _, logic_pos)] =
+            SAMPLE_FIELDS;
+        let adc_max = (1 << adc_bits) - 1;
+        let amps = m.micro_amps / 1e6;
+        let range = (0..RANGES)
+            .find(|&r| calibration.convert(adc_max, r) >= amps)
+            .unwrap_or(RANGES - 1);
+        // The conversion increases with the ADC value
+        let (mut adc, mut high) = (0, adc_max);
+        while adc < high {
+            let mid = (adc + high) / 2;
+            if calibration.convert(mid, range) < amps {
+                adc = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let counter = counter as u32 & ((1 << counter_bits) - 1);
+        adc << adc_pos
+            | (range as u32) << range_pos
+            | counter << counter_pos
+            | u32::from(u8::from(m.pins)) << logic_pos
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "Operation timed out")
+    }
+}
+
+impl Read for MockPpk2 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.disconnected {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                if std::mem::take(&mut state.glitched) {
+                    state.measuring = None;
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                if !state.output.is_empty() {
+                    let n = buf.len().min(state.output.len());
+                    for (b, out) in buf.iter_mut().zip(state.output.drain(..n)) {
+                        *b = out;
+                    }
+                    return Ok(n);
+                }
+                if let Some((start, produced)) = state.measuring {
+                    let due = state.clock.index_at(start.elapsed());
+                    let n = due
+                        .saturating_sub(produced)
+                        .min((buf.len() / SAMPLE_SIZE) as u64);
+                    if n > 0 {
+                        let calibration = Calibration::from(&state.metadata);
+                        let mut len = 0;
+                        for i in produced..produced + n {
+                            if let Some(m) = (state.samples)(i) {
+                                let raw = Self::encode(&calibration, &m, i);
+                                buf[len..len + SAMPLE_SIZE].copy_from_slice(&raw.to_le_bytes());
+                                len += SAMPLE_SIZE;
+                            }
+                        }
+                        state.measuring = Some((start, produced + n));
+                        if len > 0 {
+                            return Ok(len);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Self::timed_out());
+            }
+            thread::sleep(Duration::from_micros(500));
+        }
+    }
+}
+
+impl Write for MockPpk2 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.commands.push(buf.to_vec());
+        match buf.first() {
+            Some(&Self::GET_METADATA) => {
+                let mut response = Vec::new();
+                state
+                    .metadata
+                    .to_writer(&mut response)
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                state.output.extend(response);
+            }
+            Some(&Self::AVERAGE_START) => state.measuring = Some((Instant::now(), 0)),
+            Some(&Self::AVERAGE_STOP) => state.measuring = None,
+            _ => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockPpk2 {
+    fn name(&self) -> Option<String> {
+        Some("mock".to_owned())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(9600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::Hardware)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.state.lock().unwrap().output.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            self.state.lock().unwrap().output.clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::MockPpk2;
+    use crate::{
+        calibration::Calibration,
+        measurement::{Measurement, MeasurementMatch, MeasurementOptions},
+        types::{MeasurementMode, MeasurementRange, SampleRate},
+        Ppk2,
+    };
+
+    #[test]
+    pub fn test_mock() {
+        let mock = MockPpk2::new().samples(|i| {
+            // Every 10th sample is lost
+            (i % 10 != 9).then_some(Measurement {
+                micro_amps: 250.,
+                pins: 0b1010_0101u8.into(),
+                ..Default::default()
+            })
+        });
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        assert_eq!(ppk2.metadata().vdd, 3000);
+        ppk2.set_hardware_spike_filtering(false).unwrap();
+        assert_eq!(mock.commands().last().unwrap(), &[0x16]);
+        ppk2.set_user_gain(MeasurementRange::Range2, 1.5).unwrap();
+        assert_eq!(
+            mock.commands().last().unwrap(),
+            &[0x25, 2, 0x00, 0x00, 0xC0, 0x3F]
+        );
+        assert_eq!(Calibration::from(ppk2.metadata()).range(2).ug, 1.5);
+        assert!(ppk2.set_user_gain(MeasurementRange::Range2, 0.).is_err());
+        ppk2.reset_user_calibration().unwrap();
+        assert!(mock.commands().contains(&vec![0x12]));
+        assert_eq!(Calibration::from(ppk2.metadata()).range(2).ug, 1.);
+
+        let (rx, handle) = ppk2
+            .start_measurement_with(MeasurementOptions::new(
+                SampleRate::per_second(100).unwrap(),
+            ))
+            .unwrap();
+        let MeasurementMatch::Match(m) = rx.recv_timeout(Duration::from_secs(1)).unwrap() else {
+            panic!("Expected a matching measurement");
+        };
+        assert!((m.micro_amps - 250.).abs() < 2.5, "{} µA", m.micro_amps);
+        assert_eq!(u8::from(m.pins), 0b1010_0101);
+        let missed = handle.qos_report().missed_samples;
+        handle.stop().unwrap();
+        assert!(missed > 0);
+        assert_eq!(mock.commands()[0], [0x19]);
+        assert_eq!(mock.commands().last().unwrap(), &[0x07]);
+    }
+}
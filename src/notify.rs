@@ -0,0 +1,139 @@
+//! Notification hooks for [MeasurementEvent]s, for alerting on events
+//! during unattended measurements.
+
+use std::{
+    process::{self, Stdio},
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+};
+
+use crate::{measurement::MeasurementEvent, Error, Result};
+
+/// A sink for [MeasurementEvent]s.
+pub trait Notifier: Send {
+    /// Handle a single event.
+    fn notify(&mut self, event: &MeasurementEvent) -> Result<()>;
+}
+
+impl<F: FnMut(&MeasurementEvent) -> Result<()> + Send> Notifier for F {
+    fn notify(&mut self, event: &MeasurementEvent) -> Result<()> {
+        self(event)
+    }
+}
+
+/// Runs a command for every event. The command is passed the event kind
+/// in the `PPK2_EVENT` environment variable, and a description of the event in
+/// `PPK2_EVENT_DETAILS`. To call a webhook, use a command like `curl`.
+#[derive(Debug, Clone)]
+pub struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandNotifier {
+    /// Create a new [CommandNotifier] that runs the passed program.
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Add an argument to pass to the program.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&mut self, event: &MeasurementEvent) -> Result<()> {
+        let status = process::Command::new(&self.program)
+            .args(&self.args)
+            .env("PPK2_EVENT", event_kind(event))
+            .env("PPK2_EVENT_DETAILS", format!("{event:?}"))
+            .stdin(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(Error::Notify(format!(
+                "{} exited with {status}",
+                self.program
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Short, stable name of the kind of the event.
+pub fn event_kind(event: &MeasurementEvent) -> &'static str {
+    match event {
+        MeasurementEvent::SegmentEnd(_) => "segment_end",
+        MeasurementEvent::Overcurrent { .. } => "overcurrent",
+        MeasurementEvent::SuspectedDutReset { .. } => "suspected_dut_reset",
+        MeasurementEvent::TagChange { .. } => "tag_change",
+        MeasurementEvent::LogicEdge { .. } => "logic_edge",
+        MeasurementEvent::AutoZero(_) => "auto_zero",
+        MeasurementEvent::HostSuspended(_) => "host_suspended",
+        MeasurementEvent::Reconnected(_) => "reconnected",
+        MeasurementEvent::IrDrop(_) => "ir_drop",
+        MeasurementEvent::Ended(_) => "ended",
+    }
+}
+
+/// Spawn a thread that passes every event received from `events` to the
+/// [Notifier], until the measurement ends. Errors are logged, and don't
+/// stop the notifier.
+/// Use [crate::MeasurementHandle::subscribe] to obtain the events.
+pub fn spawn(
+    events: Receiver<MeasurementEvent>,
+    mut notifier: impl Notifier + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for event in events {
+            if let Err(e) = notifier.notify(&event) {
+                tracing::error!("Error notifying event {:?}: {:?}", event, e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_kind, CommandNotifier, Notifier};
+    use crate::{
+        measurement::{MeasurementEnd, MeasurementEvent},
+        Error,
+    };
+
+    #[test]
+    pub fn test_event_kind() {
+        let overcurrent = MeasurementEvent::Overcurrent {
+            sample: 10,
+            micro_amps: 5000.,
+        };
+        assert_eq!(event_kind(&overcurrent), "overcurrent");
+        let ended = MeasurementEvent::Ended(MeasurementEnd::Stopped);
+        assert_eq!(event_kind(&ended), "ended");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_command_notifier() {
+        let event = MeasurementEvent::Ended(MeasurementEnd::Stopped);
+        assert!(CommandNotifier::new("true").notify(&event).is_ok());
+        assert!(matches!(
+            CommandNotifier::new("false").notify(&event),
+            Err(Error::Notify(_))
+        ));
+        // The event is passed in the environment
+        let check = CommandNotifier::new("sh")
+            .arg("-c")
+            .arg("test \"$PPK2_EVENT\" = ended");
+        assert!(check.clone().notify(&event).is_ok());
+        let overcurrent = MeasurementEvent::Overcurrent {
+            sample: 0,
+            micro_amps: 1.,
+        };
+        assert!(check.clone().notify(&overcurrent).is_err());
+    }
+}
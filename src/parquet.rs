@@ -0,0 +1,454 @@
+//! Minimal export of measurements as an Apache Parquet file, for analysis
+//! with data frame libraries like pandas, polars or DuckDB.
+//!
+//! The file has a single row group with three required columns:
+//! - `time_s`: the time since the start in seconds, as a `DOUBLE`;
+//! - `current_ua`: the current in µA, as a `FLOAT`;
+//! - `pins`: the logic port pins as an `INT32` annotated as `UINT_8`,
+//!   with pin 0 in the least significant bit.
+//!
+//! Values are PLAIN encoded and not compressed. Timestamps are derived from
+//! the sample index using a [SampleClock].
+//!
+//! The writer is not yet validated against other Parquet readers, so it
+//! requires the `unstable` feature.
+
+use std::io::{self, Write};
+
+use crate::{clock::SampleClock, measurement::Measurement, Result};
+
+/// Magic number at the start and end of a Parquet file.
+const MAGIC: &[u8; 4] = b"PAR1";
+
+/// Maximum number of values in a data page.
+const PAGE_VALUES: usize = 1 << 16;
+
+/// Physical types
+const DOUBLE: i32 = 5;
+const FLOAT: i32 = 4;
+const INT32: i32 = 1;
+/// Converted type of unsigned 8-bit integers
+const UINT_8: i32 = 11;
+/// Encodings
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+
+/// Write the measurements as a Parquet file. Fails if there are none,
+/// as the columns would have no data pages.
+pub fn write_parquet(
+    mut writer: impl Write,
+    measurements: &[Measurement],
+    clock: &SampleClock,
+) -> Result<()> {
+    #[cfg(feature = "unstable")]
+    crate::unstable::warn("parquet::write_parquet");
+    if measurements.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No measurements to write").into());
+    }
+    type Encode = fn(&mut Vec<u8>, usize, &Measurement, &SampleClock);
+    let columns: [(&str, i32, Encode); 3] = [
+        ("time_s", DOUBLE, |buf, i, _, clock| {
+            buf.extend(clock.time_at(i as u64).as_secs_f64().to_le_bytes())
+        }),
+        ("current_ua", FLOAT, |buf, _, m, _| {
+            buf.extend(m.micro_amps.to_le_bytes())
+        }),
+        ("pins", INT32, |buf, _, m, _| {
+            buf.extend(i32::from(u8::from(m.pins)).to_le_bytes())
+        }),
+    ];
+
+    writer.write_all(MAGIC)?;
+    let mut offset = MAGIC.len() as i64;
+    let mut chunks = Vec::new();
+    let mut total_size = 0;
+    for (name, physical, encode) in columns {
+        let data_page_offset = offset;
+        for (page, values) in measurements.chunks(PAGE_VALUES).enumerate() {
+            let mut data = Vec::new();
+            for (i, m) in values.iter().enumerate() {
+                encode(&mut data, page * PAGE_VALUES + i, m, clock);
+            }
+            let header = Struct::default()
+                // Data page
+                .i32(1, 0)
+                .i32(2, data.len() as i32)
+                .i32(3, data.len() as i32)
+                .structure(
+                    5,
+                    Struct::default()
+                        .i32(1, values.len() as i32)
+                        .i32(2, PLAIN)
+                        .i32(3, RLE)
+                        .i32(4, RLE),
+                )
+                .finish();
+            writer.write_all(&header)?;
+            writer.write_all(&data)?;
+            offset += (header.len() + data.len()) as i64;
+        }
+        let size = offset - data_page_offset;
+        total_size += size;
+        let meta_data = Struct::default()
+            .i32(1, physical)
+            .list(2, I32, vec![zigzag_varint(PLAIN.into())])
+            .list(3, BINARY, vec![binary(name.as_bytes())])
+            // Uncompressed
+            .i32(4, 0)
+            .i64(5, measurements.len() as i64)
+            .i64(6, size)
+            .i64(7, size)
+            .i64(9, data_page_offset);
+        chunks.push(
+            Struct::default()
+                .i64(2, data_page_offset)
+                .structure(3, meta_data)
+                .finish(),
+        );
+    }
+
+    let mut schema = vec![Struct::default()
+        .binary(4, b"schema")
+        .i32(5, columns.len() as i32)
+        .finish()];
+    for (name, physical, _) in columns {
+        let element = Struct::default()
+            .i32(1, physical)
+            // Required
+            .i32(3, 0)
+            .binary(4, name.as_bytes());
+        schema.push(
+            match physical {
+                INT32 => element.i32(6, UINT_8),
+                _ => element,
+            }
+            .finish(),
+        );
+    }
+    let row_group = Struct::default()
+        .list(1, STRUCT, chunks)
+        .i64(2, total_size)
+        .i64(3, measurements.len() as i64)
+        .finish();
+    let metadata = Struct::default()
+        .i32(1, 1)
+        .list(2, STRUCT, schema)
+        .i64(3, measurements.len() as i64)
+        .list(4, STRUCT, vec![row_group])
+        .binary(
+            6,
+            concat!("ppk2-rs version ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        )
+        .finish();
+    writer.write_all(&metadata)?;
+    writer.write_all(&(metadata.len() as u32).to_le_bytes())?;
+    writer.write_all(MAGIC)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Thrift compact protocol types
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+/// Encodes a struct in the Thrift compact protocol, which Parquet uses for
+/// its metadata. Fields must be added in increasing order of their ids.
+#[derive(Default)]
+struct Struct {
+    buf: Vec<u8>,
+    last_id: i16,
+}
+
+impl Struct {
+    fn field(&mut self, id: i16, ty: u8) {
+        let delta = id - self.last_id;
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | ty);
+        } else {
+            self.buf.push(ty);
+            self.buf.extend(zigzag_varint(id.into()));
+        }
+        self.last_id = id;
+    }
+
+    fn i32(self, id: i16, value: i32) -> Self {
+        self.i64_as(id, I32, value.into())
+    }
+
+    fn i64(self, id: i16, value: i64) -> Self {
+        self.i64_as(id, I64, value)
+    }
+
+    fn i64_as(mut self, id: i16, ty: u8, value: i64) -> Self {
+        self.field(id, ty);
+        self.buf.extend(zigzag_varint(value));
+        self
+    }
+
+    fn binary(mut self, id: i16, value: &[u8]) -> Self {
+        self.field(id, BINARY);
+        self.buf.extend(binary(value));
+        self
+    }
+
+    fn structure(mut self, id: i16, value: Struct) -> Self {
+        self.field(id, STRUCT);
+        self.buf.extend(value.finish());
+        self
+    }
+
+    /// Add a list of already encoded elements of type `ty`.
+    fn list(mut self, id: i16, ty: u8, elements: Vec<Vec<u8>>) -> Self {
+        self.field(id, LIST);
+        match elements.len() {
+            len @ 0..=14 => self.buf.push((len as u8) << 4 | ty),
+            len => {
+                self.buf.push(0xF0 | ty);
+                self.buf.extend(varint(len as u64));
+            }
+        }
+        self.buf.extend(elements.concat());
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        // Field stop
+        self.buf.push(0);
+        self.buf
+    }
+}
+
+fn varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn zigzag_varint(value: i64) -> Vec<u8> {
+    varint(((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn binary(value: &[u8]) -> Vec<u8> {
+    let mut bytes = varint(value.len() as u64);
+    bytes.extend(value);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        write_parquet, zigzag_varint, Struct, BINARY, I32, I64, LIST, MAGIC, PAGE_VALUES, STRUCT,
+    };
+    use crate::{clock::SampleClock, measurement::Measurement};
+
+    /// A decoded Thrift compact protocol value.
+    #[derive(Debug)]
+    enum Value {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Value>),
+        Struct(Vec<(i16, Value)>),
+    }
+
+    impl Value {
+        fn int(&self) -> i64 {
+            let Value::Int(v) = self else {
+                panic!("{self:?} is no integer")
+            };
+            *v
+        }
+
+        fn binary(&self) -> &[u8] {
+            let Value::Binary(v) = self else {
+                panic!("{self:?} is no binary")
+            };
+            v
+        }
+
+        fn list(&self) -> &[Value] {
+            let Value::List(v) = self else {
+                panic!("{self:?} is no list")
+            };
+            v
+        }
+
+        fn field(&self, id: i16) -> &Value {
+            let Value::Struct(fields) = self else {
+                panic!("{self:?} is no struct")
+            };
+            let field = fields.iter().find(|(i, _)| *i == id);
+            &field.unwrap_or_else(|| panic!("No field {id}")).1
+        }
+    }
+
+    /// Decodes the Thrift compact protocol, to check the written metadata.
+    struct Decoder<'a> {
+        buf: &'a [u8],
+        pos: usize,
+    }
+
+    impl Decoder<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.buf[self.pos - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = self.byte();
+                value |= u64::from(byte & 0x7F) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let value = self.varint();
+            (value >> 1) as i64 ^ -((value & 1) as i64)
+        }
+
+        fn value(&mut self, ty: u8) -> Value {
+            match ty {
+                I32 | I64 => Value::Int(self.zigzag()),
+                BINARY => {
+                    let len = self.varint() as usize;
+                    self.pos += len;
+                    Value::Binary(self.buf[self.pos - len..self.pos].to_vec())
+                }
+                LIST => {
+                    let header = self.byte();
+                    let len = match header >> 4 {
+                        15 => self.varint() as usize,
+                        len => len as usize,
+                    };
+                    Value::List((0..len).map(|_| self.value(header & 0xF)).collect())
+                }
+                STRUCT => {
+                    let mut fields = Vec::new();
+                    let mut id = 0;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            return Value::Struct(fields);
+                        }
+                        id = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => id + i16::from(delta),
+                        };
+                        fields.push((id, self.value(header & 0xF)));
+                    }
+                }
+                _ => panic!("Unexpected type {ty}"),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_write_parquet() {
+        assert_eq!(zigzag_varint(-1), [1]);
+        assert_eq!(zigzag_varint(300), [0xD8, 0x04]);
+        // Field 1 is i32 1, field 17 needs a long header
+        assert_eq!(
+            Struct::default().i32(1, 1).i32(17, -2).finish(),
+            [0x15, 2, 0x05, 34, 3, 0]
+        );
+
+        let measurements: Vec<_> = (0..PAGE_VALUES + 1)
+            .map(|i| Measurement {
+                micro_amps: i as f32,
+                pins: (i as u8).into(),
+                ..Default::default()
+            })
+            .collect();
+        let mut file = Vec::new();
+        write_parquet(&mut file, &measurements, &SampleClock::with_rate(1000.)).unwrap();
+
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+        let len = file.len();
+        let footer_len = u32::from_le_bytes(file[len - 8..len - 4].try_into().unwrap()) as usize;
+        let footer = &file[len - 8 - footer_len..len - 8];
+        let find = |needle: &[u8]| footer.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"time_s") && find(b"current_ua") && find(b"pins"));
+        // The last value of the time column, at 65.536 s
+        let last_time = 65.536f64.to_le_bytes();
+        assert!(file.windows(8).any(|w| w == last_time));
+        // 3 columns of 2 pages, 20 bytes per row, plus headers and metadata
+        assert!(len > measurements.len() * 16);
+    }
+
+    #[test]
+    pub fn test_parquet_metadata() {
+        let rows = PAGE_VALUES + 1;
+        let measurements = vec![Measurement::default(); rows];
+        let mut file = Vec::new();
+        write_parquet(&mut file, &measurements, &SampleClock::nominal()).unwrap();
+
+        let len = file.len();
+        let footer_len = u32::from_le_bytes(file[len - 8..len - 4].try_into().unwrap()) as usize;
+        let footer_start = len - 8 - footer_len;
+        let mut decoder = Decoder {
+            buf: &file[..len - 8],
+            pos: footer_start,
+        };
+        let metadata = decoder.value(STRUCT);
+        assert_eq!(decoder.pos, len - 8);
+        assert_eq!(metadata.field(3).int(), rows as i64);
+        // The schema root and 3 columns
+        let names: Vec<_> = metadata
+            .field(2)
+            .list()
+            .iter()
+            .map(|element| element.field(4).binary())
+            .collect();
+        assert_eq!(names, [&b"schema"[..], b"time_s", b"current_ua", b"pins"]);
+        let [row_group] = metadata.field(4).list() else {
+            panic!("Expected a single row group");
+        };
+        assert_eq!(row_group.field(3).int(), rows as i64);
+
+        // The column chunks follow each other, up to the footer
+        let mut offset = MAGIC.len();
+        for chunk in row_group.field(1).list() {
+            let chunk_meta = chunk.field(3);
+            assert_eq!(chunk.field(2).int(), offset as i64);
+            assert_eq!(chunk_meta.field(9).int(), offset as i64);
+            assert_eq!(chunk_meta.field(5).int(), rows as i64);
+            let size = chunk_meta.field(7).int() as usize;
+            assert_eq!(chunk_meta.field(6).int(), size as i64);
+
+            // The pages fill the chunk, and hold all values
+            let mut pages = Decoder {
+                buf: &file,
+                pos: offset,
+            };
+            let mut values = 0;
+            while pages.pos < offset + size {
+                let header = pages.value(STRUCT);
+                assert_eq!(header.field(2).int(), header.field(3).int());
+                values += header.field(5).field(1).int();
+                pages.pos += header.field(3).int() as usize;
+            }
+            assert_eq!(pages.pos, offset + size);
+            assert_eq!(values, rows as i64);
+            offset += size;
+        }
+        assert_eq!(offset, footer_start);
+        assert_eq!(row_group.field(2).int(), (offset - MAGIC.len()) as i64);
+
+        assert!(write_parquet(Vec::new(), &[], &SampleClock::nominal()).is_err());
+    }
+}
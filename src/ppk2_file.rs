@@ -0,0 +1,299 @@
+//! Export and import of [Session]s in the `.ppk2` file format of the
+//! nRF Connect Power Profiler app, so recordings can be inspected in the
+//! app, and recordings made with the app can be analyzed with this crate.
+//!
+//! A `.ppk2` file is a ZIP archive containing:
+//! - `metadata.json`: the sample rate and the start time of the
+//!   recording in milliseconds since the Unix epoch, with format version 2;
+//! - `session.raw`: 6 bytes per sample, namely the current in µA as
+//!   a little endian `f32`, followed by the logic port pins as a little
+//!   endian `u16`, with pin 0 in the least significant bit.
+//!
+//! - `preview.raw`: a [Preview] of the session at [preview::DEFAULT_RATE],
+//!   encoded as by [Preview::to_bytes]. This entry is specific to this
+//!   crate, and ignored by the app. It can be read without the
+//!   measurements with [read_preview].
+//! - `segments.txt`: the labeled segments of the session, if any, one per
+//!   line with the start, the number of samples, the number of missed
+//!   samples and the average current in µA, followed by the label, separated
+//!   by tabs. Also specific to this crate, and ignored by the app.
+//!
+//! The minimap overview that newer versions of the app add is not
+//! written, and ignored when reading. Entries are written uncompressed,
+//! and may be stored or deflated when reading.
+
+use std::{
+    io::{Read, Seek, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::SampleClock,
+    measurement::{Measurement, SegmentSummary},
+    preview::{self, Preview},
+    session::Session,
+    zip::{self, ZipWriter},
+    Error, Result,
+};
+
+/// Version of the file format that is written.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Size of a sample in `session.raw`.
+pub const FRAME_SIZE: usize = 6;
+
+/// Name of the entry holding the [Preview].
+const PREVIEW_ENTRY: &str = "preview.raw";
+
+/// Name of the entry holding the labeled segments.
+const SEGMENTS_ENTRY: &str = "segments.txt";
+
+/// Write the session as a `.ppk2` file, recorded starting at `start`.
+pub fn write_session(writer: impl Write, session: &Session, start: SystemTime) -> Result<()> {
+    let start_ms = start
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let metadata = format!(
+        r#"{{"metadata":{{"samplesPerSecond":{},"startSystemTime":{start_ms}}},"formatVersion":{FORMAT_VERSION}}}"#,
+        session.clock.rate().round() as u64,
+    );
+
+    let mut raw = Vec::with_capacity(session.len() * FRAME_SIZE);
+    for m in &session.measurements {
+        raw.extend(m.micro_amps.to_le_bytes());
+        raw.extend(u16::from(u8::from(m.pins)).to_le_bytes());
+    }
+
+    let mut zip = ZipWriter::new(writer);
+    zip.add("metadata.json", metadata.as_bytes())?;
+    zip.add("session.raw", &raw)?;
+    let preview = Preview::new(session, preview::DEFAULT_RATE);
+    zip.add(PREVIEW_ENTRY, &preview.to_bytes())?;
+    if !session.segments.is_empty() {
+        zip.add(SEGMENTS_ENTRY, write_segments(&session.segments).as_bytes())?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// A `.ppk2` file, as read by [read_session].
+#[derive(Debug, Clone)]
+pub struct Ppk2File {
+    /// The recorded measurements, sampled at the rate of the file.
+    pub session: Session,
+    /// When the recording started, if the file records it.
+    pub start: Option<SystemTime>,
+    /// Preview of the session, if the file contains one.
+    pub preview: Option<Preview>,
+}
+
+/// Read a `.ppk2` file. The logic port pins are taken from the lower
+/// 8 bits of the digital channels.
+pub fn read_session(mut reader: impl Read) -> Result<Ppk2File> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let entries = zip::read_entries(&data)?;
+    let entry = |name: &str| {
+        entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| contents)
+            .ok_or_else(|| Error::Parse(format!("{name} missing from .ppk2 file")))
+    };
+
+    let metadata = std::str::from_utf8(entry("metadata.json")?)?;
+    let rate = json_number(metadata, "samplesPerSecond")
+        .filter(|&rate| rate > 0.)
+        .ok_or_else(|| Error::Parse(metadata.to_owned()))?;
+    let start = json_number(metadata, "startSystemTime")
+        .map(|ms| UNIX_EPOCH + Duration::from_millis(ms as u64));
+
+    let measurements = entry("session.raw")?
+        .chunks_exact(FRAME_SIZE)
+        .map(|frame| Measurement {
+            micro_amps: f32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]),
+            pins: frame[4].into(),
+            ..Default::default()
+        })
+        .collect();
+    let preview = entry(PREVIEW_ENTRY)
+        .ok()
+        .map(|bytes| Preview::from_bytes(bytes))
+        .transpose()?;
+    let mut session = Session::from_measurements(SampleClock::with_rate(rate), measurements);
+    if let Ok(segments) = entry(SEGMENTS_ENTRY) {
+        session.segments = read_segments(std::str::from_utf8(segments)?)?;
+    }
+    Ok(Ppk2File {
+        session,
+        start,
+        preview,
+    })
+}
+
+/// Read only the [Preview] of a `.ppk2` file, without reading its
+/// measurements. Returns [None] if the file contains no preview, like
+/// the files written by the app.
+pub fn read_preview(reader: impl Read + Seek) -> Result<Option<Preview>> {
+    zip::read_entry(reader, PREVIEW_ENTRY)?
+        .map(|bytes| Preview::from_bytes(&bytes))
+        .transpose()
+}
+
+/// Write segments in the layout of the `segments.txt` entry.
+fn write_segments(segments: &[SegmentSummary]) -> String {
+    segments
+        .iter()
+        .map(|s| {
+            let avg = s.avg_micro_amps.map(|a| a.to_string()).unwrap_or_default();
+            let label = s.label.replace(['\t', '\n'], " ");
+            format!("{}\t{}\t{}\t{avg}\t{label}\n", s.start, s.samples, s.missed)
+        })
+        .collect()
+}
+
+/// Read segments written by [write_segments].
+fn read_segments(text: &str) -> Result<Vec<SegmentSummary>> {
+    text.lines()
+        .map(|line| {
+            let parse = || -> Option<SegmentSummary> {
+                let mut fields = line.splitn(5, '\t');
+                let [start, samples, missed, avg, label] =
+                    [(); 5].map(|_| fields.next().unwrap_or_default());
+                Some(SegmentSummary {
+                    label: label.to_owned(),
+                    start: start.parse().ok()?,
+                    samples: samples.parse().ok()?,
+                    missed: missed.parse().ok()?,
+                    avg_micro_amps: match avg {
+                        "" => None,
+                        avg => Some(avg.parse().ok()?),
+                    },
+                })
+            };
+            parse().ok_or_else(|| Error::Parse(line.to_owned()))
+        })
+        .collect()
+}
+
+/// Find the number value of the first occurrence of `key` in a JSON text.
+/// Enough for the flat metadata of `.ppk2` files.
+fn json_number(json: &str, key: &str) -> Option<f64> {
+    let at = json.find(&format!("\"{key}\""))? + key.len() + 2;
+    let value = json[at..].trim_start().strip_prefix(':')?.trim_start();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use std::io::Cursor;
+
+    use super::{read_preview, read_session, write_session};
+    use crate::{
+        clock::SampleClock,
+        measurement::{Measurement, SegmentSummary},
+        session::Session,
+    };
+
+    #[test]
+    pub fn test_write_session() {
+        let measurements = [1.5f32, 2.]
+            .into_iter()
+            .map(|micro_amps| Measurement {
+                micro_amps,
+                pins: 0b1000_0001u8.into(),
+                ..Default::default()
+            })
+            .collect();
+        let session = Session::from_measurements(SampleClock::nominal(), measurements);
+        let mut file = Vec::new();
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        write_session(&mut file, &session, start).unwrap();
+
+        assert!(file.starts_with(b"PK\x03\x04"));
+        let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle);
+        assert!(find(
+            br#"{"metadata":{"samplesPerSecond":100000,"startSystemTime":1700000000123},"formatVersion":2}"#
+        )
+        .is_some());
+        let mut raw = Vec::new();
+        raw.extend(1.5f32.to_le_bytes());
+        raw.extend([0x81, 0]);
+        raw.extend(2f32.to_le_bytes());
+        raw.extend([0x81, 0]);
+        let raw_at = find(&raw).unwrap();
+        // The local header of session.raw directly precedes its data
+        assert_eq!(&file[raw_at - 11..raw_at], b"session.raw");
+        // Three entries in the end of central directory record
+        assert_eq!(&file[file.len() - 22..file.len() - 18], b"PK\x05\x06");
+        assert_eq!(file[file.len() - 12], 3);
+    }
+
+    #[test]
+    pub fn test_read_session() {
+        let measurements = (0..100u8)
+            .map(|i| Measurement {
+                micro_amps: f32::from(i) * 0.5,
+                pins: i.into(),
+                ..Default::default()
+            })
+            .collect();
+        let mut session = Session::from_measurements(SampleClock::with_rate(1000.), measurements);
+        session.segments = vec![
+            SegmentSummary {
+                label: String::new(),
+                start: 0,
+                samples: 5000,
+                missed: 0,
+                avg_micro_amps: Some(12.25),
+            },
+            SegmentSummary {
+                label: "tx\tburst".into(),
+                start: 5000,
+                samples: 0,
+                missed: 7,
+                avg_micro_amps: None,
+            },
+        ];
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let mut file = Vec::new();
+        write_session(&mut file, &session, start).unwrap();
+
+        let read = read_session(file.as_slice()).unwrap();
+        assert_eq!(read.start, Some(start));
+        assert_eq!(read.session.clock.rate(), 1000.);
+        assert_eq!(read.session.len(), 100);
+        let m = &read.session.measurements[99];
+        assert_eq!((m.micro_amps, u8::from(m.pins)), (49.5, 99));
+        let segments: Vec<_> = read
+            .session
+            .segments
+            .iter()
+            .map(|s| {
+                (
+                    s.label.as_str(),
+                    s.start,
+                    s.samples,
+                    s.missed,
+                    s.avg_micro_amps,
+                )
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                ("", 0, 5000, 0, Some(12.25)),
+                ("tx burst", 5000, 0, 7, None)
+            ]
+        );
+        let preview = read_preview(Cursor::new(&file)).unwrap().unwrap();
+        assert_eq!(preview.buckets.len(), 1);
+        assert_eq!(read.preview, Some(preview));
+    }
+}
@@ -0,0 +1,175 @@
+//! Current profile presets for common Nordic development kits.
+//!
+//! The ranges are typical values for the board's SoC with the board
+//! peripherals that cannot be switched off included, measured through
+//! the board's current measurement header. They are meant to produce
+//! sensible defaults for reports, not as a specification.
+
+use std::{fmt::Display, ops::Range, str::FromStr};
+
+use crate::types::ParseTypeError;
+
+/// Power state a current was classified as by [BoardPreset::classify].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    /// Current is within the expected sleep range.
+    Sleep,
+    /// Current is within the expected active range.
+    Active,
+    /// Current is outside any of the expected ranges.
+    Unexpected,
+}
+
+/// Expected current profile and measurement settings for a board.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardPreset {
+    /// Board name
+    pub name: &'static str,
+    /// Expected current range in µA while the SoC is sleeping.
+    pub sleep_micro_amps: Range<f32>,
+    /// Expected current range in µA while the SoC is active, including radio activity.
+    pub active_micro_amps: Range<f32>,
+    /// Recommended number of samples per second to pass to
+    /// [crate::Ppk2::start_measurement].
+    pub recommended_sps: usize,
+    /// Logic port pin that firmware toggles to mark regions of interest.
+    pub marker_pin: usize,
+}
+
+impl BoardPreset {
+    /// nRF52840 DK
+    pub const NRF52840_DK: Self = Self {
+        name: "nrf52840dk",
+        sleep_micro_amps: 0.3..10.,
+        active_micro_amps: 500.0..20_000.,
+        recommended_sps: 10_000,
+        marker_pin: 0,
+    };
+
+    /// nRF52832 DK
+    pub const NRF52_DK: Self = Self {
+        name: "nrf52dk",
+        sleep_micro_amps: 0.3..10.,
+        active_micro_amps: 500.0..15_000.,
+        recommended_sps: 10_000,
+        marker_pin: 0,
+    };
+
+    /// nRF5340 DK
+    pub const NRF5340_DK: Self = Self {
+        name: "nrf5340dk",
+        sleep_micro_amps: 0.5..10.,
+        active_micro_amps: 500.0..15_000.,
+        recommended_sps: 10_000,
+        marker_pin: 0,
+    };
+
+    /// nRF9160 DK. The LTE modem draws far more during transmission.
+    pub const NRF9160_DK: Self = Self {
+        name: "nrf9160dk",
+        sleep_micro_amps: 1.0..50.,
+        active_micro_amps: 1_000.0..500_000.,
+        recommended_sps: 100_000,
+        marker_pin: 0,
+    };
+
+    /// Thingy:53. Sensors and PMIC add to the sleep current.
+    pub const THINGY53: Self = Self {
+        name: "thingy53",
+        sleep_micro_amps: 5.0..100.,
+        active_micro_amps: 1_000.0..30_000.,
+        recommended_sps: 10_000,
+        marker_pin: 0,
+    };
+
+    /// Thingy:91. Sensors and PMIC add to the sleep current.
+    pub const THINGY91: Self = Self {
+        name: "thingy91",
+        sleep_micro_amps: 10.0..200.,
+        active_micro_amps: 1_000.0..500_000.,
+        recommended_sps: 100_000,
+        marker_pin: 0,
+    };
+
+    /// All known presets
+    pub const ALL: [Self; 6] = [
+        Self::NRF52840_DK,
+        Self::NRF52_DK,
+        Self::NRF5340_DK,
+        Self::NRF9160_DK,
+        Self::THINGY53,
+        Self::THINGY91,
+    ];
+
+    /// Classify the passed current according to the preset ranges.
+    pub fn classify(&self, micro_amps: f32) -> PowerState {
+        if self.sleep_micro_amps.contains(&micro_amps) {
+            PowerState::Sleep
+        } else if self.active_micro_amps.contains(&micro_amps) {
+            PowerState::Active
+        } else {
+            PowerState::Unexpected
+        }
+    }
+}
+
+impl Display for BoardPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl FromStr for BoardPreset {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let name = s.to_lowercase().replace(['-', '_', ':'], "");
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| {
+                ParseTypeError::new(
+                    s.to_owned(),
+                    "[nrf52840dk | nrf52dk | nrf5340dk | nrf9160dk | thingy53 | thingy91]",
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoardPreset, PowerState};
+
+    #[test]
+    pub fn test_parse_presets() {
+        for preset in BoardPreset::ALL {
+            assert_eq!(preset.to_string().parse::<BoardPreset>().unwrap(), preset);
+        }
+        assert_eq!(
+            "nRF52840-DK".parse::<BoardPreset>().unwrap(),
+            BoardPreset::NRF52840_DK
+        );
+        assert_eq!(
+            "Thingy:53".parse::<BoardPreset>().unwrap(),
+            BoardPreset::THINGY53
+        );
+        assert!("nrf51dk".parse::<BoardPreset>().is_err());
+    }
+
+    #[test]
+    pub fn test_classify() {
+        let preset = BoardPreset::NRF52840_DK;
+        for (micro_amps, state) in [
+            (0.29, PowerState::Unexpected),
+            (0.3, PowerState::Sleep),
+            (9.99, PowerState::Sleep),
+            // The end of a range is excluded
+            (10., PowerState::Unexpected),
+            (500., PowerState::Active),
+            (19_999., PowerState::Active),
+            (20_000., PowerState::Unexpected),
+        ] {
+            assert_eq!(preset.classify(micro_amps), state, "{micro_amps} µA");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Coarse preview tracks of [Session]s, so viewers can render an overview
+//! of a long capture before loading its measurements. [crate::ppk2_file]
+//! stores the preview of a session in its file, where it can be read
+//! without the measurements with [crate::ppk2_file::read_preview].
+
+use crate::{session::Session, Error, Result};
+
+/// Default number of preview buckets per second.
+pub const DEFAULT_RATE: f64 = 10.;
+
+/// Size of an encoded [PreviewBucket].
+const BUCKET_SIZE: usize = 12;
+
+/// The current range of a bucket of measurements, in µA.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewBucket {
+    /// The lowest current
+    pub min: f32,
+    /// The average current
+    pub avg: f32,
+    /// The highest current
+    pub max: f32,
+}
+
+/// A session reduced to a fixed number of [PreviewBucket]s per second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    /// Number of buckets per second
+    pub rate: f64,
+    /// The buckets, the last of which may cover less time than the others
+    pub buckets: Vec<PreviewBucket>,
+}
+
+impl Preview {
+    /// Create the preview of the passed session, with `rate` buckets per
+    /// second. The extremes include the envelopes of combined measurements.
+    pub fn new(session: &Session, rate: f64) -> Self {
+        let per_bucket = (session.clock.rate() / rate).round().max(1.) as usize;
+        let buckets = session
+            .measurements
+            .chunks(per_bucket)
+            .map(|bucket| {
+                let sum: f32 = bucket.iter().map(|m| m.micro_amps).sum();
+                let (min, max) = bucket
+                    .iter()
+                    .map(|m| {
+                        m.envelope
+                            .map_or((m.micro_amps, m.micro_amps), |e| (e.min, e.max))
+                    })
+                    .fold(
+                        (f32::INFINITY, f32::NEG_INFINITY),
+                        |(lo, hi), (min, max)| (lo.min(min), hi.max(max)),
+                    );
+                PreviewBucket {
+                    min,
+                    avg: sum / bucket.len() as f32,
+                    max,
+                }
+            })
+            .collect();
+        Self {
+            rate: session.clock.rate() / per_bucket as f64,
+            buckets,
+        }
+    }
+
+    /// Encode the preview: the rate as a little endian `f64`, followed by
+    /// the minimum, average and maximum of each bucket as little endian `f32`s.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.buckets.len() * BUCKET_SIZE);
+        bytes.extend(self.rate.to_le_bytes());
+        for b in &self.buckets {
+            [b.min, b.avg, b.max]
+                .iter()
+                .for_each(|v| bytes.extend(v.to_le_bytes()));
+        }
+        bytes
+    }
+
+    /// Decode a preview encoded with [Preview::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invalid = || Error::Parse("invalid preview".to_owned());
+        let (rate, buckets) = bytes.split_first_chunk::<8>().ok_or_else(invalid)?;
+        if buckets.len() % BUCKET_SIZE != 0 {
+            return Err(invalid());
+        }
+        let f32_at = |b: &[u8], i: usize| f32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Ok(Self {
+            rate: f64::from_le_bytes(*rate),
+            buckets: buckets
+                .chunks_exact(BUCKET_SIZE)
+                .map(|b| PreviewBucket {
+                    min: f32_at(b, 0),
+                    avg: f32_at(b, 4),
+                    max: f32_at(b, 8),
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Preview, PreviewBucket};
+    use crate::{
+        clock::SampleClock,
+        measurement::{Envelope, Measurement},
+        session::Session,
+    };
+
+    #[test]
+    pub fn test_preview() {
+        let measurements = (0..25)
+            .map(|i| Measurement {
+                micro_amps: i as f32,
+                envelope: (i == 3).then_some(Envelope { min: -1., max: 50. }),
+                ..Default::default()
+            })
+            .collect();
+        let session = Session::from_measurements(SampleClock::with_rate(100.), measurements);
+
+        let preview = Preview::new(&session, 10.);
+        assert_eq!(preview.rate, 10.);
+        assert_eq!(preview.buckets.len(), 3);
+        let bucket = |min, avg, max| PreviewBucket { min, avg, max };
+        assert_eq!(preview.buckets[0], bucket(-1., 4.5, 50.));
+        assert_eq!(preview.buckets[2], bucket(20., 22., 24.));
+
+        let decoded = Preview::from_bytes(&preview.to_bytes()).unwrap();
+        assert_eq!(decoded, preview);
+        assert!(Preview::from_bytes(&[0; 9]).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Machine-readable description of the serial protocol, generated from
+//! [Command] and the sample parser, so tooling in other languages can
+//! stay in sync with this crate. The CLI example prints it with
+//! `cli protocol`.
+
+use std::fmt::Write;
+
+use crate::{
+    cmd::Command,
+    measurement::{SAMPLE_FIELDS, SAMPLE_SIZE},
+    types::{DevicePower, MeasurementMode, MeasurementRange, SourceVoltage},
+};
+
+/// A field in the payload of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadField {
+    /// Name of the field
+    pub name: &'static str,
+    /// Size of the field in bytes
+    pub size: usize,
+    /// How the field is encoded
+    pub encoding: &'static str,
+}
+
+/// Description of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandInfo {
+    /// Name of the [Command] variant
+    pub name: &'static str,
+    /// The opcode, which is the first byte of the command
+    pub opcode: u8,
+    /// The payload following the opcode
+    pub payload: &'static [PayloadField],
+    /// Expected length of the response in bytes. See
+    /// [Command::expected_response_len].
+    pub response_len: usize,
+}
+
+/// A bitfield of the little endian sample word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleField {
+    /// Name of the field
+    pub name: &'static str,
+    /// Width of the field in bits
+    pub bits: u32,
+    /// Position of the least significant bit of the field
+    pub pos: u32,
+}
+
+/// Description of the serial protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolDescription {
+    /// All commands, ordered by opcode
+    pub commands: Vec<CommandInfo>,
+    /// Size of a sample in bytes
+    pub sample_size: usize,
+    /// The bitfields of a sample
+    pub sample_fields: Vec<SampleField>,
+}
+
+const U16_BE: &str = "u16 big endian";
+
+/// Commands with an example payload, from which the opcode is taken.
+fn commands() -> Vec<(&'static str, Command, &'static [PayloadField])> {
+    use Command::*;
+    let samples: &'static [PayloadField] = &[PayloadField {
+        name: "samples",
+        size: 2,
+        encoding: U16_BE,
+    }];
+    vec![
+        ("NoOp", NoOp, &[]),
+        (
+            "TriggerSet",
+            TriggerSet(0),
+            &[PayloadField {
+                name: "micro_amps",
+                size: 3,
+                encoding: "u24 big endian",
+            }],
+        ),
+        ("AvgNumSet", AvgNumSet(1), samples),
+        ("TriggerWindowSet", TriggerWindowSet(0), samples),
+        ("TriggerIntervalSet", TriggerIntervalSet(0), samples),
+        ("TriggerSingleSet", TriggerSingleSet, &[]),
+        ("AverageStart", AverageStart, &[]),
+        ("AverageStop", AverageStop, &[]),
+        (
+            "RangeSet",
+            RangeSet(MeasurementRange::Range0),
+            &[PayloadField {
+                name: "range",
+                size: 1,
+                encoding: "u8, 0 to 4",
+            }],
+        ),
+        ("LcdSet", LcdSet, &[]),
+        ("TriggerStop", TriggerStop, &[]),
+        (
+            "DeviceRunningSet",
+            DeviceRunningSet(DevicePower::Enabled),
+            &[PayloadField {
+                name: "power",
+                size: 1,
+                encoding: "u8: 0 disabled, 1 enabled",
+            }],
+        ),
+        (
+            "RegulatorSet",
+            RegulatorSet(SourceVoltage::from_millivolts(3300)),
+            &[PayloadField {
+                name: "millivolts",
+                size: 2,
+                encoding: "u16 big endian, 800 to 5000",
+            }],
+        ),
+        (
+            "SwitchPointDown",
+            SwitchPointDown(0),
+            &[PayloadField {
+                name: "potentiometer",
+                size: 2,
+                encoding: "u16 big endian",
+            }],
+        ),
+        (
+            "SwitchPointUp",
+            SwitchPointUp(0),
+            &[PayloadField {
+                name: "potentiometer",
+                size: 2,
+                encoding: "u16 big endian",
+            }],
+        ),
+        ("TriggerExtToggle", TriggerExtToggle, &[]),
+        (
+            "SetPowerMode",
+            SetPowerMode(MeasurementMode::Source),
+            &[PayloadField {
+                name: "mode",
+                size: 1,
+                encoding: "u8: 1 ampere meter, 2 source meter",
+            }],
+        ),
+        ("ResUserSet", ResUserSet, &[]),
+        ("SpikeFilteringOn", SpikeFilteringOn, &[]),
+        ("SpikeFilteringOff", SpikeFilteringOff, &[]),
+        ("GetMetaData", GetMetaData, &[]),
+        ("Reset", Reset, &[]),
+        (
+            "SetUserGains",
+            SetUserGains(MeasurementRange::Range0, 1.),
+            &[
+                PayloadField {
+                    name: "range",
+                    size: 1,
+                    encoding: "u8, 0 to 4",
+                },
+                PayloadField {
+                    name: "gain",
+                    size: 4,
+                    encoding: "f32 little endian",
+                },
+            ],
+        ),
+    ]
+}
+
+impl ProtocolDescription {
+    /// Generate the description of the protocol implemented by this crate.
+    pub fn generate() -> Self {
+        let commands = commands()
+            .into_iter()
+            .map(|(name, cmd, payload)| CommandInfo {
+                name,
+                opcode: cmd.bytes().next().unwrap_or_default(),
+                payload,
+                response_len: cmd.expected_response_len(),
+            })
+            .collect();
+        let sample_fields = SAMPLE_FIELDS
+            .iter()
+            .map(|&(name, bits, pos)| SampleField { name, bits, pos })
+            .collect();
+        Self {
+            commands,
+            sample_size: SAMPLE_SIZE,
+            sample_fields,
+        }
+    }
+
+    /// Serialize the description as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        // Names and encodings are plain ASCII without quotes or backslashes,
+        // so nothing needs escaping.
+        let mut json = String::from("{\n  \"commands\": [\n");
+        for (i, cmd) in self.commands.iter().enumerate() {
+            let payload: Vec<_> = cmd
+                .payload
+                .iter()
+                .map(|f| {
+                    format!(
+                        "{{ \"name\": \"{}\", \"size\": {}, \"encoding\": \"{}\" }}",
+                        f.name, f.size, f.encoding
+                    )
+                })
+                .collect();
+            let _ = writeln!(
+                json,
+                "    {{ \"name\": \"{}\", \"opcode\": {}, \"payload\": [{}], \"response_len\": {} }}{}",
+                cmd.name,
+                cmd.opcode,
+                payload.join(", "),
+                cmd.response_len,
+                if i + 1 < self.commands.len() { "," } else { "" }
+            );
+        }
+        let _ = write!(
+            json,
+            "  ],\n  \"sample\": {{\n    \"size\": {},\n    \"endianness\": \"little\",\n    \"fields\": [\n",
+            self.sample_size
+        );
+        for (i, field) in self.sample_fields.iter().enumerate() {
+            let _ = writeln!(
+                json,
+                "      {{ \"name\": \"{}\", \"bits\": {}, \"pos\": {} }}{}",
+                field.name,
+                field.bits,
+                field.pos,
+                if i + 1 < self.sample_fields.len() {
+                    ","
+                } else {
+                    ""
+                }
+            );
+        }
+        json.push_str("    ]\n  }\n}\n");
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{commands, ProtocolDescription};
+
+    #[test]
+    pub fn test_protocol_description() {
+        // The documented payloads match the bytes that are actually sent
+        for (name, cmd, payload) in commands() {
+            let size: usize = payload.iter().map(|f| f.size).sum();
+            assert_eq!(cmd.bytes().count(), 1 + size, "{name}");
+        }
+
+        let desc = ProtocolDescription::generate();
+        let opcodes: Vec<_> = desc.commands.iter().map(|c| c.opcode).collect();
+        let mut sorted = opcodes.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(opcodes, sorted);
+        // The sample fields fill the sample word without overlapping
+        let bits: u32 = desc.sample_fields.iter().map(|f| f.bits).sum();
+        assert!(bits <= 8 * desc.sample_size as u32);
+        assert!(desc
+            .sample_fields
+            .windows(2)
+            .all(|w| w[0].pos + w[0].bits <= w[1].pos));
+
+        let json = desc.to_json();
+        assert!(json.contains(
+            r#"{ "name": "GetMetaData", "opcode": 25, "payload": [], "response_len": 512 }"#
+        ));
+        assert!(json.contains(r#"{ "name": "counter", "bits": 6, "pos": 18 }"#));
+    }
+}
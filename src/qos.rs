@@ -0,0 +1,233 @@
+//! Quality of service of a measurement: how well the delivered combined
+//! measurements match what was requested. Automated tests can set minimum
+//! requirements with [crate::measurement::MeasurementOptions::qos], so
+//! they fail instead of silently relying on degraded captures.
+
+#[cfg(feature = "serial")]
+use std::sync::Mutex;
+use std::{fmt, time::Duration};
+
+use crate::{Error, Result};
+
+/// Delivery quality of a measurement.
+/// See [crate::MeasurementHandle::qos_report].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QosReport {
+    /// The requested number of combined measurements per second.
+    pub requested_sps: f64,
+    /// Number of combined measurements delivered.
+    pub chunks: u64,
+    /// Mean number of device samples per combined measurement,
+    /// including missed samples.
+    pub mean_chunk_len: f64,
+    /// Standard deviation of the number of device samples per combined
+    /// measurement.
+    pub chunk_jitter: f64,
+    /// Number of device samples, including missed ones.
+    pub samples: u64,
+    /// Number of device samples that were missed.
+    pub missed_samples: u64,
+    /// Time since the measurement started.
+    pub elapsed: Duration,
+}
+
+impl QosReport {
+    /// The achieved number of combined measurements per second.
+    pub fn achieved_sps(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0. => self.chunks as f64 / secs,
+            _ => 0.,
+        }
+    }
+
+    /// Fraction of the device samples that were missed, from 0 to 1.
+    pub fn missed_ratio(&self) -> f64 {
+        match self.samples {
+            0 => 0.,
+            samples => self.missed_samples as f64 / samples as f64,
+        }
+    }
+
+    /// Chunk jitter relative to the mean chunk length.
+    pub fn relative_jitter(&self) -> f64 {
+        match self.mean_chunk_len {
+            mean if mean > 0. => self.chunk_jitter / mean,
+            _ => 0.,
+        }
+    }
+}
+
+impl fmt::Display for QosReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} of {:.1} requested sps, chunk length {:.1} ± {:.1} samples, {:.3}% samples missed",
+            self.achieved_sps(),
+            self.requested_sps,
+            self.mean_chunk_len,
+            self.chunk_jitter,
+            self.missed_ratio() * 100.
+        )
+    }
+}
+
+/// Minimum delivery quality of a measurement. Checked when the measurement
+/// stops, if set with [crate::measurement::MeasurementOptions::qos].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QosRequirements {
+    min_sps_ratio: Option<f64>,
+    max_missed_ratio: Option<f64>,
+    max_relative_jitter: Option<f64>,
+}
+
+impl QosRequirements {
+    /// Create a new [QosRequirements] without any requirements.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require the achieved number of measurements per second to be at least
+    /// the passed fraction of the requested number, e.g. 0.95.
+    pub fn min_sps_ratio(mut self, ratio: f64) -> Self {
+        self.min_sps_ratio = Some(ratio);
+        self
+    }
+
+    /// Require at most the passed fraction of device samples to be
+    /// missed, e.g. 0.001 for 0.1%.
+    pub fn max_missed_ratio(mut self, ratio: f64) -> Self {
+        self.max_missed_ratio = Some(ratio);
+        self
+    }
+
+    /// Require the chunk jitter to be at most the passed fraction
+    /// of the mean chunk length.
+    pub fn max_relative_jitter(mut self, ratio: f64) -> Self {
+        self.max_relative_jitter = Some(ratio);
+        self
+    }
+
+    /// Check the report against the requirements, returning
+    /// [Error::QosBelowMinimum] listing the violated ones.
+    pub fn check(&self, report: &QosReport) -> Result<()> {
+        let mut violations = Vec::new();
+        if let Some(min) = self.min_sps_ratio {
+            let ratio = report.achieved_sps() / report.requested_sps;
+            if ratio.is_nan() || ratio < min {
+                violations.push(format!(
+                    "achieved {:.1} sps is below {:.0}% of the requested {:.1}",
+                    report.achieved_sps(),
+                    min * 100.,
+                    report.requested_sps
+                ));
+            }
+        }
+        if let Some(max) = self.max_missed_ratio.filter(|&m| report.missed_ratio() > m) {
+            violations.push(format!(
+                "{:.3}% of the samples were missed, more than {:.3}%",
+                report.missed_ratio() * 100.,
+                max * 100.
+            ));
+        }
+        if let Some(max) = self
+            .max_relative_jitter
+            .filter(|&m| report.relative_jitter() > m)
+        {
+            violations.push(format!(
+                "chunk jitter of {:.1}% exceeds {:.1}%",
+                report.relative_jitter() * 100.,
+                max * 100.
+            ));
+        }
+        match violations.is_empty() {
+            true => Ok(()),
+            false => Err(Error::QosBelowMinimum(QosViolation {
+                violations: violations.join("; "),
+                #[cfg(feature = "serial")]
+                device: Mutex::new(None),
+            })),
+        }
+    }
+}
+
+/// Requirements that a measurement didn't meet, in [Error::QosBelowMinimum].
+/// When returned by [crate::MeasurementHandle::join], it holds the device,
+/// which is stopped and restored like on success.
+pub struct QosViolation {
+    violations: String,
+    // In a mutex, so the error is Sync
+    #[cfg(feature = "serial")]
+    device: Mutex<Option<Box<crate::Ppk2>>>,
+}
+
+impl QosViolation {
+    /// Descriptions of the violated requirements, separated by `; `.
+    pub fn violations(&self) -> &str {
+        &self.violations
+    }
+
+    /// Get the device back, if the violation was returned when
+    /// stopping a measurement.
+    #[cfg(feature = "serial")]
+    pub fn into_device(self) -> Option<crate::Ppk2> {
+        self.device.into_inner().unwrap().map(|ppk2| *ppk2)
+    }
+
+    #[cfg(feature = "serial")]
+    pub(crate) fn with_device(self, ppk2: crate::Ppk2) -> Self {
+        Self {
+            device: Mutex::new(Some(Box::new(ppk2))),
+            ..self
+        }
+    }
+}
+
+impl fmt::Debug for QosViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QosViolation")
+            .field("violations", &self.violations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for QosViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{QosReport, QosRequirements};
+    use crate::Error;
+
+    #[test]
+    pub fn test_qos() {
+        let report = QosReport {
+            requested_sps: 100.,
+            chunks: 990,
+            mean_chunk_len: 1000.,
+            chunk_jitter: 20.,
+            samples: 1_000_000,
+            missed_samples: 500,
+            elapsed: Duration::from_secs(10),
+        };
+        assert_eq!(report.achieved_sps(), 99.);
+        assert_eq!(report.missed_ratio(), 0.0005);
+        assert_eq!(report.relative_jitter(), 0.02);
+
+        let lenient = QosRequirements::new()
+            .min_sps_ratio(0.95)
+            .max_missed_ratio(0.001)
+            .max_relative_jitter(0.05);
+        assert!(lenient.check(&report).is_ok());
+
+        let strict = lenient.min_sps_ratio(0.995).max_missed_ratio(0.0001);
+        let Err(Error::QosBelowMinimum(violation)) = strict.check(&report) else {
+            panic!("Expected a QoS violation");
+        };
+        assert_eq!(violation.violations().matches("; ").count(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+//! Soft ramping of the source voltage when enabling device power, to limit
+//! inrush current into fragile prototypes.
+
+use std::time::Duration;
+
+use crate::types::SourceVoltage;
+
+/// Ramp of the source voltage from a low starting voltage up to the
+/// target voltage, applied in equal steps when device power is enabled.
+/// See [crate::Ppk2::enable_power_ramped] and
+/// [crate::measurement::MeasurementOptions::power_ramp].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerRamp {
+    start_mv: u16,
+    target_mv: u16,
+    duration: Duration,
+    steps: u32,
+}
+
+impl PowerRamp {
+    /// Label of the segment covering the ramp when ramping while measuring.
+    pub const SEGMENT_LABEL: &'static str = "power-ramp";
+
+    /// Create a new [PowerRamp] to the passed target voltage in millivolts,
+    /// taking the passed amount of time. Starts at the minimum source voltage
+    /// of 800 mV, in steps of at most 10 ms.
+    pub fn new(target_mv: u16, duration: Duration) -> Self {
+        Self {
+            start_mv: 800,
+            target_mv,
+            duration,
+            steps: (duration.as_millis() / 10).max(1) as u32,
+        }
+    }
+
+    /// Set the voltage in millivolts the ramp starts at.
+    pub fn from_millivolts(mut self, start_mv: u16) -> Self {
+        self.start_mv = start_mv;
+        self
+    }
+
+    /// Set the number of steps in which the voltage is raised.
+    pub fn steps(mut self, steps: u32) -> Self {
+        self.steps = steps.max(1);
+        self
+    }
+
+    /// Get the time the ramp takes.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Get the [SourceVoltage] the ramp ends at.
+    pub fn target(&self) -> SourceVoltage {
+        SourceVoltage::from_millivolts(self.target_mv)
+    }
+
+    /// Get the time between steps.
+    pub fn step_interval(&self) -> Duration {
+        self.duration / self.steps
+    }
+
+    /// Iterate over the voltages to apply, from the starting
+    /// voltage up to and including the target voltage.
+    pub fn voltages(&self) -> impl Iterator<Item = SourceVoltage> + '_ {
+        let start = f32::from(self.start_mv);
+        let step = (f32::from(self.target_mv) - start) / self.steps as f32;
+        (0..=self.steps)
+            .map(move |i| SourceVoltage::from_millivolts((start + step * i as f32).round() as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PowerRamp;
+
+    #[test]
+    pub fn test_voltages() {
+        let ramp = PowerRamp::new(3000, Duration::from_millis(100))
+            .from_millivolts(1000)
+            .steps(4);
+        let mv: Vec<_> = ramp.voltages().map(|v| v.millivolts()).collect();
+        assert_eq!(mv, [1000, 1500, 2000, 2500, 3000]);
+        assert_eq!(ramp.step_interval(), Duration::from_millis(25));
+    }
+}
@@ -0,0 +1,65 @@
+//! Recovery from brief USB disconnects during a measurement, such as those
+//! caused by a device under test that disturbs the USB connection of the
+//! PPK2. Reads that time out are retried. Without a [ReconnectPolicy], any
+//! other failed read ends the measurement. With one, when a read fails
+//! because the device was disconnected, the serial port is reopened
+//! instead, the metadata is read again, and the device is restarted. The
+//! samples taken in the meantime are lost, and count as missed samples. The port is reopened with the
+//! connection parameters of the [crate::Ppk2Builder], and in case the PPK2
+//! itself was reset, the settings made through the [crate::Ppk2] and its
+//! [crate::ControlHandle] are applied again: the measurement mode, source
+//! voltage, device power, user gains, measurement range, switch points and
+//! hardware averaging.
+
+use std::time::Duration;
+
+/// How to recover from a failed read of the serial port during a measurement.
+/// See [crate::measurement::MeasurementOptions::reconnect].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub(crate) timeout: Duration,
+    pub(crate) interval: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReconnectPolicy {
+    /// Create a new [ReconnectPolicy], trying to reopen the serial port
+    /// every 250 ms, for up to 10 s.
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            interval: Duration::from_millis(250),
+        }
+    }
+
+    /// Set how long to try to reopen the serial port before giving up,
+    /// after which the measurement ends with the error of the failed read.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the interval between attempts to reopen the serial port.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// A gap in a measurement caused by a USB disconnect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disconnection {
+    /// Index of the first sample of the gap, counted from the start of the
+    /// measurement and including missed samples.
+    pub sample: usize,
+    /// Time of that sample since the start of the measurement.
+    pub time: Duration,
+    /// Estimated duration of the gap, from the last successful read until
+    /// the device was restarted. The samples in it count as missed.
+    pub duration: Duration,
+}
@@ -0,0 +1,115 @@
+//! Offline replay of raw dumps, as recorded with
+//! [crate::measurement::MeasurementOptions::raw_dump], through the same
+//! pipeline as a live measurement. This makes parsing issues reproducible,
+//! and allows rerunning analyses deterministically.
+//!
+//! Bytes of a dump don't arrive in real time, so with
+//! [crate::measurement::WindowPolicy::Sps], windows are closed based on the
+//! number of samples only, at the rate of the configured [SampleClock]. Live
+//! measurements additionally close windows on time, and adapt to the rate
+//! at which samples arrive, so they produce the same output only if the
+//! device delivered samples at its nominal rate. Commands the pipeline
+//! would send to the device, like for IR drop emulation or auto-zero
+//! windows, are skipped.
+
+use std::io::Read;
+
+use crate::{
+    clock::SampleClock,
+    measurement::{MeasurementEvent, MeasurementMatch, MeasurementOptions},
+    types::Metadata,
+    worker, Result,
+};
+
+/// Outputs of replaying a raw dump.
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    /// The combined measurements, as sent to the receiver of a live measurement.
+    pub measurements: Vec<MeasurementMatch>,
+    /// The events emitted, as received by
+    /// [crate::MeasurementHandle::subscribe] during a live measurement.
+    pub events: Vec<MeasurementEvent>,
+}
+
+/// Replays raw dumps through the measurement pipeline.
+#[derive(Debug, Clone)]
+pub struct Replayer {
+    metadata: Metadata,
+    options: MeasurementOptions,
+    clock: SampleClock,
+}
+
+impl Replayer {
+    /// Create a new [Replayer], decoding samples with the metadata of the
+    /// device the dump was recorded with, and processing them according
+    /// to the passed [MeasurementOptions].
+    pub fn new(metadata: Metadata, options: MeasurementOptions) -> Self {
+        Self {
+            metadata,
+            options,
+            clock: SampleClock::nominal(),
+        }
+    }
+
+    /// Set the native clock of the device the dump was recorded with.
+    /// Defaults to [SampleClock::nominal]. See [crate::Ppk2::native_clock].
+    pub fn clock(mut self, clock: SampleClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replay the raw dump read from `reader`.
+    pub fn run(&self, mut reader: impl Read) -> Result<Replay> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let (measurements, events) = worker::replay(
+            &bytes,
+            self.metadata.clone(),
+            self.clock,
+            self.options.clone(),
+        )?;
+        Ok(Replay {
+            measurements,
+            events,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Replayer;
+    use crate::{
+        measurement::{MeasurementEvent, MeasurementMatch, MeasurementOptions},
+        types::{Metadata, SampleRate},
+    };
+
+    #[test]
+    pub fn test_replay() {
+        // 1000 samples, of which the 6 after sample 499 are missing
+        let dump: Vec<u8> = (0..1006u32)
+            .filter(|i| !(500..506).contains(i))
+            .flat_map(|i| (100 | (i % 64) << 18).to_le_bytes())
+            .collect();
+        let options = MeasurementOptions::new(SampleRate::per_second(1000).unwrap());
+        let replayer = Replayer::new(Metadata::default(), options);
+        let replay = replayer.run(dump.as_slice()).unwrap();
+
+        // Chunks of 100 received samples
+        assert_eq!(replay.measurements.len(), 10);
+        assert!(replay
+            .measurements
+            .iter()
+            .all(|m| matches!(m, MeasurementMatch::Match(_))));
+        let [MeasurementEvent::SegmentEnd(summary)] = replay.events.as_slice() else {
+            panic!("Expected a single segment end");
+        };
+        assert_eq!((summary.samples, summary.missed), (1000, 6));
+
+        // Replays are deterministic
+        let again = replayer.run(dump.as_slice()).unwrap();
+        assert_eq!(
+            format!("{:?}", again.measurements),
+            format!("{:?}", replay.measurements)
+        );
+    }
+}
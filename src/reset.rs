@@ -0,0 +1,155 @@
+//! Detection of device under test resets from the current signature:
+//! the current drops to near zero while the device is held in reset or
+//! browns out, followed by an inrush as it powers up again.
+
+use std::time::Duration;
+
+use crate::clock::SampleClock;
+
+/// Current signature that is considered a reset of the device under test.
+/// See [crate::measurement::MeasurementOptions::detect_resets].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResetSignature {
+    dip_micro_amps: f32,
+    min_dip: usize,
+    inrush_micro_amps: f32,
+    max_delay: usize,
+}
+
+impl Default for ResetSignature {
+    /// A dip below 1 µA for at least 1 ms, followed by an inrush
+    /// above 10 mA within 5 ms.
+    fn default() -> Self {
+        Self::new()
+            .dip(1., Duration::from_millis(1))
+            .inrush(10_000., Duration::from_millis(5))
+    }
+}
+
+impl ResetSignature {
+    fn new() -> Self {
+        Self {
+            dip_micro_amps: 0.,
+            min_dip: 0,
+            inrush_micro_amps: 0.,
+            max_delay: 0,
+        }
+    }
+
+    /// Set the current in µA the device must drop below, for at least the passed duration.
+    pub fn dip(mut self, micro_amps: f32, min_duration: Duration) -> Self {
+        self.dip_micro_amps = micro_amps;
+        self.min_dip = SampleClock::nominal().samples_in(min_duration).max(1);
+        self
+    }
+
+    /// Set the current in µA the device must exceed within the passed duration after the dip.
+    pub fn inrush(mut self, micro_amps: f32, within: Duration) -> Self {
+        self.inrush_micro_amps = micro_amps;
+        self.max_delay = SampleClock::nominal().samples_in(within);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Idle,
+    Dip { start: usize, len: usize },
+    AwaitInrush { start: usize, since: usize },
+}
+
+/// State machine matching the [ResetSignature] against a stream of samples.
+#[derive(Debug, Clone)]
+pub(crate) struct ResetDetector {
+    signature: ResetSignature,
+    state: State,
+}
+
+impl ResetDetector {
+    pub(crate) fn new(signature: ResetSignature) -> Self {
+        Self {
+            signature,
+            state: State::Idle,
+        }
+    }
+
+    /// Feed the sample with the passed index. Returns the index of
+    /// the first sample of the dip if a reset was detected.
+    pub(crate) fn feed(&mut self, index: usize, micro_amps: f32) -> Option<usize> {
+        let sig = &self.signature;
+        if micro_amps < sig.dip_micro_amps {
+            self.state = match self.state {
+                State::Dip { start, len } => State::Dip {
+                    start,
+                    len: len + 1,
+                },
+                _ => State::Dip {
+                    start: index,
+                    len: 1,
+                },
+            };
+            return None;
+        }
+        let (next, detected) = match self.state {
+            State::Idle => (State::Idle, None),
+            State::Dip { start, len } if len >= sig.min_dip => {
+                if micro_amps > sig.inrush_micro_amps {
+                    (State::Idle, Some(start))
+                } else {
+                    (State::AwaitInrush { start, since: 1 }, None)
+                }
+            }
+            State::Dip { .. } => (State::Idle, None),
+            State::AwaitInrush { start, .. } if micro_amps > sig.inrush_micro_amps => {
+                (State::Idle, Some(start))
+            }
+            State::AwaitInrush { start, since } if since < sig.max_delay => (
+                State::AwaitInrush {
+                    start,
+                    since: since + 1,
+                },
+                None,
+            ),
+            State::AwaitInrush { .. } => (State::Idle, None),
+        };
+        self.state = next;
+        detected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ResetDetector, ResetSignature};
+
+    #[test]
+    pub fn test_detect() {
+        let signature = ResetSignature::default()
+            .dip(1., Duration::from_micros(30))
+            .inrush(1000., Duration::from_micros(20));
+        let mut detector = ResetDetector::new(signature);
+
+        // Too short a dip, then a proper dip followed by a late
+        // inrush, then a proper dip followed by an inrush in time.
+        let trace = [
+            [100.; 5].as_slice(),
+            &[0.; 2],
+            &[5000.; 2],
+            &[0.; 4],
+            &[100.; 3],
+            &[5000.; 2],
+            &[100.; 2],
+            &[0.; 3],
+            &[100.],
+            &[5000.],
+        ]
+        .concat();
+        let detected: Vec<_> = trace
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &ua)| detector.feed(i, ua))
+            .collect();
+        assert_eq!(detected, [20]);
+    }
+}
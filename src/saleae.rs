@@ -0,0 +1,124 @@
+//! Export of measurements in the CSV layout Saleae Logic 2 uses for
+//! its raw data export, so captures can be compared side by side with
+//! Logic 2 protocol captures.
+//!
+//! Logic 2 exports digital and analog channels to separate files, so
+//! this module does too. Timestamps are derived from the sample index
+//! using a [SampleClock], so the measurements should be contiguous.
+//! Use [crate::measurement::MeasurementAccumulator::interpolate_gaps] to
+//! fill gaps of missed samples.
+
+use std::io::Write;
+
+use crate::{clock::SampleClock, measurement::Measurement, Result};
+
+/// Write the logic port pins as digital channels 0 to 7. As in Logic 2
+/// exports, a row is only written for the first sample and for every
+/// sample at which any of the pins changed.
+pub fn write_digital_csv<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+) -> Result<()> {
+    writeln!(
+        writer,
+        "Time [s],Channel 0,Channel 1,Channel 2,Channel 3,Channel 4,Channel 5,Channel 6,Channel 7"
+    )?;
+    write_digital_rows(writer, measurements, clock, 0, &mut None)
+}
+
+/// Write the rows of [write_digital_csv], the first being the sample with
+/// index `first`. `prev` holds the levels of the last row written, if any.
+pub(crate) fn write_digital_rows<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+    first: u64,
+    prev: &mut Option<[u8; 8]>,
+) -> Result<()> {
+    for (i, m) in measurements.into_iter().enumerate() {
+        let levels = m.pins.inner().map(|l| l.is_high() as u8);
+        if *prev == Some(levels) {
+            continue;
+        }
+        write!(
+            writer,
+            "{:.9}",
+            clock.time_at(first + i as u64).as_secs_f64()
+        )?;
+        for level in levels {
+            write!(writer, ",{level}")?;
+        }
+        writeln!(writer)?;
+        *prev = Some(levels);
+    }
+    Ok(())
+}
+
+/// Write the current as analog channel 0, in amperes, one row per sample.
+pub fn write_analog_csv<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+) -> Result<()> {
+    writeln!(writer, "Time [s],Channel 0")?;
+    write_analog_rows(writer, measurements, clock, 0)
+}
+
+/// Write the rows of [write_analog_csv], the first being the sample
+/// with index `first`.
+pub(crate) fn write_analog_rows<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+    first: u64,
+) -> Result<()> {
+    for (i, m) in measurements.into_iter().enumerate() {
+        writeln!(
+            writer,
+            "{:.9},{:e}",
+            clock.time_at(first + i as u64).as_secs_f64(),
+            m.micro_amps as f64 / 1e6
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_analog_csv, write_digital_csv};
+    use crate::{clock::SampleClock, measurement::Measurement};
+
+    #[test]
+    pub fn test_logic2_csv() {
+        let clock = SampleClock::with_rate(1000.);
+        let measurements: Vec<_> = [(0b01, 1000.), (0b01, 2.5), (0b11, 250.), (0b11, 0.)]
+            .into_iter()
+            .map(|(pins, micro_amps): (u8, f32)| Measurement {
+                micro_amps,
+                pins: pins.into(),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut digital = Vec::new();
+        write_digital_csv(&mut digital, &measurements, &clock).unwrap();
+        assert_eq!(
+            String::from_utf8(digital).unwrap(),
+            "Time [s],Channel 0,Channel 1,Channel 2,Channel 3,Channel 4,Channel 5,Channel 6,Channel 7\n\
+             0.000000000,1,0,0,0,0,0,0,0\n\
+             0.002000000,1,1,0,0,0,0,0,0\n"
+        );
+
+        let mut analog = Vec::new();
+        write_analog_csv(&mut analog, &measurements, &clock).unwrap();
+        assert_eq!(
+            String::from_utf8(analog).unwrap(),
+            "Time [s],Channel 0\n\
+             0.000000000,1e-3\n\
+             0.001000000,2.5e-6\n\
+             0.002000000,2.5e-4\n\
+             0.003000000,0e0\n"
+        );
+    }
+}
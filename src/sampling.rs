@@ -0,0 +1,80 @@
+//! Selection of how a requested number of measurements per second is
+//! reached: by averaging on the device, by combining samples on the host,
+//! or both.
+//!
+//! Hardware averaging lowers the data rate over USB, which relieves slow
+//! hosts and reduces the chance of lost samples, but the device then only
+//! reports the average current and the logic port state of the last ADC
+//! sample of each average. Short pulses on the logic port may be missed, and
+//! pin matching and tags work on the coarser samples. Host downsampling
+//! keeps every sample available to the pipeline, at the cost of CPU time
+//! and USB bandwidth. A [SamplingPlan] averages on the device as much as
+//! allowed, and combines the remainder on the host.
+
+use crate::clock::SampleClock;
+
+/// How a requested number of measurements per second is reached.
+/// See [crate::measurement::MeasurementOptions::hardware_averaging].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplingPlan {
+    /// Number of ADC samples the device averages into one sample.
+    /// 1 means no hardware averaging.
+    pub hardware_averages: u16,
+    /// Number of device samples combined into one measurement on the host.
+    pub host_chunk: usize,
+}
+
+impl SamplingPlan {
+    /// Plan how to produce `sps` measurements per second, averaging at most
+    /// `max_hardware_averages` samples on the device.
+    pub fn for_sps(sps: usize, max_hardware_averages: u16) -> Self {
+        let total = SampleClock::nominal().chunk_len(sps);
+        let hardware_averages = total.min(usize::from(max_hardware_averages.max(1))) as u16;
+        Self {
+            hardware_averages,
+            host_chunk: (total / usize::from(hardware_averages)).max(1),
+        }
+    }
+
+    /// The nominal number of samples per second the device sends.
+    pub fn device_rate(&self) -> f64 {
+        SampleClock::NOMINAL_RATE as f64 / f64::from(self.hardware_averages)
+    }
+
+    /// The nominal number of measurements per second produced.
+    pub fn output_sps(&self) -> f64 {
+        self.device_rate() / self.host_chunk as f64
+    }
+}
+
+impl Default for SamplingPlan {
+    /// No hardware averaging, and no host downsampling.
+    fn default() -> Self {
+        Self {
+            hardware_averages: 1,
+            host_chunk: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SamplingPlan;
+
+    #[test]
+    pub fn test_sampling_plan() {
+        // Everything on the device if allowed
+        let plan = SamplingPlan::for_sps(1000, 1000);
+        assert_eq!((plan.hardware_averages, plan.host_chunk), (100, 1));
+        assert_eq!(plan.output_sps(), 1000.);
+
+        // The remainder on the host
+        let plan = SamplingPlan::for_sps(100, 10);
+        assert_eq!((plan.hardware_averages, plan.host_chunk), (10, 100));
+        assert_eq!(plan.device_rate(), 10_000.);
+
+        // No hardware averaging
+        let plan = SamplingPlan::for_sps(100, 1);
+        assert_eq!((plan.hardware_averages, plan.host_chunk), (1, 1000));
+    }
+}
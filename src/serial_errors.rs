@@ -0,0 +1,146 @@
+//! Detection of data lost between the device and the host. Where the
+//! platform allows, the error counters of the serial driver are queried.
+//! As the PPK2 sample counter wraps every 64 samples, losing a multiple of
+//! 64 samples goes unnoticed by the counter, so the number of samples
+//! received is also compared to the number the device should have sent.
+
+use std::time::Duration;
+
+use serialport::{SerialPort, SerialPortBuilder};
+
+use crate::{clock::SampleClock, Result};
+
+/// Error counters kept by the serial driver of the operating system.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OsSerialCounters {
+    /// Number of hardware receive overruns.
+    pub overrun: u64,
+    /// Number of receive buffer overruns in the driver.
+    pub buf_overrun: u64,
+    /// Number of framing errors.
+    pub frame: u64,
+    /// Number of parity errors.
+    pub parity: u64,
+}
+
+impl OsSerialCounters {
+    /// The counts since the passed earlier reading.
+    pub fn since(&self, earlier: &OsSerialCounters) -> OsSerialCounters {
+        OsSerialCounters {
+            overrun: self.overrun.saturating_sub(earlier.overrun),
+            buf_overrun: self.buf_overrun.saturating_sub(earlier.buf_overrun),
+            frame: self.frame.saturating_sub(earlier.frame),
+            parity: self.parity.saturating_sub(earlier.parity),
+        }
+    }
+
+    /// Total number of errors.
+    pub fn total(&self) -> u64 {
+        self.overrun + self.buf_overrun + self.frame + self.parity
+    }
+}
+
+/// Serial error statistics of a running measurement.
+/// See [crate::MeasurementHandle::serial_errors].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SerialErrors {
+    /// Errors counted by the serial driver since the measurement started,
+    /// or [None] if the platform or driver doesn't support reading them.
+    pub os: Option<OsSerialCounters>,
+    /// Number of samples received or detected missing by the sample counter.
+    pub samples: u64,
+    /// Time since the measurement started.
+    pub elapsed: Duration,
+}
+
+impl SerialErrors {
+    /// Minimum measurement duration for [SerialErrors::suspects_data_loss]
+    /// to take the number of received samples into account.
+    const MIN_ELAPSED: Duration = Duration::from_secs(1);
+
+    /// Number of samples the device should have sent.
+    pub fn expected_samples(&self) -> u64 {
+        SampleClock::nominal().samples_in(self.elapsed) as u64
+    }
+
+    /// Number of samples lost without being noticed by the sample counter.
+    pub fn suspected_lost_samples(&self) -> u64 {
+        self.expected_samples().saturating_sub(self.samples)
+    }
+
+    /// Whether data appears to be lost: either the serial driver counted
+    /// errors, or more than 1% of the samples went missing unnoticed.
+    pub fn suspects_data_loss(&self) -> bool {
+        let os_errors = self.os.is_some_and(|os| os.total() > 0);
+        let unnoticed_loss = self.elapsed >= Self::MIN_ELAPSED
+            && self.suspected_lost_samples() > self.expected_samples() / 100;
+        os_errors || unnoticed_loss
+    }
+}
+
+/// Reads the error counters of the serial driver, if supported.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OsCounterSource {
+    #[cfg(target_os = "linux")]
+    fd: Option<std::os::unix::io::RawFd>,
+}
+
+impl OsCounterSource {
+    /// Read the current counters.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn read(&self) -> Option<OsSerialCounters> {
+        /// `struct serial_icounter_struct` from `linux/serial.h`
+        #[repr(C)]
+        #[derive(Default)]
+        struct SerialIcounter {
+            cts: libc::c_int,
+            dsr: libc::c_int,
+            rng: libc::c_int,
+            dcd: libc::c_int,
+            rx: libc::c_int,
+            tx: libc::c_int,
+            frame: libc::c_int,
+            overrun: libc::c_int,
+            parity: libc::c_int,
+            brk: libc::c_int,
+            buf_overrun: libc::c_int,
+            reserved: [libc::c_int; 9],
+        }
+
+        let fd = self.fd?;
+        let mut counters = SerialIcounter::default();
+        // SAFETY: the fd belongs to the open port, and TIOCGICOUNT
+        // writes a serial_icounter_struct to the passed pointer
+        let res = unsafe { libc::ioctl(fd, libc::TIOCGICOUNT, &mut counters) };
+        (res == 0).then_some(OsSerialCounters {
+            overrun: counters.overrun as u64,
+            buf_overrun: counters.buf_overrun as u64,
+            frame: counters.frame as u64,
+            parity: counters.parity as u64,
+        })
+    }
+
+    /// Read the current counters.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn read(&self) -> Option<OsSerialCounters> {
+        None
+    }
+}
+
+/// Open the serial port, keeping what's needed to read its error counters.
+pub(crate) fn open(builder: SerialPortBuilder) -> Result<(Box<dyn SerialPort>, OsCounterSource)> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let port = builder.open_native()?;
+        let source = OsCounterSource {
+            fd: Some(port.as_raw_fd()),
+        };
+        Ok((Box::new(port), source))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok((builder.open()?, OsCounterSource::default()))
+    }
+}
@@ -0,0 +1,243 @@
+//! Recorded measurement sessions, for analysis after capturing.
+
+use std::time::Duration;
+
+use crate::{
+    autozero::ZeroCorrection,
+    battery::{IrDropModel, IrDropUpdate},
+    clock::SampleClock,
+    measurement::{
+        Measurement, MeasurementEvent, MeasurementIterExt, MeasurementMatch, PinVote,
+        SegmentSummary,
+    },
+    reconnect::Disconnection,
+    suspend::Suspension,
+    types::Metadata,
+};
+
+/// A recorded capture: a series of uniformly sampled [Measurement]s, along
+/// with the [SampleClock] they were sampled at.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    /// Metadata of the device the session was recorded with, if known.
+    pub metadata: Option<Metadata>,
+    /// The clock the measurements were sampled at.
+    pub clock: SampleClock,
+    /// The recorded measurements.
+    pub measurements: Vec<Measurement>,
+    /// Duration of the [crate::ramp::PowerRamp] at the start of the session, if any.
+    pub power_ramp: Option<Duration>,
+    /// Offset corrections made by [crate::autozero] during the session.
+    pub corrections: Vec<ZeroCorrection>,
+    /// Gaps caused by the host being suspended during the session.
+    pub suspensions: Vec<Suspension>,
+    /// Gaps caused by USB disconnects during the session.
+    pub disconnections: Vec<Disconnection>,
+    /// Labeled segments of the session, as closed by
+    /// [crate::MeasurementHandle::next_segment].
+    pub segments: Vec<SegmentSummary>,
+    /// The internal resistance model emulated during the session, if any.
+    pub ir_drop: Option<IrDropModel>,
+    /// Source voltage updates made by the internal resistance emulation.
+    pub ir_drop_updates: Vec<IrDropUpdate>,
+}
+
+impl Session {
+    /// Create a new, empty [Session] sampled at the passed [SampleClock].
+    pub fn new(clock: SampleClock) -> Self {
+        Self {
+            metadata: None,
+            clock,
+            measurements: Vec::new(),
+            power_ramp: None,
+            corrections: Vec::new(),
+            suspensions: Vec::new(),
+            disconnections: Vec::new(),
+            segments: Vec::new(),
+            ir_drop: None,
+            ir_drop_updates: Vec::new(),
+        }
+    }
+
+    /// Create a new [Session] with the passed [Measurement]s.
+    pub fn from_measurements(clock: SampleClock, measurements: Vec<Measurement>) -> Self {
+        Self {
+            metadata: None,
+            clock,
+            measurements,
+            power_ramp: None,
+            corrections: Vec::new(),
+            suspensions: Vec::new(),
+            disconnections: Vec::new(),
+            segments: Vec::new(),
+            ir_drop: None,
+            ir_drop_updates: Vec::new(),
+        }
+    }
+
+    /// Set the metadata of the device the session was recorded with.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Record that the session starts with a [crate::ramp::PowerRamp] of the passed duration.
+    pub fn with_power_ramp(mut self, duration: Duration) -> Self {
+        self.power_ramp = Some(duration);
+        self
+    }
+
+    /// The measurements taken after the power ramp, if any, completed.
+    pub fn settled(&self) -> &[Measurement] {
+        let skip = self.power_ramp.map_or(0, |d| self.clock.samples_in(d));
+        &self.measurements[skip.min(self.measurements.len())..]
+    }
+
+    /// Append a combined measurement as received from [crate::Ppk2::start_measurement_with].
+    /// [MeasurementMatch::NoMatch] is ignored.
+    pub fn push(&mut self, measurement: MeasurementMatch) {
+        if let MeasurementMatch::Match(m) = measurement {
+            self.measurements.push(m);
+        }
+    }
+
+    /// Record the relevant details of an event as received from
+    /// [crate::MeasurementHandle::subscribe]. Currently, only
+    /// [MeasurementEvent::AutoZero] corrections, the gaps of
+    /// [MeasurementEvent::HostSuspended] and [MeasurementEvent::Reconnected],
+    /// the segments of [MeasurementEvent::SegmentEnd], and the model and
+    /// voltage updates of [MeasurementEvent::IrDrop] are recorded.
+    pub fn record(&mut self, event: &MeasurementEvent) {
+        match event {
+            MeasurementEvent::AutoZero(correction) => self.corrections.push(*correction),
+            MeasurementEvent::HostSuspended(suspension) => self.suspensions.push(*suspension),
+            MeasurementEvent::Reconnected(gap) => self.disconnections.push(*gap),
+            MeasurementEvent::SegmentEnd(segment) => self.segments.push(segment.clone()),
+            MeasurementEvent::IrDrop(update) => {
+                self.ir_drop = Some(update.model);
+                self.ir_drop_updates.push(*update);
+            }
+            _ => {}
+        }
+    }
+
+    /// Number of recorded measurements.
+    pub fn len(&self) -> usize {
+        self.measurements.len()
+    }
+
+    /// Whether the session contains no measurements.
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty()
+    }
+
+    /// Duration of the session.
+    pub fn duration(&self) -> Duration {
+        self.clock.time_at(self.measurements.len() as u64)
+    }
+
+    /// Resample the session at the passed number of samples per second.
+    /// When downsampling, each output sample is the average of the input
+    /// samples it spans, and its pin levels are decided by majority. When
+    /// upsampling, the current is interpolated linearly, pin levels are
+    /// taken from the preceding input sample, and the index and time are
+    /// those of the output sample at the new rate.
+    pub fn resample(&self, rate: f64) -> Session {
+        let clock = SampleClock::with_rate(rate);
+        let ratio = self.clock.rate() / clock.rate();
+        let out_len = (self.measurements.len() as f64 / ratio).floor() as usize;
+        let input = &self.measurements;
+
+        let measurements = (0..out_len)
+            .map(|k| {
+                let pos = k as f64 * ratio;
+                if ratio > 1. {
+                    let start = pos.round() as usize;
+                    let end = (((k + 1) as f64 * ratio).round() as usize).min(input.len());
+                    let bin = &input[start..end.max(start + 1)];
+                    match bin.iter().cloned().combine_with(PinVote::Majority) {
+                        MeasurementMatch::Match(m) => m,
+                        // Only synthetic samples in this bin
+                        MeasurementMatch::NoMatch => Measurement {
+                            micro_amps: bin.iter().map(|m| m.micro_amps).sum::<f32>()
+                                / bin.len() as f32,
+                            pins: bin[0].pins,
+                            synthetic: true,
+                            envelope: None,
+                            ..bin[bin.len() - 1].clone()
+                        },
+                    }
+                } else {
+                    let i = pos.floor() as usize;
+                    let a = &input[i];
+                    let b = input.get(i + 1).unwrap_or(a);
+                    let frac = (pos - i as f64) as f32;
+                    Measurement {
+                        micro_amps: a.micro_amps + (b.micro_amps - a.micro_amps) * frac,
+                        pins: a.pins,
+                        synthetic: a.synthetic || (frac > 0. && b.synthetic),
+                        envelope: None,
+                        range: a.range,
+                        adc: a.adc,
+                        index: k as u64,
+                        time: clock.time_at(k as u64),
+                    }
+                }
+            })
+            .collect();
+
+        Session {
+            metadata: self.metadata.clone(),
+            clock,
+            measurements,
+            power_ramp: self.power_ramp,
+            corrections: self.corrections.clone(),
+            suspensions: self.suspensions.clone(),
+            disconnections: self.disconnections.clone(),
+            segments: self.segments.clone(),
+            ir_drop: self.ir_drop,
+            ir_drop_updates: self.ir_drop_updates.clone(),
+        }
+    }
+}
+
+impl Extend<Measurement> for Session {
+    fn extend<T: IntoIterator<Item = Measurement>>(&mut self, iter: T) {
+        self.measurements.extend(iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Session;
+    use crate::{clock::SampleClock, measurement::Measurement};
+
+    #[test]
+    pub fn test_resample() {
+        let clock = SampleClock::with_rate(1000.);
+        let measurements = (0..1000)
+            .map(|i| Measurement {
+                micro_amps: i as f32,
+                pins: 0u8.into(),
+                synthetic: false,
+                envelope: None,
+                index: i,
+                time: clock.time_at(i),
+                ..Default::default()
+            })
+            .collect();
+        let session = Session::from_measurements(clock, measurements);
+
+        let down = session.resample(10.);
+        assert_eq!(down.len(), 10);
+        // Average of 0..100
+        assert_eq!(down.measurements[0].micro_amps, 49.5);
+        assert_eq!(down.duration(), session.duration());
+
+        let up = session.resample(2000.);
+        assert_eq!(up.len(), 2000);
+        assert_eq!(up.measurements[3].micro_amps, 1.5);
+        assert_eq!(up.measurements[3].index, 3);
+        assert!(up.measurements.windows(2).all(|w| w[0].time < w[1].time));
+    }
+}
@@ -0,0 +1,168 @@
+//! Cache of device settings, keyed by device serial number, so a device
+//! comes up in the same state after being reconnected.
+//! See [crate::Ppk2::open_with_cached_settings]. The settings file of a
+//! device also serves as its profile for unattended measurements, such as
+//! the command to notify on events with.
+
+use std::{fmt::Display, fs, io, path::PathBuf, str::FromStr};
+
+use crate::{
+    notify::CommandNotifier,
+    types::{DevicePower, MeasurementMode, SourceVoltage},
+    Error, Result,
+};
+
+/// Settings of a device that are cached.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CachedSettings {
+    /// Measurement mode
+    pub mode: MeasurementMode,
+    /// Source voltage, if set
+    pub vdd: Option<SourceVoltage>,
+    /// Device power
+    pub power: DevicePower,
+    /// User gains of the 5 measurement ranges, if set.
+    /// See [crate::Ppk2::set_user_gain].
+    pub user_gains: Option<[f32; 5]>,
+    /// Command to run on measurement events, with its arguments separated
+    /// by whitespace. Only set by editing the settings file, as
+    /// `notify: <command> [args...]`. See [CachedSettings::notifier].
+    pub notify: Option<String>,
+}
+
+impl CachedSettings {
+    /// Directory in which settings are cached: `ppk2` in the user's configuration
+    /// directory, i.e. `$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`.
+    pub fn cache_dir() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+        Some(config.join("ppk2"))
+    }
+
+    fn path(serial_number: &str) -> Option<PathBuf> {
+        // Serial numbers are hex strings, but don't trust them blindly
+        let name: String = serial_number
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        Self::cache_dir().map(|d| d.join(format!("{name}.conf")))
+    }
+
+    /// Load the cached settings of the device with the passed serial number.
+    /// Returns [None] if there are none.
+    pub fn load(serial_number: &str) -> Result<Option<Self>> {
+        let Some(path) = Self::path(serial_number) else {
+            return Ok(None);
+        };
+        match fs::read_to_string(path) {
+            Ok(s) => Ok(Some(s.parse()?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get a [CommandNotifier] running the configured notify command, if any.
+    pub fn notifier(&self) -> Option<CommandNotifier> {
+        let mut words = self.notify.as_deref()?.split_whitespace();
+        let notifier = CommandNotifier::new(words.next()?);
+        Some(words.fold(notifier, CommandNotifier::arg))
+    }
+
+    /// Cache the settings of the device with the passed serial number.
+    pub fn save(&self, serial_number: &str) -> Result<()> {
+        let path = Self::path(serial_number)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No configuration directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl Display for CachedSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "mode: {}", u8::from(self.mode))?;
+        if let Some(vdd) = self.vdd {
+            writeln!(f, "vdd: {}", vdd.millivolts())?;
+        }
+        writeln!(f, "power: {}", u8::from(self.power))?;
+        if let Some(gains) = self.user_gains {
+            let gains: Vec<_> = gains.iter().map(f32::to_string).collect();
+            writeln!(f, "user_gains: {}", gains.join(" "))?;
+        }
+        if let Some(notify) = &self.notify {
+            writeln!(f, "notify: {notify}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CachedSettings {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        use Error::Parse;
+
+        let mut settings = Self::default();
+        for line in s.lines().filter(|l| !l.trim().is_empty()) {
+            let parse_u8 = |v: &str| v.parse::<u8>().map_err(|_| Parse(line.to_owned()));
+            match line.split_once(": ") {
+                Some(("mode", mode)) => {
+                    settings.mode = parse_u8(mode)?
+                        .try_into()
+                        .map_err(|_| Parse(line.to_owned()))?
+                }
+                Some(("vdd", vdd)) => {
+                    settings.vdd = Some(vdd.parse().map_err(|_| Parse(line.to_owned()))?)
+                }
+                Some(("power", power)) => {
+                    settings.power = parse_u8(power)?
+                        .try_into()
+                        .map_err(|_| Parse(line.to_owned()))?
+                }
+                Some(("user_gains", gains)) => {
+                    let gains: Vec<f32> = gains
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<std::result::Result<_, _>>()
+                        .map_err(|_| Parse(line.to_owned()))?;
+                    settings.user_gains =
+                        Some(gains.try_into().map_err(|_| Parse(line.to_owned()))?)
+                }
+                Some(("notify", notify)) => settings.notify = Some(notify.trim().to_owned()),
+                _ => return Err(Parse(line.to_owned())),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CachedSettings;
+    use crate::types::{DevicePower, MeasurementMode, SourceVoltage};
+
+    #[test]
+    pub fn test_round_trip() {
+        let settings = CachedSettings {
+            mode: MeasurementMode::Ampere,
+            vdd: Some(SourceVoltage::from_millivolts(3300)),
+            power: DevicePower::Enabled,
+            user_gains: Some([1., 1., 1.5, 0.998, 1.]),
+            notify: Some("curl -d @- https://example.com/hook".to_owned()),
+        };
+        let parsed: CachedSettings = settings.to_string().parse().unwrap();
+        assert_eq!(parsed, settings);
+        assert!(parsed.notifier().is_some());
+        assert!(CachedSettings::default().notifier().is_none());
+        assert_eq!(parsed.vdd.unwrap().millivolts(), 3300);
+        assert!("vdd: lots".parse::<CachedSettings>().is_err());
+        assert!("user_gains: 1 1 1".parse::<CachedSettings>().is_err());
+        // Files cached by older versions have no user gains
+        let old: CachedSettings = "mode: 1\npower: 0\n".parse().unwrap();
+        assert_eq!(old.user_gains, None);
+    }
+}
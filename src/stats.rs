@@ -0,0 +1,353 @@
+//! Streaming statistics of measured currents, which can be queried at any
+//! time without storing the measurements.
+
+use std::time::Duration;
+
+use crate::{clock::SampleClock, measurement::Measurement};
+
+/// Estimates a single percentile using the P² algorithm by Jain and
+/// Chlamtac, which keeps five markers instead of all observations.
+#[derive(Debug, Clone)]
+struct P2Quantile {
+    p: f64,
+    /// Marker heights
+    q: [f64; 5],
+    /// Marker positions
+    n: [f64; 5],
+    /// Desired marker positions
+    desired: [f64; 5],
+    /// Increments of the desired marker positions
+    increments: [f64; 5],
+    /// The first observations, until there are enough to place the markers
+    initial: Vec<f64>,
+}
+
+impl P2Quantile {
+    fn new(p: f64) -> Self {
+        let p = p.clamp(0., 1.);
+        Self {
+            p,
+            q: [0.; 5],
+            n: [1., 2., 3., 4., 5.],
+            desired: [1., 1. + 2. * p, 1. + 4. * p, 3. + 2. * p, 5.],
+            increments: [0., p / 2., p, (1. + p) / 2., 1.],
+            initial: Vec::with_capacity(5),
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        if self.initial.len() < 5 {
+            self.initial.push(x);
+            if self.initial.len() == 5 {
+                self.initial.sort_by(f64::total_cmp);
+                self.q.copy_from_slice(&self.initial);
+            }
+            return;
+        }
+
+        let q = &mut self.q;
+        let k = if x < q[0] {
+            q[0] = x;
+            0
+        } else if x >= q[4] {
+            q[4] = x;
+            3
+        } else {
+            (0..4).find(|&i| x < q[i + 1]).unwrap_or(3)
+        };
+        self.n[k + 1..].iter_mut().for_each(|n| *n += 1.);
+        self.desired
+            .iter_mut()
+            .zip(self.increments)
+            .for_each(|(d, inc)| *d += inc);
+
+        for i in 1..4 {
+            let d = self.desired[i] - self.n[i];
+            if (d >= 1. && self.n[i + 1] - self.n[i] > 1.)
+                || (d <= -1. && self.n[i - 1] - self.n[i] < -1.)
+            {
+                let d = d.signum();
+                let parabolic = self.parabolic(i, d);
+                self.q[i] = if self.q[i - 1] < parabolic && parabolic < self.q[i + 1] {
+                    parabolic
+                } else {
+                    self.linear(i, d)
+                };
+                self.n[i] += d;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.q, &self.n);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let j = if d > 0. { i + 1 } else { i - 1 };
+        self.q[i] + d * (self.q[j] - self.q[i]) / (self.n[j] - self.n[i])
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        if self.initial.len() == 5 {
+            return Some(self.q[2]);
+        }
+        // Too few observations for the markers: use the nearest rank
+        let mut sorted = self.initial.clone();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (self.p * (sorted.len() as f64 - 1.)).round() as usize;
+        sorted.get(rank).copied()
+    }
+}
+
+/// Streaming statistics of currents: minimum, maximum, mean and variance,
+/// computed with Welford's algorithm, and estimates of configurable
+/// percentiles. Feed it currents with [Stats::add], or [Measurement]s
+/// through [Extend], which skips synthetic measurements.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    count: u64,
+    min: f32,
+    max: f32,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    percentiles: Vec<P2Quantile>,
+}
+
+impl Stats {
+    /// Create a new, empty [Stats], without percentiles.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also estimate the passed percentiles, given as fractions
+    /// between 0 and 1, e.g. 0.99 for the 99th percentile.
+    pub fn percentiles(mut self, percentiles: &[f64]) -> Self {
+        self.percentiles = percentiles.iter().map(|&p| P2Quantile::new(p)).collect();
+        self
+    }
+
+    /// Add a current in µA.
+    pub fn add(&mut self, micro_amps: f32) {
+        if self.count == 0 {
+            (self.min, self.max) = (micro_amps, micro_amps);
+        } else {
+            self.min = self.min.min(micro_amps);
+            self.max = self.max.max(micro_amps);
+        }
+        self.count += 1;
+        let x = f64::from(micro_amps);
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+        self.percentiles.iter_mut().for_each(|p| p.add(x));
+    }
+
+    /// Number of currents added.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The lowest current in µA, or [None] if nothing was added.
+    pub fn min(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// The highest current in µA, or [None] if nothing was added.
+    pub fn max(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// The mean current in µA, or [None] if nothing was added.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    /// The sample variance in µA², or [None] if fewer than two
+    /// currents were added.
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 1).then(|| self.m2 / (self.count - 1) as f64)
+    }
+
+    /// The sample standard deviation in µA, or [None] if fewer than
+    /// two currents were added.
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    /// The estimate of the passed percentile in µA, or [None] if it wasn't
+    /// configured with [Stats::percentiles], or nothing was added.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        self.percentiles
+            .iter()
+            .find(|q| q.p == p)
+            .and_then(P2Quantile::estimate)
+    }
+}
+
+impl<'a> Extend<&'a Measurement> for Stats {
+    fn extend<T: IntoIterator<Item = &'a Measurement>>(&mut self, iter: T) {
+        iter.into_iter()
+            .filter(|m| !m.synthetic)
+            .for_each(|m| self.add(m.micro_amps));
+    }
+}
+
+/// A bucket of a [Histogram].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    /// Inclusive lower bound in µA, or [None] for the bucket below
+    /// the lowest boundary
+    pub lower: Option<f32>,
+    /// Exclusive upper bound in µA, or [None] for the bucket at or
+    /// above the highest boundary
+    pub upper: Option<f32>,
+    /// Number of currents in the bucket
+    pub count: u64,
+}
+
+impl Bucket {
+    /// The time spent in the bucket, with each current spanning one sample
+    /// period of the passed clock.
+    pub fn duration(&self, clock: &SampleClock) -> Duration {
+        clock.time_at(self.count)
+    }
+}
+
+/// Histogram of currents over configurable bucket boundaries, e.g. to see
+/// how much time a device spends sleeping versus with the radio active.
+/// Besides the buckets between the boundaries, there is a bucket below
+/// the lowest and one at or above the highest boundary. Feed it currents
+/// with [Histogram::add], or [Measurement]s through [Extend], which skips
+/// synthetic measurements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    boundaries: Vec<f32>,
+    /// One more count than boundaries
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Create a new [Histogram] with the passed bucket boundaries in µA.
+    /// The boundaries are sorted, and duplicates and non-finite values
+    /// are removed.
+    pub fn new(boundaries: impl IntoIterator<Item = f32>) -> Self {
+        let mut boundaries: Vec<_> = boundaries.into_iter().filter(|b| b.is_finite()).collect();
+        boundaries.sort_by(f32::total_cmp);
+        boundaries.dedup();
+        let counts = vec![0; boundaries.len() + 1];
+        Self { boundaries, counts }
+    }
+
+    /// Create a new [Histogram] with `buckets` equally wide buckets
+    /// from `min` to `max` µA.
+    pub fn linear(min: f32, max: f32, buckets: usize) -> Self {
+        let buckets = buckets.max(1);
+        let width = (max - min) / buckets as f32;
+        Self::new((0..=buckets).map(|i| min + width * i as f32))
+    }
+
+    /// Create a new [Histogram] with logarithmic buckets from `min` to
+    /// `max` µA, with `per_decade` buckets per factor of ten.
+    /// `min` must be positive, e.g. 0.1 µA.
+    pub fn logarithmic(min: f32, max: f32, per_decade: usize) -> Self {
+        let per_decade = per_decade.max(1) as f64;
+        let (min, max) = (f64::from(min), f64::from(max));
+        let buckets = ((max / min).log10() * per_decade).ceil().max(1.) as usize;
+        Self::new((0..=buckets).map(|i| (min * 10f64.powf(i as f64 / per_decade)) as f32))
+    }
+
+    /// Add a current in µA. Non-finite currents are ignored.
+    pub fn add(&mut self, micro_amps: f32) {
+        if micro_amps.is_finite() {
+            let i = self.boundaries.partition_point(|&b| b <= micro_amps);
+            self.counts[i] += 1;
+        }
+    }
+
+    /// Total number of currents added.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The buckets, from low to high, including the buckets below the
+    /// lowest and at or above the highest boundary.
+    pub fn buckets(&self) -> impl Iterator<Item = Bucket> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| Bucket {
+            lower: i.checked_sub(1).map(|i| self.boundaries[i]),
+            upper: self.boundaries.get(i).copied(),
+            count,
+        })
+    }
+
+    /// The fraction of currents at or above `lower` and below `upper` µA,
+    /// summed over the buckets that lie within that range.
+    pub fn fraction(&self, lower: f32, upper: f32) -> f64 {
+        let total = self.count();
+        if total == 0 {
+            return 0.;
+        }
+        let within: u64 = self
+            .buckets()
+            .filter(|b| b.lower.is_some_and(|l| l >= lower) && b.upper.is_some_and(|u| u <= upper))
+            .map(|b| b.count)
+            .sum();
+        within as f64 / total as f64
+    }
+}
+
+impl<'a> Extend<&'a Measurement> for Histogram {
+    fn extend<T: IntoIterator<Item = &'a Measurement>>(&mut self, iter: T) {
+        iter.into_iter()
+            .filter(|m| !m.synthetic)
+            .for_each(|m| self.add(m.micro_amps));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, Stats};
+    use crate::clock::SampleClock;
+
+    #[test]
+    pub fn test_stats() {
+        let mut stats = Stats::new().percentiles(&[0.5, 0.9]);
+        assert_eq!(stats.mean(), None);
+        assert_eq!(stats.percentile(0.5), None);
+
+        // 1..=1000 in a scrambled order
+        (0..1000u32).for_each(|i| stats.add((i * 617 % 1000 + 1) as f32));
+        assert_eq!(stats.count(), 1000);
+        assert_eq!((stats.min(), stats.max()), (Some(1.), Some(1000.)));
+        assert!((stats.mean().unwrap() - 500.5).abs() < 1e-9);
+        // Variance of 1..=n is n(n+1)/12
+        assert!((stats.variance().unwrap() - 1000. * 1001. / 12.).abs() < 1e-6);
+        assert!((stats.percentile(0.5).unwrap() - 500.).abs() < 10.);
+        assert!((stats.percentile(0.9).unwrap() - 900.).abs() < 10.);
+        assert_eq!(stats.percentile(0.99), None);
+    }
+
+    #[test]
+    pub fn test_histogram() {
+        let log = Histogram::logarithmic(1., 1000., 1);
+        let bounds: Vec<_> = log.buckets().filter_map(|b| b.lower).collect();
+        assert_eq!(bounds, [1., 10., 100., 1000.]);
+
+        let mut hist = Histogram::new([10., 1., 1000.]);
+        [0.5, 1., 5., 10., 500., 999., 1000., 2000., f32::NAN]
+            .into_iter()
+            .for_each(|c| hist.add(c));
+        let counts: Vec<_> = hist.buckets().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 2, 3, 2]);
+        assert_eq!(hist.count(), 8);
+        assert!((hist.fraction(1., 1000.) - 5. / 8.).abs() < 1e-12);
+
+        let last = hist.buckets().last().unwrap();
+        assert_eq!((last.lower, last.upper), (Some(1000.), None));
+        let clock = SampleClock::nominal();
+        assert_eq!(last.duration(&clock), std::time::Duration::from_micros(20));
+    }
+}
@@ -0,0 +1,138 @@
+//! [Stream] adapter for the measurement [Receiver], so the measurement
+//! pipeline can be `select!`-ed against other async events without
+//! switching to the async API.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{mpsc::Receiver, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use futures_core::Stream;
+
+#[derive(Default)]
+struct Shared<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// Wraps a [Receiver], like the one returned by
+/// [crate::Ppk2::start_measurement_with], into a [Stream].
+///
+/// A forwarding thread blocks on the receiver and wakes the stream when
+/// items arrive. The stream ends when all senders are dropped, i.e. when
+/// the measurement is stopped. If the stream is dropped first, the thread
+/// keeps running until then.
+pub struct ReceiverStream<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T: Send + 'static> ReceiverStream<T> {
+    /// Start forwarding the items received by `rx` to the stream.
+    pub fn new(rx: Receiver<T>) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            items: VecDeque::new(),
+            waker: None,
+            closed: false,
+        }));
+        let forward = shared.clone();
+        thread::spawn(move || loop {
+            let item = rx.recv();
+            let mut shared = forward.lock().unwrap();
+            match item {
+                Ok(item) => shared.items.push_back(item),
+                Err(_) => shared.closed = true,
+            }
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+            // Stop once either side is gone
+            if shared.closed || Arc::strong_count(&forward) == 1 {
+                break;
+            }
+        });
+        Self { shared }
+    }
+}
+
+impl<T> From<Receiver<T>> for ReceiverStream<T>
+where
+    T: Send + 'static,
+{
+    fn from(rx: Receiver<T>) -> Self {
+        Self::new(rx)
+    }
+}
+
+impl<T> Stream for ReceiverStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if shared.closed => Poll::Ready(None),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::mpsc,
+        task::{Context, Poll, Wake, Waker},
+        thread,
+    };
+
+    use futures_core::Stream;
+
+    use super::ReceiverStream;
+
+    /// Waker that unparks the polling thread
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = std::pin::pin!(fut);
+        let waker = Waker::from(std::sync::Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_receiver_stream() {
+        let (tx, rx) = mpsc::channel();
+        let mut stream = ReceiverStream::new(rx);
+        let sender = thread::spawn(move || (0..100).for_each(|i| tx.send(i).unwrap()));
+
+        let mut received = Vec::new();
+        block_on(std::future::poll_fn(|cx| loop {
+            match Pin::new(&mut stream).poll_next(cx) {
+                Poll::Ready(Some(i)) => received.push(i),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            }
+        }));
+        sender.join().unwrap();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,84 @@
+//! Detection of host suspends during a measurement. While the host is
+//! suspended, nothing is read from the serial port, and the samples the
+//! device took in the meantime are lost. The sample counter wraps every 64
+//! samples, so such a gap can't be detected from the samples themselves.
+//! Instead, the wall-clock time between reads is compared with the
+//! sample-clock time of the samples read. The wall clock is used rather
+//! than a monotonic clock, as the latter stops while suspended on some
+//! platforms. Note that setting the system time can be mistaken for a suspend.
+
+use std::time::{Duration, SystemTime};
+
+use crate::{clock::SampleClock, measurement::SAMPLE_SIZE};
+
+/// Default minimum gap that is considered a host suspend.
+/// See [crate::measurement::MeasurementOptions::suspend_threshold].
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// A gap in a measurement caused by the host being suspended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Suspension {
+    /// Index of the first sample of the gap, counted from the start of the
+    /// measurement and including missed samples.
+    pub sample: usize,
+    /// Time of that sample since the start of the measurement.
+    pub time: Duration,
+    /// Estimated duration of the gap. The samples in it count as missed.
+    pub duration: Duration,
+}
+
+/// Compares the wall-clock time between reads with the sample-clock
+/// time of the samples read.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+pub(crate) struct SuspendDetector {
+    threshold: Duration,
+    clock: SampleClock,
+    last: SystemTime,
+}
+
+#[cfg_attr(not(feature = "serial"), allow(dead_code))]
+impl SuspendDetector {
+    pub(crate) fn new(threshold: Duration, clock: SampleClock) -> Self {
+        Self {
+            threshold,
+            clock,
+            last: SystemTime::now(),
+        }
+    }
+
+    /// Register a read of `bytes` bytes that completed at `now`. Returns the
+    /// duration of the gap if the host was suspended since the last read.
+    pub(crate) fn read(&mut self, now: SystemTime, bytes: usize) -> Option<Duration> {
+        // The wall clock may go backwards when the system time is set
+        let wall = now.duration_since(self.last).unwrap_or_default();
+        self.last = now;
+        let sampled = self.clock.time_at((bytes / SAMPLE_SIZE) as u64);
+        Some(wall.saturating_sub(sampled)).filter(|&gap| gap > self.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{SuspendDetector, DEFAULT_THRESHOLD};
+    use crate::clock::SampleClock;
+
+    #[test]
+    pub fn test_suspend_detector() {
+        let mut detector = SuspendDetector::new(DEFAULT_THRESHOLD, SampleClock::nominal());
+        let start = detector.last;
+        let ms = Duration::from_millis;
+
+        // 1000 samples in 10 ms
+        assert_eq!(detector.read(start + ms(10), 4000), None);
+        // A slow read is not a suspend
+        assert_eq!(detector.read(start + ms(1010), 0), None);
+        // 256 samples after 5 s
+        let gap = detector.read(start + ms(6010), 1024).unwrap();
+        assert_eq!(gap, ms(5000) - Duration::from_micros(2560));
+        // The system time was set back
+        assert_eq!(detector.read(start, 1024), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! Sweeps of the source voltage, for characterizing the current
+//! consumption of the device under test against its supply voltage.
+
+use std::time::Duration;
+
+use crate::{
+    stats::Stats,
+    types::{SampleRate, SourceVoltage},
+};
+
+/// A sweep of the source voltage in equal steps, measuring the current at
+/// each step. See [crate::Ppk2::sweep_voltage].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoltageSweep {
+    start_mv: u16,
+    end_mv: u16,
+    step_mv: u16,
+    settle: Duration,
+    dwell: Duration,
+    sps: SampleRate,
+}
+
+impl VoltageSweep {
+    /// Create a new [VoltageSweep] from `start_mv` to `end_mv`, including both,
+    /// in steps of `step_mv` millivolts. Sweeps down if `end_mv` is below
+    /// `start_mv`. Settles for 100 ms, and measures for 500 ms at 1000
+    /// measurements per second at each step by default.
+    pub fn new(start_mv: u16, end_mv: u16, step_mv: u16) -> Self {
+        Self {
+            start_mv,
+            end_mv,
+            step_mv: step_mv.max(1),
+            settle: Duration::from_millis(100),
+            dwell: Duration::from_millis(500),
+            sps: SampleRate::per_second(1000).expect("Valid sample rate"),
+        }
+    }
+
+    /// Set the time to wait after changing the voltage, before measuring.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Set the time to measure at each step.
+    pub fn dwell(mut self, dwell: Duration) -> Self {
+        self.dwell = dwell;
+        self
+    }
+
+    /// Set the number of combined measurements per second the statistics of
+    /// each step are computed over. Lower rates average out short peaks.
+    pub fn sample_rate(mut self, sps: SampleRate) -> Self {
+        self.sps = sps;
+        self
+    }
+
+    /// Get the time to wait after changing the voltage.
+    pub fn settle_time(&self) -> Duration {
+        self.settle
+    }
+
+    /// Get the number of combined measurements per second.
+    pub fn sps(&self) -> SampleRate {
+        self.sps
+    }
+
+    /// Get the number of combined measurements taken at each step.
+    pub fn measurements_per_step(&self) -> usize {
+        ((self.dwell.as_secs_f64() * self.sps.get() as f64).round() as usize).max(1)
+    }
+
+    /// Iterate over the voltages of the steps. The last step is at
+    /// `end_mv`, even if it's closer to the step before it.
+    pub fn voltages(&self) -> impl Iterator<Item = SourceVoltage> + '_ {
+        let (start, end, step) = (
+            i32::from(self.start_mv),
+            i32::from(self.end_mv),
+            i32::from(self.step_mv),
+        );
+        let direction = if end < start { -1 } else { 1 };
+        let steps = ((end - start).abs() + step - 1) / step;
+        (0..=steps).map(move |i| {
+            let mv = match i == steps {
+                true => end,
+                false => start + direction * step * i,
+            };
+            SourceVoltage::from_millivolts(mv as u16)
+        })
+    }
+}
+
+/// The current measured at a step of a [VoltageSweep].
+#[derive(Debug, Clone)]
+pub struct SweepStep {
+    /// The source voltage of the step
+    pub vdd: SourceVoltage,
+    /// Statistics of the combined measurements taken at the step
+    pub stats: Stats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VoltageSweep;
+
+    #[test]
+    pub fn test_voltages() {
+        let mv =
+            |sweep: VoltageSweep| -> Vec<_> { sweep.voltages().map(|v| v.millivolts()).collect() };
+        assert_eq!(
+            mv(VoltageSweep::new(1800, 2100, 100)),
+            [1800, 1900, 2000, 2100]
+        );
+        assert_eq!(
+            mv(VoltageSweep::new(1800, 2050, 100)),
+            [1800, 1900, 2000, 2050]
+        );
+        assert_eq!(mv(VoltageSweep::new(3000, 2800, 100)), [3000, 2900, 2800]);
+        assert_eq!(mv(VoltageSweep::new(3000, 3000, 100)), [3000]);
+        assert_eq!(
+            VoltageSweep::new(1800, 3600, 100).measurements_per_step(),
+            500
+        );
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    pub fn test_sweep_voltage() {
+        use std::time::Duration;
+
+        use crate::{
+            mock::MockPpk2,
+            types::{MeasurementMode, SourceVoltage},
+            Error, Ppk2,
+        };
+
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        ppk2.set_source_voltage(SourceVoltage::from_millivolts(3000))
+            .unwrap();
+        let sweep = VoltageSweep::new(1800, 2000, 100)
+            .settle(Duration::ZERO)
+            .dwell(Duration::from_millis(20));
+        let steps = ppk2.sweep_voltage(&sweep).unwrap();
+        let mv: Vec<_> = steps.iter().map(|s| s.vdd.millivolts()).collect();
+        assert_eq!(mv, [1800, 1900, 2000]);
+        for step in steps {
+            assert_eq!(step.stats.count(), 20);
+            assert!((step.stats.mean().unwrap() - 1000.).abs() < 10.);
+        }
+        assert_eq!(
+            ppk2.source_voltage(),
+            Some(SourceVoltage::from_millivolts(3000))
+        );
+        let regulator_sets = mock.commands().iter().filter(|c| c[0] == 0x0D).count();
+        assert_eq!(regulator_sets, 1 + 3 + 1);
+
+        let mut ampere = Ppk2::with_port(Box::new(mock), MeasurementMode::Ampere).unwrap();
+        assert!(matches!(
+            ampere.sweep_voltage(&sweep),
+            Err(Error::SourceModeRequired(_))
+        ));
+    }
+}
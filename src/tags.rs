@@ -0,0 +1,127 @@
+//! Tagging of measurements by firmware, by interpreting (a subset of) the
+//! logic port pins as a small integer. This gives firmware up to 256
+//! addressable measurement regions, without any coordination with the host.
+
+use crate::{measurement::Measurement, types::LogicPortPins};
+
+/// Mask selecting the logic port pins that encode the tag. The selected
+/// pins form the bits of the tag, the lowest selected pin being the least
+/// significant bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMask(pub u8);
+
+impl Default for TagMask {
+    /// All 8 pins
+    fn default() -> Self {
+        Self(0xFF)
+    }
+}
+
+impl TagMask {
+    /// Decode the tag from the passed logic port state.
+    pub fn tag(&self, pins: LogicPortPins) -> u8 {
+        let bits = u8::from(pins);
+        (0..8)
+            .filter(|i| self.0 & 1 << i != 0)
+            .enumerate()
+            .fold(0, |tag, (bit, pin)| tag | ((bits >> pin) & 1) << bit)
+    }
+}
+
+/// Statistics of the measurements with a single tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagStats {
+    /// The tag
+    pub tag: u8,
+    /// Number of samples with this tag.
+    pub samples: usize,
+    /// Average current in µA.
+    pub avg_micro_amps: f32,
+    /// Highest current in µA.
+    pub max_micro_amps: f32,
+}
+
+/// Accumulates [TagStats] per tag from a stream of [Measurement]s.
+/// Synthetic measurements are ignored.
+#[derive(Debug, Clone)]
+pub struct TagAccumulator {
+    mask: TagMask,
+    current: Option<u8>,
+    stats: Vec<(usize, f64, f32)>,
+}
+
+impl TagAccumulator {
+    /// Create a new [TagAccumulator] decoding tags using the passed [TagMask].
+    pub fn new(mask: TagMask) -> Self {
+        Self {
+            mask,
+            current: None,
+            stats: vec![(0, 0., f32::MIN); 256],
+        }
+    }
+
+    /// Add a [Measurement]. Returns the previous and the new tag
+    /// if the tag changed.
+    pub fn add(&mut self, measurement: &Measurement) -> Option<(u8, u8)> {
+        if measurement.synthetic {
+            return None;
+        }
+        let tag = self.mask.tag(measurement.pins);
+        let (samples, sum, max) = &mut self.stats[tag as usize];
+        *samples += 1;
+        *sum += measurement.micro_amps as f64;
+        *max = max.max(measurement.micro_amps);
+
+        let prev = self.current.replace(tag);
+        prev.filter(|&p| p != tag).map(|p| (p, tag))
+    }
+
+    /// Get the statistics of all tags that occurred, ordered by tag.
+    pub fn stats(&self) -> Vec<TagStats> {
+        self.stats
+            .iter()
+            .enumerate()
+            .filter(|(_, (samples, _, _))| *samples > 0)
+            .map(|(tag, &(samples, sum, max))| TagStats {
+                tag: tag as u8,
+                samples,
+                avg_micro_amps: (sum / samples as f64) as f32,
+                max_micro_amps: max,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TagAccumulator, TagMask};
+    use crate::measurement::Measurement;
+
+    #[test]
+    pub fn test_tags() {
+        let mask = TagMask(0b1010_0100);
+        assert_eq!(mask.tag(0b1000_0100u8.into()), 0b101);
+        assert_eq!(mask.tag(0b0101_1011u8.into()), 0);
+
+        let mut acc = TagAccumulator::new(mask);
+        let changes: Vec<_> = [(0b100, 10.), (0b100, 20.), (0b1011, 5.)]
+            .into_iter()
+            .filter_map(|(pins, micro_amps)| {
+                acc.add(&Measurement {
+                    micro_amps,
+                    pins: (pins as u8).into(),
+                    synthetic: false,
+                    envelope: None,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        assert_eq!(changes, [(1, 0)]);
+
+        let stats = acc.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[1].tag, stats[1].samples), (1, 2));
+        assert_eq!(stats[1].avg_micro_amps, 15.);
+        assert_eq!(stats[1].max_micro_amps, 20.);
+    }
+}
@@ -0,0 +1,380 @@
+//! Capturing a window of samples once the current crosses a threshold.
+//! See [crate::Ppk2::start_triggered_capture] for a single capture using
+//! the device trigger, and [SoftwareTrigger] for continuous captures in
+//! the measurement pipeline. Requires the `unstable` feature.
+
+use std::{collections::VecDeque, str::FromStr};
+
+use crate::{
+    clock::SampleClock,
+    measurement::Measurement,
+    types::{ParseTypeError, WindowSpec},
+};
+
+/// Collects the window of [Measurement]s starting at the first measurement
+/// with a current at or above the threshold.
+#[derive(Debug, Clone)]
+pub struct TriggerCapture {
+    threshold: f32,
+    len: usize,
+    window: Vec<Measurement>,
+}
+
+impl TriggerCapture {
+    /// Create a new [TriggerCapture] of `len` measurements, triggering
+    /// at `threshold` µA.
+    pub fn new(threshold: f32, len: usize) -> Self {
+        Self {
+            threshold,
+            len,
+            window: Vec::with_capacity(len),
+        }
+    }
+
+    /// Whether the trigger fired.
+    pub fn triggered(&self) -> bool {
+        !self.window.is_empty()
+    }
+
+    /// Whether the window is complete.
+    pub fn is_complete(&self) -> bool {
+        self.window.len() >= self.len
+    }
+
+    /// Feed measurements. Returns whether the window is complete,
+    /// after which further measurements are ignored.
+    pub fn feed(&mut self, measurements: impl IntoIterator<Item = Measurement>) -> bool {
+        let remaining = self.len - self.window.len().min(self.len);
+        if self.triggered() {
+            self.window.extend(measurements.into_iter().take(remaining));
+        } else {
+            let threshold = self.threshold;
+            self.window.extend(
+                measurements
+                    .into_iter()
+                    .skip_while(|m| m.synthetic || m.micro_amps < threshold)
+                    .take(remaining),
+            );
+        }
+        self.is_complete()
+    }
+
+    /// The captured window, which is shorter than requested if
+    /// the capture was not complete.
+    pub fn finish(self) -> Vec<Measurement> {
+        self.window
+    }
+}
+
+/// Edge of a logic port pin signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// Low to high
+    Rising,
+    /// High to low
+    Falling,
+}
+
+impl FromStr for Edge {
+    type Err = ParseTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rising" | "r" => Ok(Edge::Rising),
+            "falling" | "f" => Ok(Edge::Falling),
+            _ => Err(ParseTypeError::new(
+                s.to_owned(),
+                "[rising | r | falling | f]",
+            )),
+        }
+    }
+}
+
+/// Condition on which a [SoftwareTrigger] fires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// The current rises to `threshold` µA. The trigger only re-arms once
+    /// the current dropped below `threshold - hysteresis` µA, to avoid
+    /// firing repeatedly on noise.
+    Current {
+        /// The threshold in µA
+        threshold: f32,
+        /// The hysteresis in µA
+        hysteresis: f32,
+    },
+    /// A logic port pin has an edge.
+    PinEdge {
+        /// The pin, 0 to 7. Never fires for other values.
+        pin: usize,
+        /// The edge
+        edge: Edge,
+    },
+}
+
+/// Configuration of a software trigger, which captures the samples around
+/// every occurrence of a [Trigger] condition.
+/// See [crate::measurement::MeasurementOptions::software_trigger].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftwareTrigger {
+    trigger: Trigger,
+    pre: WindowSpec,
+    post: WindowSpec,
+}
+
+impl SoftwareTrigger {
+    /// Create a new [SoftwareTrigger], firing when the current rises
+    /// to `threshold` µA. Captures 1 ms before and 10 ms after the
+    /// crossing by default, without hysteresis.
+    pub fn new(threshold: f32) -> Self {
+        Self::on(Trigger::Current {
+            threshold,
+            hysteresis: 0.,
+        })
+    }
+
+    /// Create a new [SoftwareTrigger], firing on the passed condition.
+    /// Captures 1 ms before and 10 ms after the trigger by default.
+    pub fn on(trigger: Trigger) -> Self {
+        Self {
+            trigger,
+            pre: WindowSpec::Samples(100),
+            post: WindowSpec::Samples(1000),
+        }
+    }
+
+    /// Set the hysteresis of a [Trigger::Current] condition.
+    /// Has no effect on other conditions.
+    pub fn hysteresis(mut self, hysteresis: f32) -> Self {
+        if let Trigger::Current { hysteresis: h, .. } = &mut self.trigger {
+            *h = hysteresis.max(0.);
+        }
+        self
+    }
+
+    /// Set the window captured before the crossing.
+    pub fn pre(mut self, pre: WindowSpec) -> Self {
+        self.pre = pre;
+        self
+    }
+
+    /// Set the window captured after the crossing, including the
+    /// sample that crossed the threshold.
+    pub fn post(mut self, post: WindowSpec) -> Self {
+        self.post = post;
+        self
+    }
+}
+
+/// The samples around a trigger captured by a [SoftwareTrigger].
+#[derive(Debug, Clone)]
+pub struct Capture {
+    /// Index of the sample that fired the trigger, counted from the
+    /// start of the measurement and including missed samples.
+    pub sample: usize,
+    /// Number of samples before the trigger. Lower than requested if the
+    /// trigger fired shortly after the start of the measurement.
+    pub pre: usize,
+    /// The captured samples.
+    pub measurements: Vec<Measurement>,
+}
+
+/// State of a [SoftwareTrigger]: keeps the most recent samples in a ring
+/// buffer and produces a [Capture] every time the trigger fires. A trigger
+/// is armed once its signal was seen in the state before the crossing or
+/// edge, so e.g. a measurement that starts above the threshold doesn't
+/// count as a crossing.
+#[derive(Debug, Clone)]
+pub struct SoftwareTriggerState {
+    trigger: Trigger,
+    pre: usize,
+    post: usize,
+    ring: VecDeque<Measurement>,
+    armed: bool,
+    /// The capture in progress
+    capture: Option<Capture>,
+}
+
+impl SoftwareTriggerState {
+    /// Create the state of the passed trigger.
+    pub fn new(trigger: SoftwareTrigger) -> Self {
+        let clock = SampleClock::nominal();
+        let pre = trigger.pre.samples(&clock);
+        Self {
+            trigger: trigger.trigger,
+            pre,
+            post: trigger.post.samples(&clock).max(1),
+            ring: VecDeque::with_capacity(pre),
+            armed: false,
+            capture: None,
+        }
+    }
+
+    /// Feed the sample with the passed index. Returns a [Capture] once
+    /// the window after a crossing is complete.
+    pub fn feed(&mut self, sample: usize, measurement: &Measurement) -> Option<Capture> {
+        let mut done = None;
+        if let Some(capture) = &mut self.capture {
+            capture.measurements.push(measurement.clone());
+            if capture.measurements.len() - capture.pre >= self.post {
+                done = self.capture.take();
+            }
+        } else if self.armed && self.fires(measurement) {
+            self.armed = false;
+            let mut measurements: Vec<_> = self.ring.iter().cloned().collect();
+            let pre = measurements.len();
+            measurements.push(measurement.clone());
+            let capture = Capture {
+                sample,
+                pre,
+                measurements,
+            };
+            match self.post {
+                1 => done = Some(capture),
+                _ => self.capture = Some(capture),
+            }
+        }
+
+        if self.arms(measurement) {
+            self.armed = true;
+        }
+        if self.pre > 0 {
+            if self.ring.len() == self.pre {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(measurement.clone());
+        }
+        done
+    }
+
+    fn fires(&self, measurement: &Measurement) -> bool {
+        match self.trigger {
+            Trigger::Current { threshold, .. } => measurement.micro_amps >= threshold,
+            Trigger::PinEdge { pin, edge } => {
+                pin_high(measurement, pin) == Some(edge == Edge::Rising)
+            }
+        }
+    }
+
+    fn arms(&self, measurement: &Measurement) -> bool {
+        match self.trigger {
+            Trigger::Current {
+                threshold,
+                hysteresis,
+            } => measurement.micro_amps < threshold - hysteresis,
+            Trigger::PinEdge { pin, edge } => {
+                pin_high(measurement, pin) == Some(edge == Edge::Falling)
+            }
+        }
+    }
+}
+
+/// Level of the passed pin, or [None] if there is no such pin.
+fn pin_high(measurement: &Measurement, pin: usize) -> Option<bool> {
+    measurement.pins.inner().get(pin).map(|l| l.is_high())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Edge, SoftwareTrigger, SoftwareTriggerState, Trigger, TriggerCapture};
+    use crate::{
+        cmd::Command,
+        measurement::Measurement,
+        types::{LogicPortPins, WindowSpec},
+    };
+
+    fn measurements(currents: &[f32]) -> Vec<Measurement> {
+        currents
+            .iter()
+            .map(|&micro_amps| Measurement {
+                micro_amps,
+                pins: LogicPortPins::default(),
+                synthetic: false,
+                envelope: None,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_trigger_capture() {
+        let mut capture = TriggerCapture::new(10., 3);
+        assert!(!capture.feed(measurements(&[1., 2., 5.])));
+        assert!(!capture.triggered());
+        assert!(!capture.feed(measurements(&[3., 12.])));
+        assert!(capture.triggered());
+        // Values below the threshold are captured after triggering
+        assert!(capture.feed(measurements(&[4., 20., 30.])));
+        let window: Vec<f32> = capture.finish().iter().map(|m| m.micro_amps).collect();
+        assert_eq!(window, [12., 4., 20.]);
+    }
+
+    #[test]
+    pub fn test_trigger_commands() {
+        let bytes = |c: Command| Vec::from_iter(c.bytes());
+        assert_eq!(
+            bytes(Command::TriggerSet(0x123456)),
+            [0x01, 0x12, 0x34, 0x56]
+        );
+        assert_eq!(bytes(Command::TriggerWindowSet(0x0102)), [0x03, 0x01, 0x02]);
+        assert_eq!(bytes(Command::TriggerIntervalSet(500)), [0x04, 0x01, 0xF4]);
+        assert_eq!(bytes(Command::TriggerSingleSet), [0x05]);
+        assert_eq!(bytes(Command::TriggerStop), [0x0A]);
+    }
+
+    #[test]
+    pub fn test_software_trigger() {
+        let trigger = SoftwareTrigger::new(10.)
+            .hysteresis(5.)
+            .pre(WindowSpec::Samples(2))
+            .post(WindowSpec::Samples(3));
+        let mut state = SoftwareTriggerState::new(trigger);
+        // Starts above the threshold, so not armed. After the first crossing,
+        // the current doesn't drop below the re-arm level of 5 µA until the end.
+        let currents = [12., 3., 4., 11., 6., 9., 11., 7., 12., 1., 2.];
+        let captures: Vec<_> = measurements(&currents)
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| state.feed(i, m))
+            .collect();
+        assert_eq!(captures.len(), 1);
+        let capture = &captures[0];
+        assert_eq!((capture.sample, capture.pre), (3, 2));
+        let window: Vec<f32> = capture.measurements.iter().map(|m| m.micro_amps).collect();
+        assert_eq!(window, [3., 4., 11., 6., 9.]);
+    }
+
+    #[test]
+    pub fn test_pin_edge_trigger() {
+        let trigger = SoftwareTrigger::on(Trigger::PinEdge {
+            pin: 3,
+            edge: Edge::Rising,
+        })
+        .pre(WindowSpec::Samples(1))
+        .post(WindowSpec::Samples(2));
+        let mut state = SoftwareTriggerState::new(trigger);
+        // Starts high, so the first rising edge is at index 3
+        let levels = [1u8, 1, 0, 1, 1, 0, 1, 1];
+        let captures: Vec<_> = levels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &level)| {
+                let m = Measurement {
+                    micro_amps: i as f32,
+                    pins: (level << 3).into(),
+                    synthetic: false,
+                    envelope: None,
+                    ..Default::default()
+                };
+                state.feed(i, &m)
+            })
+            .collect();
+        let starts: Vec<_> = captures.iter().map(|c| (c.sample, c.pre)).collect();
+        assert_eq!(starts, [(3, 1), (6, 1)]);
+        let window: Vec<f32> = captures[0]
+            .measurements
+            .iter()
+            .map(|m| m.micro_amps)
+            .collect();
+        assert_eq!(window, [2., 3., 4.]);
+    }
+}
@@ -1,137 +1,278 @@
 //! Several utility types used to communicate with the device.
 
-use std::{fmt::Display, num::ParseIntError, str::FromStr};
-
-use crate::{Error, Result};
-use num_enum::{IntoPrimitive, TryFromPrimitive};
-
-/// Error parsing one of the types defined by this crate.
-#[derive(Debug)]
-pub struct ParseTypeError(String, &'static str);
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    calibration::{Calibration, CalibrationInfo, CalibrationWarning},
+    clock::SampleClock,
+    Error, Result,
+};
+use ppk2_core::conversion::Modifiers;
+
+pub use ppk2_core::types::{
+    DevicePower, MeasurementMode, MeasurementRange, ParseTypeError, SourceVoltage,
+};
+
+/// A current, parsed from a human readable string with a unit, like
+/// `1.5mA`, `250uA` or `0.2 A`. Values without a unit are in µA.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CurrentArg {
+    micro_amps: f64,
+}
 
-impl std::error::Error for ParseTypeError {}
+impl CurrentArg {
+    /// Units and their scale to µA. Prefixed units go first, so they're matched
+    /// before "A", and larger units go first, so they're preferred for display.
+    const UNITS: [(&'static str, f64); 5] = [
+        ("mA", 1e3),
+        ("uA", 1.),
+        ("µA", 1.),
+        ("nA", 1e-3),
+        ("A", 1e6),
+    ];
+
+    /// Create a [CurrentArg] from the passed current in µA.
+    pub fn from_micro_amps(micro_amps: f64) -> Self {
+        Self { micro_amps }
+    }
 
-impl Display for ParseTypeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Error parsing: expected one of {}, but got {}",
-            self.1, self.0
-        )
+    /// The current in µA.
+    pub fn micro_amps(&self) -> f64 {
+        self.micro_amps
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-/// Device source voltage.
-pub struct SourceVoltage {
-    raw: [u8; 2],
+impl From<CurrentArg> for f32 {
+    /// The current in µA
+    fn from(current: CurrentArg) -> Self {
+        current.micro_amps as f32
+    }
 }
 
-impl FromStr for SourceVoltage {
-    type Err = ParseIntError;
+impl FromStr for CurrentArg {
+    type Err = ParseTypeError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let mv = s.parse()?;
-        Ok(Self::from_millivolts(mv))
+        let err =
+            || ParseTypeError::new(s.to_owned(), "a current like [1.5A | 1.5mA | 250uA | 20nA]");
+        let trimmed = s.trim();
+        let (value, scale) = Self::UNITS
+            .iter()
+            .find_map(|(unit, scale)| Some((trimmed.strip_suffix(unit)?, *scale)))
+            .unwrap_or((trimmed, 1.));
+        let value: f64 = value.trim().parse().map_err(|_| err())?;
+        Ok(Self::from_micro_amps(value * scale))
     }
 }
 
-impl SourceVoltage {
-    const VDD_MIN_MV: u16 = 800;
-    const VDD_MAX_MV: u16 = 5000;
-    const OFFSET: u16 = 32;
+impl Display for CurrentArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Use the largest unit the value can be written in without losing precision
+        for (unit, scale) in [("A", 1e6), ("mA", 1e3)] {
+            let value = self.micro_amps / scale;
+            if value.abs() >= 1. && value * scale == self.micro_amps {
+                return write!(f, "{value}{unit}");
+            }
+        }
+        write!(f, "{}uA", self.micro_amps)
+    }
+}
 
-    /// Create a [SourceVoltage] from the passed amount of millivolts.
-    pub fn from_millivolts(mv: u16) -> Self {
-        let mv = mv.clamp(Self::VDD_MIN_MV, Self::VDD_MAX_MV);
+/// A duration given with units, like `1.5s`, `250ms` or `1m30s`,
+/// for use as a command line argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DurationArg(pub Duration);
+
+impl DurationArg {
+    /// Units and their length in seconds. Longer unit names go first,
+    /// so "ms" is matched before "m", and "min" before "m".
+    const UNITS: [(&'static str, f64); 8] = [
+        ("min", 60.),
+        ("ms", 1e-3),
+        ("us", 1e-6),
+        ("µs", 1e-6),
+        ("ns", 1e-9),
+        ("h", 3600.),
+        ("m", 60.),
+        ("s", 1.),
+    ];
+}
 
-        let diff_to_baseline = mv - Self::VDD_MIN_MV + Self::OFFSET;
+impl From<DurationArg> for Duration {
+    fn from(duration: DurationArg) -> Self {
+        duration.0
+    }
+}
 
-        let ratio = (diff_to_baseline / 256) as u8;
-        let remainder = (diff_to_baseline % 256) as u8;
+impl FromStr for DurationArg {
+    type Err = ParseTypeError;
 
-        Self {
-            raw: [ratio + 3, remainder],
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let err = || {
+            ParseTypeError::new(
+                s.to_owned(),
+                "a duration like [1h | 2m30s | 1.5s | 250ms | 20us]",
+            )
+        };
+        let mut rest = s.trim();
+        if rest.is_empty() {
+            return Err(err());
+        }
+        let mut secs = 0f64;
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .ok_or_else(err)?;
+            let value: f64 = rest[..split].parse().map_err(|_| err())?;
+            let unit = rest[split..].trim_start();
+            let (name, scale) = Self::UNITS
+                .iter()
+                .find(|(name, _)| unit.starts_with(name))
+                .ok_or_else(err)?;
+            secs += value * scale;
+            rest = unit[name.len()..].trim_start();
         }
+        Duration::try_from_secs_f64(secs)
+            .map(Self)
+            .map_err(|_| err())
     }
+}
 
-    pub(crate) fn raw(&self) -> &[u8; 2] {
-        &self.raw
+impl Display for DurationArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub(crate) struct Modifiers {
-    pub(crate) r: [f32; 5],
-    pub(crate) gs: [f32; 5],
-    pub(crate) gi: [f32; 5],
-    pub(crate) o: [f32; 5],
-    pub(crate) s: [f32; 5],
-    pub(crate) i: [f32; 5],
-    pub(crate) ug: [f32; 5],
+/// A number of combined measurements per second, from 1 up to the
+/// nominal device rate of [SampleClock::NOMINAL_RATE].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SampleRate(usize);
+
+impl SampleRate {
+    /// Create a [SampleRate] of `sps` measurements per second. Returns
+    /// [Error::UnsupportedSampleRate] if `sps` is 0 or above [SampleRate::max].
+    pub fn per_second(sps: usize) -> Result<Self> {
+        match sps {
+            1..=SampleClock::NOMINAL_RATE => Ok(Self(sps)),
+            _ => Err(Error::UnsupportedSampleRate(sps)),
+        }
+    }
+
+    /// The highest rate, at which every device sample is a measurement.
+    pub const fn max() -> Self {
+        Self(SampleClock::NOMINAL_RATE)
+    }
+
+    /// The number of measurements per second.
+    pub const fn get(&self) -> usize {
+        self.0
+    }
 }
 
-impl Default for Modifiers {
+impl Default for SampleRate {
+    /// 100 measurements per second
     fn default() -> Self {
-        Self {
-            r: [1031.64, 101.65, 10.15, 0.94, 0.043],
-            gs: [1., 1., 1., 1., 1.],
-            gi: [1., 1., 1., 1., 1.],
-            o: [0., 0., 0., 0., 0.],
-            s: [0., 0., 0., 0., 0.],
-            i: [0., 0., 0., 0., 0.],
-            ug: [1., 1., 1., 1., 1.],
-        }
+        Self(100)
     }
 }
 
-#[repr(u8)]
-#[derive(TryFromPrimitive, IntoPrimitive, Debug, Default, Clone, Copy, PartialEq, Eq)]
-/// Device current measurement mode
-pub enum MeasurementMode {
-    /// Act as ammeter, measuring the current through the
-    /// VIN and GND pins.
-    Ampere = 0x01,
-    #[default]
-    /// Act as source meter, returing the current supplied
-    /// from the device voltage source.
-    Source = 0x02,
+impl TryFrom<usize> for SampleRate {
+    type Error = Error;
+
+    fn try_from(sps: usize) -> Result<Self> {
+        Self::per_second(sps)
+    }
+}
+
+impl From<SampleRate> for usize {
+    fn from(rate: SampleRate) -> Self {
+        rate.0
+    }
 }
 
-impl FromStr for MeasurementMode {
+impl FromStr for SampleRate {
     type Err = ParseTypeError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "ampere" | "amp" | "a" => Ok(Self::Ampere),
-            "source" | "s" => Ok(Self::Source),
-            _ => Err(ParseTypeError(
-                s.to_owned(),
-                "[ampere | amp | a | source | s]",
-            )),
+        let err =
+            || ParseTypeError::new(s.to_owned(), "a number of samples per second [1..=100000]");
+        let sps = s.trim().parse().map_err(|_| err())?;
+        Self::per_second(sps).map_err(|_| err())
+    }
+}
+
+impl Display for SampleRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Length of a window of samples, given either as a duration or as
+/// a number of samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowSpec {
+    /// A window of the passed duration
+    Duration(Duration),
+    /// A window of the passed number of samples
+    Samples(usize),
+}
+
+impl WindowSpec {
+    /// Number of samples in the window, at the rate of the passed clock.
+    pub fn samples(&self, clock: &SampleClock) -> usize {
+        match self {
+            WindowSpec::Duration(duration) => clock.samples_in(*duration),
+            WindowSpec::Samples(samples) => *samples,
+        }
+    }
+
+    /// Duration of the window, at the rate of the passed clock.
+    pub fn duration(&self, clock: &SampleClock) -> Duration {
+        match self {
+            WindowSpec::Duration(duration) => *duration,
+            WindowSpec::Samples(samples) => clock.time_at(*samples as u64),
         }
     }
 }
 
-#[repr(u8)]
-#[derive(TryFromPrimitive, IntoPrimitive, Debug, Default, Clone, Copy, PartialEq, Eq)]
-/// Device power
-pub enum DevicePower {
-    #[default]
-    /// Device is disabled
-    Disabled = 0x00,
-    /// Device is enabled
-    Enabled = 0x01,
+impl From<Duration> for WindowSpec {
+    fn from(duration: Duration) -> Self {
+        WindowSpec::Duration(duration)
+    }
 }
 
-impl FromStr for DevicePower {
+impl FromStr for WindowSpec {
     type Err = ParseTypeError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "disabled" | "d" => Ok(Self::Disabled),
-            "enabled" | "e" => Ok(Self::Enabled),
-            _ => Err(ParseTypeError(s.to_owned(), "[disabled | d | enabled | e]")),
+        let trimmed = s.trim();
+        let samples = trimmed
+            .strip_suffix("samples")
+            .or_else(|| trimmed.strip_suffix("sample"))
+            .unwrap_or(trimmed);
+        if let Ok(samples) = samples.trim().parse() {
+            return Ok(WindowSpec::Samples(samples));
+        }
+        trimmed
+            .parse::<DurationArg>()
+            .map(|d| WindowSpec::Duration(d.0))
+            .map_err(|_| {
+                ParseTypeError::new(s.to_owned(), "a window like [10ms | 1.5s | 1000 samples]")
+            })
+    }
+}
+
+impl Display for WindowSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowSpec::Duration(duration) => write!(f, "{duration:?}"),
+            WindowSpec::Samples(samples) => write!(f, "{samples} samples"),
         }
     }
 }
@@ -171,13 +312,13 @@ impl Level {
 
     /// Check whether the [Level] matches another.
     pub fn matches(&self, other: Level) -> bool {
-        match (self, other) {
-            (_, Level::Either) => true,
-            (Level::Either, _) => true,
-            (Level::Low, Level::Low) => true,
-            (Level::High, Level::High) => true,
-            _ => false,
-        }
+        matches!(
+            (self, other),
+            (_, Level::Either)
+                | (Level::Either, _)
+                | (Level::Low, Level::Low)
+                | (Level::High, Level::High)
+        )
     }
 }
 
@@ -235,6 +376,15 @@ impl From<u8> for LogicPortPins {
     }
 }
 
+impl From<LogicPortPins> for u8 {
+    fn from(pins: LogicPortPins) -> Self {
+        pins.pin_levels
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, l)| acc | (l.is_high() as u8) << i)
+    }
+}
+
 impl From<u32> for LogicPortPins {
     fn from(v: u32) -> Self {
         (v as u8).into()
@@ -440,6 +590,57 @@ impl Metadata {
 
         Ok(metadata)
     }
+
+    /// Read [Metadata] in the text format reported by the device, for instance
+    /// from a file written by [Metadata::to_writer].
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Write the [Metadata] in the text format reported by the device,
+    /// so it can be stored alongside a capture for offline processing.
+    pub fn to_writer(&self, mut writer: impl Write) -> Result<()> {
+        let m = &self.modifiers;
+        writeln!(writer, "Calibrated: {}", self.calibrated as u8)?;
+        let ranges = |writer: &mut dyn Write, key: &str, values: &[f32; 5]| {
+            values
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, v)| writeln!(writer, "{key}{i}: {v}"))
+        };
+        ranges(&mut writer, "R", &m.r)?;
+        ranges(&mut writer, "GS", &m.gs)?;
+        ranges(&mut writer, "GI", &m.gi)?;
+        ranges(&mut writer, "O", &m.o)?;
+        writeln!(writer, "VDD: {}", self.vdd)?;
+        writeln!(writer, "HW: {}", self.hw)?;
+        writeln!(writer, "mode: {}", u8::from(self.mode))?;
+        ranges(&mut writer, "S", &m.s)?;
+        ranges(&mut writer, "I", &m.i)?;
+        ranges(&mut writer, "UG", &m.ug)?;
+        writeln!(writer, "IA: {}", self.ia)?;
+        writeln!(writer, "END")?;
+        Ok(())
+    }
+
+    /// Check the plausibility of the calibration the device reports.
+    /// See [CalibrationWarning].
+    pub fn calibration_warnings(&self) -> Vec<CalibrationWarning> {
+        let mut warnings = Vec::new();
+        if !self.calibrated {
+            warnings.push(CalibrationWarning::NotCalibrated);
+        }
+        warnings.extend(Calibration::from(self).warnings());
+        warnings
+    }
+
+    /// Get the calibration coefficients of all ranges, along with
+    /// the warnings about their plausibility.
+    pub fn calibration_info(&self) -> CalibrationInfo {
+        CalibrationInfo::from(self)
+    }
 }
 
 #[cfg(test)]
@@ -447,9 +648,13 @@ mod tests {
 
     use crate::types::Metadata;
 
-    use super::{MeasurementMode, Modifiers};
+    use std::time::Duration;
+
+    use super::{CurrentArg, DurationArg, MeasurementMode, Modifiers, SampleRate, WindowSpec};
+    use crate::{clock::SampleClock, Error};
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     #[ignore = "assert_eq! doesn't work for floats, need to find another solution"]
     pub fn get_adc_result() {
         let raw_metadata = r#"Calibrated: 0
@@ -530,4 +735,76 @@ END
 
         assert_eq!(expected_metadata, metadata);
     }
+
+    #[test]
+    pub fn test_metadata_round_trip() {
+        let mut metadata = Metadata {
+            calibrated: true,
+            vdd: 3300,
+            hw: 9173,
+            mode: MeasurementMode::Ampere,
+            ia: 56,
+            ..Default::default()
+        };
+        metadata.modifiers.o = [112.942, 75.4627, 64.602, 50.4983, 87.2177];
+        metadata.modifiers.i[4] = -0.009388455;
+
+        let mut text = Vec::new();
+        metadata.to_writer(&mut text).unwrap();
+        assert_eq!(Metadata::from_reader(text.as_slice()).unwrap(), metadata);
+    }
+
+    #[test]
+    pub fn test_current_arg() {
+        let parse = |s: &str| s.parse::<CurrentArg>().unwrap().micro_amps();
+        assert_eq!(parse("1.5mA"), 1500.);
+        assert_eq!(parse("250uA"), 250.);
+        assert_eq!(parse("250 µA"), 250.);
+        assert_eq!(parse("2A"), 2e6);
+        assert_eq!(parse("500nA"), 0.5);
+        assert_eq!(parse("20"), 20.);
+        assert!("20 furlongs".parse::<CurrentArg>().is_err());
+
+        for s in ["1.5mA", "250uA", "2A", "0.5uA"] {
+            assert_eq!(s.parse::<CurrentArg>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    pub fn test_window_spec() {
+        let duration = |s: &str| Duration::from(s.parse::<DurationArg>().unwrap());
+        assert_eq!(duration("250ms"), Duration::from_millis(250));
+        assert_eq!(duration("1.5s"), Duration::from_millis(1500));
+        assert_eq!(duration("2m30s"), Duration::from_secs(150));
+        assert_eq!(duration("1h 5min"), Duration::from_secs(3900));
+        assert_eq!(duration("20us"), Duration::from_micros(20));
+        assert!("20".parse::<DurationArg>().is_err());
+        assert!("20 furlongs".parse::<DurationArg>().is_err());
+
+        let clock = SampleClock::nominal();
+        let window = |s: &str| s.parse::<WindowSpec>().unwrap();
+        assert_eq!(window("1000 samples"), WindowSpec::Samples(1000));
+        assert_eq!(window("1000"), WindowSpec::Samples(1000));
+        assert_eq!(window("10ms").samples(&clock), 1000);
+        assert_eq!(
+            window("1000 samples").duration(&clock),
+            Duration::from_millis(10)
+        );
+        assert!("10 furlongs".parse::<WindowSpec>().is_err());
+    }
+
+    #[test]
+    pub fn test_sample_rate() {
+        assert_eq!(SampleRate::per_second(1000).unwrap().get(), 1000);
+        assert_eq!(SampleRate::per_second(100_000).unwrap(), SampleRate::max());
+        for sps in [0, 100_001] {
+            assert!(matches!(
+                SampleRate::per_second(sps),
+                Err(Error::UnsupportedSampleRate(n)) if n == sps
+            ));
+        }
+        assert_eq!("250".parse::<SampleRate>().unwrap().get(), 250);
+        assert!("0".parse::<SampleRate>().is_err());
+        assert!("1e6".parse::<SampleRate>().is_err());
+    }
 }
@@ -0,0 +1,15 @@
+//! Experimental APIs, enabled with the `unstable` cargo feature. They ship
+//! early to gather feedback, and may change or be removed in any release.
+//! Using one logs a warning, once per API.
+
+use std::sync::Mutex;
+
+/// Warn that the named experimental API is used, if not warned before.
+pub(crate) fn warn(api: &'static str) {
+    static WARNED: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+    let mut warned = WARNED.lock().unwrap();
+    if !warned.contains(&api) {
+        warned.push(api);
+        tracing::warn!("{api} is an unstable API, which may change in any release");
+    }
+}
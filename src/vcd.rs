@@ -0,0 +1,142 @@
+//! Export of measurements as a Value Change Dump (VCD), so captures can be
+//! viewed alongside logic analyzer traces in waveform viewers like GTKWave
+//! or Surfer.
+//!
+//! The current is written as a real-valued variable in µA, and the logic
+//! port pins as 8 single-bit wires. As in any VCD, a value is only written
+//! when it changes. Timestamps are derived from the sample index using a
+//! [SampleClock], in nanoseconds.
+
+use std::{io::Write, time::Duration};
+
+use crate::{clock::SampleClock, measurement::Measurement, Result};
+
+/// Identifier code of the current variable. The pins use the codes after it.
+const CURRENT_ID: char = '!';
+
+/// Identifier code of logic port pin `pin`.
+fn pin_id(pin: usize) -> char {
+    (CURRENT_ID as u8 + 1 + pin as u8) as char
+}
+
+/// Write the measurements as a VCD.
+pub fn write_vcd<'m>(
+    mut writer: impl Write,
+    measurements: impl IntoIterator<Item = &'m Measurement>,
+    clock: &SampleClock,
+) -> Result<()> {
+    let mut vcd = VcdWriter::new(*clock);
+    vcd.write_header(&mut writer)?;
+    vcd.write_rows(&mut writer, measurements)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a VCD in parts, keeping track of the values written so far.
+pub(crate) struct VcdWriter {
+    clock: SampleClock,
+    written: u64,
+    prev: Option<(f32, u8)>,
+}
+
+impl VcdWriter {
+    pub(crate) fn new(clock: SampleClock) -> Self {
+        Self {
+            clock,
+            written: 0,
+            prev: None,
+        }
+    }
+
+    /// Write the header, declaring the variables.
+    pub(crate) fn write_header(&self, mut writer: impl Write) -> Result<()> {
+        writeln!(
+            writer,
+            "$version ppk2-rs {} $end",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(writer, "$timescale 1 ns $end")?;
+        writeln!(writer, "$scope module ppk2 $end")?;
+        writeln!(writer, "$var real 64 {CURRENT_ID} current_ua $end")?;
+        for pin in 0..8 {
+            writeln!(writer, "$var wire 1 {} d{pin} $end", pin_id(pin))?;
+        }
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")?;
+        Ok(())
+    }
+
+    /// Write the value changes of the next measurements.
+    pub(crate) fn write_rows<'m>(
+        &mut self,
+        mut writer: impl Write,
+        measurements: impl IntoIterator<Item = &'m Measurement>,
+    ) -> Result<()> {
+        for m in measurements {
+            let pins = u8::from(m.pins);
+            let (amps_changed, changed_pins) = match self.prev {
+                Some((amps, prev_pins)) => (amps != m.micro_amps, pins ^ prev_pins),
+                None => (true, !0),
+            };
+            if amps_changed || changed_pins != 0 {
+                let ns = self.clock.time_at(self.written).as_nanos();
+                writeln!(writer, "#{ns}")?;
+                if amps_changed {
+                    writeln!(writer, "r{} {CURRENT_ID}", m.micro_amps)?;
+                }
+                for pin in (0..8).filter(|pin| changed_pins & (1 << pin) != 0) {
+                    writeln!(writer, "{}{}", (pins >> pin) & 1, pin_id(pin))?;
+                }
+            }
+            self.prev = Some((m.micro_amps, pins));
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    /// Write a comment, with the time since the start of the capture.
+    pub(crate) fn write_comment(
+        &self,
+        mut writer: impl Write,
+        time: Duration,
+        text: &str,
+    ) -> Result<()> {
+        let text = text.replace("$end", "end");
+        writeln!(
+            writer,
+            "$comment {:.3} ms: {text} $end",
+            time.as_secs_f64() * 1e3
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_vcd;
+    use crate::{clock::SampleClock, measurement::Measurement};
+
+    #[test]
+    pub fn test_write_vcd() {
+        let measurements: Vec<_> = [(1.5, 0b01), (1.5, 0b01), (2., 0b01), (2., 0b10)]
+            .into_iter()
+            .map(|(micro_amps, pins): (f32, u8)| Measurement {
+                micro_amps,
+                pins: pins.into(),
+                ..Default::default()
+            })
+            .collect();
+        let mut vcd = Vec::new();
+        write_vcd(&mut vcd, &measurements, &SampleClock::with_rate(1000.)).unwrap();
+        let vcd = String::from_utf8(vcd).unwrap();
+        let (header, changes) = vcd.split_once("$enddefinitions $end\n").unwrap();
+        assert!(header.contains("$var real 64 ! current_ua $end\n"));
+        assert!(header.contains("$var wire 1 ) d7 $end\n"));
+        assert_eq!(
+            changes,
+            "#0\nr1.5 !\n1\"\n0#\n0$\n0%\n0&\n0'\n0(\n0)\n\
+             #2000000\nr2 !\n\
+             #3000000\n0\"\n1#\n"
+        );
+    }
+}
@@ -0,0 +1,1065 @@
+//! The measurement pipeline run by [crate::Ppk2::start_measurement_with].
+//!
+//! To avoid serial buffer overruns on slow hosts, reading and parsing are
+//! done in separate threads. The reader thread does nothing but read raw
+//! bytes from the serial port into buffers, which it passes to the parser
+//! thread through a queue. Emptied buffers are passed back for reuse.
+//! The reader thread also detects host suspends, see [crate::suspend],
+//! and reopens the serial port if a suspend or a USB disconnect closed it,
+//! see [crate::reconnect].
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, SendError, Sender},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+#[cfg(feature = "serial")]
+use {
+    serialport::{FlowControl, SerialPort},
+    std::{
+        fs::File,
+        io::{self, BufWriter, Write},
+        sync::{
+            mpsc::{Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError},
+            Condvar,
+        },
+        thread::{self, JoinHandle},
+        time::{Duration, SystemTime},
+    },
+};
+
+use crate::{
+    autozero::{AutoZeroState, ZeroStep},
+    battery::{IrDropModel, IrDropUpdate},
+    charge::ChargeAccumulator,
+    clock::SampleClock,
+    cmd::Command,
+    measurement::{
+        ChunkTimer, EventSubscribers, History, Measurement, MeasurementAccumulator,
+        MeasurementEvent, MeasurementIterExt, MeasurementMatch, MeasurementOptions, PinVote,
+        PipelineCounters, SegmentAccumulator, Subscribers, SAMPLE_SIZE,
+    },
+    reset::ResetDetector,
+    tags::TagAccumulator,
+    trigger::{Capture, SoftwareTriggerState},
+    types::{DevicePower, LogicPortPins, Metadata},
+    Result,
+};
+
+#[cfg(feature = "serial")]
+use crate::{
+    measurement::MeasurementEnd,
+    reconnect::{Disconnection, ReconnectPolicy},
+    suspend::{SuspendDetector, Suspension},
+    types::{MeasurementMode, MeasurementRange},
+    ControlHandle, StopHandle,
+};
+
+/// Default size of the buffers the reader thread reads into. The parser
+/// splits them into samples, so larger buffers don't affect the resolution
+/// of the combined measurements.
+pub(crate) const READ_BUF_SIZE: usize = 1024;
+
+#[cfg(feature = "serial")]
+/// How often the parser thread checks for stop signals and segment
+/// changes when no data is coming in.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[cfg(feature = "serial")]
+/// How often a full measurement channel is checked for room.
+const FULL_CHANNEL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[cfg(feature = "serial")]
+/// How long after a host suspend a failed read is attributed to the
+/// suspend, in which case the serial port is reopened even without
+/// a [ReconnectPolicy].
+const SUSPEND_RECONNECT_WINDOW: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "serial")]
+/// What the reader thread passes to the parser thread.
+enum ReadChunk {
+    /// Raw bytes read from the serial port
+    Data(Vec<u8>),
+    /// The host was suspended for the passed duration
+    Suspended(Duration),
+    /// The serial port was reopened after a suspend or disconnect
+    Reconnected {
+        port: Box<dyn SerialPort>,
+        /// The metadata read again after reopening, if that succeeded
+        metadata: Option<Metadata>,
+        /// The duration of the gap, if it wasn't reported as a suspend
+        gap: Option<Duration>,
+    },
+}
+
+/// Where the pipeline sends the combined measurements, depending on
+/// whether the channel has a capacity, see [crate::Ppk2Builder::channel_capacity].
+pub(crate) enum MeasurementSender {
+    Unbounded(Sender<MeasurementMatch>),
+    /// Stops waiting for room in the channel when the measurement is stopped
+    #[cfg(feature = "serial")]
+    Bounded(SyncSender<MeasurementMatch>, StopHandle),
+}
+
+impl MeasurementSender {
+    /// Send a measurement, waiting for room in the channel if it's full.
+    /// The measurement is dropped if the measurement is stopped meanwhile.
+    /// Returns whether the measurement was delivered.
+    fn send(
+        &self,
+        measurement: MeasurementMatch,
+    ) -> std::result::Result<bool, SendError<MeasurementMatch>> {
+        match self {
+            Self::Unbounded(tx) => tx.send(measurement).map(|_| true),
+            #[cfg(feature = "serial")]
+            Self::Bounded(tx, stop) => {
+                let mut measurement = measurement;
+                loop {
+                    match tx.try_send(measurement) {
+                        Ok(()) => return Ok(true),
+                        Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+                        Err(TrySendError::Full(_)) if stop.is_stopped() => return Ok(false),
+                        Err(TrySendError::Full(m)) => {
+                            measurement = m;
+                            thread::sleep(FULL_CHANNEL_POLL_INTERVAL);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+/// State shared between the pipeline and the [crate::MeasurementHandle].
+pub(crate) struct WorkerContext {
+    pub(crate) port: Box<dyn SerialPort>,
+    pub(crate) metadata: Metadata,
+    /// The native sample clock of the device
+    pub(crate) clock: SampleClock,
+    pub(crate) options: MeasurementOptions,
+    /// File the raw bytes read from the serial port are written to
+    pub(crate) raw_dump: Option<File>,
+    /// Size of the buffers the serial port is read into
+    pub(crate) read_buf_size: usize,
+    /// Signals that the serial port input buffer was cleared.
+    pub(crate) ready: Arc<(Mutex<bool>, Condvar)>,
+    pub(crate) meas_tx: MeasurementSender,
+    pub(crate) seg_rx: Receiver<String>,
+    pub(crate) stop: StopHandle,
+    pub(crate) events: EventSubscribers,
+    pub(crate) counters: Arc<PipelineCounters>,
+    pub(crate) history: History,
+    pub(crate) tags: Option<Arc<Mutex<TagAccumulator>>>,
+    pub(crate) charge: Arc<Mutex<ChargeAccumulator>>,
+    pub(crate) complement: Subscribers<MeasurementMatch>,
+    pub(crate) captures: Subscribers<Capture>,
+    /// Sends commands to the device, ordered with those of the user.
+    pub(crate) control: ControlHandle,
+    /// Applied again if the serial port is reopened
+    pub(crate) setup: DeviceSetup,
+}
+
+#[cfg(feature = "serial")]
+/// Connection parameters and device settings that are applied again when
+/// the serial port is reopened after a disconnect.
+#[derive(Clone)]
+pub(crate) struct DeviceSetup {
+    pub(crate) timeout: Duration,
+    pub(crate) flow_control: FlowControl,
+    /// Opens the serial port again
+    pub(crate) open: PortOpener,
+    pub(crate) mode: MeasurementMode,
+    pub(crate) range: Option<MeasurementRange>,
+    pub(crate) switch_points: Option<(u16, u16)>,
+    pub(crate) spike_filtering: Option<bool>,
+    pub(crate) user_gains: [f32; 5],
+    pub(crate) hardware_averages: u16,
+}
+
+#[cfg(feature = "serial")]
+/// Opens the serial port with the passed name, with the timeout and flow
+/// control of the [DeviceSetup].
+pub(crate) type PortOpener =
+    Arc<dyn Fn(&str, &DeviceSetup) -> serialport::Result<Box<dyn SerialPort>> + Send + Sync>;
+
+#[cfg(feature = "serial")]
+/// The [PortOpener] of devices opened by name.
+pub(crate) fn open_port(
+    name: &str,
+    setup: &DeviceSetup,
+) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(name, 9600)
+        .timeout(setup.timeout)
+        .flow_control(setup.flow_control)
+        .open()
+}
+
+#[cfg(feature = "serial")]
+/// Spawn the parser thread, which in turn spawns the reader thread
+/// once the serial port is ready. Returns the reopened serial port,
+/// if it was reopened after a host suspend or disconnect.
+pub(crate) fn spawn(ctx: WorkerContext) -> JoinHandle<Result<Option<Box<dyn SerialPort>>>> {
+    let events = ctx.events.clone();
+    thread::spawn(move || {
+        let res = run(ctx);
+        let end = match &res {
+            Ok(_) => MeasurementEnd::Stopped,
+            Err(e) => {
+                tracing::error!("Error fetching measurements: {:?}", e);
+                MeasurementEnd::Failed(e.to_string())
+            }
+        };
+        events.emit(MeasurementEvent::Ended(end));
+        res
+    })
+}
+
+#[cfg(feature = "serial")]
+fn run(ctx: WorkerContext) -> Result<Option<Box<dyn SerialPort>>> {
+    let WorkerContext {
+        port,
+        metadata,
+        clock,
+        options,
+        raw_dump,
+        read_buf_size,
+        ready,
+        meas_tx,
+        seg_rx,
+        stop,
+        events,
+        counters,
+        history,
+        tags,
+        charge,
+        complement,
+        captures,
+        control,
+        setup,
+    } = ctx;
+    let reader_port = port.try_clone()?;
+    let reader_config = ReaderConfig {
+        suspend: options
+            .suspend_threshold
+            .map(|threshold| SuspendDetector::new(threshold, clock)),
+        reconnect: options.reconnect,
+        read_buf_size,
+        setup,
+        control: control.clone(),
+    };
+    let outputs = Outputs {
+        meas_tx,
+        events,
+        history,
+        tags,
+        charge,
+        complement,
+        captures,
+        counters: counters.clone(),
+    };
+    let mut parser = Parser::new(metadata, clock, options, outputs);
+    parser.control = Some(control.clone());
+
+    // First wait for main thread to clear
+    // serial port input buffer
+    let (lock, cvar) = &*ready;
+    drop(
+        cvar.wait_while(lock.lock().unwrap(), |ready| !*ready)
+            .unwrap(),
+    );
+
+    let (data_tx, data_rx) = mpsc::channel::<ReadChunk>();
+    let (free_tx, free_rx) = mpsc::channel::<Vec<u8>>();
+    let reader_stop = stop.clone();
+    let reader = thread::spawn(move || {
+        read_loop(
+            reader_port,
+            reader_stop,
+            counters,
+            reader_config,
+            data_tx,
+            free_rx,
+        )
+    });
+    // Written from the parser thread, to keep the reader thread lean
+    let mut raw_dump = raw_dump.map(BufWriter::new);
+
+    let res = (|| -> Result<()> {
+        loop {
+            // Check whether the main thread has signaled
+            // us to stop
+            if stop.is_stopped() {
+                parser.finish();
+                return Ok(());
+            }
+
+            // Check whether a new segment should be started
+            match seg_rx.try_recv() {
+                Ok(label) => parser.next_segment(label),
+                Err(TryRecvError::Empty) => {}
+                // The measurement handle was dropped without stopping
+                Err(TryRecvError::Disconnected) => {
+                    if let Err(e) = control.send(Command::AverageStop) {
+                        tracing::warn!("Failed to stop the device: {e:?}");
+                    }
+                    parser.finish();
+                    return Ok(());
+                }
+            }
+
+            match data_rx.recv_timeout(POLL_INTERVAL) {
+                Ok(ReadChunk::Data(buf)) => {
+                    if let Some(dump) = &mut raw_dump {
+                        dump.write_all(&buf)?;
+                    }
+                    parser.feed(&buf)?;
+                    // The reader may have stopped already
+                    let _ = free_tx.send(buf);
+                }
+                Ok(ReadChunk::Suspended(duration)) => parser.host_suspended(duration),
+                Ok(ReadChunk::Reconnected {
+                    port,
+                    metadata,
+                    gap,
+                }) => {
+                    control.set_port(port);
+                    parser.reconnected(metadata, gap);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // The reader stopped, either because of an error or a stop signal
+                Err(RecvTimeoutError::Disconnected) => {
+                    parser.finish();
+                    return Ok(());
+                }
+            }
+        }
+    })();
+
+    // Make sure the reader stops too if parsing failed
+    stop.stop();
+    let read_res = reader.join().expect("Serial reader thread panicked");
+    let dump_res = raw_dump.map_or(Ok(()), |mut dump| dump.flush());
+    res.and(read_res)
+        .and_then(|port| dump_res.map(|_| port).map_err(Into::into))
+}
+
+/// Run the pipeline over previously read raw bytes, as if they were read
+/// from the device, and return the combined measurements and events.
+/// Windows are closed based on the number of samples only, as the bytes
+/// don't arrive in real time.
+pub(crate) fn replay(
+    bytes: &[u8],
+    metadata: Metadata,
+    clock: SampleClock,
+    options: MeasurementOptions,
+) -> Result<(Vec<MeasurementMatch>, Vec<MeasurementEvent>)> {
+    let (meas_tx, meas_rx) = mpsc::channel();
+    let events = EventSubscribers::default();
+    let events_rx = events.subscribe();
+    let outputs = Outputs {
+        meas_tx: MeasurementSender::Unbounded(meas_tx),
+        events,
+        history: History::new(options.history, clock),
+        tags: options
+            .tags
+            .map(|mask| Arc::new(Mutex::new(TagAccumulator::new(mask)))),
+        charge: Arc::new(Mutex::new(ChargeAccumulator::new())),
+        complement: Subscribers::default(),
+        captures: Subscribers::default(),
+        counters: Arc::new(PipelineCounters::new()),
+    };
+    let mut parser = Parser::new(metadata, clock, options, outputs);
+    parser.chunk_timer = parser.chunk_timer.offline();
+    for buf in bytes.chunks(READ_BUF_SIZE) {
+        parser.feed(buf)?;
+    }
+    parser.finish();
+    Ok((meas_rx.try_iter().collect(), events_rx.try_iter().collect()))
+}
+
+#[cfg(feature = "serial")]
+/// Settings of the reader thread.
+struct ReaderConfig {
+    suspend: Option<SuspendDetector>,
+    reconnect: Option<ReconnectPolicy>,
+    /// Size of the buffers the serial port is read into
+    read_buf_size: usize,
+    setup: DeviceSetup,
+    /// Source of the device power and source voltage set by the user
+    control: ControlHandle,
+}
+
+#[cfg(feature = "serial")]
+/// Read raw bytes from the serial port until signaled to stop. Reads that
+/// time out are retried. If the port is disconnected shortly after a host
+/// suspend, or at any time with a [ReconnectPolicy], it is reopened, and
+/// returned.
+fn read_loop(
+    mut port: Box<dyn SerialPort>,
+    stop: StopHandle,
+    counters: Arc<PipelineCounters>,
+    config: ReaderConfig,
+    data_tx: Sender<ReadChunk>,
+    free_rx: Receiver<Vec<u8>>,
+) -> Result<Option<Box<dyn SerialPort>>> {
+    let ReaderConfig {
+        mut suspend,
+        reconnect,
+        read_buf_size,
+        setup,
+        control,
+    } = config;
+    let mut reconnected = false;
+    let mut last_suspend: Option<Instant> = None;
+    let mut last_read = Instant::now();
+    while !stop.is_stopped() {
+        let mut buf = free_rx.try_recv().unwrap_or_default();
+        buf.resize(read_buf_size, 0);
+        let res = port.read(&mut buf);
+        let n = *res.as_ref().unwrap_or(&0);
+        if let Some(gap) = suspend.as_mut().and_then(|s| s.read(SystemTime::now(), n)) {
+            tracing::warn!("Host was suspended for {gap:.3?}, samples were lost");
+            last_suspend = Some(Instant::now());
+            if data_tx.send(ReadChunk::Suspended(gap)).is_err() {
+                break;
+            }
+        }
+        if let Err(e) = res {
+            if e.kind() == io::ErrorKind::TimedOut {
+                continue;
+            }
+            if !is_disconnect(&e) {
+                return Err(e.into());
+            }
+            let after_suspend =
+                last_suspend.is_some_and(|t| t.elapsed() < SUSPEND_RECONNECT_WINDOW);
+            let policy = match (reconnect, after_suspend) {
+                (Some(policy), _) => policy,
+                (None, true) => ReconnectPolicy::new(),
+                (None, false) => return Err(e.into()),
+            };
+            tracing::warn!("Reading from serial port failed, reopening port: {e:?}");
+            let metadata;
+            (port, metadata) = reopen(port.as_ref(), e, &policy, &setup, &control)?;
+            reconnected = true;
+            // The gap of a suspend was reported already
+            let gap = (!after_suspend).then(|| last_read.elapsed());
+            last_read = Instant::now();
+            let chunk = ReadChunk::Reconnected {
+                port: port.try_clone()?,
+                metadata,
+                gap,
+            };
+            if data_tx.send(chunk).is_err() {
+                break;
+            }
+            continue;
+        }
+        last_read = Instant::now();
+        counters.add_bytes(n);
+        buf.truncate(n);
+        if data_tx.send(ReadChunk::Data(buf)).is_err() {
+            // Parser is gone
+            break;
+        }
+    }
+    Ok(reconnected.then_some(port))
+}
+
+#[cfg(feature = "serial")]
+/// Whether a failed read means the device was disconnected, as reported
+/// by serialport, rather than the read itself failing.
+fn is_disconnect(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    matches!(
+        error.kind(),
+        BrokenPipe | NotConnected | PermissionDenied | Other
+    )
+}
+
+#[cfg(feature = "serial")]
+/// Reopen the serial port after a host suspend or disconnect closed it, read
+/// the metadata again, and restart measuring with the device settings in
+/// `setup` and those of `control`. Failed attempts are retried until the
+/// timeout of `policy`, after which `error`, the error that closed the port,
+/// is returned.
+fn reopen(
+    port: &dyn SerialPort,
+    error: io::Error,
+    policy: &ReconnectPolicy,
+    setup: &DeviceSetup,
+    control: &ControlHandle,
+) -> Result<(Box<dyn SerialPort>, Option<Metadata>)> {
+    let Some(name) = port.name() else {
+        return Err(error.into());
+    };
+    let deadline = Instant::now() + policy.timeout;
+    loop {
+        let restarted = (setup.open)(&name, setup)
+            .map_err(Into::into)
+            .and_then(|port| restart(port, setup, control));
+        match restarted {
+            Ok(restarted) => {
+                tracing::info!("Reopened {name}");
+                return Ok(restarted);
+            }
+            Err(e) if Instant::now() >= deadline => {
+                tracing::error!("Failed to reopen {name}: {e:?}");
+                return Err(error.into());
+            }
+            Err(e) => {
+                tracing::debug!("Failed to reopen {name}, retrying: {e:?}");
+                thread::sleep(policy.interval);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+/// Read the metadata from a reopened port, apply the device settings again,
+/// and restart measuring. Returns the metadata with the user gains of `setup`.
+fn restart(
+    mut port: Box<dyn SerialPort>,
+    setup: &DeviceSetup,
+    control: &ControlHandle,
+) -> Result<(Box<dyn SerialPort>, Option<Metadata>)> {
+    port.write_data_terminal_ready(true)?;
+    let mut metadata = read_metadata(port.as_mut())
+        .inspect_err(|e| tracing::warn!("Failed to read metadata: {e:?}"))
+        .ok();
+
+    let (power, vdd) = control.settings();
+    let mut commands = vec![Command::SetPowerMode(setup.mode)];
+    commands.extend(vdd.map(Command::RegulatorSet));
+    for (range, &gain) in setup.user_gains.iter().enumerate() {
+        // The device may have kept them
+        if metadata.as_ref().map(|m| m.modifiers.ug[range]) != Some(gain) {
+            let range = MeasurementRange::try_from(range as u8).expect("5 ranges");
+            commands.push(Command::SetUserGains(range, gain));
+        }
+    }
+    commands.extend(setup.range.map(Command::RangeSet));
+    if let Some((up, down)) = setup.switch_points {
+        commands.extend([Command::SwitchPointUp(up), Command::SwitchPointDown(down)]);
+    }
+    commands.extend(setup.spike_filtering.map(|enabled| match enabled {
+        true => Command::SpikeFilteringOn,
+        false => Command::SpikeFilteringOff,
+    }));
+    commands.push(Command::DeviceRunningSet(power));
+    if setup.hardware_averages > 1 {
+        commands.push(Command::AvgNumSet(setup.hardware_averages));
+    }
+    commands.push(Command::AverageStart);
+    for command in commands {
+        port.write_all(&Vec::from_iter(command.bytes()))?;
+    }
+
+    if let Some(metadata) = &mut metadata {
+        metadata.modifiers.ug = setup.user_gains;
+    }
+    Ok((port, metadata))
+}
+
+#[cfg(feature = "serial")]
+/// Stop the device in case it is still sending samples, and read its metadata.
+fn read_metadata(port: &mut dyn SerialPort) -> Result<Metadata> {
+    port.write_all(&Vec::from_iter(Command::AverageStop.bytes()))?;
+    port.clear(serialport::ClearBuffer::Input)?;
+    let command = Command::GetMetaData;
+    port.write_all(&Vec::from_iter(command.bytes()))?;
+    let mut response = Vec::with_capacity(command.expected_response_len());
+    let mut buf = [0u8; 128];
+    while !command.response_complete(&response) {
+        let n = port.read(&mut buf)?;
+        response.extend_from_slice(&buf[..n]);
+    }
+    Metadata::from_bytes(&response)
+}
+
+/// Where the parser sends its results.
+struct Outputs {
+    meas_tx: MeasurementSender,
+    events: EventSubscribers,
+    history: History,
+    tags: Option<Arc<Mutex<TagAccumulator>>>,
+    charge: Arc<Mutex<ChargeAccumulator>>,
+    complement: Subscribers<MeasurementMatch>,
+    captures: Subscribers<Capture>,
+    counters: Arc<PipelineCounters>,
+}
+
+/// Parsing and processing state of the pipeline.
+struct Parser {
+    /// Handle used for sending commands, e.g. for IR drop emulation.
+    /// [None] when replaying, in which case commands are not sent.
+    #[cfg(feature = "serial")]
+    control: Option<ControlHandle>,
+    accumulator: MeasurementAccumulator,
+    chunk_timer: ChunkTimer,
+    measurement_buf: VecDeque<Measurement>,
+    missed: usize,
+    pins: LogicPortPins,
+    pin_vote: PinVote,
+    overcurrent: Option<f32>,
+    over_limit: bool,
+    // State for emulating battery internal resistance
+    ir_drop: Option<IrDropModel>,
+    ir_drop_sum: f32,
+    ir_drop_count: usize,
+    ir_drop_last_update: Instant,
+    // State of the current segment
+    segment: SegmentAccumulator,
+    sample_index: usize,
+    reset_detector: Option<ResetDetector>,
+    tags: Option<Arc<Mutex<TagAccumulator>>>,
+    charge: Arc<Mutex<ChargeAccumulator>>,
+    complement: Subscribers<MeasurementMatch>,
+    trigger: Option<SoftwareTriggerState>,
+    captures: Subscribers<Capture>,
+    meas_tx: MeasurementSender,
+    events: EventSubscribers,
+    history: History,
+    counters: Arc<PipelineCounters>,
+    strict: bool,
+    /// Pins of the last received sample, in logic-only mode
+    logic_edges: Option<Option<LogicPortPins>>,
+    current_only: bool,
+    auto_zero: Option<AutoZeroState>,
+    clock: SampleClock,
+}
+
+impl Parser {
+    fn new(
+        metadata: Metadata,
+        clock: SampleClock,
+        options: MeasurementOptions,
+        outputs: Outputs,
+    ) -> Self {
+        let Outputs {
+            meas_tx,
+            events,
+            history,
+            tags,
+            charge,
+            complement,
+            captures,
+            counters,
+        } = outputs;
+        let MeasurementOptions {
+            sps,
+            pins,
+            ir_drop,
+            segment,
+            initial_sync,
+            max_gap,
+            history: _,
+            overcurrent,
+            glitch_filter,
+            pin_vote,
+            power_ramp: _,
+            reset_signature,
+            tags: _,
+            strict,
+            logic_only,
+            current_only,
+            non_finite,
+            spike_filter,
+            window,
+            software_trigger,
+            max_hardware_averages: _,
+            auto_zero,
+            qos: _,
+            raw_dump: _,
+            suspend_threshold: _,
+            reconnect: _,
+            stop: _,
+        } = options;
+        Self {
+            #[cfg(feature = "serial")]
+            control: None,
+            // Create an accumulator with the current device metadata
+            accumulator: MeasurementAccumulator::new(metadata)
+                .clock(clock)
+                .initial_sync(initial_sync)
+                .interpolate_gaps(max_gap)
+                .glitch_filter(glitch_filter)
+                .logic_only(logic_only)
+                .current_only(current_only)
+                .non_finite(non_finite)
+                .spike_filter(spike_filter),
+            chunk_timer: ChunkTimer::new(sps, window, clock),
+            measurement_buf: VecDeque::with_capacity(SampleClock::NOMINAL_RATE),
+            missed: 0,
+            pins,
+            pin_vote,
+            overcurrent,
+            over_limit: false,
+            ir_drop,
+            ir_drop_sum: 0.,
+            ir_drop_count: 0,
+            ir_drop_last_update: Instant::now(),
+            segment: SegmentAccumulator::new(segment, 0),
+            sample_index: 0,
+            reset_detector: reset_signature.map(ResetDetector::new),
+            tags,
+            charge,
+            complement,
+            trigger: software_trigger.map(SoftwareTriggerState::new),
+            captures,
+            meas_tx,
+            events,
+            history,
+            counters,
+            strict,
+            logic_edges: logic_only.then_some(None),
+            current_only,
+            auto_zero: auto_zero.map(|config| AutoZeroState::new(config, clock)),
+            clock,
+        }
+    }
+
+    #[cfg(feature = "serial")]
+    /// Close the current segment and start a new one.
+    fn next_segment(&mut self, label: String) {
+        let next = SegmentAccumulator::new(label, self.sample_index);
+        let prev = std::mem::replace(&mut self.segment, next);
+        self.events
+            .emit(MeasurementEvent::SegmentEnd(prev.finish()));
+    }
+
+    #[cfg(feature = "serial")]
+    /// Account for the samples lost while the host was suspended.
+    fn host_suspended(&mut self, duration: Duration) {
+        let lost = self.clock.samples_in(duration);
+        self.events
+            .emit(MeasurementEvent::HostSuspended(Suspension {
+                sample: self.sample_index,
+                time: self.clock.time_at(self.sample_index as u64),
+                duration,
+            }));
+        self.sample_index += lost;
+        self.segment.add_missed(lost);
+        self.accumulator.add_missed(lost);
+    }
+
+    #[cfg(feature = "serial")]
+    /// Restart parsing after the serial port was reopened, accounting
+    /// for the samples lost during the gap, if any.
+    fn reconnected(&mut self, metadata: Option<Metadata>, gap: Option<Duration>) {
+        self.accumulator.restart(metadata.as_ref());
+        let Some(duration) = gap else {
+            return;
+        };
+        tracing::warn!("Serial port was disconnected for {duration:.3?}, samples were lost");
+        let lost = self.clock.samples_in(duration);
+        self.events
+            .emit(MeasurementEvent::Reconnected(Disconnection {
+                sample: self.sample_index,
+                time: self.clock.time_at(self.sample_index as u64),
+                duration,
+            }));
+        self.sample_index += lost;
+        self.segment.add_missed(lost);
+        self.accumulator.add_missed(lost);
+    }
+
+    /// Close the last segment.
+    fn finish(mut self) {
+        if self.auto_zero.as_ref().is_some_and(|z| z.in_window()) {
+            // Don't leave the device powered off by auto-zero
+            if let Err(e) = self.restore_power() {
+                tracing::warn!("Failed to restore device power: {e:?}");
+            }
+        }
+        self.events
+            .emit(MeasurementEvent::SegmentEnd(self.segment.finish()));
+    }
+
+    fn send(&mut self, command: Command) -> Result<()> {
+        #[cfg(feature = "serial")]
+        if let Some(control) = &self.control {
+            control.send(command)?;
+        }
+        #[cfg(not(feature = "serial"))]
+        let _ = command;
+        Ok(())
+    }
+
+    /// Whether the user has device power enabled. Always true when replaying.
+    fn powered(&self) -> bool {
+        #[cfg(feature = "serial")]
+        if let Some(control) = &self.control {
+            return control.device_power() == DevicePower::Enabled;
+        }
+        true
+    }
+
+    /// Restore the device power set by the user, after auto-zero disabled it.
+    fn restore_power(&mut self) -> Result<()> {
+        #[cfg(feature = "serial")]
+        if let Some(control) = &self.control {
+            control.restore_device_power()?;
+        }
+        Ok(())
+    }
+
+    /// Parse and process raw bytes, a sample at a time, so combined
+    /// measurements are produced exactly as if read sample by sample.
+    fn feed(&mut self, bytes: &[u8]) -> Result<()> {
+        self.counters.add_parsed_bytes(bytes.len());
+        bytes
+            .chunks(SAMPLE_SIZE)
+            .try_for_each(|sample| self.feed_sample(sample))
+    }
+
+    fn feed_sample(&mut self, bytes: &[u8]) -> Result<()> {
+        let prev_len = self.measurement_buf.len();
+        let new_missed = if self.strict {
+            self.accumulator
+                .try_feed_into(bytes, &mut self.measurement_buf)?
+        } else {
+            self.accumulator.feed_into(bytes, &mut self.measurement_buf)
+        };
+        let new_missed = new_missed + self.auto_zero(prev_len, new_missed)?;
+        self.missed += new_missed;
+        let len = self.measurement_buf.len();
+        let received = || {
+            self.measurement_buf
+                .range(prev_len..)
+                .filter(|m| !m.synthetic)
+        };
+
+        if let Some(detector) = &mut self.reset_detector {
+            for (i, m) in received().enumerate() {
+                if let Some(sample) =
+                    detector.feed(self.sample_index + new_missed + i, m.micro_amps)
+                {
+                    self.events.emit(MeasurementEvent::SuspectedDutReset {
+                        sample,
+                        time: self.clock.time_at(sample as u64),
+                    });
+                }
+            }
+        }
+
+        if let Some(tags) = &self.tags {
+            let mut tags = tags.lock().unwrap();
+            for (i, m) in received().enumerate() {
+                if let Some((from, to)) = tags.add(m) {
+                    self.events.emit(MeasurementEvent::TagChange {
+                        sample: self.sample_index + new_missed + i,
+                        from,
+                        to,
+                    });
+                }
+            }
+        }
+
+        if let Some(last) = &mut self.logic_edges {
+            for (i, m) in received().enumerate() {
+                match *last {
+                    Some(from) if u8::from(from) != u8::from(m.pins) => {
+                        self.events.emit(MeasurementEvent::LogicEdge {
+                            sample: self.sample_index + new_missed + i,
+                            from,
+                            to: m.pins,
+                        });
+                    }
+                    _ => {}
+                }
+                *last = Some(m.pins);
+            }
+        }
+
+        let new_samples = new_missed + received().count();
+        self.sample_index += new_samples;
+        self.counters.add_samples(new_samples);
+
+        if let Some(trigger) = &mut self.trigger {
+            // Index of the first new measurement, which are followed only
+            // by missed samples that were not interpolated
+            let first = self.sample_index - (len - prev_len);
+            for (i, m) in self.measurement_buf.range(prev_len..).enumerate() {
+                if let Some(capture) = trigger.feed(first + i, m) {
+                    self.captures.emit(capture);
+                }
+            }
+        }
+        self.segment.add_missed(new_missed);
+        self.measurement_buf
+            .range(prev_len..)
+            .for_each(|m| self.segment.add(m));
+
+        if let Some(model) = self.ir_drop {
+            for m in received() {
+                self.ir_drop_sum += m.micro_amps;
+                self.ir_drop_count += 1;
+            }
+            if self.ir_drop_count > 0 && self.ir_drop_last_update.elapsed() >= model.interval() {
+                let avg = self.ir_drop_sum / self.ir_drop_count as f32;
+                let vdd = model.source_voltage_at(avg);
+                tracing::trace!("Emulating IR drop: {avg:.2} µA -> {vdd:?}");
+                self.send(Command::RegulatorSet(vdd))?;
+                self.events.emit(MeasurementEvent::IrDrop(IrDropUpdate {
+                    model,
+                    sample: self.sample_index,
+                    time: self.clock.time_at(self.sample_index as u64),
+                    micro_amps: avg,
+                    vdd,
+                }));
+                self.ir_drop_sum = 0.;
+                self.ir_drop_count = 0;
+                self.ir_drop_last_update = Instant::now();
+            }
+        }
+
+        self.history.extend(self.measurement_buf.range(prev_len..));
+        self.chunk_timer.received(len - prev_len);
+        self.chunk_timer.advance(new_samples);
+        if self.chunk_timer.should_flush(len) {
+            self.chunk_timer.flushed();
+            self.counters.add_chunk(len + self.missed, self.missed);
+            self.charge
+                .lock()
+                .unwrap()
+                .extend(self.measurement_buf.iter());
+            let measurements = self.measurement_buf.drain(..);
+            let measurement = if self.current_only {
+                measurements.combine_current()
+            } else if self.complement.has_subscribers() {
+                let (measurement, complement) =
+                    measurements.combine_split(self.pins, self.pin_vote);
+                self.complement.emit(complement);
+                measurement
+            } else {
+                measurements.combine_matching_with(self.pins, self.pin_vote)
+            };
+            if let (Some(limit), MeasurementMatch::Match(m)) = (self.overcurrent, &measurement) {
+                let above = m.micro_amps > limit;
+                if above && !self.over_limit {
+                    self.events.emit(MeasurementEvent::Overcurrent {
+                        sample: self.sample_index,
+                        micro_amps: m.micro_amps,
+                    });
+                }
+                self.over_limit = above;
+            }
+            let sent = self.meas_tx.send(measurement);
+            if !matches!(sent, Ok(true)) {
+                self.counters.add_send_failure();
+            }
+            sent?;
+            self.missed = 0;
+        }
+        Ok(())
+    }
+
+    /// Apply the auto-zero offset to the new measurements, and drop the ones
+    /// in a reference window. Returns the number of dropped device samples,
+    /// which count as missed.
+    fn auto_zero(&mut self, prev_len: usize, new_missed: usize) -> Result<usize> {
+        let powered = self.powered();
+        let Some(zero) = &mut self.auto_zero else {
+            return Ok(0);
+        };
+        let new: Vec<_> = self.measurement_buf.drain(prev_len..).collect();
+        let received = new.iter().filter(|m| !m.synthetic).count();
+        // Index of the first new measurement, see the software trigger
+        let first = self.sample_index + new_missed + received - new.len();
+        let mut dropped = 0;
+        // Whether each window started (true) or ended (false)
+        let mut power_offs = Vec::new();
+        for (i, mut m) in new.into_iter().enumerate() {
+            match zero.feed(first + i, m.micro_amps, powered) {
+                ZeroStep::Keep(micro_amps) => {
+                    m.micro_amps = micro_amps;
+                    self.measurement_buf.push_back(m);
+                    continue;
+                }
+                ZeroStep::Drop => {}
+                ZeroStep::PowerOff => power_offs.push(true),
+                ZeroStep::Restore(correction) => {
+                    tracing::debug!("Auto-zero offset: {:.3} µA", correction.offset);
+                    power_offs.push(false);
+                    self.events.emit(MeasurementEvent::AutoZero(correction));
+                }
+            }
+            if !m.synthetic {
+                dropped += 1;
+            }
+        }
+        for power_off in power_offs {
+            if power_off {
+                self.send(Command::DeviceRunningSet(DevicePower::Disabled))?;
+            } else {
+                self.restore_power()?;
+            }
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "serial")]
+    pub fn test_restart() {
+        use std::{sync::Arc, time::Duration};
+
+        use serialport::FlowControl;
+
+        use super::{open_port, restart, DeviceSetup};
+        use crate::{
+            cmd::Command,
+            mock::MockPpk2,
+            types::{DevicePower, MeasurementMode, MeasurementRange, SourceVoltage},
+            ControlHandle, Ppk2,
+        };
+
+        let mock = MockPpk2::new();
+        let mut ppk2 = Ppk2::with_port(Box::new(mock.clone()), MeasurementMode::Source).unwrap();
+        let vdd = SourceVoltage::from_millivolts(1800);
+        ppk2.set_source_voltage(vdd).unwrap();
+        ppk2.set_device_power(DevicePower::Enabled).unwrap();
+        let control = ControlHandle::new(&ppk2).unwrap();
+        let setup = DeviceSetup {
+            timeout: Duration::from_millis(500),
+            flow_control: FlowControl::Hardware,
+            open: Arc::new(open_port),
+            mode: MeasurementMode::Source,
+            range: Some(MeasurementRange::Range3),
+            switch_points: None,
+            spike_filtering: Some(false),
+            user_gains: [1., 1., 1.5, 1., 1.],
+            hardware_averages: 4,
+        };
+
+        let before = mock.commands().len();
+        let (_port, metadata) = restart(Box::new(mock.clone()), &setup, &control).unwrap();
+        assert_eq!(metadata.unwrap().modifiers.ug[2], 1.5);
+        let sent = &mock.commands()[before..];
+        let bytes = |command: Command| Vec::from_iter(command.bytes());
+        for command in [
+            Command::SetPowerMode(MeasurementMode::Source),
+            Command::RegulatorSet(vdd),
+            Command::SetUserGains(MeasurementRange::Range2, 1.5),
+            Command::RangeSet(MeasurementRange::Range3),
+            Command::SpikeFilteringOff,
+            Command::DeviceRunningSet(DevicePower::Enabled),
+            Command::AvgNumSet(4),
+        ] {
+            let command = bytes(command);
+            assert!(sent.contains(&command), "{command:x?} not sent");
+        }
+        // Only the gain that differs from the metadata is set
+        assert_eq!(sent.iter().filter(|c| c[0] == 0x25).count(), 1);
+        assert_eq!(sent.last().unwrap(), &bytes(Command::AverageStart));
+    }
+}
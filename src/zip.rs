@@ -0,0 +1,569 @@
+//! Minimal ZIP archive support for the [crate::ppk2_file] format: a writer
+//! of stored, uncompressed entries, and a reader of stored and deflated
+//! entries.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Local file header signature
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+/// Central directory file header signature
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+/// End of central directory record signature
+const END_OF_CENTRAL_DIR: u32 = 0x0605_4b50;
+/// Version needed to extract stored entries: 2.0
+const VERSION: u16 = 20;
+/// 1980-01-01, the earliest date the format can represent
+const DOS_DATE: u16 = 0x21;
+
+/// CRC-32 as used by ZIP, with the reflected polynomial 0xEDB88320.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes entries to a ZIP archive, without compression. Sizes are limited
+/// to 4 GiB, as ZIP64 is not supported.
+pub(crate) struct ZipWriter<W: Write> {
+    writer: W,
+    offset: u32,
+    entries: Vec<Entry>,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid ZIP archive: {what}"),
+    )
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "ZIP archive exceeds 4 GiB")
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.offset = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|n| self.offset.checked_add(n))
+            .ok_or_else(too_large)?;
+        Ok(())
+    }
+
+    /// Add an entry with the passed name and contents.
+    pub(crate) fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let entry = Entry {
+            name: name.to_owned(),
+            crc: crc32(data),
+            size: u32::try_from(data.len()).map_err(|_| too_large())?,
+            offset: self.offset,
+        };
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(VERSION.to_le_bytes());
+        // Flags, method (stored) and time
+        header.extend([0; 6]);
+        header.extend(DOS_DATE.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        // Compressed and uncompressed size
+        header.extend(entry.size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        // Extra field length
+        header.extend([0; 2]);
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory, and return the inner writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let start = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            let mut header = Vec::with_capacity(46 + entry.name.len());
+            header.extend(CENTRAL_HEADER.to_le_bytes());
+            // Version made by and needed
+            header.extend(VERSION.to_le_bytes());
+            header.extend(VERSION.to_le_bytes());
+            // Flags, method (stored) and time
+            header.extend([0; 6]);
+            header.extend(DOS_DATE.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            // Extra field and comment length, disk number,
+            // internal and external attributes
+            header.extend([0; 12]);
+            header.extend(entry.offset.to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            self.write(&header)?;
+        }
+        let size = self.offset - start;
+        let count = entries.len() as u16;
+        let mut end = Vec::with_capacity(22);
+        end.extend(END_OF_CENTRAL_DIR.to_le_bytes());
+        // Disk numbers
+        end.extend([0; 4]);
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(start.to_le_bytes());
+        // Comment length
+        end.extend([0; 2]);
+        self.write(&end)?;
+        Ok(self.writer)
+    }
+}
+
+/// Compression method of stored entries
+const STORED: u16 = 0;
+/// Compression method of deflated entries
+const DEFLATED: u16 = 8;
+
+fn u16_at(data: &[u8], at: usize) -> io::Result<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+fn u32_at(data: &[u8], at: usize) -> io::Result<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+/// Central directory header of an entry.
+struct CentralHeader {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed: usize,
+    size: usize,
+    /// Offset of the local header
+    offset: usize,
+}
+
+/// Find the end of central directory record, which is followed
+/// by a comment of at most 64 KiB.
+fn find_end(data: &[u8]) -> io::Result<usize> {
+    (0..data.len().saturating_sub(21))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|&i| u32_at(data, i).ok() == Some(END_OF_CENTRAL_DIR))
+        .ok_or_else(|| invalid("no end of central directory"))
+}
+
+/// Parse the central directory header at `at`, and advance `at` past it.
+fn central_header(dir: &[u8], at: &mut usize) -> io::Result<CentralHeader> {
+    if u32_at(dir, *at)? != CENTRAL_HEADER {
+        return Err(invalid("bad central directory header"));
+    }
+    let name_len = u16_at(dir, *at + 28)? as usize;
+    let extra_len = u16_at(dir, *at + 30)? as usize;
+    let comment_len = u16_at(dir, *at + 32)? as usize;
+    let name = dir
+        .get(*at + 46..*at + 46 + name_len)
+        .ok_or_else(|| invalid("truncated"))?;
+    let header = CentralHeader {
+        name: String::from_utf8_lossy(name).into_owned(),
+        method: u16_at(dir, *at + 10)?,
+        crc: u32_at(dir, *at + 16)?,
+        compressed: u32_at(dir, *at + 20)? as usize,
+        size: u32_at(dir, *at + 24)? as usize,
+        offset: u32_at(dir, *at + 42)? as usize,
+    };
+    *at += 46 + name_len + extra_len + comment_len;
+    Ok(header)
+}
+
+/// Offset of the data of an entry from its local header.
+fn data_offset(local: &[u8]) -> io::Result<usize> {
+    if u32_at(local, 0)? != LOCAL_HEADER {
+        return Err(invalid("bad local header"));
+    }
+    Ok(30 + u16_at(local, 26)? as usize + u16_at(local, 28)? as usize)
+}
+
+/// Decompress and check the raw data of an entry.
+fn decode(header: &CentralHeader, raw: &[u8]) -> io::Result<Vec<u8>> {
+    let contents = match header.method {
+        STORED => raw.to_vec(),
+        DEFLATED => inflate(raw, header.size)?,
+        _ => return Err(invalid("unsupported compression method")),
+    };
+    if contents.len() != header.size || crc32(&contents) != header.crc {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(contents)
+}
+
+/// Read all entries of a ZIP archive, as pairs of name and contents.
+/// Only stored and deflated entries are supported, and no ZIP64.
+pub(crate) fn read_entries(data: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let end = find_end(data)?;
+    let count = u16_at(data, end + 10)?;
+    let mut at = u32_at(data, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let header = central_header(data, &mut at)?;
+        let local = data
+            .get(header.offset..)
+            .ok_or_else(|| invalid("truncated"))?;
+        let start = header.offset + data_offset(local)?;
+        let raw = data
+            .get(start..start + header.compressed)
+            .ok_or_else(|| invalid("truncated"))?;
+        let contents = decode(&header, raw)?;
+        entries.push((header.name, contents));
+    }
+    Ok(entries)
+}
+
+/// Read the entry with the passed name from a ZIP archive, reading only
+/// the central directory and that entry. Returns [None] if there is no
+/// such entry.
+pub(crate) fn read_entry(mut reader: impl Read + Seek, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(22 + u64::from(u16::MAX));
+    reader.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+    let end = find_end(&tail)?;
+    let count = u16_at(&tail, end + 10)?;
+    let dir_len = u32_at(&tail, end + 12)?;
+    // Don't allocate for sizes of corrupt files
+    if u64::from(dir_len) > len {
+        return Err(invalid("truncated"));
+    }
+    let mut dir = vec![0; dir_len as usize];
+    reader.seek(SeekFrom::Start(u32_at(&tail, end + 16)?.into()))?;
+    reader.read_exact(&mut dir)?;
+
+    let mut at = 0;
+    for _ in 0..count {
+        let header = central_header(&dir, &mut at)?;
+        if header.name != name {
+            continue;
+        }
+        let mut local = [0; 30];
+        reader.seek(SeekFrom::Start(header.offset as u64))?;
+        reader.read_exact(&mut local)?;
+        let start = header.offset + data_offset(&local)?;
+        if (start + header.compressed) as u64 > len {
+            return Err(invalid("truncated"));
+        }
+        let mut raw = vec![0; header.compressed];
+        reader.seek(SeekFrom::Start(start as u64))?;
+        reader.read_exact(&mut raw)?;
+        return decode(&header, &raw).map(Some);
+    }
+    Ok(None)
+}
+
+/// Canonical Huffman code, decoded as in zlib's `puff`.
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        lengths.iter().for_each(|&l| counts[l as usize] += 1);
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        (0..n).try_fold(0, |value, i| {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or_else(|| invalid("truncated deflate stream"))?;
+            let bit = (byte >> (self.pos % 8)) & 1;
+            self.pos += 1;
+            Ok(value | u32::from(bit) << i)
+        })
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0usize);
+        for &count in &huffman.counts[1..] {
+            code |= self.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(huffman.symbols[index + (code - first) as usize]);
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the code length code lengths are sent
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompress a raw DEFLATE stream, as specified in RFC 1951. Fails if
+/// it decompresses to more than `size` bytes, the size the entry should
+/// have, so corrupt or malicious files can't exhaust the memory.
+pub(crate) fn inflate(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size.min(1 << 20));
+    let mut bits = BitReader { data, pos: 0 };
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                // Stored block, starting at the next byte boundary
+                let at = bits.pos.div_ceil(8);
+                let len = u16_at(data, at)?;
+                if u16_at(data, at + 2)? != !len {
+                    return Err(invalid("bad stored block length"));
+                }
+                let len = len as usize;
+                let block = data
+                    .get(at + 4..at + 4 + len)
+                    .ok_or_else(|| invalid("truncated stored block"))?;
+                out.extend_from_slice(block);
+                bits.pos = (at + 4 + len) * 8;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut bits, &mut out, &literals, &distances, size)?;
+            }
+            2 => {
+                let n_literals = bits.bits(5)? as usize + 257;
+                let n_distances = bits.bits(5)? as usize + 1;
+                let n_code_lengths = bits.bits(4)? as usize + 4;
+                let mut code_lengths = [0u8; 19];
+                for &i in &CODE_LENGTH_ORDER[..n_code_lengths] {
+                    code_lengths[i] = bits.bits(3)? as u8;
+                }
+                let code_lengths = Huffman::new(&code_lengths);
+
+                let mut lengths = Vec::with_capacity(n_literals + n_distances);
+                while lengths.len() < n_literals + n_distances {
+                    let (len, repeat) = match bits.decode(&code_lengths)? {
+                        symbol @ 0..=15 => (symbol as u8, 1),
+                        16 => {
+                            let prev = *lengths.last().ok_or_else(|| invalid("bad lengths"))?;
+                            (prev, 3 + bits.bits(2)?)
+                        }
+                        17 => (0, 3 + bits.bits(3)?),
+                        _ => (0, 11 + bits.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(len, repeat as usize));
+                }
+                if lengths.len() > n_literals + n_distances {
+                    return Err(invalid("bad lengths"));
+                }
+                let literals = Huffman::new(&lengths[..n_literals]);
+                let distances = Huffman::new(&lengths[n_literals..]);
+                inflate_block(&mut bits, &mut out, &literals, &distances, size)?;
+            }
+            _ => return Err(invalid("bad block type")),
+        }
+        if out.len() > size {
+            return Err(invalid("entry larger than its size"));
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn inflate_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    size: usize,
+) -> io::Result<()> {
+    loop {
+        if out.len() > size {
+            return Err(invalid("entry larger than its size"));
+        }
+        let symbol = bits.decode(literals)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                let len = *LENGTH_BASE.get(i).ok_or_else(|| invalid("bad length"))? as usize
+                    + bits.bits(u32::from(LENGTH_EXTRA[i]))? as usize;
+                let i = bits.decode(distances)? as usize;
+                let dist = *DIST_BASE.get(i).ok_or_else(|| invalid("bad distance"))? as usize
+                    + bits.bits(u32::from(DIST_EXTRA[i]))? as usize;
+                let start = out
+                    .len()
+                    .checked_sub(dist)
+                    .ok_or_else(|| invalid("distance too far back"))?;
+                // The copy may overlap the bytes it produces
+                (start..start + len).for_each(|i| out.push(out[i]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{crc32, inflate, read_entries, read_entry, ZipWriter};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// A ZIP archive made with Python's zipfile, with a stored `session.raw`
+    /// and a deflated `metadata.json`
+    const ARCHIVE: &str = "504b0304140000000000000021003c2ea60d28000000280000000b00000073657373696f6e2e726177000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f2021222324252627504b0304140000000800000021007b681a99550000006b0000000d0000006d657461646174612e6a736f6eabe6525050ca4d2d494c492c4954b252a806f28122c589b90539a9c501a945c1a9c9f979294019430310d081c81701458b5232f3d25d4a8b124b32f3f3a00a80b2b520254a69f945b9892561a945c5104923ae5a00504b01021403140000000000000021003c2ea60d28000000280000000b000000000000000000000080010000000073657373696f6e2e726177504b01021403140000000800000021007b681a99550000006b0000000d00000000000000000000008001510000006d657461646174612e6a736f6e504b0506000000000200020074000000d10000000000";
+
+    #[test]
+    pub fn test_inflate() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        // Raw DEFLATE streams with fixed and dynamic Huffman codes, made with zlib
+        let hex = |s: &str| -> Vec<u8> {
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                .collect()
+        };
+        let fixed =
+            hex("0bc94855482e2d2a4acd2b51c82c5630d43355c875d4530819151e7ac28949c929a969e919995900");
+        let mut expected = b"The current is 1.5 mA. ".repeat(20);
+        expected.extend(b"abcdefghij");
+        assert_eq!(inflate(&fixed, expected.len()).unwrap(), expected);
+        let dynamic = hex("ddccc90dc0300c03c15a29518765baff6f1c205564df830560ceec9145cb6b7b9f54ec506251cd334568ba32788bac6bc19ac316173e7cda77b93aecdaa41bdefeb57e00");
+        let expected: Vec<u8> = (0..300u32).map(|i| ((i * i / 7) % 13 + 97) as u8).collect();
+        assert_eq!(inflate(&dynamic, expected.len()).unwrap(), expected);
+
+        // A stored block, and a corrupt copy of its length
+        let stored = hex("010c00f3ff73746f72656420626c6f636b");
+        assert_eq!(inflate(&stored, 12).unwrap(), b"stored block");
+        let mut corrupt = stored.clone();
+        corrupt[3] ^= 1;
+        assert!(inflate(&corrupt, 12).is_err());
+        // Larger than the entry should be
+        assert!(inflate(&stored, 11).is_err());
+        assert!(inflate(&fixed, 100).is_err());
+        for len in 0..fixed.len() {
+            assert!(inflate(&fixed[..len], 1000).is_err());
+        }
+        // Block type 3 doesn't exist
+        assert!(inflate(&[0b111], 0).is_err());
+    }
+
+    #[test]
+    pub fn test_read_entries() {
+        let archive = hex(ARCHIVE);
+        let entries = read_entries(&archive).unwrap();
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["session.raw", "metadata.json"]);
+        assert_eq!(entries[0].1, (0..40).collect::<Vec<u8>>());
+        let metadata = String::from_utf8(entries[1].1.clone()).unwrap();
+        assert!(metadata.contains("\"samplesPerSecond\": 100000"));
+        let entry = read_entry(Cursor::new(&archive), "metadata.json").unwrap();
+        assert_eq!(entry.as_ref(), Some(&entries[1].1));
+        assert_eq!(read_entry(Cursor::new(&archive), "other").unwrap(), None);
+
+        // The writer's archives read back
+        let mut writer = ZipWriter::new(Vec::new());
+        writer.add("a.txt", b"a").unwrap();
+        let written = writer.finish().unwrap();
+        assert_eq!(
+            read_entries(&written).unwrap(),
+            [("a.txt".into(), b"a".to_vec())]
+        );
+    }
+
+    #[test]
+    pub fn test_read_corrupt() {
+        let archive = hex(ARCHIVE);
+        // Truncated and corrupt archives fail, rather than panic
+        for len in 0..archive.len() {
+            assert!(read_entries(&archive[..len]).is_err());
+            assert!(read_entry(Cursor::new(&archive[..len]), "metadata.json").is_err());
+        }
+        for i in 0..archive.len() {
+            for flip in [0x01, 0x80, 0xFF] {
+                let mut corrupt = archive.clone();
+                corrupt[i] ^= flip;
+                let _ = read_entries(&corrupt);
+                let _ = read_entry(Cursor::new(&corrupt), "metadata.json");
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Hardware-in-the-loop tests, run against a PPK2 connected to the serial
+//! port set in the `PPK2_PORT` environment variable:
+//!
+//! ```sh
+//! PPK2_PORT=/dev/ttyACM0 cargo test --test hardware -- --ignored
+//! ```
+//!
+//! The tests use source meter mode, and don't require a device under test.
+//! They share the PPK2, so they are serialized.
+
+use std::{
+    env,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use ppk2::{
+    list_ppk2_devices,
+    measurement::{MeasurementMatch, MeasurementOptions},
+    types::{DevicePower, MeasurementMode, SampleRate, SourceVoltage},
+    watch_for_ppk2, Ppk2, Result,
+};
+
+static PPK2: Mutex<()> = Mutex::new(());
+
+/// Open the PPK2 at `PPK2_PORT`, holding a guard so only one test uses it.
+fn open() -> Result<(MutexGuard<'static, ()>, Ppk2)> {
+    let guard = PPK2.lock().unwrap_or_else(|e| e.into_inner());
+    let port = env::var("PPK2_PORT").expect("Set PPK2_PORT to the serial port of the PPK2");
+    let mut ppk2 = Ppk2::new(port, MeasurementMode::Source)?;
+    ppk2.set_source_voltage(SourceVoltage::from_millivolts(3000))?;
+    ppk2.set_device_power(DevicePower::Enabled)?;
+    Ok((guard, ppk2))
+}
+
+/// Measure for `duration`, returning the number of combined measurements.
+fn capture(ppk2: Ppk2, sps: usize, duration: Duration) -> Result<(Ppk2, usize)> {
+    let options = MeasurementOptions::new(SampleRate::per_second(sps)?);
+    let (rx, handle) = ppk2.start_measurement_with(options)?;
+    let start = Instant::now();
+    let mut count = 0;
+    while start.elapsed() < duration {
+        if let Ok(MeasurementMatch::Match(m)) = rx.recv_timeout(Duration::from_millis(100)) {
+            assert!(m.micro_amps.is_finite());
+            count += 1;
+        }
+    }
+    Ok((handle.stop()?, count))
+}
+
+#[test]
+#[ignore = "requires a PPK2 at PPK2_PORT"]
+pub fn test_connect_metadata() -> Result<()> {
+    let (_guard, mut ppk2) = open()?;
+    assert_eq!(ppk2.measurement_mode(), MeasurementMode::Source);
+    assert!(ppk2.calibration_warnings().is_empty());
+    let metadata = ppk2.get_metadata()?;
+    assert_eq!(&metadata, ppk2.metadata());
+    Ok(())
+}
+
+#[test]
+#[ignore = "requires a PPK2 at PPK2_PORT"]
+pub fn test_list_devices() -> Result<()> {
+    let (_guard, ppk2) = open()?;
+    let devices = list_ppk2_devices()?;
+    let device = devices
+        .iter()
+        .find(|d| Some(d.port_name.as_str()) == env::var("PPK2_PORT").ok().as_deref())
+        .expect("PPK2_PORT is listed");
+    assert_eq!(device.serial_number.as_deref(), ppk2.serial_number());
+    assert!(watch_for_ppk2(Some(Duration::ZERO)).is_ok());
+    Ok(())
+}
+
+#[test]
+#[ignore = "requires a PPK2 at PPK2_PORT"]
+pub fn test_source_voltage() -> Result<()> {
+    let (_guard, mut ppk2) = open()?;
+    for mv in [1800, 3300, 5000] {
+        let vdd = SourceVoltage::from_millivolts(mv);
+        ppk2.set_source_voltage(vdd)?;
+        assert_eq!(ppk2.source_voltage(), Some(vdd));
+        let reported = ppk2.get_metadata()?.vdd;
+        assert!(reported.abs_diff(mv) <= 50, "{reported} mV set as {mv} mV");
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore = "requires a PPK2 at PPK2_PORT"]
+pub fn test_capture_rates() -> Result<()> {
+    let (_guard, mut ppk2) = open()?;
+    for sps in [10, 100, 1000, 10_000] {
+        let count;
+        (ppk2, count) = capture(ppk2, sps, Duration::from_secs(1))?;
+        let expected = sps as f64;
+        assert!(
+            (0.8 * expected..=1.2 * expected).contains(&(count as f64)),
+            "{count} measurements in a second at {sps} sps"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore = "requires a PPK2 at PPK2_PORT"]
+pub fn test_stop_restart() -> Result<()> {
+    let (_guard, mut ppk2) = open()?;
+    for _ in 0..3 {
+        let count;
+        (ppk2, count) = capture(ppk2, 100, Duration::from_millis(500))?;
+        assert!(count > 0);
+    }
+
+    let (rx, handle) = ppk2.restart_measurement()?;
+    rx.recv_timeout(Duration::from_secs(1))
+        .expect("No measurements after restarting");
+    let ppk2 = handle.stop()?;
+    assert!(ppk2.last_measurement_options().is_some());
+    Ok(())
+}